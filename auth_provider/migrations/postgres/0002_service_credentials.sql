@@ -0,0 +1,9 @@
+create table service_credentials (
+    credential_id TEXT primary key,
+    name TEXT not null,
+    secret_hash BYTEA not null,
+    -- comma separated list of scopes
+    scopes TEXT not null,
+    created_at TIMESTAMPTZ not null,
+    rotated_at TIMESTAMPTZ
+);
@@ -0,0 +1,6 @@
+-- join tickets are verified by signature, this only exists to stop them being redeemed twice
+create table redeemed_join_tickets (
+    ticket_id TEXT primary key,
+    -- rows can be dropped once the ticket would have expired anyway
+    expires_at TIMESTAMPTZ not null
+);
@@ -0,0 +1,3 @@
+alter table users add column is_guest BOOLEAN not null default false;
+-- only kept up to date for guests, who are deleted after a period of inactivity
+alter table users add column last_seen_at TIMESTAMPTZ;
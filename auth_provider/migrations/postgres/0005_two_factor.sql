@@ -0,0 +1,19 @@
+create table user_roles (
+    user_id TEXT references users(user_id) not null,
+    role TEXT not null,
+    primary key (user_id, role)
+);
+
+create table totp_credentials (
+    user_id TEXT primary key references users(user_id),
+    secret BYTEA not null,
+    -- null until the first code has been confirmed
+    enabled_at TIMESTAMPTZ,
+    last_used_step BIGINT
+);
+
+create table recovery_codes (
+    user_id TEXT references users(user_id) not null,
+    code_hash BYTEA not null,
+    primary key (user_id, code_hash)
+);
@@ -0,0 +1,12 @@
+create table passkey_credentials (
+    credential_id BYTEA primary key,
+    user_id TEXT references users(user_id) not null,
+    name TEXT not null,
+    -- COSE_Key encoded
+    public_key BYTEA not null,
+    sign_count BIGINT not null,
+    created_at TIMESTAMPTZ not null,
+    last_used_at TIMESTAMPTZ
+);
+
+create index passkey_credentials_user_id on passkey_credentials (user_id);
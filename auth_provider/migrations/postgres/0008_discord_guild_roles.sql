@@ -0,0 +1,5 @@
+-- roles granted through the discord role mapping are replaced whenever the member is re-checked
+alter table user_roles add column granted_by_discord BOOLEAN not null default false;
+
+-- null for links made before tokens were refreshed
+alter table discord_oauth_users add column refreshed_at TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- hash of the cookie in the browser that asked for the link, null for links sent before
+alter table email_login_codes add column browser_hash BYTEA;
@@ -0,0 +1,9 @@
+create table service_credentials (
+    credential_id TEXT primary key,
+    name TEXT not null,
+    secret_hash BLOB not null,
+    -- comma separated list of scopes
+    scopes TEXT not null,
+    created_at TEXT not null,
+    rotated_at TEXT
+);
@@ -0,0 +1,12 @@
+create table passkey_credentials (
+    credential_id BLOB primary key,
+    user_id TEXT references users(user_id) not null,
+    name TEXT not null,
+    -- COSE_Key encoded
+    public_key BLOB not null,
+    sign_count INTEGER not null,
+    created_at TEXT not null,
+    last_used_at TEXT
+);
+
+create index passkey_credentials_user_id on passkey_credentials (user_id);
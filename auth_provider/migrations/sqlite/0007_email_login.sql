@@ -0,0 +1,17 @@
+create table email_users (
+    -- normalized to lowercase
+    email TEXT primary key,
+    user_id TEXT references users(user_id) not null,
+    created_at TEXT not null
+);
+
+create index email_users_user_id on email_users (user_id);
+
+-- only the hash of the code sent in the link is stored
+create table email_login_codes (
+    code_hash BLOB primary key,
+    email TEXT not null,
+    -- not a foreign key, the guest may be garbage collected before the link is used
+    upgrade_user_id TEXT,
+    expires_at TEXT not null
+);
@@ -0,0 +1,413 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    caller::ServiceCaller,
+    database::{
+        models::{Role, ServiceCredential, ServiceCredentialId, ServiceScope, UserId},
+        repository::StoreError,
+    },
+    WebState,
+};
+
+pub fn routes() -> Router<WebState> {
+    Router::new()
+        .route(
+            "/service-credentials",
+            get(list_service_credentials).post(create_service_credential),
+        )
+        .route(
+            "/service-credentials/:credential_id",
+            delete(revoke_service_credential),
+        )
+        .route(
+            "/service-credentials/:credential_id/rotate",
+            post(rotate_service_credential),
+        )
+        .route("/users/:user_id/roles", get(list_user_roles))
+        .route(
+            "/users/:user_id/roles/:role",
+            put(grant_user_role).delete(revoke_user_role),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateServiceCredentialRequest {
+    name: String,
+    scopes: Vec<ServiceScope>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateServiceCredentialResponse {
+    credential: ServiceCredential,
+    /// Only ever shown once.
+    key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RotateServiceCredentialResponse {
+    key: String,
+}
+
+async fn list_service_credentials(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+) -> Result<Json<Vec<ServiceCredential>>, Response> {
+    caller
+        .require(ServiceScope::ManageCredentials)
+        .map_err(IntoResponse::into_response)?;
+
+    let credentials = state
+        .database
+        .list_service_credentials()
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(credentials))
+}
+
+async fn create_service_credential(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Json(request): Json<CreateServiceCredentialRequest>,
+) -> Result<impl IntoResponse, Response> {
+    caller
+        .require(ServiceScope::ManageCredentials)
+        .map_err(IntoResponse::into_response)?;
+
+    let (credential, key) = state
+        .database
+        .create_service_credential(&request.name, &request.scopes)
+        .await
+        .map_err(internal_error)?;
+
+    info!(
+        issued_by = caller.0.credential_id.0,
+        credential_id = credential.credential_id.0,
+        name = credential.name,
+        "issued service credential"
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateServiceCredentialResponse {
+            credential,
+            key: key.to_string(),
+        }),
+    ))
+}
+
+async fn rotate_service_credential(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Path(credential_id): Path<String>,
+) -> Result<Json<RotateServiceCredentialResponse>, Response> {
+    caller
+        .require(ServiceScope::ManageCredentials)
+        .map_err(IntoResponse::into_response)?;
+
+    let key = state
+        .database
+        .rotate_service_credential(&ServiceCredentialId(credential_id))
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    info!(
+        rotated_by = caller.0.credential_id.0,
+        credential_id = key.credential_id.0,
+        "rotated service credential"
+    );
+
+    Ok(Json(RotateServiceCredentialResponse {
+        key: key.to_string(),
+    }))
+}
+
+async fn revoke_service_credential(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Path(credential_id): Path<String>,
+) -> Result<StatusCode, Response> {
+    caller
+        .require(ServiceScope::ManageCredentials)
+        .map_err(IntoResponse::into_response)?;
+
+    let was_revoked = state
+        .database
+        .revoke_service_credential(&ServiceCredentialId(credential_id.clone()))
+        .await
+        .map_err(internal_error)?;
+
+    if !was_revoked {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    info!(
+        revoked_by = caller.0.credential_id.0,
+        credential_id, "revoked service credential"
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_user_roles(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Path(user_id): Path<String>,
+) -> Result<Json<Vec<Role>>, Response> {
+    caller
+        .require(ServiceScope::ManageUsers)
+        .map_err(IntoResponse::into_response)?;
+
+    let user_id = existing_user(&state, user_id).await?;
+
+    let roles = state
+        .database
+        .get_user_roles(&user_id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(roles))
+}
+
+async fn grant_user_role(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Path((user_id, role)): Path<(String, Role)>,
+) -> Result<StatusCode, Response> {
+    caller
+        .require(ServiceScope::ManageUsers)
+        .map_err(IntoResponse::into_response)?;
+
+    let user_id = existing_user(&state, user_id).await?;
+
+    let was_granted = state
+        .database
+        .grant_role(&user_id, role)
+        .await
+        .map_err(internal_error)?;
+
+    if was_granted {
+        info!(
+            granted_by = caller.0.credential_id.0,
+            user_id = user_id.0,
+            role = role.as_str(),
+            "granted role"
+        );
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_user_role(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Path((user_id, role)): Path<(String, Role)>,
+) -> Result<StatusCode, Response> {
+    caller
+        .require(ServiceScope::ManageUsers)
+        .map_err(IntoResponse::into_response)?;
+
+    let user_id = UserId(user_id);
+
+    let was_revoked = state
+        .database
+        .revoke_role(&user_id, role)
+        .await
+        .map_err(internal_error)?;
+
+    if !was_revoked {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    info!(
+        revoked_by = caller.0.credential_id.0,
+        user_id = user_id.0,
+        role = role.as_str(),
+        "revoked role"
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn existing_user(state: &WebState, user_id: String) -> Result<UserId, Response> {
+    let user_id = UserId(user_id);
+
+    if state
+        .database
+        .user_id_exists(&user_id)
+        .await
+        .map_err(internal_error)?
+    {
+        Ok(user_id)
+    } else {
+        Err((StatusCode::NOT_FOUND, "unknown user").into_response())
+    }
+}
+
+fn internal_error(error: StoreError) -> Response {
+    warn!(?error, "database error while handling admin request");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::{header, Request};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::database::models::{ServiceKey, UserKind};
+
+    async fn call(state: &WebState, method: &str, uri: &str, bearer: &str) -> (StatusCode, Value) {
+        let body = match method {
+            "POST" if uri == "/admin/service-credentials" => {
+                r#"{"name": "game server", "scopes": ["game_server"]}"#
+            }
+            _ => "",
+        };
+
+        let response = crate::router(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {bearer}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn key_with(state: &WebState, scopes: &[ServiceScope]) -> ServiceKey {
+        state
+            .database
+            .create_service_credential("admin", scopes)
+            .await
+            .unwrap()
+            .1
+    }
+
+    #[tokio::test]
+    async fn credentials_can_be_issued_rotated_and_revoked() {
+        let state = crate::test_state();
+        let admin = key_with(&state, &[ServiceScope::ManageCredentials])
+            .await
+            .to_string();
+
+        let (status, created) = call(&state, "POST", "/admin/service-credentials", &admin).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["credential"]["scopes"][0], "game_server");
+        let credential_id = created["credential"]["credential_id"].as_str().unwrap();
+        let key = created["key"].as_str().unwrap();
+
+        let (status, me) = call(&state, "GET", "/auth/services/me", key).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["credential_id"], credential_id);
+
+        let (_, listed) = call(&state, "GET", "/admin/service-credentials", &admin).await;
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+
+        let (status, rotated) = call(
+            &state,
+            "POST",
+            &format!("/admin/service-credentials/{credential_id}/rotate"),
+            &admin,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let rotated_key = rotated["key"].as_str().unwrap();
+
+        let (status, _) = call(&state, "GET", "/auth/services/me", key).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&state, "GET", "/auth/services/me", rotated_key).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = format!("/admin/service-credentials/{credential_id}");
+        let (status, _) = call(&state, "DELETE", &uri, &admin).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&state, "DELETE", &uri, &admin).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&state, "GET", "/auth/services/me", rotated_key).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn managing_credentials_needs_the_scope() {
+        let state = crate::test_state();
+        let game_server = key_with(&state, &[ServiceScope::GameServer])
+            .await
+            .to_string();
+        let target = key_with(&state, &[ServiceScope::GameServer]).await;
+        let target_id = &target.credential_id.0;
+
+        for (method, uri) in [
+            ("GET", "/admin/service-credentials".to_string()),
+            ("POST", "/admin/service-credentials".to_string()),
+            (
+                "POST",
+                format!("/admin/service-credentials/{target_id}/rotate"),
+            ),
+            ("DELETE", format!("/admin/service-credentials/{target_id}")),
+        ] {
+            let (status, _) = call(&state, method, &uri, &game_server).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri}");
+        }
+
+        // nothing was changed along the way
+        let (status, _) = call(&state, "GET", "/auth/services/me", &target.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn players_and_unknown_keys_are_not_services() {
+        let state = crate::test_state();
+        let user = state
+            .database
+            .create_new_user(UserKind::Full)
+            .await
+            .unwrap();
+        let token = state
+            .database
+            .create_auth_token(&user.user_id, std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let (status, _) = call(
+            &state,
+            "GET",
+            "/admin/service-credentials",
+            &token.to_hex_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = call(
+            &state,
+            "GET",
+            "/admin/service-credentials",
+            "svc.unknown.00",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
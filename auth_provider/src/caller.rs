@@ -0,0 +1,202 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use http::{header, request::Parts, StatusCode};
+use tracing::warn;
+
+use crate::{
+    database::models::{ServiceCredential, ServiceKey, ServiceScope, Token, User},
+    WebState,
+};
+
+/// Whoever made the request, identified either by a player's auth token (cookie or bearer) or
+/// by a service key (bearer only).
+#[derive(Debug, Clone)]
+pub enum Caller {
+    Player(User),
+    Service(ServiceCredential),
+}
+
+/// Only accepts service callers.
+#[derive(Debug, Clone)]
+pub struct ServiceCaller(pub ServiceCredential);
+
+impl ServiceCaller {
+    pub fn require(&self, scope: ServiceScope) -> Result<(), CallerRejection> {
+        if self.0.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(CallerRejection::MissingScope(scope))
+        }
+    }
+}
+
+/// Only accepts players, including guests.
+#[derive(Debug, Clone)]
+pub struct PlayerCaller(pub User);
+
+/// Only accepts players with a full account, for anything guests aren't trusted with.
+#[derive(Debug, Clone)]
+pub struct FullPlayerCaller(pub User);
+
+#[derive(Debug)]
+pub enum CallerRejection {
+    Unauthenticated,
+    InvalidCredentials,
+    NotAService,
+    NotAPlayer,
+    GuestNotAllowed,
+    MissingScope(ServiceScope),
+    Internal,
+}
+
+impl IntoResponse for CallerRejection {
+    fn into_response(self) -> Response {
+        match self {
+            CallerRejection::Unauthenticated => {
+                (StatusCode::UNAUTHORIZED, "missing credentials").into_response()
+            }
+            CallerRejection::InvalidCredentials => {
+                (StatusCode::UNAUTHORIZED, "invalid or expired credentials").into_response()
+            }
+            CallerRejection::NotAService => {
+                (StatusCode::FORBIDDEN, "only services may call this").into_response()
+            }
+            CallerRejection::NotAPlayer => {
+                (StatusCode::FORBIDDEN, "only players may call this").into_response()
+            }
+            CallerRejection::GuestNotAllowed => (
+                StatusCode::FORBIDDEN,
+                "guests may not call this, link a login provider first",
+            )
+                .into_response(),
+            CallerRejection::MissingScope(scope) => (
+                StatusCode::FORBIDDEN,
+                format!("missing the `{}` scope", scope.as_str()),
+            )
+                .into_response(),
+            CallerRejection::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+/// Finds the caller's player token, preferring the `Authorization` header over the cookie.
+pub fn player_token(parts: &Parts, state: &WebState) -> Option<Token> {
+    match bearer_token(parts) {
+        Some(bearer) => Token::from_hex_string(bearer),
+        None => CookieJar::from_headers(&parts.headers)
+            .get(&state.config.cookie.name)
+            .and_then(|cookie| Token::from_hex_string(cookie.value())),
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+#[async_trait]
+impl FromRequestParts<WebState> for Caller {
+    type Rejection = CallerRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(key) =
+            bearer_token(parts).filter(|bearer| bearer.starts_with(ServiceKey::PREFIX))
+        {
+            let key = ServiceKey::parse(key).ok_or(CallerRejection::InvalidCredentials)?;
+
+            return match state.database.get_service_credential_by_key(&key).await {
+                Ok(Some(credential)) => Ok(Caller::Service(credential)),
+                Ok(None) => Err(CallerRejection::InvalidCredentials),
+                Err(error) => {
+                    warn!(?error, "failed to look up service credential");
+                    Err(CallerRejection::Internal)
+                }
+            };
+        }
+
+        let has_credentials = bearer_token(parts).is_some()
+            || CookieJar::from_headers(&parts.headers)
+                .get(&state.config.cookie.name)
+                .is_some();
+        if !has_credentials {
+            return Err(CallerRejection::Unauthenticated);
+        }
+
+        let token = player_token(parts, state).ok_or(CallerRejection::InvalidCredentials)?;
+
+        match state.database.get_user_by_token(&token).await {
+            Ok(Some(user)) => {
+                if user.is_guest {
+                    if let Err(error) = state.database.touch_user(&user.user_id).await {
+                        warn!(?error, "failed to record guest activity");
+                    }
+                }
+
+                Ok(Caller::Player(user))
+            }
+            Ok(None) => Err(CallerRejection::InvalidCredentials),
+            Err(error) => {
+                warn!(?error, "failed to look up auth token");
+                Err(CallerRejection::Internal)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<WebState> for ServiceCaller {
+    type Rejection = CallerRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebState,
+    ) -> Result<Self, Self::Rejection> {
+        match Caller::from_request_parts(parts, state).await? {
+            Caller::Service(credential) => Ok(ServiceCaller(credential)),
+            Caller::Player(_) => Err(CallerRejection::NotAService),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<WebState> for PlayerCaller {
+    type Rejection = CallerRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebState,
+    ) -> Result<Self, Self::Rejection> {
+        match Caller::from_request_parts(parts, state).await? {
+            Caller::Player(user) => Ok(PlayerCaller(user)),
+            Caller::Service(_) => Err(CallerRejection::NotAPlayer),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<WebState> for FullPlayerCaller {
+    type Rejection = CallerRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebState,
+    ) -> Result<Self, Self::Rejection> {
+        let PlayerCaller(user) = PlayerCaller::from_request_parts(parts, state).await?;
+
+        if user.is_guest {
+            return Err(CallerRejection::GuestNotAllowed);
+        }
+
+        Ok(FullPlayerCaller(user))
+    }
+}
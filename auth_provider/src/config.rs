@@ -0,0 +1,1299 @@
+use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use lettre::message::Mailbox;
+use serde::Deserialize;
+
+use crate::{
+    database::models::{Role, ServiceScope},
+    provider::{
+        discord::{DiscordInfo, GuildGate},
+        email::EmailInfo,
+        passkey::PasskeyInfo,
+    },
+};
+
+const DEFAULT_CONFIG_PATH: &str = "auth_provider.toml";
+const DEFAULT_DATABASE_URL: &str = "./data.db";
+
+/// Account and login service for the game backend.
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to a TOML config file. Defaults to `auth_provider.toml` if it exists.
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: CliOverrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Apply pending migrations and run the server. This is the default.
+    Serve,
+    /// Inspect or apply database migrations without starting the server.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateCommand,
+    },
+    /// Manage service credentials without going through the admin api, e.g. to create the
+    /// first credential with the `manage_credentials` scope.
+    ServiceCredential {
+        #[command(subcommand)]
+        action: ServiceCredentialCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum MigrateCommand {
+    /// Show every known migration and whether it has been applied.
+    Status,
+    /// Apply all pending migrations.
+    Run,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ServiceCredentialCommand {
+    /// Issue a new credential and print its key.
+    Create {
+        #[arg(long)]
+        name: String,
+        /// May be given multiple times.
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<ServiceScope>,
+    },
+    List,
+    /// Replace a credential's secret and print the new key.
+    Rotate {
+        credential_id: String,
+    },
+    Revoke {
+        credential_id: String,
+    },
+}
+
+// every value here takes precedence over both the environment and the config file
+#[derive(Debug, clap::Args)]
+pub struct CliOverrides {
+    #[arg(long)]
+    bind_address: Option<String>,
+    #[arg(long)]
+    admin_bind_address: Option<String>,
+    #[arg(long)]
+    database_url: Option<String>,
+    #[arg(long)]
+    readiness_check_discord: Option<bool>,
+    #[arg(long)]
+    public_base_url: Option<String>,
+    #[arg(long)]
+    shutdown_timeout_secs: Option<u64>,
+    #[arg(long)]
+    cookie_name: Option<String>,
+    #[arg(long)]
+    cookie_domain: Option<String>,
+    #[arg(long)]
+    cookie_ttl_secs: Option<u64>,
+    #[arg(long)]
+    auth_token_ttl_secs: Option<u64>,
+    #[arg(long)]
+    state_code_ttl_secs: Option<u64>,
+    #[arg(long)]
+    token_gc_interval_secs: Option<u64>,
+    #[arg(long)]
+    discord_client_id: Option<String>,
+    #[arg(long)]
+    discord_client_secret: Option<String>,
+    #[arg(long)]
+    discord_api_base: Option<String>,
+    #[arg(long)]
+    discord_guild_id: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    discord_guild_required_roles: Option<Vec<String>>,
+    /// Pairs of `<discord role id>=<role>`.
+    #[arg(long, value_delimiter = ',', value_parser = parse_role_mapping)]
+    discord_guild_role_mapping: Option<Vec<(String, String)>>,
+    #[arg(long)]
+    discord_guild_recheck_interval_secs: Option<u64>,
+    #[arg(long)]
+    email_code_ttl_secs: Option<u64>,
+    #[arg(long)]
+    passkey_rp_id: Option<String>,
+    #[arg(long)]
+    passkey_rp_name: Option<String>,
+    #[arg(long)]
+    passkey_ceremony_ttl_secs: Option<u64>,
+    #[arg(long)]
+    ticket_ttl_secs: Option<u64>,
+    #[arg(long)]
+    ticket_signing_key: Option<String>,
+    #[arg(long)]
+    guest_token_ttl_secs: Option<u64>,
+    #[arg(long)]
+    guest_inactivity_timeout_secs: Option<u64>,
+    #[arg(long)]
+    totp_issuer: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    sensitive_roles: Option<Vec<String>>,
+    #[arg(long)]
+    two_factor_challenge_ttl_secs: Option<u64>,
+    #[arg(long)]
+    two_factor_challenge_page: Option<String>,
+    #[arg(long)]
+    mail_transport: Option<String>,
+    #[arg(long)]
+    mail_from: Option<String>,
+    #[arg(long)]
+    smtp_url: Option<String>,
+    #[arg(long)]
+    mail_sink_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind_address: String,
+    /// Serves `/metrics`. Nothing is served separately if unset.
+    pub admin_bind_address: Option<String>,
+    pub database_url: String,
+    pub public_base_url: url::Url,
+    /// How long in-flight requests and background tasks get to finish after a shutdown signal.
+    pub shutdown_timeout: Duration,
+    pub readiness: ReadinessConfig,
+    pub cookie: CookieConfig,
+    pub tokens: TokenConfig,
+    pub tickets: TicketConfig,
+    pub guests: GuestConfig,
+    pub two_factor: TwoFactorConfig,
+    pub mail: MailConfig,
+    pub providers: ProvidersConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadinessConfig {
+    /// Also requires the discord api to be reachable before `/readyz` reports ready.
+    pub check_discord: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    pub name: String,
+    pub domain: Option<String>,
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    pub auth_token_ttl: Duration,
+    pub state_code_ttl: Duration,
+    pub gc_interval: Duration,
+}
+
+#[derive(Clone)]
+pub struct TicketConfig {
+    pub ttl: Duration,
+    /// The ed25519 seed join tickets are signed with.
+    pub signing_key: Option<[u8; 32]>,
+}
+
+impl std::fmt::Debug for TicketConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketConfig")
+            .field("ttl", &self.ttl)
+            .field("signing_key", &self.signing_key.map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GuestConfig {
+    pub token_ttl: Duration,
+    /// Guests that haven't been seen for this long are deleted.
+    pub inactivity_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TwoFactorConfig {
+    /// Shown next to the account in authenticator apps.
+    pub issuer: String,
+    /// Roles that only take effect once the user has two-factor authentication enabled.
+    pub sensitive_roles: Vec<Role>,
+    /// How long a login has to complete its second step.
+    pub challenge_ttl: Duration,
+    /// Where the frontend asks for the second step. Logins are redirected here instead of `/`.
+    pub challenge_page: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct MailConfig {
+    pub from: Mailbox,
+    pub transport: MailTransport,
+}
+
+#[derive(Clone)]
+pub enum MailTransport {
+    /// Nothing is delivered, messages are only logged and optionally appended to a file.
+    Log {
+        sink_path: Option<PathBuf>,
+    },
+    Smtp {
+        url: String,
+    },
+}
+
+impl std::fmt::Debug for MailTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailTransport::Log { sink_path } => {
+                f.debug_struct("Log").field("sink_path", sink_path).finish()
+            }
+            // the url usually contains the smtp password
+            MailTransport::Smtp { .. } => {
+                f.debug_struct("Smtp").field("url", &"<redacted>").finish()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProvidersConfig {
+    pub discord: DiscordInfo,
+    pub email: EmailInfo,
+    pub passkey: PasskeyInfo,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid configuration:\n{}", .0.iter().map(|error| format!("  - {error}")).collect::<Vec<_>>().join("\n"))]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl Config {
+    /// Builds the config from, in increasing order of precedence, the config file, the
+    /// environment and the command line. All problems are collected rather than stopping at the
+    /// first one.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigErrors> {
+        let mut errors = Vec::new();
+
+        let mut layer = ConfigLayer::from_file(cli.config.as_ref(), &mut errors);
+        layer.merge(ConfigLayer::from_env(&mut errors));
+        layer.merge(ConfigLayer::from_cli(&cli.overrides));
+
+        let config = layer.validate(&mut errors);
+
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(ConfigErrors(errors)),
+        }
+    }
+
+    /// Builds the config from a TOML document alone, ignoring the environment and command line.
+    /// Lets tests set up exactly the config they need.
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigErrors> {
+        let mut errors = Vec::new();
+
+        let layer: ConfigLayer = toml::from_str(contents)
+            .map_err(|error| ConfigErrors(vec![format!("failed to parse config: {error}")]))?;
+
+        let config = layer.validate(&mut errors);
+
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(ConfigErrors(errors)),
+        }
+    }
+
+    /// Resolves only the database url, for commands that don't need the rest of the config to
+    /// be valid.
+    pub fn load_database_url(cli: &Cli) -> Result<String, ConfigErrors> {
+        let mut errors = Vec::new();
+
+        let mut layer = ConfigLayer::from_file(cli.config.as_ref(), &mut errors);
+        layer.merge(ConfigLayer::from_env(&mut errors));
+        layer.merge(ConfigLayer::from_cli(&cli.overrides));
+
+        let database_url = layer
+            .database_url
+            .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string());
+        if database_url.is_empty() {
+            errors.push("database_url must not be empty".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(database_url)
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    pub fn public_url(&self, path: &str) -> String {
+        format!(
+            "{}{}",
+            self.public_base_url.as_str().trim_end_matches('/'),
+            path
+        )
+    }
+}
+
+impl CookieConfig {
+    pub fn auth_cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build((self.name.clone(), value))
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(
+                self.ttl
+                    .try_into()
+                    .expect("cookie ttl was validated on startup"),
+            );
+
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+
+        cookie.build()
+    }
+
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = Cookie::build(self.name.clone()).path("/");
+
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+
+        cookie.build()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigLayer {
+    bind_address: Option<String>,
+    admin_bind_address: Option<String>,
+    database_url: Option<String>,
+    public_base_url: Option<String>,
+    shutdown_timeout_secs: Option<u64>,
+    readiness: ReadinessLayer,
+    cookie: CookieLayer,
+    tokens: TokenLayer,
+    tickets: TicketLayer,
+    guests: GuestLayer,
+    two_factor: TwoFactorLayer,
+    mail: MailLayer,
+    providers: ProvidersLayer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReadinessLayer {
+    check_discord: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CookieLayer {
+    name: Option<String>,
+    domain: Option<String>,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TokenLayer {
+    auth_token_ttl_secs: Option<u64>,
+    state_code_ttl_secs: Option<u64>,
+    gc_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TicketLayer {
+    ttl_secs: Option<u64>,
+    signing_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GuestLayer {
+    token_ttl_secs: Option<u64>,
+    inactivity_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TwoFactorLayer {
+    issuer: Option<String>,
+    sensitive_roles: Option<Vec<String>>,
+    challenge_ttl_secs: Option<u64>,
+    challenge_page: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MailLayer {
+    transport: Option<String>,
+    from: Option<String>,
+    smtp_url: Option<String>,
+    sink_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProvidersLayer {
+    discord: DiscordLayer,
+    email: EmailLayer,
+    passkey: PasskeyLayer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DiscordLayer {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    api_base: Option<String>,
+    guild: DiscordGuildLayer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DiscordGuildLayer {
+    id: Option<String>,
+    required_roles: Option<Vec<String>>,
+    role_mapping: Option<HashMap<String, String>>,
+    recheck_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EmailLayer {
+    code_ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PasskeyLayer {
+    rp_id: Option<String>,
+    rp_name: Option<String>,
+    ceremony_ttl_secs: Option<u64>,
+}
+
+impl ConfigLayer {
+    fn from_file(path: Option<&PathBuf>, errors: &mut Vec<String>) -> Self {
+        let (path, required) = match path {
+            Some(path) => (path.clone(), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if !required && error.kind() == std::io::ErrorKind::NotFound => {
+                return Self::default()
+            }
+            Err(error) => {
+                errors.push(format!("failed to read {}: {error}", path.display()));
+                return Self::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(layer) => layer,
+            Err(error) => {
+                errors.push(format!("failed to parse {}: {error}", path.display()));
+                Self::default()
+            }
+        }
+    }
+
+    fn from_env(errors: &mut Vec<String>) -> Self {
+        Self {
+            bind_address: env_string("AUTH_BIND_ADDRESS"),
+            admin_bind_address: env_string("AUTH_ADMIN_BIND_ADDRESS"),
+            database_url: env_string("AUTH_DATABASE_URL"),
+            public_base_url: env_string("AUTH_PUBLIC_BASE_URL")
+                .or_else(|| env_string("DOMAIN_BASE")),
+            shutdown_timeout_secs: env_parse("AUTH_SHUTDOWN_TIMEOUT_SECS", errors),
+            readiness: ReadinessLayer {
+                check_discord: env_parse("AUTH_READINESS_CHECK_DISCORD", errors),
+            },
+            cookie: CookieLayer {
+                name: env_string("AUTH_COOKIE_NAME"),
+                domain: env_string("AUTH_COOKIE_DOMAIN"),
+                ttl_secs: env_parse("AUTH_COOKIE_TTL_SECS", errors),
+            },
+            tokens: TokenLayer {
+                auth_token_ttl_secs: env_parse("AUTH_TOKEN_TTL_SECS", errors),
+                state_code_ttl_secs: env_parse("AUTH_STATE_CODE_TTL_SECS", errors),
+                gc_interval_secs: env_parse("AUTH_TOKEN_GC_INTERVAL_SECS", errors),
+            },
+            tickets: TicketLayer {
+                ttl_secs: env_parse("AUTH_TICKET_TTL_SECS", errors),
+                signing_key: env_string("AUTH_TICKET_SIGNING_KEY"),
+            },
+            guests: GuestLayer {
+                token_ttl_secs: env_parse("AUTH_GUEST_TOKEN_TTL_SECS", errors),
+                inactivity_timeout_secs: env_parse("AUTH_GUEST_INACTIVITY_TIMEOUT_SECS", errors),
+            },
+            two_factor: TwoFactorLayer {
+                issuer: env_string("AUTH_TOTP_ISSUER"),
+                sensitive_roles: env_list("AUTH_SENSITIVE_ROLES"),
+                challenge_ttl_secs: env_parse("AUTH_TWO_FACTOR_CHALLENGE_TTL_SECS", errors),
+                challenge_page: env_string("AUTH_TWO_FACTOR_CHALLENGE_PAGE"),
+            },
+            mail: MailLayer {
+                transport: env_string("AUTH_MAIL_TRANSPORT"),
+                from: env_string("AUTH_MAIL_FROM"),
+                smtp_url: env_string("AUTH_SMTP_URL"),
+                sink_path: env_string("AUTH_MAIL_SINK_PATH").map(PathBuf::from),
+            },
+            providers: ProvidersLayer {
+                discord: DiscordLayer {
+                    client_id: env_string("DISCORD_OAUTH_CLIENT_ID"),
+                    client_secret: env_string("DISCORD_OAUTH_CLIENT_SECRET"),
+                    api_base: env_string("DISCORD_API_BASE"),
+                    guild: DiscordGuildLayer {
+                        id: env_string("DISCORD_GUILD_ID"),
+                        required_roles: env_list("DISCORD_GUILD_REQUIRED_ROLES"),
+                        role_mapping: env_list("DISCORD_GUILD_ROLE_MAPPING").map(|pairs| {
+                            pairs
+                                .iter()
+                                .filter_map(|pair| match parse_role_mapping(pair) {
+                                    Ok(pair) => Some(pair),
+                                    Err(error) => {
+                                        errors.push(format!(
+                                            "environment variable DISCORD_GUILD_ROLE_MAPPING: {error}"
+                                        ));
+                                        None
+                                    }
+                                })
+                                .collect()
+                        }),
+                        recheck_interval_secs: env_parse(
+                            "DISCORD_GUILD_RECHECK_INTERVAL_SECS",
+                            errors,
+                        ),
+                    },
+                },
+                email: EmailLayer {
+                    code_ttl_secs: env_parse("AUTH_EMAIL_CODE_TTL_SECS", errors),
+                },
+                passkey: PasskeyLayer {
+                    rp_id: env_string("AUTH_PASSKEY_RP_ID"),
+                    rp_name: env_string("AUTH_PASSKEY_RP_NAME"),
+                    ceremony_ttl_secs: env_parse("AUTH_PASSKEY_CEREMONY_TTL_SECS", errors),
+                },
+            },
+        }
+    }
+
+    fn from_cli(overrides: &CliOverrides) -> Self {
+        Self {
+            bind_address: overrides.bind_address.clone(),
+            admin_bind_address: overrides.admin_bind_address.clone(),
+            database_url: overrides.database_url.clone(),
+            public_base_url: overrides.public_base_url.clone(),
+            shutdown_timeout_secs: overrides.shutdown_timeout_secs,
+            readiness: ReadinessLayer {
+                check_discord: overrides.readiness_check_discord,
+            },
+            cookie: CookieLayer {
+                name: overrides.cookie_name.clone(),
+                domain: overrides.cookie_domain.clone(),
+                ttl_secs: overrides.cookie_ttl_secs,
+            },
+            tokens: TokenLayer {
+                auth_token_ttl_secs: overrides.auth_token_ttl_secs,
+                state_code_ttl_secs: overrides.state_code_ttl_secs,
+                gc_interval_secs: overrides.token_gc_interval_secs,
+            },
+            tickets: TicketLayer {
+                ttl_secs: overrides.ticket_ttl_secs,
+                signing_key: overrides.ticket_signing_key.clone(),
+            },
+            guests: GuestLayer {
+                token_ttl_secs: overrides.guest_token_ttl_secs,
+                inactivity_timeout_secs: overrides.guest_inactivity_timeout_secs,
+            },
+            two_factor: TwoFactorLayer {
+                issuer: overrides.totp_issuer.clone(),
+                sensitive_roles: overrides.sensitive_roles.clone(),
+                challenge_ttl_secs: overrides.two_factor_challenge_ttl_secs,
+                challenge_page: overrides.two_factor_challenge_page.clone(),
+            },
+            mail: MailLayer {
+                transport: overrides.mail_transport.clone(),
+                from: overrides.mail_from.clone(),
+                smtp_url: overrides.smtp_url.clone(),
+                sink_path: overrides.mail_sink_path.clone(),
+            },
+            providers: ProvidersLayer {
+                discord: DiscordLayer {
+                    client_id: overrides.discord_client_id.clone(),
+                    client_secret: overrides.discord_client_secret.clone(),
+                    api_base: overrides.discord_api_base.clone(),
+                    guild: DiscordGuildLayer {
+                        id: overrides.discord_guild_id.clone(),
+                        required_roles: overrides.discord_guild_required_roles.clone(),
+                        role_mapping: overrides
+                            .discord_guild_role_mapping
+                            .clone()
+                            .map(|pairs| pairs.into_iter().collect()),
+                        recheck_interval_secs: overrides.discord_guild_recheck_interval_secs,
+                    },
+                },
+                email: EmailLayer {
+                    code_ttl_secs: overrides.email_code_ttl_secs,
+                },
+                passkey: PasskeyLayer {
+                    rp_id: overrides.passkey_rp_id.clone(),
+                    rp_name: overrides.passkey_rp_name.clone(),
+                    ceremony_ttl_secs: overrides.passkey_ceremony_ttl_secs,
+                },
+            },
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        merge(&mut self.bind_address, other.bind_address);
+        merge(&mut self.admin_bind_address, other.admin_bind_address);
+        merge(&mut self.database_url, other.database_url);
+        merge(&mut self.public_base_url, other.public_base_url);
+        merge(&mut self.shutdown_timeout_secs, other.shutdown_timeout_secs);
+        merge(
+            &mut self.readiness.check_discord,
+            other.readiness.check_discord,
+        );
+        merge(&mut self.cookie.name, other.cookie.name);
+        merge(&mut self.cookie.domain, other.cookie.domain);
+        merge(&mut self.cookie.ttl_secs, other.cookie.ttl_secs);
+        merge(
+            &mut self.tokens.auth_token_ttl_secs,
+            other.tokens.auth_token_ttl_secs,
+        );
+        merge(
+            &mut self.tokens.state_code_ttl_secs,
+            other.tokens.state_code_ttl_secs,
+        );
+        merge(
+            &mut self.tokens.gc_interval_secs,
+            other.tokens.gc_interval_secs,
+        );
+        merge(&mut self.tickets.ttl_secs, other.tickets.ttl_secs);
+        merge(&mut self.tickets.signing_key, other.tickets.signing_key);
+        merge(&mut self.guests.token_ttl_secs, other.guests.token_ttl_secs);
+        merge(
+            &mut self.guests.inactivity_timeout_secs,
+            other.guests.inactivity_timeout_secs,
+        );
+        merge(&mut self.two_factor.issuer, other.two_factor.issuer);
+        merge(
+            &mut self.two_factor.sensitive_roles,
+            other.two_factor.sensitive_roles,
+        );
+        merge(
+            &mut self.two_factor.challenge_ttl_secs,
+            other.two_factor.challenge_ttl_secs,
+        );
+        merge(
+            &mut self.two_factor.challenge_page,
+            other.two_factor.challenge_page,
+        );
+        merge(&mut self.mail.transport, other.mail.transport);
+        merge(&mut self.mail.from, other.mail.from);
+        merge(&mut self.mail.smtp_url, other.mail.smtp_url);
+        merge(&mut self.mail.sink_path, other.mail.sink_path);
+        merge(
+            &mut self.providers.discord.client_id,
+            other.providers.discord.client_id,
+        );
+        merge(
+            &mut self.providers.discord.client_secret,
+            other.providers.discord.client_secret,
+        );
+        merge(
+            &mut self.providers.discord.api_base,
+            other.providers.discord.api_base,
+        );
+        merge(
+            &mut self.providers.discord.guild.id,
+            other.providers.discord.guild.id,
+        );
+        merge(
+            &mut self.providers.discord.guild.required_roles,
+            other.providers.discord.guild.required_roles,
+        );
+        merge(
+            &mut self.providers.discord.guild.role_mapping,
+            other.providers.discord.guild.role_mapping,
+        );
+        merge(
+            &mut self.providers.discord.guild.recheck_interval_secs,
+            other.providers.discord.guild.recheck_interval_secs,
+        );
+        merge(
+            &mut self.providers.email.code_ttl_secs,
+            other.providers.email.code_ttl_secs,
+        );
+        merge(
+            &mut self.providers.passkey.rp_id,
+            other.providers.passkey.rp_id,
+        );
+        merge(
+            &mut self.providers.passkey.rp_name,
+            other.providers.passkey.rp_name,
+        );
+        merge(
+            &mut self.providers.passkey.ceremony_ttl_secs,
+            other.providers.passkey.ceremony_ttl_secs,
+        );
+    }
+
+    fn validate(self, errors: &mut Vec<String>) -> Option<Config> {
+        let bind_address = self
+            .bind_address
+            .unwrap_or_else(|| "localhost:12121".to_string());
+        check_bind_address("bind_address", &bind_address, errors);
+
+        let admin_bind_address = self.admin_bind_address;
+        if let Some(admin_bind_address) = &admin_bind_address {
+            check_bind_address("admin_bind_address", admin_bind_address, errors);
+            if admin_bind_address == &bind_address {
+                errors.push(
+                    "admin_bind_address must be different from bind_address, metrics aren't public"
+                        .to_string(),
+                );
+            }
+        }
+
+        let database_url = self
+            .database_url
+            .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string());
+        if database_url.is_empty() {
+            errors.push("database_url must not be empty".to_string());
+        }
+
+        let public_base_url = match self.public_base_url {
+            Some(public_base_url) => url("public_base_url", &public_base_url, errors),
+            None => {
+                errors.push("public_base_url is required".to_string());
+                None
+            }
+        };
+
+        let shutdown_timeout = positive_duration(
+            "shutdown_timeout_secs",
+            self.shutdown_timeout_secs,
+            30,
+            errors,
+        );
+
+        let cookie_name = self.cookie.name.unwrap_or_else(|| "AuthToken".to_string());
+        if cookie_name.is_empty()
+            || !cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            errors.push(format!(
+                "cookie.name `{cookie_name}` must be non-empty and only contain [A-Za-z0-9_-]"
+            ));
+        }
+
+        let auth_token_ttl = positive_duration(
+            "tokens.auth_token_ttl_secs",
+            self.tokens.auth_token_ttl_secs,
+            60 * 60 * 24 * 30,
+            errors,
+        );
+        let state_code_ttl = positive_duration(
+            "tokens.state_code_ttl_secs",
+            self.tokens.state_code_ttl_secs,
+            60 * 10,
+            errors,
+        );
+        let gc_interval = positive_duration(
+            "tokens.gc_interval_secs",
+            self.tokens.gc_interval_secs,
+            60 * 60,
+            errors,
+        );
+        let cookie_ttl = positive_duration(
+            "cookie.ttl_secs",
+            self.cookie.ttl_secs,
+            auth_token_ttl.as_secs(),
+            errors,
+        );
+
+        let ticket_ttl = positive_duration("tickets.ttl_secs", self.tickets.ttl_secs, 30, errors);
+        let ticket_signing_key = self.tickets.signing_key.and_then(|signing_key| {
+            let signing_key = hex::decode(&signing_key)
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+
+            if signing_key.is_none() {
+                errors.push("tickets.signing_key must be 32 hex encoded bytes".to_string());
+            }
+
+            signing_key
+        });
+
+        let guest_token_ttl = positive_duration(
+            "guests.token_ttl_secs",
+            self.guests.token_ttl_secs,
+            60 * 60 * 24 * 7,
+            errors,
+        );
+        let guest_inactivity_timeout = positive_duration(
+            "guests.inactivity_timeout_secs",
+            self.guests.inactivity_timeout_secs,
+            60 * 60 * 24 * 30,
+            errors,
+        );
+
+        let totp_issuer = self
+            .two_factor
+            .issuer
+            .unwrap_or_else(|| "NeuroTCG".to_string());
+        if totp_issuer.is_empty() || totp_issuer.contains(':') {
+            errors.push(format!(
+                "two_factor.issuer `{totp_issuer}` must be non-empty and not contain `:`"
+            ));
+        }
+        let sensitive_roles = match self.two_factor.sensitive_roles {
+            Some(roles) => roles
+                .iter()
+                .filter_map(|role| match role.parse::<Role>() {
+                    Ok(role) => Some(role),
+                    Err(error) => {
+                        errors.push(format!("two_factor.sensitive_roles: {error}"));
+                        None
+                    }
+                })
+                .collect(),
+            None => vec![Role::Admin, Role::TournamentOrganizer],
+        };
+        let two_factor_challenge_ttl = positive_duration(
+            "two_factor.challenge_ttl_secs",
+            self.two_factor.challenge_ttl_secs,
+            60 * 5,
+            errors,
+        );
+        let two_factor_challenge_page = self
+            .two_factor
+            .challenge_page
+            .unwrap_or_else(|| "/login/two-factor".to_string());
+        if !two_factor_challenge_page.starts_with('/') {
+            errors.push(format!(
+                "two_factor.challenge_page `{two_factor_challenge_page}` must be an absolute path"
+            ));
+        }
+
+        let public_host = public_base_url
+            .as_ref()
+            .and_then(|url| url.host_str())
+            .map(str::to_string);
+
+        let mail_from = self.mail.from.unwrap_or_else(|| {
+            format!(
+                "NeuroTCG <noreply@{}>",
+                public_host.as_deref().unwrap_or("localhost")
+            )
+        });
+        let mail_from = match mail_from.parse::<Mailbox>() {
+            Ok(mail_from) => Some(mail_from),
+            Err(error) => {
+                errors.push(format!(
+                    "mail.from `{mail_from}` is not a valid mailbox: {error}"
+                ));
+                None
+            }
+        };
+        let mail_transport = match self.mail.transport.as_deref().unwrap_or("log") {
+            "log" => Some(MailTransport::Log {
+                sink_path: self.mail.sink_path,
+            }),
+            "smtp" => match self.mail.smtp_url {
+                Some(smtp_url)
+                    if url::Url::parse(&smtp_url)
+                        .is_ok_and(|url| matches!(url.scheme(), "smtp" | "smtps")) =>
+                {
+                    Some(MailTransport::Smtp { url: smtp_url })
+                }
+                Some(_) => {
+                    errors.push("mail.smtp_url must be an smtp or smtps url".to_string());
+                    None
+                }
+                None => {
+                    errors.push("mail.smtp_url is required for the smtp transport".to_string());
+                    None
+                }
+            },
+            transport => {
+                errors.push(format!(
+                    "mail.transport `{transport}` must be one of `log` or `smtp`"
+                ));
+                None
+            }
+        };
+
+        let email_code_ttl = positive_duration(
+            "providers.email.code_ttl_secs",
+            self.providers.email.code_ttl_secs,
+            60 * 15,
+            errors,
+        );
+
+        let discord_client_id = required(
+            "providers.discord.client_id",
+            self.providers.discord.client_id,
+            errors,
+        );
+        let discord_client_secret = required(
+            "providers.discord.client_secret",
+            self.providers.discord.client_secret,
+            errors,
+        );
+
+        let discord_api_base = url(
+            "providers.discord.api_base",
+            &self
+                .providers
+                .discord
+                .api_base
+                .unwrap_or_else(|| "https://discord.com".to_string()),
+            errors,
+        );
+
+        let guild = self.providers.discord.guild;
+        let required_roles = guild.required_roles.unwrap_or_default();
+        let role_mapping = guild.role_mapping.unwrap_or_default();
+        for role_id in required_roles.iter().chain(role_mapping.keys()) {
+            if !is_snowflake(role_id) {
+                errors.push(format!(
+                    "providers.discord.guild: `{role_id}` is not a discord role id"
+                ));
+            }
+        }
+        let role_mapping = role_mapping
+            .into_iter()
+            .filter_map(|(role_id, role)| match role.parse::<Role>() {
+                Ok(role) => Some((role_id, role)),
+                Err(error) => {
+                    errors.push(format!("providers.discord.guild.role_mapping: {error}"));
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        let recheck_interval = positive_duration(
+            "providers.discord.guild.recheck_interval_secs",
+            guild.recheck_interval_secs,
+            60 * 60 * 6,
+            errors,
+        );
+        let discord_guild = match guild.id {
+            Some(guild_id) => {
+                if !is_snowflake(&guild_id) {
+                    errors.push(format!(
+                        "providers.discord.guild.id `{guild_id}` is not a discord guild id"
+                    ));
+                }
+
+                Some(GuildGate {
+                    guild_id,
+                    required_roles,
+                    role_mapping,
+                    recheck_interval,
+                })
+            }
+            None => {
+                if !required_roles.is_empty() || !role_mapping.is_empty() {
+                    errors.push(
+                        "providers.discord.guild.id is required for required_roles and role_mapping"
+                            .to_string(),
+                    );
+                }
+
+                None
+            }
+        };
+
+        // the relying party id has to be the public host or a parent domain of it
+        let passkey_rp_id = self
+            .providers
+            .passkey
+            .rp_id
+            .or_else(|| public_host.clone())
+            .unwrap_or_default();
+        if let Some(public_host) = &public_host {
+            if public_host != &passkey_rp_id && !public_host.ends_with(&format!(".{passkey_rp_id}"))
+            {
+                errors.push(format!(
+                    "providers.passkey.rp_id `{passkey_rp_id}` must be `{public_host}` or a parent domain of it"
+                ));
+            }
+        }
+        let passkey_rp_name = self
+            .providers
+            .passkey
+            .rp_name
+            .unwrap_or_else(|| "NeuroTCG".to_string());
+        let passkey_origin = public_base_url
+            .as_ref()
+            .map(|url| url.origin().ascii_serialization());
+        let passkey_ceremony_ttl = positive_duration(
+            "providers.passkey.ceremony_ttl_secs",
+            self.providers.passkey.ceremony_ttl_secs,
+            60 * 5,
+            errors,
+        );
+
+        Some(Config {
+            bind_address,
+            admin_bind_address,
+            database_url,
+            public_base_url: public_base_url?,
+            shutdown_timeout,
+            readiness: ReadinessConfig {
+                check_discord: self.readiness.check_discord.unwrap_or(false),
+            },
+            cookie: CookieConfig {
+                name: cookie_name,
+                domain: self.cookie.domain,
+                ttl: cookie_ttl,
+            },
+            tokens: TokenConfig {
+                auth_token_ttl,
+                state_code_ttl,
+                gc_interval,
+            },
+            tickets: TicketConfig {
+                ttl: ticket_ttl,
+                signing_key: ticket_signing_key,
+            },
+            guests: GuestConfig {
+                token_ttl: guest_token_ttl,
+                inactivity_timeout: guest_inactivity_timeout,
+            },
+            two_factor: TwoFactorConfig {
+                issuer: totp_issuer,
+                sensitive_roles,
+                challenge_ttl: two_factor_challenge_ttl,
+                challenge_page: two_factor_challenge_page,
+            },
+            mail: MailConfig {
+                from: mail_from?,
+                transport: mail_transport?,
+            },
+            providers: ProvidersConfig {
+                discord: DiscordInfo {
+                    client_id: discord_client_id?,
+                    client_secret: discord_client_secret?,
+                    api_base: discord_api_base?,
+                    guild: discord_guild,
+                },
+                email: EmailInfo {
+                    code_ttl: email_code_ttl,
+                },
+                passkey: PasskeyInfo {
+                    rp_id: passkey_rp_id,
+                    rp_name: passkey_rp_name,
+                    origin: passkey_origin?,
+                    ceremony_ttl: passkey_ceremony_ttl,
+                },
+            },
+        })
+    }
+}
+
+fn merge<T>(base: &mut Option<T>, other: Option<T>) {
+    if other.is_some() {
+        *base = other;
+    }
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Comma separated, ignoring empty entries.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env_string(name).map(|list| {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+fn env_parse<T>(name: &str, errors: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = env_string(name)?;
+
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(error) => {
+            errors.push(format!("environment variable {name}=`{value}`: {error}"));
+            None
+        }
+    }
+}
+
+fn parse_role_mapping(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((role_id, role)) => Ok((role_id.trim().to_string(), role.trim().to_string())),
+        None => Err(format!(
+            "`{pair}` is not of the form <discord role id>=<role>"
+        )),
+    }
+}
+
+/// Discord ids are decimal integers.
+fn is_snowflake(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_digit())
+}
+
+fn check_bind_address(name: &str, value: &str, errors: &mut Vec<String>) {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+        _ => errors.push(format!("{name} `{value}` is not of the form host:port")),
+    }
+}
+
+fn required(name: &str, value: Option<String>, errors: &mut Vec<String>) -> Option<String> {
+    match value {
+        Some(value) if !value.is_empty() => Some(value),
+        _ => {
+            errors.push(format!("{name} is required"));
+            None
+        }
+    }
+}
+
+fn url(name: &str, value: &str, errors: &mut Vec<String>) -> Option<url::Url> {
+    match url::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url),
+        Ok(_) => {
+            errors.push(format!("{name} `{value}` must be an http or https url"));
+            None
+        }
+        Err(error) => {
+            errors.push(format!("{name} `{value}` is not a valid url: {error}"));
+            None
+        }
+    }
+}
+
+fn positive_duration(
+    name: &str,
+    secs: Option<u64>,
+    default_secs: u64,
+    errors: &mut Vec<String>,
+) -> Duration {
+    let secs = secs.unwrap_or(default_secs);
+
+    if secs == 0 {
+        errors.push(format!("{name} must be greater than zero"));
+    }
+
+    Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use clap::Parser;
+
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        public_base_url = "https://game.example"
+
+        [providers.discord]
+        client_id = "client"
+        client_secret = "secret"
+    "#;
+
+    // the only test that touches the environment, so nothing else can see these variables
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            bind_address = "file:1"
+            shutdown_timeout_secs = 5
+            {MINIMAL}
+            [cookie]
+            name = "FromFile"
+            "#
+        )
+        .unwrap();
+
+        std::env::set_var("AUTH_BIND_ADDRESS", "env:2");
+        std::env::set_var("AUTH_COOKIE_NAME", "FromEnv");
+        let cli = Cli::parse_from([
+            "auth_provider",
+            "--config",
+            file.path().to_str().unwrap(),
+            "--cookie-name",
+            "FromCli",
+        ]);
+        let config = Config::load(&cli);
+        std::env::remove_var("AUTH_BIND_ADDRESS");
+        std::env::remove_var("AUTH_COOKIE_NAME");
+
+        let config = config.unwrap();
+        assert_eq!(config.cookie.name, "FromCli");
+        assert_eq!(config.bind_address, "env:2");
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(5));
+        // and defaults fill in whatever no layer set
+        assert_eq!(
+            config.tokens.auth_token_ttl,
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let layer: ConfigLayer = toml::from_str(
+            r#"
+            bind_address = "nowhere"
+            shutdown_timeout_secs = 0
+
+            [cookie]
+            name = "not a cookie name"
+            "#,
+        )
+        .unwrap();
+
+        let mut errors = Vec::new();
+        assert!(layer.validate(&mut errors).is_none());
+
+        for expected in [
+            "bind_address `nowhere` is not of the form host:port",
+            "public_base_url is required",
+            "shutdown_timeout_secs must be greater than zero",
+            "cookie.name `not a cookie name` must be non-empty and only contain [A-Za-z0-9_-]",
+            "providers.discord.client_id is required",
+            "providers.discord.client_secret is required",
+        ] {
+            assert!(
+                errors.iter().any(|error| error == expected),
+                "`{expected}` missing from {errors:#?}"
+            );
+        }
+    }
+
+    #[test]
+    fn minimal_config_is_valid() {
+        let config = Config::from_toml(MINIMAL).unwrap();
+
+        assert_eq!(config.bind_address, "localhost:12121");
+        assert_eq!(
+            config.public_url("/auth/me"),
+            "https://game.example/auth/me"
+        );
+    }
+}
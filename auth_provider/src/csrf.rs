@@ -0,0 +1,284 @@
+//! Double-submit cookie CSRF protection.
+//!
+//! Every response to a safe request hands the browser a random `CsrfToken` cookie that scripts on
+//! our own origin can read. Any state-changing request must then echo that value back in the
+//! `X-CSRF-Token` header, and must come from our own origin according to `Origin`/`Referer`.
+//!
+//! Requests authenticated by an `Authorization: Bearer` header are exempt, since browsers never
+//! attach that header on their own and so it can't be forged cross-site. The bearer has to
+//! actually authenticate: a made up one next to the auth cookie still goes through the checks.
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use http::{header, HeaderMap, Method, StatusCode};
+use rand::RngCore;
+use tracing::debug;
+
+use crate::{caller::Caller, WebState};
+
+pub const CSRF_COOKIE_NAME: &str = "CsrfToken";
+pub const CSRF_HEADER_NAME: &str = "X-CSRF-Token";
+
+pub async fn protect(
+    State(state): State<WebState>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cookie_token = jar
+        .get(CSRF_COOKIE_NAME)
+        .map(|cookie| cookie.value().to_owned());
+
+    if is_safe_method(request.method()) {
+        let mut response = next.run(request).await;
+
+        if cookie_token.is_none() {
+            response.headers_mut().append(
+                header::SET_COOKIE,
+                format!(
+                    "{CSRF_COOKIE_NAME}={}; Path=/; SameSite=Strict; Secure",
+                    generate_csrf_token()
+                )
+                .try_into()
+                .unwrap(),
+            );
+        }
+
+        return response;
+    }
+
+    // with a bearer present, the caller is identified by it alone and never by the cookie
+    let (mut parts, body) = request.into_parts();
+    let bearer_authenticated = has_bearer_token(&parts.headers)
+        && Caller::from_request_parts(&mut parts, &state).await.is_ok();
+    let request = Request::from_parts(parts, body);
+    if bearer_authenticated {
+        return next.run(request).await;
+    }
+
+    if !is_same_origin(request.headers(), &state.config.public_base_url) {
+        debug!("rejecting cross-origin state-changing request");
+        return (StatusCode::FORBIDDEN, "cross-origin request rejected").into_response();
+    }
+
+    let header_token = request
+        .headers()
+        .get(CSRF_HEADER_NAME)
+        .and_then(|value| value.to_str().ok());
+
+    match (cookie_token, header_token) {
+        (Some(cookie_token), Some(header_token))
+            if constant_time_eq(cookie_token.as_bytes(), header_token.as_bytes()) =>
+        {
+            next.run(request).await
+        }
+        _ => {
+            debug!("rejecting request with missing or mismatched csrf token");
+            (StatusCode::FORBIDDEN, "missing or invalid csrf token").into_response()
+        }
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn has_bearer_token(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "))
+}
+
+/// Checks `Origin`, falling back to `Referer`, against the public base url. Requests with
+/// neither header are rejected, as every browser we care about sends at least one of them.
+fn is_same_origin(headers: &HeaderMap, expected: &url::Url) -> bool {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| url::Url::parse(value).ok());
+
+    match source {
+        Some(source) => source.origin() == expected.origin(),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn generate_csrf_token() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const ORIGIN: &str = "https://game.example";
+
+    /// A logout without a session, which passes straight through to the handler once the
+    /// request gets past the middleware.
+    fn logout() -> http::request::Builder {
+        Request::post("/auth/logout")
+    }
+
+    async fn status(request: http::request::Builder) -> StatusCode {
+        crate::router(crate::test_state())
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    fn with_tokens(
+        request: http::request::Builder,
+        cookie_token: &str,
+        header_token: &str,
+    ) -> http::request::Builder {
+        request
+            .header(header::COOKIE, format!("{CSRF_COOKIE_NAME}={cookie_token}"))
+            .header(CSRF_HEADER_NAME, header_token)
+    }
+
+    #[tokio::test]
+    async fn safe_requests_are_handed_a_token_once() {
+        let response = crate::router(crate::test_state())
+            .oneshot(Request::get("/auth/me").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with(&format!("{CSRF_COOKIE_NAME}=")));
+
+        let response = crate::router(crate::test_state())
+            .oneshot(
+                Request::get("/auth/me")
+                    .header(header::COOKIE, format!("{CSRF_COOKIE_NAME}=token"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+    }
+
+    #[tokio::test]
+    async fn matching_token_from_our_origin_is_accepted() {
+        let request = with_tokens(logout().header(header::ORIGIN, ORIGIN), "token", "token");
+
+        assert_eq!(status(request).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn referer_is_used_without_origin() {
+        let request = with_tokens(
+            logout().header(header::REFERER, format!("{ORIGIN}/login")),
+            "token",
+            "token",
+        );
+        assert_eq!(status(request).await, StatusCode::OK);
+
+        let request = with_tokens(
+            logout().header(header::REFERER, "https://evil.example/login"),
+            "token",
+            "token",
+        );
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn mismatched_or_missing_token_is_rejected() {
+        let request = with_tokens(logout().header(header::ORIGIN, ORIGIN), "token", "other");
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+
+        let request = logout()
+            .header(header::ORIGIN, ORIGIN)
+            .header(header::COOKIE, format!("{CSRF_COOKIE_NAME}=token"));
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+
+        let request = logout()
+            .header(header::ORIGIN, ORIGIN)
+            .header(CSRF_HEADER_NAME, "token");
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn missing_or_foreign_origin_is_rejected() {
+        assert_eq!(
+            status(with_tokens(logout(), "token", "token")).await,
+            StatusCode::FORBIDDEN
+        );
+
+        for origin in [
+            "https://evil.example",
+            "http://game.example",
+            "https://game.example:8443",
+            "null",
+        ] {
+            let request = with_tokens(logout().header(header::ORIGIN, origin), "token", "token");
+            assert_eq!(status(request).await, StatusCode::FORBIDDEN, "{origin}");
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticated_bearer_requests_are_exempt() {
+        let state = crate::test_state();
+        let user = state
+            .database
+            .create_new_user(crate::database::models::UserKind::Guest)
+            .await
+            .unwrap();
+        let token = state
+            .database
+            .create_auth_token(&user.user_id, std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let response = crate::router(state)
+            .oneshot(
+                logout()
+                    .header(header::ORIGIN, "https://evil.example")
+                    .header(
+                        header::AUTHORIZATION,
+                        format!("Bearer {}", token.to_hex_string()),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn made_up_bearers_are_not_exempt() {
+        for bearer in ["Bearer 00", "Bearer not-even-hex"] {
+            let request = logout()
+                .header(header::ORIGIN, "https://evil.example")
+                .header(header::COOKIE, "AuthToken=00")
+                .header(header::AUTHORIZATION, bearer);
+
+            assert_eq!(status(request).await, StatusCode::FORBIDDEN, "{bearer}");
+        }
+    }
+}
@@ -1,11 +1,18 @@
+pub mod memory;
 pub mod models;
+pub mod repository;
 
-use futures::StreamExt;
-use models::{DiscordOauthUser, DiscordUserId, Token, TokenHash, User, UserId};
+use async_trait::async_trait;
+use models::{
+    DiscordOauthUser, DiscordUserId, EmailLoginCode, PasskeyCredential, Role, ServiceCredential,
+    ServiceCredentialId, ServiceKey, ServiceScope, Token, TokenHash, TotpCredential, User, UserId,
+    UserKind,
+};
 use rand::{Rng, RngCore};
-use sqlx::Executor;
-use time::format_description::well_known::Iso8601;
-use tracing::warn;
+use repository::{
+    DiscordIdentityStore, EmailIdentityStore, HealthStore, JoinTicketStore, PasskeyStore,
+    RoleStore, ServiceCredentialStore, StoreError, TokenStore, TwoFactorStore, UserStore,
+};
 
 const USER_ID_CHARACTERS: [char; 62] = [
     'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
@@ -14,36 +21,73 @@ const USER_ID_CHARACTERS: [char; 62] = [
     '5', '6', '7', '8', '9',
 ];
 
-pub struct Database {
-    pool: sqlx::SqlitePool,
+/// Runs `$body` against whichever backend the pool is for. Both backends accept `$N`
+/// placeholders, so most queries can be shared verbatim between them.
+macro_rules! with_pool {
+    ($pool:expr, |$conn:ident| $body:expr) => {
+        match $pool {
+            Pool::Sqlite($conn) => {
+                let _timer = crate::metrics::DATABASE_OPERATION_DURATION
+                    .with_label_values(&["sqlite"])
+                    .start_timer();
+                $body
+            }
+            Pool::Postgres($conn) => {
+                let _timer = crate::metrics::DATABASE_OPERATION_DURATION
+                    .with_label_values(&["postgres"])
+                    .start_timer();
+                $body
+            }
+        }
+    };
 }
 
-impl Database {
-    pub async fn new(file: &str) -> Result<Self, sqlx::Error> {
-        let pool = sqlx::SqlitePool::connect(file).await?;
+pub mod migrations;
 
-        let mut results = pool.execute_many(include_str!("../schema.sql"));
+enum Pool {
+    Sqlite(sqlx::SqlitePool),
+    Postgres(sqlx::PgPool),
+}
 
-        while let Some(result) = results.next().await {
-            match result {
-                Ok(_) => (),
-                Err(error) => warn!(?error, "error when creating initial database tables"),
-            }
-        }
+pub struct Database {
+    pool: Pool,
+}
+
+impl Database {
+    /// Opens a postgres database for `postgres://` and `postgresql://` urls, and a sqlite one
+    /// for anything else. This does not touch the schema, see [`Database::run_migrations`].
+    pub async fn new(url: &str) -> Result<Self, sqlx::Error> {
+        let pool = if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Pool::Postgres(sqlx::PgPool::connect(url).await?)
+        } else {
+            Pool::Sqlite(sqlx::SqlitePool::connect(url).await?)
+        };
 
         Ok(Self { pool })
     }
 
-    pub async fn user_id_exists(&self, user_id: &UserId) -> Result<bool, sqlx::Error> {
-        let row: Option<_> = sqlx::query("select 1 from users where user_id = ?")
-            .bind(user_id)
-            .fetch_optional(&self.pool)
-            .await?;
+    /// Waits for checked out connections to be returned, then closes all of them.
+    pub async fn close(&self) {
+        match &self.pool {
+            Pool::Sqlite(pool) => pool.close().await,
+            Pool::Postgres(pool) => pool.close().await,
+        }
+    }
+}
 
-        Ok(row.is_some())
+#[async_trait]
+impl UserStore for Database {
+    async fn user_id_exists(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query("select 1 from users where user_id = $1")
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?
+                .is_some())
+        })
     }
 
-    pub async fn create_new_user(&self) -> Result<User, sqlx::Error> {
+    async fn create_new_user(&self, kind: UserKind) -> Result<User, StoreError> {
         let user_id = loop {
             let user_id = generate_random_user_id(24);
 
@@ -54,145 +98,990 @@ impl Database {
             break user_id;
         };
 
-        sqlx::query("insert into users (user_id) values (?)")
-            .bind(&user_id)
-            .execute(&self.pool)
-            .await?;
+        let is_guest = kind == UserKind::Guest;
+
+        with_pool!(&self.pool, |pool| {
+            sqlx::query("insert into users (user_id, is_guest, last_seen_at) values ($1, $2, $3)")
+                .bind(&user_id)
+                .bind(is_guest)
+                .bind(time::OffsetDateTime::now_utc())
+                .execute(pool)
+                .await?;
+        });
 
-        Ok(User { user_id })
+        Ok(User { user_id, is_guest })
     }
 
-    pub async fn discord_user_registered(
+    async fn upgrade_guest_user(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
+
+            let upgraded =
+                sqlx::query("update users set is_guest = false where user_id = $1 and is_guest")
+                    .bind(user_id)
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected()
+                    > 0;
+            if upgraded {
+                sqlx::query("delete from auth_tokens where user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+
+            transaction.commit().await?;
+
+            Ok(upgraded)
+        })
+    }
+
+    async fn touch_user(&self, user_id: &UserId) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query("update users set last_seen_at = $1 where user_id = $2")
+                .bind(time::OffsetDateTime::now_utc())
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+
+    async fn garbage_collect_inactive_guests(
         &self,
-        user_id: &DiscordUserId,
-    ) -> Result<bool, sqlx::Error> {
-        Ok(
-            sqlx::query("select 1 from discord_oauth_users where discord_id = ?")
+        inactive_since: time::OffsetDateTime,
+    ) -> Result<usize, StoreError> {
+        let inactive = match self.pool {
+            Pool::Sqlite(_) => {
+                "is_guest and (last_seen_at is null or julianday(last_seen_at) < julianday($1))"
+            }
+            Pool::Postgres(_) => "is_guest and (last_seen_at is null or last_seen_at < $1)",
+        };
+
+        let num_deleted = with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
+
+            for table in [
+                "auth_tokens",
+                "user_roles",
+                "totp_credentials",
+                "recovery_codes",
+            ] {
+                sqlx::query(&format!(
+                    "delete from {table} where user_id in (select user_id from users where {inactive})"
+                ))
+                .bind(inactive_since)
+                .execute(&mut *transaction)
+                .await?;
+            }
+
+            let num_deleted = sqlx::query(&format!("delete from users where {inactive}"))
+                .bind(inactive_since)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+
+            transaction.commit().await?;
+
+            num_deleted
+        });
+
+        Ok(num_deleted as usize)
+    }
+}
+
+#[async_trait]
+impl TokenStore for Database {
+    async fn create_auth_token(
+        &self,
+        user_id: &UserId,
+        ttl: std::time::Duration,
+    ) -> Result<Token, StoreError> {
+        let token_bytes = generate_random_token_bytes();
+
+        let token = Token(token_bytes.clone());
+        let token_hash = token.get_hash();
+
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "insert into auth_tokens(user_id, token_hash, expires_at) values ($1, $2, $3)",
+            )
+            .bind(user_id)
+            .bind(&token_hash)
+            .bind(time::OffsetDateTime::now_utc() + ttl)
+            .execute(pool)
+            .await?;
+        });
+
+        Ok(token)
+    }
+
+    async fn get_user_by_token(&self, token: &Token) -> Result<Option<User>, StoreError> {
+        let result: Option<(UserId, bool, Option<time::OffsetDateTime>)> =
+            with_pool!(&self.pool, |pool| {
+                sqlx::query_as(
+                    "select users.user_id, users.is_guest, auth_tokens.expires_at
+                    from auth_tokens join users on users.user_id = auth_tokens.user_id
+                    where auth_tokens.token_hash = $1",
+                )
+                .bind(token.get_hash())
+                .fetch_optional(pool)
+                .await?
+            });
+
+        match result {
+            Some((user_id, is_guest, Some(expires_at)))
+                if expires_at > time::OffsetDateTime::now_utc() =>
+            {
+                Ok(Some(User { user_id, is_guest }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Tokens without an expiry predate token ttls, so they are treated as expired.
+    async fn garbage_collect_expired_tokens(&self) -> Result<usize, StoreError> {
+        let query = match self.pool {
+            // sqlite stores timestamps as rfc3339 text, which doesn't compare correctly as a string
+            Pool::Sqlite(_) => {
+                "delete from auth_tokens
+                where expires_at is null or julianday(expires_at) <= julianday('now')"
+            }
+            Pool::Postgres(_) => {
+                "delete from auth_tokens where expires_at is null or expires_at <= now()"
+            }
+        };
+
+        let num_deleted = with_pool!(&self.pool, |pool| {
+            sqlx::query(query).execute(pool).await?.rows_affected()
+        });
+
+        Ok(num_deleted as usize)
+    }
+
+    async fn count_active_auth_tokens(&self) -> Result<usize, StoreError> {
+        let query = match self.pool {
+            Pool::Sqlite(_) => {
+                "select count(*) from auth_tokens where julianday(expires_at) > julianday('now')"
+            }
+            Pool::Postgres(_) => "select count(*) from auth_tokens where expires_at > now()",
+        };
+
+        let (count,): (i64,) = with_pool!(&self.pool, |pool| {
+            sqlx::query_as(query).fetch_one(pool).await?
+        });
+
+        Ok(count as usize)
+    }
+
+    async fn revoke_auth_token(&self, token: &Token) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query("delete from auth_tokens where token_hash = $1")
+                .bind(token.get_hash())
+                .execute(pool)
+                .await?
+                .rows_affected()
+                > 0)
+        })
+    }
+
+    async fn revoke_user_auth_tokens(&self, user_id: &UserId) -> Result<usize, StoreError> {
+        let num_revoked = with_pool!(&self.pool, |pool| {
+            sqlx::query("delete from auth_tokens where user_id = $1")
                 .bind(user_id)
-                .fetch_optional(&self.pool)
+                .execute(pool)
                 .await?
-                .is_some(),
-        )
+                .rows_affected()
+        });
+
+        Ok(num_revoked as usize)
+    }
+}
+
+#[async_trait]
+impl DiscordIdentityStore for Database {
+    async fn discord_user_registered(&self, user_id: &DiscordUserId) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(
+                sqlx::query("select 1 from discord_oauth_users where discord_id = $1")
+                    .bind(user_id)
+                    .fetch_optional(pool)
+                    .await?
+                    .is_some(),
+            )
+        })
     }
 
-    pub async fn link_discord_id_to_user_id(
+    async fn link_discord_id_to_user_id(
         &self,
         user_id: &UserId,
         discord_info: &DiscordOauthUser,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "insert into discord_oauth_users (
-            linked_to_user_id,
-            discord_id,
-            refresh_token,
-            access_token,
-            expires_at
-        ) values (?, ?, ?, ?, ?)",
-        )
-        .bind(user_id)
-        .bind(&discord_info.discord_id)
-        .bind(&discord_info.refresh_token)
-        .bind(&discord_info.refresh_token)
-        .bind(&discord_info.expires_at)
-        .execute(&self.pool)
-        .await?;
+    ) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "insert into discord_oauth_users (
+                linked_to_user_id,
+                discord_id,
+                refresh_token,
+                access_token,
+                expires_at,
+                refreshed_at
+            ) values ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(user_id)
+            .bind(&discord_info.discord_id)
+            .bind(&discord_info.refresh_token)
+            .bind(&discord_info.access_token)
+            .bind(discord_info.expires_at)
+            .bind(time::OffsetDateTime::now_utc())
+            .execute(pool)
+            .await?;
+        });
 
         Ok(())
     }
 
-    pub async fn get_user_by_discord_id(
+    async fn get_user_by_discord_id(
         &self,
         user_id: &DiscordUserId,
-    ) -> Result<Option<UserId>, sqlx::Error> {
-        sqlx::query_as("select linked_to_user_id from discord_oauth_users where discord_id = ?")
+    ) -> Result<Option<UserId>, StoreError> {
+        Ok(with_pool!(&self.pool, |pool| {
+            sqlx::query_as(
+                "select linked_to_user_id from discord_oauth_users where discord_id = $1",
+            )
             .bind(user_id)
-            .fetch_optional(&self.pool)
-            .await
+            .fetch_optional(pool)
+            .await?
+        }))
     }
 
-    pub async fn create_auth_token(&self, user_id: &UserId) -> Result<Token, sqlx::Error> {
-        let token_bytes = generate_random_token_bytes();
+    async fn update_discord_tokens(
+        &self,
+        discord_info: &DiscordOauthUser,
+    ) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "update discord_oauth_users
+                set refresh_token = $1, access_token = $2, expires_at = $3, refreshed_at = $4
+                where discord_id = $5",
+            )
+            .bind(&discord_info.refresh_token)
+            .bind(&discord_info.access_token)
+            .bind(discord_info.expires_at)
+            .bind(time::OffsetDateTime::now_utc())
+            .bind(&discord_info.discord_id)
+            .execute(pool)
+            .await?;
+        });
 
-        let token = Token(token_bytes.clone());
-        let token_hash = token.get_hash();
+        Ok(())
+    }
+
+    async fn list_discord_users_refreshed_before(
+        &self,
+        refreshed_before: time::OffsetDateTime,
+    ) -> Result<Vec<DiscordOauthUser>, StoreError> {
+        let query = match self.pool {
+            Pool::Sqlite(_) => {
+                "select discord_id, linked_to_user_id, refresh_token, access_token, expires_at
+                from discord_oauth_users
+                where refreshed_at is null or julianday(refreshed_at) < julianday($1)"
+            }
+            Pool::Postgres(_) => {
+                "select discord_id, linked_to_user_id, refresh_token, access_token, expires_at
+                from discord_oauth_users
+                where refreshed_at is null or refreshed_at < $1"
+            }
+        };
+
+        Ok(with_pool!(&self.pool, |pool| {
+            sqlx::query_as(query)
+                .bind(refreshed_before)
+                .fetch_all(pool)
+                .await?
+        }))
+    }
+}
+
+#[async_trait]
+impl EmailIdentityStore for Database {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<UserId>, StoreError> {
+        Ok(with_pool!(&self.pool, |pool| {
+            sqlx::query_as("select user_id from email_users where email = $1")
+                .bind(email)
+                .fetch_optional(pool)
+                .await?
+        }))
+    }
 
-        sqlx::query("insert into auth_tokens(user_id, token_hash, expires_at) values (?, ?, ?)")
+    async fn link_email_to_user_id(
+        &self,
+        user_id: &UserId,
+        email: &str,
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "insert into email_users (email, user_id, created_at) values ($1, $2, $3)
+                on conflict do nothing",
+            )
+            .bind(email)
             .bind(user_id)
-            .bind(&token_hash)
-            .execute(&self.pool)
+            .bind(time::OffsetDateTime::now_utc())
+            .execute(pool)
+            .await?
+            .rows_affected()
+                > 0)
+        })
+    }
+
+    async fn create_email_login_code(&self, code: &EmailLoginCode) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "insert into email_login_codes
+                (code_hash, email, upgrade_user_id, expires_at, browser_hash)
+                values ($1, $2, $3, $4, $5)",
+            )
+            .bind(&code.code_hash)
+            .bind(&code.email)
+            .bind(&code.upgrade_guest)
+            .bind(code.expires_at)
+            .bind(&code.browser_hash)
+            .execute(pool)
             .await?;
+        });
 
-        Ok(token)
+        Ok(())
+    }
+
+    async fn consume_email_login_code(
+        &self,
+        code_hash: &TokenHash,
+        browser_hash: &TokenHash,
+    ) -> Result<Option<EmailLoginCode>, StoreError> {
+        // deleting and reading in one statement means two requests can't both redeem a code
+        let row: Option<(String, Option<UserId>, time::OffsetDateTime)> =
+            with_pool!(&self.pool, |pool| {
+                sqlx::query_as(
+                    "delete from email_login_codes where code_hash = $1 and browser_hash = $2
+                    returning email, upgrade_user_id, expires_at",
+                )
+                .bind(code_hash)
+                .bind(browser_hash)
+                .fetch_optional(pool)
+                .await?
+            });
+
+        Ok(row
+            .filter(|(_, _, expires_at)| *expires_at > time::OffsetDateTime::now_utc())
+            .map(|(email, upgrade_guest, expires_at)| EmailLoginCode {
+                code_hash: code_hash.clone(),
+                email,
+                upgrade_guest,
+                expires_at,
+                browser_hash: browser_hash.clone(),
+            }))
     }
 
-    pub async fn get_user_id_by_token(&self, token: &Token) -> Result<Option<UserId>, sqlx::Error> {
-        let result: Option<(UserId, String)> =
-            sqlx::query_as("select user_id from auth_tokens where token_hash = ?")
-                .bind(&token.get_hash())
-                .fetch_optional(&self.pool)
+    async fn garbage_collect_expired_email_login_codes(&self) -> Result<usize, StoreError> {
+        let query = match self.pool {
+            Pool::Sqlite(_) => {
+                "delete from email_login_codes where julianday(expires_at) <= julianday('now')"
+            }
+            Pool::Postgres(_) => "delete from email_login_codes where expires_at <= now()",
+        };
+
+        let num_deleted = with_pool!(&self.pool, |pool| {
+            sqlx::query(query).execute(pool).await?.rows_affected()
+        });
+
+        Ok(num_deleted as usize)
+    }
+}
+
+#[async_trait]
+impl ServiceCredentialStore for Database {
+    async fn create_service_credential(
+        &self,
+        name: &str,
+        scopes: &[ServiceScope],
+    ) -> Result<(ServiceCredential, ServiceKey), StoreError> {
+        let key = generate_service_key();
+        let created_at = time::OffsetDateTime::now_utc();
+
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "insert into service_credentials (
+                credential_id,
+                name,
+                secret_hash,
+                scopes,
+                created_at
+            ) values ($1, $2, $3, $4, $5)",
+            )
+            .bind(&key.credential_id)
+            .bind(name)
+            .bind(key.secret.get_hash())
+            .bind(ServiceScope::join(scopes))
+            .bind(created_at)
+            .execute(pool)
+            .await?;
+        });
+
+        let credential = ServiceCredential {
+            credential_id: key.credential_id.clone(),
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created_at,
+            rotated_at: None,
+        };
+
+        Ok((credential, key))
+    }
+
+    async fn rotate_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<Option<ServiceKey>, StoreError> {
+        let key = ServiceKey {
+            credential_id: credential_id.clone(),
+            secret: generate_service_key().secret,
+        };
+
+        let rows_affected = with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "update service_credentials set secret_hash = $1, rotated_at = $2
+                where credential_id = $3",
+            )
+            .bind(key.secret.get_hash())
+            .bind(time::OffsetDateTime::now_utc())
+            .bind(credential_id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+        });
+
+        Ok((rows_affected > 0).then_some(key))
+    }
+
+    async fn revoke_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(
+                sqlx::query("delete from service_credentials where credential_id = $1")
+                    .bind(credential_id)
+                    .execute(pool)
+                    .await?
+                    .rows_affected()
+                    > 0,
+            )
+        })
+    }
+
+    async fn list_service_credentials(&self) -> Result<Vec<ServiceCredential>, StoreError> {
+        let rows: Vec<ServiceCredentialRow> = with_pool!(&self.pool, |pool| {
+            sqlx::query_as(
+                "select credential_id, name, scopes, created_at, rotated_at
+                from service_credentials order by created_at",
+            )
+            .fetch_all(pool)
+            .await?
+        });
+
+        Ok(rows.into_iter().map(service_credential_from_row).collect())
+    }
+
+    async fn get_service_credential_by_key(
+        &self,
+        key: &ServiceKey,
+    ) -> Result<Option<ServiceCredential>, StoreError> {
+        let row: Option<ServiceCredentialRow> = with_pool!(&self.pool, |pool| {
+            sqlx::query_as(
+                "select credential_id, name, scopes, created_at, rotated_at
+                from service_credentials where credential_id = $1 and secret_hash = $2",
+            )
+            .bind(&key.credential_id)
+            .bind(key.secret.get_hash())
+            .fetch_optional(pool)
+            .await?
+        });
+
+        Ok(row.map(service_credential_from_row))
+    }
+}
+
+#[async_trait]
+impl JoinTicketStore for Database {
+    async fn mark_join_ticket_redeemed(
+        &self,
+        ticket_id: &str,
+        expires_at: time::OffsetDateTime,
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "insert into redeemed_join_tickets (ticket_id, expires_at) values ($1, $2)
+                on conflict do nothing",
+            )
+            .bind(ticket_id)
+            .bind(expires_at)
+            .execute(pool)
+            .await?
+            .rows_affected()
+                > 0)
+        })
+    }
+
+    async fn garbage_collect_redeemed_join_tickets(&self) -> Result<usize, StoreError> {
+        let query = match self.pool {
+            Pool::Sqlite(_) => {
+                "delete from redeemed_join_tickets where julianday(expires_at) <= julianday('now')"
+            }
+            Pool::Postgres(_) => "delete from redeemed_join_tickets where expires_at <= now()",
+        };
+
+        let num_deleted = with_pool!(&self.pool, |pool| {
+            sqlx::query(query).execute(pool).await?.rows_affected()
+        });
+
+        Ok(num_deleted as usize)
+    }
+}
+
+#[async_trait]
+impl RoleStore for Database {
+    async fn get_user_roles(&self, user_id: &UserId) -> Result<Vec<Role>, StoreError> {
+        let roles: Vec<(String,)> = with_pool!(&self.pool, |pool| {
+            sqlx::query_as("select role from user_roles where user_id = $1 order by role")
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?
+        });
+
+        // unknown roles are dropped, like service scopes
+        Ok(roles
+            .into_iter()
+            .filter_map(|(role,)| role.parse().ok())
+            .collect())
+    }
+
+    async fn grant_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "insert into user_roles (user_id, role) values ($1, $2)
+                on conflict (user_id, role) do update set granted_by_discord = false
+                where user_roles.granted_by_discord",
+            )
+            .bind(user_id)
+            .bind(role.as_str())
+            .execute(pool)
+            .await?
+            .rows_affected()
+                > 0)
+        })
+    }
+
+    async fn revoke_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(
+                sqlx::query("delete from user_roles where user_id = $1 and role = $2")
+                    .bind(user_id)
+                    .bind(role.as_str())
+                    .execute(pool)
+                    .await?
+                    .rows_affected()
+                    > 0,
+            )
+        })
+    }
+
+    async fn sync_discord_roles(&self, user_id: &UserId, roles: &[Role]) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
+
+            sqlx::query("delete from user_roles where user_id = $1 and granted_by_discord")
+                .bind(user_id)
+                .execute(&mut *transaction)
                 .await?;
 
-        match result {
-            Some((user_id, expires_at)) => {
-                let expires_at_time = time::OffsetDateTime::parse(&expires_at, &Iso8601::DEFAULT)
-                    .expect("stored timestamp was not valid?");
+            // roles that were also granted some other way stay that way
+            for role in roles {
+                sqlx::query(
+                    "insert into user_roles (user_id, role, granted_by_discord) values ($1, $2, true)
+                    on conflict do nothing",
+                )
+                .bind(user_id)
+                .bind(role.as_str())
+                .execute(&mut *transaction)
+                .await?;
+            }
+
+            transaction.commit().await?;
+        });
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for Database {
+    async fn get_totp_credential(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<TotpCredential>, StoreError> {
+        let row: Option<(Vec<u8>, Option<time::OffsetDateTime>, Option<i64>)> =
+            with_pool!(&self.pool, |pool| {
+                sqlx::query_as(
+                    "select secret, enabled_at, last_used_step from totp_credentials
+                    where user_id = $1",
+                )
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?
+            });
+
+        Ok(
+            row.map(|(secret, enabled_at, last_used_step)| TotpCredential {
+                secret,
+                enabled_at,
+                last_used_step,
+            }),
+        )
+    }
 
-                if expires_at_time <= time::OffsetDateTime::now_utc() {
-                    return Ok(None);
-                }
+    async fn set_pending_totp_secret(
+        &self,
+        user_id: &UserId,
+        secret: &[u8],
+    ) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "insert into totp_credentials (user_id, secret) values ($1, $2)
+                on conflict (user_id) do update set secret = excluded.secret, last_used_step = null
+                where totp_credentials.enabled_at is null",
+            )
+            .bind(user_id)
+            .bind(secret)
+            .execute(pool)
+            .await?;
+        });
 
-                Ok(Some(user_id))
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
+
+            let was_pending = sqlx::query(
+                "update totp_credentials set enabled_at = $1
+                where user_id = $2 and enabled_at is null",
+            )
+            .bind(time::OffsetDateTime::now_utc())
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected()
+                > 0;
+
+            if !was_pending {
+                return Ok(false);
             }
-            None => Ok(None),
-        }
+
+            sqlx::query("delete from recovery_codes where user_id = $1")
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+
+            for code_hash in recovery_code_hashes {
+                sqlx::query("insert into recovery_codes (user_id, code_hash) values ($1, $2)")
+                    .bind(user_id)
+                    .bind(code_hash)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+
+            transaction.commit().await?;
+
+            Ok(true)
+        })
     }
 
-    // NOTE: this wouldn't need to be anywhere near as complex if we used a real db like psql that can do datetimes natively
-    pub async fn garbage_collect_expired_tokens(&self) -> Result<usize, sqlx::Error> {
-        let mut num_deleted = 0;
+    async fn record_totp_step(&self, user_id: &UserId, step: i64) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "update totp_credentials set last_used_step = $1
+                where user_id = $2 and (last_used_step is null or last_used_step < $1)",
+            )
+            .bind(step)
+            .bind(user_id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+                > 0)
+        })
+    }
 
-        let now = time::OffsetDateTime::now_utc();
+    async fn disable_totp(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
 
-        let mut stream =
-            sqlx::query_as("select (token_hash, expires_at) from auth_tokens").fetch(&self.pool);
+            sqlx::query("delete from recovery_codes where user_id = $1")
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
 
-        while let Some(entry) = stream.next().await {
-            let (token_hash, expires_at): (Vec<u8>, String) = entry?;
+            let was_enabled = sqlx::query("delete from totp_credentials where user_id = $1")
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected()
+                > 0;
 
-            let expires_at_time = time::OffsetDateTime::parse(&expires_at, &Iso8601::DEFAULT)
-                .expect("stored timestamp was not valid?");
+            transaction.commit().await?;
 
-            if expires_at_time <= now {
-                num_deleted += 1;
+            Ok(was_enabled)
+        })
+    }
 
-                sqlx::query("delete from auth_tokens where token_hash = $1")
-                    .bind(token_hash)
-                    .execute(&self.pool)
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            let mut transaction = pool.begin().await?;
+
+            sqlx::query("delete from recovery_codes where user_id = $1")
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+
+            for code_hash in recovery_code_hashes {
+                sqlx::query("insert into recovery_codes (user_id, code_hash) values ($1, $2)")
+                    .bind(user_id)
+                    .bind(code_hash)
+                    .execute(&mut *transaction)
                     .await?;
             }
-        }
 
-        Ok(num_deleted)
+            transaction.commit().await?;
+        });
+
+        Ok(())
+    }
+
+    async fn consume_recovery_code(
+        &self,
+        user_id: &UserId,
+        recovery_code_hash: &TokenHash,
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(
+                sqlx::query("delete from recovery_codes where user_id = $1 and code_hash = $2")
+                    .bind(user_id)
+                    .bind(recovery_code_hash)
+                    .execute(pool)
+                    .await?
+                    .rows_affected()
+                    > 0,
+            )
+        })
+    }
+
+    async fn count_recovery_codes(&self, user_id: &UserId) -> Result<usize, StoreError> {
+        let (count,): (i64,) = with_pool!(&self.pool, |pool| {
+            sqlx::query_as("select count(*) from recovery_codes where user_id = $1")
+                .bind(user_id)
+                .fetch_one(pool)
+                .await?
+        });
+
+        Ok(count as usize)
     }
+}
+
+#[async_trait]
+impl HealthStore for Database {
+    async fn ping(&self) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query("select 1").execute(pool).await?;
+            Ok(())
+        })
+    }
+}
 
-    pub async fn revoke_auth_token(&self, token: &Token) -> Result<bool, sqlx::Error> {
-        Ok(sqlx::query("delete from auth_tokens where token_hash = $1")
-            .bind(&token.get_hash())
-            .execute(&self.pool)
+#[async_trait]
+impl PasskeyStore for Database {
+    async fn add_passkey_credential(
+        &self,
+        credential: &PasskeyCredential,
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "insert into passkey_credentials (
+                    credential_id,
+                    user_id,
+                    name,
+                    public_key,
+                    sign_count,
+                    created_at
+                ) values ($1, $2, $3, $4, $5, $6)
+                on conflict do nothing",
+            )
+            .bind(&credential.credential_id)
+            .bind(&credential.user_id)
+            .bind(&credential.name)
+            .bind(&credential.public_key)
+            .bind(i64::from(credential.sign_count))
+            .bind(credential.created_at)
+            .execute(pool)
             .await?
             .rows_affected()
-            > 0)
+                > 0)
+        })
+    }
+
+    async fn get_passkey_credential(
+        &self,
+        credential_id: &[u8],
+    ) -> Result<Option<PasskeyCredential>, StoreError> {
+        let row: Option<PasskeyCredentialRow> = with_pool!(&self.pool, |pool| {
+            sqlx::query_as(
+                "select credential_id, user_id, name, public_key, sign_count, created_at, last_used_at
+                from passkey_credentials where credential_id = $1",
+            )
+            .bind(credential_id)
+            .fetch_optional(pool)
+            .await?
+        });
+
+        Ok(row.map(passkey_credential_from_row))
+    }
+
+    async fn list_passkey_credentials(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<PasskeyCredential>, StoreError> {
+        let rows: Vec<PasskeyCredentialRow> = with_pool!(&self.pool, |pool| {
+            sqlx::query_as(
+                "select credential_id, user_id, name, public_key, sign_count, created_at, last_used_at
+                from passkey_credentials where user_id = $1 order by created_at",
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?
+        });
+
+        Ok(rows.into_iter().map(passkey_credential_from_row).collect())
+    }
+
+    async fn update_passkey_sign_count(
+        &self,
+        credential_id: &[u8],
+        sign_count: u32,
+    ) -> Result<(), StoreError> {
+        with_pool!(&self.pool, |pool| {
+            sqlx::query(
+                "update passkey_credentials set sign_count = $1, last_used_at = $2
+                where credential_id = $3",
+            )
+            .bind(i64::from(sign_count))
+            .bind(time::OffsetDateTime::now_utc())
+            .bind(credential_id)
+            .execute(pool)
+            .await?;
+        });
+
+        Ok(())
+    }
+
+    async fn delete_passkey_credential(
+        &self,
+        user_id: &UserId,
+        credential_id: &[u8],
+    ) -> Result<bool, StoreError> {
+        with_pool!(&self.pool, |pool| {
+            Ok(sqlx::query(
+                "delete from passkey_credentials where user_id = $1 and credential_id = $2",
+            )
+            .bind(user_id)
+            .bind(credential_id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+                > 0)
+        })
+    }
+}
+
+type PasskeyCredentialRow = (
+    Vec<u8>,
+    UserId,
+    String,
+    Vec<u8>,
+    i64,
+    time::OffsetDateTime,
+    Option<time::OffsetDateTime>,
+);
+
+fn passkey_credential_from_row(
+    (credential_id, user_id, name, public_key, sign_count, created_at, last_used_at): PasskeyCredentialRow,
+) -> PasskeyCredential {
+    PasskeyCredential {
+        credential_id,
+        user_id,
+        name,
+        public_key,
+        sign_count: sign_count as u32,
+        created_at,
+        last_used_at,
     }
 }
 
-fn generate_random_token_bytes() -> Vec<u8> {
+type ServiceCredentialRow = (
+    ServiceCredentialId,
+    String,
+    String,
+    time::OffsetDateTime,
+    Option<time::OffsetDateTime>,
+);
+
+fn service_credential_from_row(
+    (credential_id, name, scopes, created_at, rotated_at): ServiceCredentialRow,
+) -> ServiceCredential {
+    ServiceCredential {
+        credential_id,
+        name,
+        scopes: ServiceScope::split(&scopes),
+        created_at,
+        rotated_at,
+    }
+}
+
+pub(crate) fn generate_service_key() -> ServiceKey {
+    let mut secret = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    ServiceKey {
+        credential_id: ServiceCredentialId(generate_random_user_id(16).0),
+        secret: Token(secret),
+    }
+}
+
+pub(crate) fn generate_random_token_bytes() -> Vec<u8> {
     let mut rng = rand::thread_rng();
     let mut token_bytes = vec![0; 512];
     rng.fill_bytes(&mut token_bytes);
     token_bytes
 }
 
-fn generate_random_user_id(size: usize) -> UserId {
+pub(crate) fn generate_random_user_id(size: usize) -> UserId {
     let mut rng = rand::thread_rng();
     let mut user_id = String::with_capacity(size);
 
@@ -202,3 +1091,358 @@ fn generate_random_user_id(size: usize) -> UserId {
 
     UserId(user_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// A throwaway postgres server in its own data directory, stopped and deleted again on drop.
+    struct TestPostgres {
+        pg_ctl: PathBuf,
+        data_dir: PathBuf,
+        url: String,
+    }
+
+    impl TestPostgres {
+        /// Uses the `initdb` and `pg_ctl` binaries on the `PATH`. Returns `None` if they aren't
+        /// installed.
+        fn start() -> Option<Self> {
+            let initdb = find_binary("initdb")?;
+            let pg_ctl = find_binary("pg_ctl")?;
+
+            // created by initdb itself, so it belongs to whoever runs the server
+            let data_dir = std::env::temp_dir().join(format!(
+                "auth_provider-test-{}",
+                hex::encode(rand::random::<[u8; 8]>())
+            ));
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .and_then(|listener| listener.local_addr())
+                .expect("failed to find a free port")
+                .port();
+
+            run(postgres_command(&initdb)
+                .args(["--auth=trust", "--username=postgres", "--no-sync", "-D"])
+                .arg(&data_dir));
+
+            let server = Self {
+                pg_ctl,
+                url: format!("postgres://postgres@127.0.0.1:{port}/postgres"),
+                data_dir,
+            };
+
+            run(postgres_command(&server.pg_ctl)
+                .args(["start", "--wait", "-D"])
+                .arg(&server.data_dir)
+                .arg("-l")
+                .arg(server.data_dir.join("server.log"))
+                .arg("-o")
+                .arg(format!(
+                    "-p {port} -c listen_addresses=127.0.0.1 -c fsync=off -k {}",
+                    server.data_dir.display()
+                )));
+
+            Some(server)
+        }
+    }
+
+    impl Drop for TestPostgres {
+        fn drop(&mut self) {
+            let _ = postgres_command(&self.pg_ctl)
+                .args(["stop", "--mode=immediate", "-D"])
+                .arg(&self.data_dir)
+                .status();
+            let _ = std::fs::remove_dir_all(&self.data_dir);
+        }
+    }
+
+    fn find_binary(name: &str) -> Option<PathBuf> {
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    /// Postgres refuses to run as root, so it's run as the `postgres` user instead when the tests
+    /// are.
+    fn postgres_command(program: &Path) -> Command {
+        // SAFETY: geteuid has no preconditions and can't fail
+        let mut command = if unsafe { libc::geteuid() } == 0 {
+            let mut command = Command::new("runuser");
+            command.args(["-u", "postgres", "--"]).arg(program);
+            command
+        } else {
+            Command::new(program)
+        };
+
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        command
+    }
+
+    fn run(command: &mut Command) {
+        let status = command.status().expect("failed to run postgres tooling");
+        assert!(status.success(), "{command:?} failed with {status}");
+    }
+
+    struct Backend {
+        name: &'static str,
+        database: Database,
+        _sqlite_dir: Option<tempfile::TempDir>,
+        _postgres: Option<TestPostgres>,
+    }
+
+    /// A freshly migrated database for each backend. Postgres is skipped if it isn't installed.
+    async fn backends() -> Vec<Backend> {
+        let sqlite_dir = tempfile::tempdir().unwrap();
+        let sqlite = Database::new(&format!(
+            "sqlite://{}?mode=rwc",
+            sqlite_dir.path().join("test.db").display()
+        ))
+        .await
+        .unwrap();
+
+        let mut backends = vec![Backend {
+            name: "sqlite",
+            database: sqlite,
+            _sqlite_dir: Some(sqlite_dir),
+            _postgres: None,
+        }];
+
+        match TestPostgres::start() {
+            Some(postgres) => backends.push(Backend {
+                name: "postgres",
+                database: Database::new(&postgres.url).await.unwrap(),
+                _sqlite_dir: None,
+                _postgres: Some(postgres),
+            }),
+            None => eprintln!("postgres isn't installed, only testing sqlite"),
+        }
+
+        for backend in &backends {
+            backend.database.run_migrations().await.unwrap();
+        }
+
+        backends
+    }
+
+    #[tokio::test]
+    async fn expired_rows_are_garbage_collected() {
+        for Backend { name, database, .. } in &backends().await {
+            let user = database.create_new_user(UserKind::Full).await.unwrap();
+            let expired = database
+                .create_auth_token(&user.user_id, Duration::ZERO)
+                .await
+                .unwrap();
+            let active = database
+                .create_auth_token(&user.user_id, Duration::from_secs(60 * 60))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                database.count_active_auth_tokens().await.unwrap(),
+                1,
+                "{name}"
+            );
+            assert_eq!(
+                database.garbage_collect_expired_tokens().await.unwrap(),
+                1,
+                "{name}"
+            );
+            assert!(database.get_user_by_token(&active).await.unwrap().is_some());
+            assert!(
+                !database.revoke_auth_token(&expired).await.unwrap(),
+                "{name}"
+            );
+
+            let now = time::OffsetDateTime::now_utc();
+            for (code, expires_at) in [
+                (b"expired".to_vec(), now - Duration::from_secs(1)),
+                (b"active".to_vec(), now + Duration::from_secs(60)),
+            ] {
+                database
+                    .create_email_login_code(&EmailLoginCode {
+                        code_hash: Token(code).get_hash(),
+                        email: "player@example.com".to_string(),
+                        upgrade_guest: None,
+                        browser_hash: Token(b"browser".to_vec()).get_hash(),
+                        expires_at,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            assert_eq!(
+                database
+                    .garbage_collect_expired_email_login_codes()
+                    .await
+                    .unwrap(),
+                1,
+                "{name}"
+            );
+            assert!(database
+                .consume_email_login_code(
+                    &Token(b"active".to_vec()).get_hash(),
+                    &Token(b"browser".to_vec()).get_hash(),
+                )
+                .await
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrading_a_guest_revokes_its_tokens() {
+        for Backend { name, database, .. } in &backends().await {
+            let guest = database.create_new_user(UserKind::Guest).await.unwrap();
+            let guest_token = database
+                .create_auth_token(&guest.user_id, Duration::from_secs(60))
+                .await
+                .unwrap();
+            let other = database.create_new_user(UserKind::Guest).await.unwrap();
+            let other_token = database
+                .create_auth_token(&other.user_id, Duration::from_secs(60))
+                .await
+                .unwrap();
+
+            assert!(
+                database.upgrade_guest_user(&guest.user_id).await.unwrap(),
+                "{name}"
+            );
+            assert!(
+                database
+                    .get_user_by_token(&guest_token)
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+            assert!(
+                database
+                    .get_user_by_token(&other_token)
+                    .await
+                    .unwrap()
+                    .is_some(),
+                "{name}"
+            );
+
+            // only the first upgrade revokes anything
+            let full_token = database
+                .create_auth_token(&guest.user_id, Duration::from_secs(60))
+                .await
+                .unwrap();
+            assert!(
+                !database.upgrade_guest_user(&guest.user_id).await.unwrap(),
+                "{name}"
+            );
+            assert!(
+                database
+                    .get_user_by_token(&full_token)
+                    .await
+                    .unwrap()
+                    .is_some(),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_inactive_guests_are_garbage_collected() {
+        for Backend { name, database, .. } in &backends().await {
+            let inactive = database.create_new_user(UserKind::Guest).await.unwrap();
+            let inactive_token = database
+                .create_auth_token(&inactive.user_id, Duration::from_secs(60))
+                .await
+                .unwrap();
+            database
+                .grant_role(&inactive.user_id, Role::Admin)
+                .await
+                .unwrap();
+            let full = database.create_new_user(UserKind::Full).await.unwrap();
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let inactive_since = time::OffsetDateTime::now_utc();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let active = database.create_new_user(UserKind::Guest).await.unwrap();
+
+            assert_eq!(
+                database
+                    .garbage_collect_inactive_guests(inactive_since)
+                    .await
+                    .unwrap(),
+                1,
+                "{name}"
+            );
+            assert!(!database.user_id_exists(&inactive.user_id).await.unwrap());
+            assert!(database
+                .get_user_by_token(&inactive_token)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(database
+                .get_user_roles(&inactive.user_id)
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(database.user_id_exists(&active.user_id).await.unwrap());
+            assert!(database.user_id_exists(&full.user_id).await.unwrap());
+
+            // being seen again keeps a guest around
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let inactive_since = time::OffsetDateTime::now_utc();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            database.touch_user(&active.user_id).await.unwrap();
+
+            assert_eq!(
+                database
+                    .garbage_collect_inactive_guests(inactive_since)
+                    .await
+                    .unwrap(),
+                0,
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn join_tickets_are_only_redeemed_once() {
+        for Backend { name, database, .. } in &backends().await {
+            let now = time::OffsetDateTime::now_utc();
+            let later = now + Duration::from_secs(60);
+
+            assert!(database
+                .mark_join_ticket_redeemed("ticket", later)
+                .await
+                .unwrap());
+            assert!(
+                !database
+                    .mark_join_ticket_redeemed("ticket", later)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            assert!(database
+                .mark_join_ticket_redeemed("other", now - Duration::from_secs(1))
+                .await
+                .unwrap());
+
+            assert_eq!(
+                database
+                    .garbage_collect_redeemed_join_tickets()
+                    .await
+                    .unwrap(),
+                1,
+                "{name}"
+            );
+            // still remembered until it expires
+            assert!(!database
+                .mark_join_ticket_redeemed("ticket", later)
+                .await
+                .unwrap());
+        }
+    }
+}
@@ -0,0 +1,616 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+
+use super::{
+    generate_random_token_bytes, generate_random_user_id, generate_service_key,
+    models::{
+        DiscordOauthUser, DiscordUserId, EmailLoginCode, PasskeyCredential, Role,
+        ServiceCredential, ServiceCredentialId, ServiceKey, ServiceScope, Token, TokenHash,
+        TotpCredential, User, UserId, UserKind,
+    },
+    repository::{
+        DiscordIdentityStore, EmailIdentityStore, HealthStore, JoinTicketStore, PasskeyStore,
+        RoleStore, ServiceCredentialStore, StoreError, TokenStore, TwoFactorStore, UserStore,
+    },
+};
+
+/// A [`Repository`](super::repository::Repository) that keeps everything in memory, for tests and
+/// local experiments. Nothing is persisted.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    /// Each user alongside when they were last seen.
+    users: DashMap<UserId, (User, time::OffsetDateTime)>,
+    auth_tokens: DashMap<TokenHash, (UserId, time::OffsetDateTime)>,
+    /// Each link alongside when its tokens were last refreshed.
+    discord_users: DashMap<DiscordUserId, (DiscordOauthUser, time::OffsetDateTime)>,
+    email_users: DashMap<String, UserId>,
+    email_login_codes: DashMap<TokenHash, EmailLoginCode>,
+    service_credentials: DashMap<ServiceCredentialId, (ServiceCredential, TokenHash)>,
+    redeemed_join_tickets: DashMap<String, time::OffsetDateTime>,
+    user_roles: DashMap<UserId, HashSet<Role>>,
+    /// The subset of `user_roles` that was granted through discord.
+    discord_roles: DashMap<UserId, HashSet<Role>>,
+    totp_credentials: DashMap<UserId, TotpCredential>,
+    recovery_codes: DashMap<UserId, HashSet<TokenHash>>,
+    passkey_credentials: DashMap<Vec<u8>, PasskeyCredential>,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryDatabase {
+    async fn user_id_exists(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        Ok(self.users.contains_key(user_id))
+    }
+
+    async fn create_new_user(&self, kind: UserKind) -> Result<User, StoreError> {
+        loop {
+            let user = User {
+                user_id: generate_random_user_id(24),
+                is_guest: kind == UserKind::Guest,
+            };
+
+            if let dashmap::Entry::Vacant(entry) = self.users.entry(user.user_id.clone()) {
+                entry.insert((user.clone(), time::OffsetDateTime::now_utc()));
+                return Ok(user);
+            }
+        }
+    }
+
+    async fn upgrade_guest_user(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        Ok(match self.users.get_mut(user_id) {
+            Some(mut entry) if entry.0.is_guest => {
+                entry.0.is_guest = false;
+                self.auth_tokens
+                    .retain(|_, (token_user_id, _)| token_user_id != user_id);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn touch_user(&self, user_id: &UserId) -> Result<(), StoreError> {
+        if let Some(mut entry) = self.users.get_mut(user_id) {
+            entry.1 = time::OffsetDateTime::now_utc();
+        }
+
+        Ok(())
+    }
+
+    async fn garbage_collect_inactive_guests(
+        &self,
+        inactive_since: time::OffsetDateTime,
+    ) -> Result<usize, StoreError> {
+        let before = self.users.len();
+
+        self.users
+            .retain(|_, (user, last_seen_at)| !user.is_guest || *last_seen_at >= inactive_since);
+        self.auth_tokens
+            .retain(|_, (user_id, _)| self.users.contains_key(user_id));
+        self.user_roles
+            .retain(|user_id, _| self.users.contains_key(user_id));
+        self.discord_roles
+            .retain(|user_id, _| self.users.contains_key(user_id));
+        self.totp_credentials
+            .retain(|user_id, _| self.users.contains_key(user_id));
+        self.recovery_codes
+            .retain(|user_id, _| self.users.contains_key(user_id));
+
+        Ok(before.saturating_sub(self.users.len()))
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryDatabase {
+    async fn create_auth_token(
+        &self,
+        user_id: &UserId,
+        ttl: std::time::Duration,
+    ) -> Result<Token, StoreError> {
+        let token = Token(generate_random_token_bytes());
+
+        self.auth_tokens.insert(
+            token.get_hash(),
+            (user_id.clone(), time::OffsetDateTime::now_utc() + ttl),
+        );
+
+        Ok(token)
+    }
+
+    async fn get_user_by_token(&self, token: &Token) -> Result<Option<User>, StoreError> {
+        let user_id = self
+            .auth_tokens
+            .get(&token.get_hash())
+            .filter(|entry| entry.1 > time::OffsetDateTime::now_utc())
+            .map(|entry| entry.0.clone());
+
+        Ok(user_id.and_then(|user_id| self.users.get(&user_id).map(|entry| entry.0.clone())))
+    }
+
+    async fn garbage_collect_expired_tokens(&self) -> Result<usize, StoreError> {
+        let now = time::OffsetDateTime::now_utc();
+        let before = self.auth_tokens.len();
+
+        self.auth_tokens
+            .retain(|_, (_, expires_at)| *expires_at > now);
+
+        Ok(before.saturating_sub(self.auth_tokens.len()))
+    }
+
+    async fn count_active_auth_tokens(&self) -> Result<usize, StoreError> {
+        let now = time::OffsetDateTime::now_utc();
+
+        Ok(self
+            .auth_tokens
+            .iter()
+            .filter(|entry| entry.1 > now)
+            .count())
+    }
+
+    async fn revoke_auth_token(&self, token: &Token) -> Result<bool, StoreError> {
+        Ok(self.auth_tokens.remove(&token.get_hash()).is_some())
+    }
+
+    async fn revoke_user_auth_tokens(&self, user_id: &UserId) -> Result<usize, StoreError> {
+        let before = self.auth_tokens.len();
+
+        self.auth_tokens
+            .retain(|_, (token_user_id, _)| token_user_id != user_id);
+
+        Ok(before.saturating_sub(self.auth_tokens.len()))
+    }
+}
+
+#[async_trait]
+impl DiscordIdentityStore for MemoryDatabase {
+    async fn discord_user_registered(&self, user_id: &DiscordUserId) -> Result<bool, StoreError> {
+        Ok(self.discord_users.contains_key(user_id))
+    }
+
+    async fn link_discord_id_to_user_id(
+        &self,
+        user_id: &UserId,
+        discord_info: &DiscordOauthUser,
+    ) -> Result<(), StoreError> {
+        self.discord_users.insert(
+            discord_info.discord_id.clone(),
+            (
+                DiscordOauthUser {
+                    linked_to_user_id: user_id.clone(),
+                    ..discord_info.clone()
+                },
+                time::OffsetDateTime::now_utc(),
+            ),
+        );
+
+        Ok(())
+    }
+
+    async fn get_user_by_discord_id(
+        &self,
+        user_id: &DiscordUserId,
+    ) -> Result<Option<UserId>, StoreError> {
+        Ok(self
+            .discord_users
+            .get(user_id)
+            .map(|entry| entry.0.linked_to_user_id.clone()))
+    }
+
+    async fn update_discord_tokens(
+        &self,
+        discord_info: &DiscordOauthUser,
+    ) -> Result<(), StoreError> {
+        if let Some(mut entry) = self.discord_users.get_mut(&discord_info.discord_id) {
+            let (link, refreshed_at) = &mut *entry;
+            link.refresh_token = discord_info.refresh_token.clone();
+            link.access_token = discord_info.access_token.clone();
+            link.expires_at = discord_info.expires_at;
+            *refreshed_at = time::OffsetDateTime::now_utc();
+        }
+
+        Ok(())
+    }
+
+    async fn list_discord_users_refreshed_before(
+        &self,
+        refreshed_before: time::OffsetDateTime,
+    ) -> Result<Vec<DiscordOauthUser>, StoreError> {
+        Ok(self
+            .discord_users
+            .iter()
+            .filter(|entry| entry.1 < refreshed_before)
+            .map(|entry| entry.0.clone())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl EmailIdentityStore for MemoryDatabase {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<UserId>, StoreError> {
+        Ok(self.email_users.get(email).map(|entry| entry.clone()))
+    }
+
+    async fn link_email_to_user_id(
+        &self,
+        user_id: &UserId,
+        email: &str,
+    ) -> Result<bool, StoreError> {
+        match self.email_users.entry(email.to_string()) {
+            dashmap::Entry::Occupied(_) => Ok(false),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(user_id.clone());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn create_email_login_code(&self, code: &EmailLoginCode) -> Result<(), StoreError> {
+        self.email_login_codes
+            .insert(code.code_hash.clone(), code.clone());
+
+        Ok(())
+    }
+
+    async fn consume_email_login_code(
+        &self,
+        code_hash: &TokenHash,
+        browser_hash: &TokenHash,
+    ) -> Result<Option<EmailLoginCode>, StoreError> {
+        Ok(self
+            .email_login_codes
+            .remove_if(code_hash, |_, code| code.browser_hash == *browser_hash)
+            .map(|(_, code)| code)
+            .filter(|code| code.expires_at > time::OffsetDateTime::now_utc()))
+    }
+
+    async fn garbage_collect_expired_email_login_codes(&self) -> Result<usize, StoreError> {
+        let now = time::OffsetDateTime::now_utc();
+        let before = self.email_login_codes.len();
+
+        self.email_login_codes
+            .retain(|_, code| code.expires_at > now);
+
+        Ok(before.saturating_sub(self.email_login_codes.len()))
+    }
+}
+
+#[async_trait]
+impl ServiceCredentialStore for MemoryDatabase {
+    async fn create_service_credential(
+        &self,
+        name: &str,
+        scopes: &[ServiceScope],
+    ) -> Result<(ServiceCredential, ServiceKey), StoreError> {
+        let key = loop {
+            let key = generate_service_key();
+
+            if !self.service_credentials.contains_key(&key.credential_id) {
+                break key;
+            }
+        };
+
+        let credential = ServiceCredential {
+            credential_id: key.credential_id.clone(),
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: time::OffsetDateTime::now_utc(),
+            rotated_at: None,
+        };
+
+        self.service_credentials.insert(
+            key.credential_id.clone(),
+            (credential.clone(), key.secret.get_hash()),
+        );
+
+        Ok((credential, key))
+    }
+
+    async fn rotate_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<Option<ServiceKey>, StoreError> {
+        let Some(mut entry) = self.service_credentials.get_mut(credential_id) else {
+            return Ok(None);
+        };
+
+        let key = ServiceKey {
+            credential_id: credential_id.clone(),
+            secret: generate_service_key().secret,
+        };
+
+        entry.0.rotated_at = Some(time::OffsetDateTime::now_utc());
+        entry.1 = key.secret.get_hash();
+
+        Ok(Some(key))
+    }
+
+    async fn revoke_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<bool, StoreError> {
+        Ok(self.service_credentials.remove(credential_id).is_some())
+    }
+
+    async fn list_service_credentials(&self) -> Result<Vec<ServiceCredential>, StoreError> {
+        Ok(self
+            .service_credentials
+            .iter()
+            .map(|entry| entry.0.clone())
+            .collect())
+    }
+
+    async fn get_service_credential_by_key(
+        &self,
+        key: &ServiceKey,
+    ) -> Result<Option<ServiceCredential>, StoreError> {
+        Ok(self
+            .service_credentials
+            .get(&key.credential_id)
+            .filter(|entry| entry.1 == key.secret.get_hash())
+            .map(|entry| entry.0.clone()))
+    }
+}
+
+#[async_trait]
+impl JoinTicketStore for MemoryDatabase {
+    async fn mark_join_ticket_redeemed(
+        &self,
+        ticket_id: &str,
+        expires_at: time::OffsetDateTime,
+    ) -> Result<bool, StoreError> {
+        Ok(self
+            .redeemed_join_tickets
+            .insert(ticket_id.to_string(), expires_at)
+            .is_none())
+    }
+
+    async fn garbage_collect_redeemed_join_tickets(&self) -> Result<usize, StoreError> {
+        let now = time::OffsetDateTime::now_utc();
+        let before = self.redeemed_join_tickets.len();
+
+        self.redeemed_join_tickets
+            .retain(|_, expires_at| *expires_at > now);
+
+        Ok(before.saturating_sub(self.redeemed_join_tickets.len()))
+    }
+}
+
+#[async_trait]
+impl RoleStore for MemoryDatabase {
+    async fn get_user_roles(&self, user_id: &UserId) -> Result<Vec<Role>, StoreError> {
+        let mut roles: Vec<Role> = self
+            .user_roles
+            .get(user_id)
+            .map(|roles| roles.iter().copied().collect())
+            .unwrap_or_default();
+        roles.sort_by_key(Role::as_str);
+
+        Ok(roles)
+    }
+
+    async fn grant_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError> {
+        let was_discord_role = self
+            .discord_roles
+            .get_mut(user_id)
+            .is_some_and(|mut roles| roles.remove(&role));
+
+        Ok(self
+            .user_roles
+            .entry(user_id.clone())
+            .or_default()
+            .insert(role)
+            || was_discord_role)
+    }
+
+    async fn revoke_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError> {
+        if let Some(mut roles) = self.discord_roles.get_mut(user_id) {
+            roles.remove(&role);
+        }
+
+        Ok(self
+            .user_roles
+            .get_mut(user_id)
+            .is_some_and(|mut roles| roles.remove(&role)))
+    }
+
+    async fn sync_discord_roles(&self, user_id: &UserId, roles: &[Role]) -> Result<(), StoreError> {
+        let mut user_roles = self.user_roles.entry(user_id.clone()).or_default();
+        let mut discord_roles = self.discord_roles.entry(user_id.clone()).or_default();
+
+        for role in discord_roles.drain() {
+            user_roles.remove(&role);
+        }
+
+        // roles that were also granted some other way stay that way
+        for role in roles {
+            if user_roles.insert(*role) {
+                discord_roles.insert(*role);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for MemoryDatabase {
+    async fn get_totp_credential(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<TotpCredential>, StoreError> {
+        Ok(self
+            .totp_credentials
+            .get(user_id)
+            .map(|entry| entry.clone()))
+    }
+
+    async fn set_pending_totp_secret(
+        &self,
+        user_id: &UserId,
+        secret: &[u8],
+    ) -> Result<(), StoreError> {
+        let mut entry = self
+            .totp_credentials
+            .entry(user_id.clone())
+            .or_insert_with(|| TotpCredential {
+                secret: Vec::new(),
+                enabled_at: None,
+                last_used_step: None,
+            });
+
+        if !entry.is_enabled() {
+            entry.secret = secret.to_vec();
+            entry.last_used_step = None;
+        }
+
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<bool, StoreError> {
+        match self.totp_credentials.get_mut(user_id) {
+            Some(mut entry) if !entry.is_enabled() => {
+                entry.enabled_at = Some(time::OffsetDateTime::now_utc());
+            }
+            _ => return Ok(false),
+        }
+
+        self.replace_recovery_codes(user_id, recovery_code_hashes)
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn record_totp_step(&self, user_id: &UserId, step: i64) -> Result<bool, StoreError> {
+        Ok(match self.totp_credentials.get_mut(user_id) {
+            Some(mut entry) if entry.last_used_step.is_none_or(|last| last < step) => {
+                entry.last_used_step = Some(step);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn disable_totp(&self, user_id: &UserId) -> Result<bool, StoreError> {
+        self.recovery_codes.remove(user_id);
+
+        Ok(self.totp_credentials.remove(user_id).is_some())
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<(), StoreError> {
+        self.recovery_codes.insert(
+            user_id.clone(),
+            recovery_code_hashes.iter().cloned().collect(),
+        );
+
+        Ok(())
+    }
+
+    async fn consume_recovery_code(
+        &self,
+        user_id: &UserId,
+        recovery_code_hash: &TokenHash,
+    ) -> Result<bool, StoreError> {
+        Ok(self
+            .recovery_codes
+            .get_mut(user_id)
+            .is_some_and(|mut codes| codes.remove(recovery_code_hash)))
+    }
+
+    async fn count_recovery_codes(&self, user_id: &UserId) -> Result<usize, StoreError> {
+        Ok(self
+            .recovery_codes
+            .get(user_id)
+            .map_or(0, |codes| codes.len()))
+    }
+}
+
+#[async_trait]
+impl HealthStore for MemoryDatabase {
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PasskeyStore for MemoryDatabase {
+    async fn add_passkey_credential(
+        &self,
+        credential: &PasskeyCredential,
+    ) -> Result<bool, StoreError> {
+        match self
+            .passkey_credentials
+            .entry(credential.credential_id.clone())
+        {
+            dashmap::Entry::Occupied(_) => Ok(false),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(credential.clone());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn get_passkey_credential(
+        &self,
+        credential_id: &[u8],
+    ) -> Result<Option<PasskeyCredential>, StoreError> {
+        Ok(self
+            .passkey_credentials
+            .get(credential_id)
+            .map(|entry| entry.clone()))
+    }
+
+    async fn list_passkey_credentials(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<PasskeyCredential>, StoreError> {
+        let mut credentials: Vec<PasskeyCredential> = self
+            .passkey_credentials
+            .iter()
+            .filter(|entry| entry.user_id == *user_id)
+            .map(|entry| entry.clone())
+            .collect();
+        credentials.sort_by_key(|credential| credential.created_at);
+
+        Ok(credentials)
+    }
+
+    async fn update_passkey_sign_count(
+        &self,
+        credential_id: &[u8],
+        sign_count: u32,
+    ) -> Result<(), StoreError> {
+        if let Some(mut entry) = self.passkey_credentials.get_mut(credential_id) {
+            entry.sign_count = sign_count;
+            entry.last_used_at = Some(time::OffsetDateTime::now_utc());
+        }
+
+        Ok(())
+    }
+
+    async fn delete_passkey_credential(
+        &self,
+        user_id: &UserId,
+        credential_id: &[u8],
+    ) -> Result<bool, StoreError> {
+        Ok(self
+            .passkey_credentials
+            .remove_if(credential_id, |_, credential| {
+                credential.user_id == *user_id
+            })
+            .is_some())
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+
+use super::{Database, Pool};
+
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// The migration was applied, but the file has since been edited. Migrations are
+    /// forward-only, so this needs a new migration rather than an edit.
+    ChecksumMismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+impl Database {
+    fn migrator(&self) -> &'static Migrator {
+        match self.pool {
+            Pool::Sqlite(_) => &SQLITE_MIGRATOR,
+            Pool::Postgres(_) => &POSTGRES_MIGRATOR,
+        }
+    }
+
+    /// Applies every pending migration in order, stopping at the first failure.
+    pub async fn run_migrations(&self) -> Result<(), MigrateError> {
+        with_pool!(&self.pool, |pool| self.migrator().run(pool).await)
+    }
+
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>, MigrateError> {
+        let applied = with_pool!(&self.pool, |pool| {
+            let mut conn = pool.acquire().await?;
+            conn.ensure_migrations_table().await?;
+            conn.list_applied_migrations().await?
+        });
+
+        let applied: HashMap<_, _> = applied
+            .into_iter()
+            .map(|migration| (migration.version, migration.checksum))
+            .collect();
+
+        Ok(self
+            .migrator()
+            .iter()
+            .map(|migration| MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state: match applied.get(&migration.version) {
+                    Some(checksum) if *checksum == migration.checksum => MigrationState::Applied,
+                    Some(_) => MigrationState::ChecksumMismatch,
+                    None => MigrationState::Pending,
+                },
+            })
+            .collect())
+    }
+}
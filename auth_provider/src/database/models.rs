@@ -11,7 +11,7 @@ pub struct DiscordOauthUser {
     pub expires_at: time::OffsetDateTime,
 }
 
-#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Type, Serialize, Deserialize)]
 #[sqlx(transparent)]
 #[serde(transparent)]
 pub struct DiscordUserId(pub String);
@@ -19,10 +19,20 @@ pub struct DiscordUserId(pub String);
 #[derive(Debug, Clone, FromRow)]
 pub struct User {
     pub user_id: UserId,
+    /// Guests have no linked login provider, so their token is the only way back into the
+    /// account.
+    pub is_guest: bool,
 }
 
-#[derive(Debug, Clone, Type, FromRow)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserKind {
+    Full,
+    Guest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Type, FromRow, Serialize, Deserialize)]
 #[sqlx(transparent)]
+#[serde(transparent)]
 pub struct UserId(pub String);
 
 #[derive(Debug, Clone, Type)]
@@ -44,10 +54,218 @@ impl Token {
     }
 
     pub fn from_hex_string(hex_string: &str) -> Option<Self> {
-        hex::decode(hex_string).map(|bytes| Token(bytes)).ok()
+        hex::decode(hex_string).map(Token).ok()
     }
 }
 
-#[derive(Debug, Clone, Type)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Type)]
 #[sqlx(transparent)]
 pub struct TokenHash(pub Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Type, Serialize, Deserialize)]
+#[sqlx(transparent)]
+#[serde(transparent)]
+pub struct ServiceCredentialId(pub String);
+
+/// A machine identity, such as a game server or the matchmaker.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceCredential {
+    pub credential_id: ServiceCredentialId,
+    pub name: String,
+    pub scopes: Vec<ServiceScope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub rotated_at: Option<time::OffsetDateTime>,
+}
+
+impl ServiceCredential {
+    pub fn has_scope(&self, scope: ServiceScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceScope {
+    /// Issue, rotate and revoke service credentials.
+    ManageCredentials,
+    /// Register as a game server with the matchmaker.
+    GameServer,
+    /// Grant and revoke user roles.
+    ManageUsers,
+    /// Drain game servers and inspect their lifecycle on the matchmaker.
+    ManageServers,
+    /// Issue join tickets on behalf of players, i.e. for the matches the matchmaker made.
+    IssueJoinTickets,
+}
+
+impl ServiceScope {
+    pub const ALL: [ServiceScope; 5] = [
+        ServiceScope::ManageCredentials,
+        ServiceScope::GameServer,
+        ServiceScope::ManageUsers,
+        ServiceScope::ManageServers,
+        ServiceScope::IssueJoinTickets,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceScope::ManageCredentials => "manage_credentials",
+            ServiceScope::GameServer => "game_server",
+            ServiceScope::ManageUsers => "manage_users",
+            ServiceScope::ManageServers => "manage_servers",
+            ServiceScope::IssueJoinTickets => "issue_join_tickets",
+        }
+    }
+
+    /// Scopes are stored as a single comma separated column.
+    pub fn join(scopes: &[ServiceScope]) -> String {
+        scopes
+            .iter()
+            .map(ServiceScope::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Unknown scopes are dropped, so removing a scope from the code revokes it everywhere.
+    pub fn split(scopes: &str) -> Vec<ServiceScope> {
+        scopes
+            .split(',')
+            .filter_map(|scope| scope.parse().ok())
+            .collect()
+    }
+}
+
+impl std::str::FromStr for ServiceScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ServiceScope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("unknown service scope `{s}`"))
+    }
+}
+
+/// What a user is allowed to do beyond playing. Roles listed as sensitive in the config only take
+/// effect once the user has two-factor authentication enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    TournamentOrganizer,
+    Moderator,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Admin, Role::TournamentOrganizer, Role::Moderator];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::TournamentOrganizer => "tournament_organizer",
+            Role::Moderator => "moderator",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("unknown role `{s}`"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TotpCredential {
+    pub secret: Vec<u8>,
+    /// `None` while enrollment is waiting for the first code to be confirmed.
+    pub enabled_at: Option<time::OffsetDateTime>,
+    /// The last time step a code was accepted for, so a code can't be used twice.
+    pub last_used_step: Option<i64>,
+}
+
+impl TotpCredential {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+}
+
+/// A WebAuthn credential registered by a user.
+#[derive(Debug, Clone, Serialize)]
+pub struct PasskeyCredential {
+    #[serde(serialize_with = "serialize_base64url")]
+    pub credential_id: Vec<u8>,
+    #[serde(skip)]
+    pub user_id: UserId,
+    pub name: String,
+    /// COSE_Key encoded.
+    #[serde(skip)]
+    pub public_key: Vec<u8>,
+    #[serde(skip)]
+    pub sign_count: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<time::OffsetDateTime>,
+}
+
+/// A pending magic link login. Only the hash of the code in the link is stored.
+#[derive(Debug, Clone)]
+pub struct EmailLoginCode {
+    pub code_hash: TokenHash,
+    /// Normalized to lowercase.
+    pub email: String,
+    /// The guest that requested the link, upgraded if the address isn't linked to an account
+    /// yet.
+    pub upgrade_guest: Option<UserId>,
+    pub expires_at: time::OffsetDateTime,
+    /// Hash of the cookie set in the browser that asked for the link. Only that browser can use
+    /// the code, so nobody can get a link sent to someone else and have them log in for them.
+    pub browser_hash: TokenHash,
+}
+
+fn serialize_base64url<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&data_encoding::BASE64URL_NOPAD.encode(bytes))
+}
+
+/// The bearer secret handed to a service, formatted as `svc.<credential id>.<secret hex>`. Only
+/// the hash of the secret part is stored.
+#[derive(Debug, Clone)]
+pub struct ServiceKey {
+    pub credential_id: ServiceCredentialId,
+    pub secret: Token,
+}
+
+impl ServiceKey {
+    pub const PREFIX: &'static str = "svc.";
+
+    pub fn parse(key: &str) -> Option<Self> {
+        let (credential_id, secret) = key.strip_prefix(Self::PREFIX)?.split_once('.')?;
+
+        Some(Self {
+            credential_id: ServiceCredentialId(credential_id.to_string()),
+            secret: Token::from_hex_string(secret)?,
+        })
+    }
+}
+
+impl std::fmt::Display for ServiceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}.{}",
+            Self::PREFIX,
+            self.credential_id.0,
+            self.secret.to_hex_string()
+        )
+    }
+}
@@ -0,0 +1,276 @@
+use async_trait::async_trait;
+
+use super::models::{
+    DiscordOauthUser, DiscordUserId, EmailLoginCode, PasskeyCredential, Role, ServiceCredential,
+    ServiceCredentialId, ServiceKey, ServiceScope, Token, TokenHash, TotpCredential, User, UserId,
+    UserKind,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn user_id_exists(&self, user_id: &UserId) -> Result<bool, StoreError>;
+
+    /// Guests are created without any linked provider.
+    async fn create_new_user(&self, kind: UserKind) -> Result<User, StoreError>;
+
+    /// Turns a guest into a full account, keeping its user id, and revokes the guest's auth
+    /// tokens so only the login that upgraded it stays signed in. Returns `false` if the user
+    /// doesn't exist or wasn't a guest.
+    async fn upgrade_guest_user(&self, user_id: &UserId) -> Result<bool, StoreError>;
+
+    /// Records activity, which keeps guests from being garbage collected.
+    async fn touch_user(&self, user_id: &UserId) -> Result<(), StoreError>;
+
+    /// Deletes guests, and their tokens, that haven't been seen since `inactive_since`.
+    async fn garbage_collect_inactive_guests(
+        &self,
+        inactive_since: time::OffsetDateTime,
+    ) -> Result<usize, StoreError>;
+}
+
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn create_auth_token(
+        &self,
+        user_id: &UserId,
+        ttl: std::time::Duration,
+    ) -> Result<Token, StoreError>;
+
+    /// Returns `None` for both unknown and expired tokens.
+    async fn get_user_by_token(&self, token: &Token) -> Result<Option<User>, StoreError>;
+
+    async fn garbage_collect_expired_tokens(&self) -> Result<usize, StoreError>;
+
+    async fn count_active_auth_tokens(&self) -> Result<usize, StoreError>;
+
+    async fn revoke_auth_token(&self, token: &Token) -> Result<bool, StoreError>;
+
+    /// Logs the user out everywhere, returning how many tokens were revoked.
+    async fn revoke_user_auth_tokens(&self, user_id: &UserId) -> Result<usize, StoreError>;
+}
+
+#[async_trait]
+pub trait DiscordIdentityStore: Send + Sync {
+    async fn discord_user_registered(&self, user_id: &DiscordUserId) -> Result<bool, StoreError>;
+
+    async fn link_discord_id_to_user_id(
+        &self,
+        user_id: &UserId,
+        discord_info: &DiscordOauthUser,
+    ) -> Result<(), StoreError>;
+
+    async fn get_user_by_discord_id(
+        &self,
+        user_id: &DiscordUserId,
+    ) -> Result<Option<UserId>, StoreError>;
+
+    /// Stores the tokens from a new login or a refresh.
+    async fn update_discord_tokens(
+        &self,
+        discord_info: &DiscordOauthUser,
+    ) -> Result<(), StoreError>;
+
+    /// Links whose tokens haven't been refreshed since `refreshed_before`, including ones that
+    /// never were.
+    async fn list_discord_users_refreshed_before(
+        &self,
+        refreshed_before: time::OffsetDateTime,
+    ) -> Result<Vec<DiscordOauthUser>, StoreError>;
+}
+
+#[async_trait]
+pub trait EmailIdentityStore: Send + Sync {
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<UserId>, StoreError>;
+
+    /// Returns `false` if the address is already linked to an account.
+    async fn link_email_to_user_id(
+        &self,
+        user_id: &UserId,
+        email: &str,
+    ) -> Result<bool, StoreError>;
+
+    async fn create_email_login_code(&self, code: &EmailLoginCode) -> Result<(), StoreError>;
+
+    /// Removes the code, so every link works at most once. Returns `None` for unknown, already
+    /// used and expired codes, and leaves codes requested from another browser alone.
+    async fn consume_email_login_code(
+        &self,
+        code_hash: &TokenHash,
+        browser_hash: &TokenHash,
+    ) -> Result<Option<EmailLoginCode>, StoreError>;
+
+    async fn garbage_collect_expired_email_login_codes(&self) -> Result<usize, StoreError>;
+}
+
+#[async_trait]
+pub trait ServiceCredentialStore: Send + Sync {
+    /// The returned key is the only time the secret is available in plain text.
+    async fn create_service_credential(
+        &self,
+        name: &str,
+        scopes: &[ServiceScope],
+    ) -> Result<(ServiceCredential, ServiceKey), StoreError>;
+
+    /// Replaces the secret, invalidating the old key immediately. Returns `None` if the
+    /// credential doesn't exist.
+    async fn rotate_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<Option<ServiceKey>, StoreError>;
+
+    async fn revoke_service_credential(
+        &self,
+        credential_id: &ServiceCredentialId,
+    ) -> Result<bool, StoreError>;
+
+    async fn list_service_credentials(&self) -> Result<Vec<ServiceCredential>, StoreError>;
+
+    async fn get_service_credential_by_key(
+        &self,
+        key: &ServiceKey,
+    ) -> Result<Option<ServiceCredential>, StoreError>;
+}
+
+#[async_trait]
+pub trait JoinTicketStore: Send + Sync {
+    /// Returns `false` if the ticket had already been redeemed.
+    async fn mark_join_ticket_redeemed(
+        &self,
+        ticket_id: &str,
+        expires_at: time::OffsetDateTime,
+    ) -> Result<bool, StoreError>;
+
+    async fn garbage_collect_redeemed_join_tickets(&self) -> Result<usize, StoreError>;
+}
+
+#[async_trait]
+pub trait RoleStore: Send + Sync {
+    async fn get_user_roles(&self, user_id: &UserId) -> Result<Vec<Role>, StoreError>;
+
+    /// Returns `false` if the user already had the role. A role the user only had through
+    /// discord is kept from then on, even if the discord role is taken away.
+    async fn grant_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError>;
+
+    async fn revoke_role(&self, user_id: &UserId, role: Role) -> Result<bool, StoreError>;
+
+    /// Replaces the roles granted through the discord role mapping. Roles granted any other way
+    /// are left alone.
+    async fn sync_discord_roles(&self, user_id: &UserId, roles: &[Role]) -> Result<(), StoreError>;
+}
+
+#[async_trait]
+pub trait TwoFactorStore: Send + Sync {
+    async fn get_totp_credential(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<TotpCredential>, StoreError>;
+
+    /// Starts (or restarts) enrollment. Does nothing if TOTP is already enabled.
+    async fn set_pending_totp_secret(
+        &self,
+        user_id: &UserId,
+        secret: &[u8],
+    ) -> Result<(), StoreError>;
+
+    /// Finishes enrollment, replacing any previous recovery codes. Returns `false` if there was
+    /// no pending enrollment.
+    async fn enable_totp(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<bool, StoreError>;
+
+    /// Returns `false` if a code for this or a later step was already accepted.
+    async fn record_totp_step(&self, user_id: &UserId, step: i64) -> Result<bool, StoreError>;
+
+    /// Removes the TOTP secret and all recovery codes.
+    async fn disable_totp(&self, user_id: &UserId) -> Result<bool, StoreError>;
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        recovery_code_hashes: &[TokenHash],
+    ) -> Result<(), StoreError>;
+
+    /// Returns `false` if the code doesn't exist or was already used.
+    async fn consume_recovery_code(
+        &self,
+        user_id: &UserId,
+        recovery_code_hash: &TokenHash,
+    ) -> Result<bool, StoreError>;
+
+    async fn count_recovery_codes(&self, user_id: &UserId) -> Result<usize, StoreError>;
+}
+
+#[async_trait]
+pub trait PasskeyStore: Send + Sync {
+    /// Returns `false` if a credential with the same id is already registered.
+    async fn add_passkey_credential(
+        &self,
+        credential: &PasskeyCredential,
+    ) -> Result<bool, StoreError>;
+
+    async fn get_passkey_credential(
+        &self,
+        credential_id: &[u8],
+    ) -> Result<Option<PasskeyCredential>, StoreError>;
+
+    async fn list_passkey_credentials(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<PasskeyCredential>, StoreError>;
+
+    /// Records a successful login with the credential.
+    async fn update_passkey_sign_count(
+        &self,
+        credential_id: &[u8],
+        sign_count: u32,
+    ) -> Result<(), StoreError>;
+
+    async fn delete_passkey_credential(
+        &self,
+        user_id: &UserId,
+        credential_id: &[u8],
+    ) -> Result<bool, StoreError>;
+}
+
+#[async_trait]
+pub trait HealthStore: Send + Sync {
+    /// Fails if the storage can't currently be reached.
+    async fn ping(&self) -> Result<(), StoreError>;
+}
+
+/// Everything the web handlers and providers need from storage.
+pub trait Repository:
+    UserStore
+    + TokenStore
+    + DiscordIdentityStore
+    + EmailIdentityStore
+    + ServiceCredentialStore
+    + JoinTicketStore
+    + RoleStore
+    + TwoFactorStore
+    + PasskeyStore
+    + HealthStore
+{
+}
+
+impl<T> Repository for T where
+    T: UserStore
+        + TokenStore
+        + DiscordIdentityStore
+        + EmailIdentityStore
+        + ServiceCredentialStore
+        + JoinTicketStore
+        + RoleStore
+        + TwoFactorStore
+        + PasskeyStore
+        + HealthStore
+{
+}
@@ -0,0 +1,61 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use http::StatusCode;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    database::models::{UserId, UserKind},
+    WebState,
+};
+
+pub fn routes() -> Router<WebState> {
+    Router::new().route("/", post(create_guest))
+}
+
+#[derive(Debug, Serialize)]
+struct CreateGuestResponse {
+    user_id: UserId,
+    /// Also set as the auth cookie. Losing it loses the account, until a login provider has been
+    /// linked through the usual login flow.
+    token: String,
+}
+
+async fn create_guest(
+    State(state): State<WebState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, Response> {
+    let user = state
+        .database
+        .create_new_user(UserKind::Guest)
+        .await
+        .map_err(|error| {
+            warn!(?error, "failed to create guest user");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    let token = state
+        .database
+        .create_auth_token(&user.user_id, state.config.guests.token_ttl)
+        .await
+        .map_err(|error| {
+            warn!(?error, "failed to create guest auth token");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    info!(user_id = user.user_id.0, "created guest user");
+
+    Ok((
+        StatusCode::CREATED,
+        jar.add(state.config.cookie.auth_cookie(token.to_hex_string())),
+        Json(CreateGuestResponse {
+            user_id: user.user_id,
+            token: token.to_hex_string(),
+        }),
+    ))
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use http::StatusCode;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::WebState;
+
+/// Discord is only a soft dependency, so it isn't waited on for long.
+const DISCORD_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub fn routes() -> Router<WebState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+// the process is up and serving requests, whatever state its dependencies are in
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    Ok,
+    Unavailable,
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    database: CheckStatus,
+    discord: CheckStatus,
+}
+
+async fn readyz(State(state): State<WebState>) -> impl IntoResponse {
+    let database = match state.database.ping().await {
+        Ok(()) => CheckStatus::Ok,
+        Err(error) => {
+            warn!(?error, "readiness check failed to reach the database");
+            CheckStatus::Unavailable
+        }
+    };
+
+    let discord = if !state.config.readiness.check_discord {
+        CheckStatus::Skipped
+    } else if state
+        .discord_authenticator
+        .api_reachable(DISCORD_CHECK_TIMEOUT)
+        .await
+    {
+        CheckStatus::Ok
+    } else {
+        warn!("readiness check failed to reach the discord api");
+        CheckStatus::Unavailable
+    };
+
+    let ready = !matches!(database, CheckStatus::Unavailable)
+        && !matches!(discord, CheckStatus::Unavailable);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            database,
+            discord,
+        }),
+    )
+}
@@ -1,14 +1,178 @@
 use std::sync::Arc;
 
-use database::Database;
-use provider::discord;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use caller::{PlayerCaller, ServiceCaller};
+use config::Config;
+use database::{
+    models::{Role, ServiceCredential, Token, UserId},
+    repository::Repository,
+};
+use http::StatusCode;
+use mail::Mailer;
+use provider::{discord, passkey};
+use serde::Serialize;
+use tickets::TicketSigner;
+use tower_http::trace::TraceLayer;
+use tracing::{trace, warn};
 
+pub mod admin;
+pub mod caller;
+pub mod config;
+pub mod csrf;
 pub mod database;
+pub mod guest;
+pub mod health;
+pub mod mail;
+pub mod metrics;
 pub mod provider;
+pub mod tickets;
+pub mod two_factor;
 
 #[derive(Clone)]
 pub struct WebState {
-    pub database: Arc<Database>,
-    pub webserver_base: Arc<String>,
+    pub database: Arc<dyn Repository>,
+    pub config: Arc<Config>,
     pub discord_authenticator: Arc<discord::Authenticator>,
+    pub mailer: Arc<dyn Mailer>,
+    pub passkey_authenticator: Arc<passkey::Authenticator>,
+    pub ticket_signer: Arc<TicketSigner>,
+    pub two_factor_challenges: Arc<two_factor::Challenges>,
+}
+
+impl WebState {
+    pub fn new(
+        database: Arc<dyn Repository>,
+        config: Arc<Config>,
+        mailer: Arc<dyn Mailer>,
+    ) -> Self {
+        Self {
+            discord_authenticator: Arc::new(discord::Authenticator::new(
+                database.clone(),
+                config.clone(),
+            )),
+            passkey_authenticator: Arc::new(passkey::Authenticator::new(
+                database.clone(),
+                config.clone(),
+            )),
+            ticket_signer: Arc::new(TicketSigner::new(
+                config.tickets.signing_key,
+                config.tickets.ttl,
+            )),
+            two_factor_challenges: Arc::new(two_factor::Challenges::new()),
+            database,
+            config,
+            mailer,
+        }
+    }
+}
+
+/// A state over an empty [`MemoryDatabase`](database::memory::MemoryDatabase), with only the
+/// required settings configured.
+#[cfg(test)]
+pub(crate) fn test_state() -> WebState {
+    test_state_with(|_| {})
+}
+
+#[cfg(test)]
+pub(crate) fn test_state_with(configure: impl FnOnce(&mut Config)) -> WebState {
+    let mut config = Config::from_toml(
+        r#"
+        public_base_url = "https://game.example"
+
+        [providers.discord]
+        client_id = "client"
+        client_secret = "secret"
+        "#,
+    )
+    .expect("test config is valid");
+    configure(&mut config);
+
+    let mailer = mail::from_config(&config.mail).expect("test config only logs mail");
+
+    WebState::new(
+        Arc::new(database::memory::MemoryDatabase::new()),
+        Arc::new(config),
+        mailer,
+    )
+}
+
+pub fn router(web_state: WebState) -> Router {
+    let mut router = Router::new()
+        .nest("/auth/providers", provider::all_routes(&web_state.config))
+        .route("/auth/logout", axum::routing::post(auth_invalidate))
+        .route("/auth/me", axum::routing::get(me))
+        .route("/auth/services/me", axum::routing::get(service_me));
+    // guests would get past the discord guild gate just the same
+    if web_state.config.providers.discord.guild.is_none() {
+        router = router.nest("/auth/guest", guest::routes());
+    }
+
+    router
+        .nest("/auth/two-factor", two_factor::routes())
+        .nest("/auth/tickets", tickets::routes())
+        .nest("/admin", admin::routes())
+        .layer(axum::middleware::from_fn_with_state(
+            web_state.clone(),
+            csrf::protect,
+        ))
+        .layer(TraceLayer::new_for_http())
+        // probes are polled constantly, so they stay out of the request logs
+        .merge(health::routes())
+        .with_state(web_state)
+}
+
+async fn auth_invalidate(State(state): State<WebState>, jar: CookieJar) -> impl IntoResponse {
+    let token = jar.get(&state.config.cookie.name);
+
+    let token = match token {
+        Some(token) => token.value(),
+        None => return jar,
+    };
+
+    let was_real_token = state
+        .database
+        .revoke_auth_token(&Token::from_hex_string(token).unwrap())
+        .await
+        .unwrap();
+
+    trace!(?was_real_token, "revoked token");
+
+    jar.remove(state.config.cookie.removal_cookie())
+}
+
+#[derive(Debug, Serialize)]
+struct MeResponse {
+    user_id: UserId,
+    is_guest: bool,
+    /// Sensitive roles are left out until two-factor authentication is enabled.
+    roles: Vec<Role>,
+}
+
+async fn me(
+    State(state): State<WebState>,
+    PlayerCaller(user): PlayerCaller,
+) -> Result<Json<MeResponse>, Response> {
+    let roles = two_factor::effective_roles(&state, &user.user_id)
+        .await
+        .map_err(|error| {
+            warn!(?error, "failed to look up user roles");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    Ok(Json(MeResponse {
+        user_id: user.user_id,
+        is_guest: user.is_guest,
+        roles,
+    }))
+}
+
+// lets other services check a key they were handed, e.g. the matchmaker authenticating game
+// servers
+async fn service_me(ServiceCaller(credential): ServiceCaller) -> Json<ServiceCredential> {
+    Json(credential)
 }
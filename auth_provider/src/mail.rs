@@ -0,0 +1,109 @@
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+use crate::config::{MailConfig, MailTransport};
+
+/// A plain text message to a single recipient.
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: Mailbox,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MailError {
+    #[error(transparent)]
+    Message(#[from] lettre::error::Error),
+    #[error(transparent)]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Builds the transport selected in the config.
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    Ok(match &config.transport {
+        MailTransport::Log { sink_path } => Arc::new(LogMailer {
+            from: config.from.clone(),
+            sink_path: sink_path.clone(),
+        }),
+        MailTransport::Smtp { url } => Arc::new(SmtpMailer::new(url, config.from.clone())?),
+    })
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// See [`AsyncSmtpTransport::from_url`] for the url format. Connections are only opened
+    /// when sending.
+    pub fn new(url: &str, from: Mailbox) -> Result<Self, MailError> {
+        Ok(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.clone())
+            .subject(&mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())?;
+
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+/// Doesn't deliver anything. Messages are appended to `sink_path` if set, so links can be copied
+/// out of it when running locally. Only the recipient and subject are logged, since bodies
+/// carry working login links.
+pub struct LogMailer {
+    from: Mailbox,
+    sink_path: Option<PathBuf>,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        debug!(to = %mail.to, subject = mail.subject, "not delivering mail");
+
+        if let Some(sink_path) = &self.sink_path {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(sink_path)
+                .await?;
+
+            let entry = format!(
+                "From: {}\nTo: {}\nSubject: {}\n\n{}\n\n",
+                self.from, mail.to, mail.subject, mail.body
+            );
+            file.write_all(entry.as_bytes()).await?;
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,18 +1,20 @@
 use std::sync::Arc;
 
 use auth_provider::{
-    database::{models::Token, Database},
-    provider::discord::{self, DiscordInfo},
+    config::{Cli, Command, Config, MailTransport, MigrateCommand, ServiceCredentialCommand},
+    database::{
+        migrations::MigrationState,
+        models::{ServiceCredentialId, ServiceScope},
+        repository::{Repository, ServiceCredentialStore},
+        Database,
+    },
+    mail, metrics,
+    provider::discord,
     WebState,
 };
-use axum::{
-    extract::State,
-    response::{IntoResponse, Response},
-    Router,
-};
-use axum_extra::extract::CookieJar;
-use tower_http::trace::TraceLayer;
-use tracing::{info, trace};
+use clap::Parser;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn};
 
 #[tokio::main]
 async fn main() {
@@ -36,54 +38,326 @@ async fn main() {
             .init();
     }
 
-    let webserver_base =
-        std::env::var("DOMAIN_BASE").expect("did not find DOMAIN_BASE environment variable");
+    let cli = Cli::parse();
+
+    match &cli.command {
+        Some(Command::Migrate { action }) => migrate(&cli, action).await,
+        Some(Command::ServiceCredential { action }) => service_credential(&cli, action).await,
+        Some(Command::Serve) | None => serve(&cli).await,
+    }
+}
+
+async fn serve(cli: &Cli) {
+    let config = match Config::load(cli) {
+        Ok(config) => Arc::new(config),
+        Err(errors) => {
+            error!("{errors}");
+            std::process::exit(1);
+        }
+    };
 
     let database = Arc::new(
-        Database::new("./data.db")
+        Database::new(&config.database_url)
             .await
             .expect("failed to open database"),
     );
 
-    let web_state = WebState {
-        database: database.clone(),
-        webserver_base: Arc::new(webserver_base),
-        discord_authenticator: Arc::new(discord::Authenticator::new(
-            database,
-            DiscordInfo::from_env(),
-        )),
+    if let Err(error) = database.run_migrations().await {
+        error!(%error, "failed to apply database migrations");
+        std::process::exit(1);
+    }
+
+    metrics::register();
+
+    let mailer = match mail::from_config(&config.mail) {
+        Ok(mailer) => mailer,
+        Err(error) => {
+            error!(%error, "failed to set up the mail transport");
+            std::process::exit(1);
+        }
     };
+    if let MailTransport::Log { .. } = config.mail.transport {
+        warn!("mail isn't delivered, email login links won't reach anyone");
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(cancel_on_signal(shutdown.clone()));
+
+    // everything here gets to finish what it's doing on shutdown
+    let tasks = TaskTracker::new();
+
+    tasks.spawn(garbage_collect_tokens(
+        database.clone(),
+        config.clone(),
+        shutdown.clone(),
+    ));
+
+    let web_state = WebState::new(database.clone(), config.clone(), mailer);
+
+    if let Some(guild) = &config.providers.discord.guild {
+        tasks.spawn(recheck_discord_guild_members(
+            web_state.discord_authenticator.clone(),
+            guild.recheck_interval,
+            shutdown.clone(),
+        ));
+    }
+
+    if let Some(admin_bind_address) = &config.admin_bind_address {
+        let admin_listener = tokio::net::TcpListener::bind(admin_bind_address)
+            .await
+            .unwrap();
 
-    let router = Router::new()
-        .layer(TraceLayer::new_for_http())
-        .nest("/auth/providers", auth_provider::provider::all_routes())
-        .route("/auth/logout", axum::routing::post(auth_invalidate))
-        .with_state(web_state);
+        info!(bind=%admin_listener.local_addr().unwrap(), "created admin listener");
 
-    let listener = tokio::net::TcpListener::bind("localhost:12121")
+        tasks.spawn(serve_until_shutdown(
+            admin_listener,
+            metrics::router(web_state.clone()),
+            shutdown.clone(),
+        ));
+    }
+
+    let router = auth_provider::router(web_state);
+
+    let listener = tokio::net::TcpListener::bind(&config.bind_address)
         .await
         .unwrap();
 
     info!(bind=%listener.local_addr().unwrap(), "created listener");
 
-    axum::serve(listener, router).await.unwrap();
+    tasks.spawn(serve_until_shutdown(listener, router, shutdown.clone()));
+    tasks.close();
+
+    shutdown.cancelled().await;
+    info!(timeout = ?config.shutdown_timeout, "shutting down, waiting for requests and background tasks");
+
+    if tokio::time::timeout(config.shutdown_timeout, tasks.wait())
+        .await
+        .is_err()
+    {
+        warn!("shutdown timed out, dropping the remaining requests and tasks");
+    }
+
+    database.close().await;
+    info!("shut down");
 }
 
-async fn auth_invalidate(State(state): State<WebState>, jar: CookieJar) -> impl IntoResponse {
-    let token = jar.get("AuthToken");
+/// Stops accepting connections once `shutdown` is cancelled, then waits for the open ones to
+/// finish their requests.
+async fn serve_until_shutdown(
+    listener: tokio::net::TcpListener,
+    router: axum::Router,
+    shutdown: CancellationToken,
+) {
+    let result = axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .await;
+
+    if let Err(error) = result {
+        error!(%error, "server failed");
+        shutdown.cancel();
+    }
+}
+
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    shutdown.cancel();
+}
 
-    let token = match token {
-        Some(token) => token.value(),
-        None => return jar,
+async fn open_database_for_command(cli: &Cli) -> Database {
+    let database_url = match Config::load_database_url(cli) {
+        Ok(database_url) => database_url,
+        Err(errors) => {
+            error!("{errors}");
+            std::process::exit(1);
+        }
     };
 
-    let was_real_token = state
-        .database
-        .revoke_auth_token(&Token::from_hex_string(token).unwrap())
+    Database::new(&database_url)
         .await
-        .unwrap();
+        .expect("failed to open database")
+}
+
+async fn migrate(cli: &Cli, action: &MigrateCommand) {
+    let database = open_database_for_command(cli).await;
+
+    if let MigrateCommand::Run = action {
+        if let Err(error) = database.run_migrations().await {
+            error!(%error, "failed to apply database migrations");
+            std::process::exit(1);
+        }
+    }
+
+    let status = match database.migration_status().await {
+        Ok(status) => status,
+        Err(error) => {
+            error!(%error, "failed to read migration status");
+            std::process::exit(1);
+        }
+    };
+
+    for migration in status {
+        let state = match migration.state {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::ChecksumMismatch => "applied, but modified since",
+        };
+
+        println!(
+            "{:>14} {:<40} {state}",
+            migration.version, migration.description
+        );
+    }
+}
+
+async fn service_credential(cli: &Cli, action: &ServiceCredentialCommand) {
+    let database = open_database_for_command(cli).await;
+
+    let result = match action {
+        ServiceCredentialCommand::Create { name, scopes } => database
+            .create_service_credential(name, scopes)
+            .await
+            .map(|(credential, key)| {
+                println!("{}", credential.credential_id.0);
+                println!("{key}");
+            }),
+        ServiceCredentialCommand::List => {
+            database
+                .list_service_credentials()
+                .await
+                .map(|credentials| {
+                    for credential in credentials {
+                        println!(
+                            "{:<18} {:<24} {}",
+                            credential.credential_id.0,
+                            credential.name,
+                            ServiceScope::join(&credential.scopes)
+                        );
+                    }
+                })
+        }
+        ServiceCredentialCommand::Rotate { credential_id } => database
+            .rotate_service_credential(&ServiceCredentialId(credential_id.clone()))
+            .await
+            .map(|key| match key {
+                Some(key) => println!("{key}"),
+                None => {
+                    error!(credential_id, "no such service credential");
+                    std::process::exit(1);
+                }
+            }),
+        ServiceCredentialCommand::Revoke { credential_id } => database
+            .revoke_service_credential(&ServiceCredentialId(credential_id.clone()))
+            .await
+            .map(|was_revoked| {
+                if !was_revoked {
+                    error!(credential_id, "no such service credential");
+                    std::process::exit(1);
+                }
+            }),
+    };
+
+    if let Err(error) = result {
+        error!(%error, "failed to manage service credentials");
+        std::process::exit(1);
+    }
+}
+
+async fn recheck_discord_guild_members(
+    authenticator: Arc<discord::Authenticator>,
+    recheck_interval: std::time::Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(recheck_interval);
+
+    loop {
+        // a re-check that already started is finished, so no member is left half updated
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        if let Err(error) = authenticator.recheck_guild_members().await {
+            warn!(?error, "failed to re-check discord guild members");
+        }
+    }
+}
+
+async fn garbage_collect_tokens(
+    database: Arc<dyn Repository>,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(config.tokens.gc_interval);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        match database.garbage_collect_expired_tokens().await {
+            Ok(num_deleted) => {
+                record_gc_deletions("auth_tokens", num_deleted);
+                debug!(num_deleted, "garbage collected expired tokens")
+            }
+            Err(error) => warn!(?error, "failed to garbage collect expired tokens"),
+        }
+
+        match database.garbage_collect_redeemed_join_tickets().await {
+            Ok(num_deleted) => {
+                record_gc_deletions("join_tickets", num_deleted);
+                debug!(num_deleted, "garbage collected redeemed join tickets")
+            }
+            Err(error) => warn!(?error, "failed to garbage collect redeemed join tickets"),
+        }
 
-    trace!(?was_real_token, "revoked token");
+        match database.garbage_collect_expired_email_login_codes().await {
+            Ok(num_deleted) => {
+                record_gc_deletions("email_login_codes", num_deleted);
+                debug!(num_deleted, "garbage collected expired email login codes")
+            }
+            Err(error) => warn!(
+                ?error,
+                "failed to garbage collect expired email login codes"
+            ),
+        }
+
+        let inactive_since = time::OffsetDateTime::now_utc() - config.guests.inactivity_timeout;
+        match database
+            .garbage_collect_inactive_guests(inactive_since)
+            .await
+        {
+            Ok(num_deleted) => {
+                record_gc_deletions("guests", num_deleted);
+                debug!(num_deleted, "garbage collected inactive guests")
+            }
+            Err(error) => warn!(?error, "failed to garbage collect inactive guests"),
+        }
+    }
+}
 
-    jar.remove("AuthToken")
+fn record_gc_deletions(kind: &str, num_deleted: usize) {
+    metrics::GC_DELETIONS
+        .with_label_values(&[kind])
+        .inc_by(num_deleted as u64);
 }
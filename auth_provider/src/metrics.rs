@@ -0,0 +1,136 @@
+use std::sync::LazyLock;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use http::{header, StatusCode};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use tracing::warn;
+
+use crate::WebState;
+
+pub static LOGIN_ATTEMPTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "auth_login_attempts_total",
+        "Finished login attempts by provider and outcome.",
+        &["provider", "outcome"]
+    )
+    .unwrap()
+});
+
+pub static DISCORD_API_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "auth_discord_api_request_duration_seconds",
+        "Time until the discord api responded, by endpoint.",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static DISCORD_API_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "auth_discord_api_errors_total",
+        "Discord api requests that failed to send, were rate limited or hit a server error.",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static ACTIVE_SESSIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("auth_active_sessions", "Auth tokens that haven't expired.").unwrap()
+});
+
+pub static DISCORD_STATE_CODES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "auth_discord_state_codes",
+        "Discord logins waiting for the redirect back, including expired ones not pruned yet."
+    )
+    .unwrap()
+});
+
+pub static GC_DELETIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "auth_gc_deleted_total",
+        "Rows removed by the periodic garbage collection, by kind.",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static DATABASE_OPERATION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "auth_database_operation_duration_seconds",
+        "Time spent on each database operation, including every query of a transaction.",
+        &["backend"]
+    )
+    .unwrap()
+});
+
+/// Registers every metric up front, so they are exported before their first observation.
+pub fn register() {
+    LazyLock::force(&LOGIN_ATTEMPTS);
+    LazyLock::force(&DISCORD_API_REQUEST_DURATION);
+    LazyLock::force(&DISCORD_API_ERRORS);
+    LazyLock::force(&ACTIVE_SESSIONS);
+    LazyLock::force(&DISCORD_STATE_CODES);
+    LazyLock::force(&GC_DELETIONS);
+    LazyLock::force(&DATABASE_OPERATION_DURATION);
+}
+
+/// Response extension marking a login that was sent on to the two-factor challenge, rather than
+/// finished. The attempt is counted again, under `two_factor`, once the second step is done.
+#[derive(Debug, Clone, Copy)]
+pub struct LoginChallenged;
+
+/// Counts a finished login attempt, judging its outcome by the response status. Meant for
+/// `axum::middleware::map_response` on the route that completes a login.
+pub fn record_login(provider: &str, response: Response) -> Response {
+    let outcome = if response.extensions().get::<LoginChallenged>().is_some() {
+        "challenged"
+    } else if response.status().is_server_error() {
+        "error"
+    } else if response.status().is_client_error() {
+        "rejected"
+    } else {
+        "success"
+    };
+
+    LOGIN_ATTEMPTS.with_label_values(&[provider, outcome]).inc();
+
+    response
+}
+
+/// Served on the admin bind address only, never next to the public routes.
+pub fn router(web_state: WebState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(web_state)
+}
+
+async fn metrics(State(state): State<WebState>) -> impl IntoResponse {
+    // gauges that are cheaper to read on scrape than to keep up to date
+    match state.database.count_active_auth_tokens().await {
+        Ok(count) => ACTIVE_SESSIONS.set(count as i64),
+        Err(error) => warn!(?error, "failed to count active sessions"),
+    }
+    DISCORD_STATE_CODES.set(state.discord_authenticator.pending_logins() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(error) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        warn!(?error, "failed to encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response()
+}
@@ -1,9 +1,20 @@
 use axum::Router;
 
-use crate::WebState;
+use crate::{config::Config, WebState};
 
 pub mod discord;
+pub mod email;
+pub mod passkey;
 
-pub fn all_routes() -> Router<WebState> {
-    Router::new().nest("/discord", discord::routes())
+pub fn all_routes(config: &Config) -> Router<WebState> {
+    let routes = Router::new().nest("/discord", discord::routes());
+
+    // membership can only be checked through discord, anything else would get around the gate
+    if config.providers.discord.guild.is_some() {
+        return routes;
+    }
+
+    routes
+        .nest("/email", email::routes())
+        .nest("/passkey", passkey::routes())
 }
@@ -1,21 +1,33 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{OriginalUri, Query, State},
-    response::{IntoResponse, Redirect},
+    response::{IntoResponse, Redirect, Response},
     routing::get,
     Router,
 };
-use dashmap::DashSet;
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use dashmap::DashMap;
+use http::StatusCode;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use crate::{
+    caller::PlayerCaller,
+    config::Config,
     database::{
-        models::{DiscordOauthUser, DiscordUserId, Token},
-        Database,
+        models::{DiscordOauthUser, DiscordUserId, Role, UserId, UserKind},
+        repository::{Repository, StoreError},
     },
-    WebState,
+    metrics, two_factor, WebState,
 };
 
 const STATE_CODE_CHARACTERS: [char; 52] = [
@@ -24,7 +36,9 @@ const STATE_CODE_CHARACTERS: [char; 52] = [
     'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
 ];
 
-const DISCORD_BASE: &'static str = "https://discord.com";
+/// Holds the state code in the browser that started the login, so the redirect back only
+/// completes it in that same browser.
+pub const STATE_COOKIE_NAME: &str = "DiscordLoginState";
 
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub struct StateCode(String);
@@ -35,21 +49,56 @@ impl StateCode {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DiscordError {
+    #[error("unknown or expired login attempt")]
+    UnknownState,
+    #[error("logging in requires being a member of the discord server")]
+    NotAllowedInGuild,
+    #[error(transparent)]
+    Api(#[from] reqwest::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl IntoResponse for DiscordError {
+    fn into_response(self) -> Response {
+        match self {
+            DiscordError::UnknownState => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            DiscordError::NotAllowedInGuild => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            DiscordError::Api(error) => {
+                warn!(?error, "discord api request failed");
+                StatusCode::BAD_GATEWAY.into_response()
+            }
+            DiscordError::Store(error) => {
+                warn!(?error, "database error while handling discord login");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Authenticator {
-    database: Arc<Database>,
-    state_codes: Arc<DashSet<StateCode>>,
+    database: Arc<dyn Repository>,
+    /// Each pending login's creation time, and the guest it should upgrade if it turns out to be
+    /// a new discord account.
+    state_codes: Arc<DashMap<StateCode, (Instant, Option<UserId>)>>,
     client: reqwest::Client,
-    info: Arc<DiscordInfo>,
+    config: Arc<Config>,
 }
 
 impl Authenticator {
-    pub fn new(database: Arc<Database>, info: DiscordInfo) -> Self {
+    pub fn new(database: Arc<dyn Repository>, config: Arc<Config>) -> Self {
         Self {
             database,
             state_codes: Arc::default(),
             client: reqwest::ClientBuilder::new()
-                .https_only(true)
+                .https_only(config.providers.discord.api_base.scheme() == "https")
                 .user_agent(concat!(
                     "DiscordBot (github.com/NeuroTCG/backend, ",
                     env!("CARGO_PKG_VERSION"),
@@ -57,21 +106,30 @@ impl Authenticator {
                 ))
                 .build()
                 .unwrap(),
-            info: Arc::new(info),
+            config,
         }
     }
 
-    pub fn start_auth(&self) -> StateCode {
+    fn info(&self) -> &DiscordInfo {
+        &self.config.providers.discord
+    }
+
+    pub fn start_auth(&self, upgrade_guest: Option<UserId>) -> StateCode {
+        let state_code_ttl = self.config.tokens.state_code_ttl;
+        self.state_codes
+            .retain(|_, (created_at, _)| created_at.elapsed() < state_code_ttl);
+
         let state_code = loop {
             let code = self.generate_state_code(32);
-            if self.state_codes.contains(&code) {
+            if self.state_codes.contains_key(&code) {
                 continue;
             } else {
                 break code;
             }
         };
 
-        self.state_codes.insert(state_code.clone());
+        self.state_codes
+            .insert(state_code.clone(), (Instant::now(), upgrade_guest));
 
         state_code
     }
@@ -81,81 +139,295 @@ impl Authenticator {
         state_code: &str,
         redirect_code: &str,
         redirect_uri: &str,
-    ) -> Token {
+    ) -> Result<UserId, DiscordError> {
         let state_code = StateCode(state_code.to_string());
 
-        if !self.state_codes.contains(&state_code) {
-            todo!()
-        }
+        let upgrade_guest = match self.state_codes.remove(&state_code) {
+            Some((_, (created_at, upgrade_guest)))
+                if created_at.elapsed() < self.config.tokens.state_code_ttl =>
+            {
+                upgrade_guest
+            }
+            _ => return Err(DiscordError::UnknownState),
+        };
 
-        // FIXME: this should not panic if discord returns an invalid response (i.e. we sent a bad request, user provided a bad auth code)
         let discord_token_info: DiscordTokenResponse = self
-            .client
-            .post(format!("{DISCORD_BASE}/api/v10/oauth2/token"))
-            .form(&HashMap::from([
-                ("grant_type", "authorization_code"),
-                ("code", redirect_code),
-                ("redirect_uri", redirect_uri),
-            ]))
-            .basic_auth(
-                self.info.client_id.to_owned(),
-                Some(self.info.client_secret.to_owned()),
+            .send(
+                "token",
+                self.client
+                    .post(self.info().api_url("/api/v10/oauth2/token"))
+                    .form(&HashMap::from([
+                        ("grant_type", "authorization_code"),
+                        ("code", redirect_code),
+                        ("redirect_uri", redirect_uri),
+                    ]))
+                    .basic_auth(
+                        self.info().client_id.to_owned(),
+                        Some(self.info().client_secret.to_owned()),
+                    ),
             )
-            .send()
-            .await
-            .unwrap()
+            .await?
+            .error_for_status()?
             .json()
-            .await
-            .unwrap();
+            .await?;
 
         let discord_auth_info: DiscordAuthInfoResponse = self
-            .client
-            .get(format!("{DISCORD_BASE}/api/v10/oauth2/@me"))
-            .bearer_auth(&discord_token_info.access_token)
-            .send()
-            .await
-            .unwrap()
+            .send(
+                "me",
+                self.client
+                    .get(self.info().api_url("/api/v10/oauth2/@me"))
+                    .bearer_auth(&discord_token_info.access_token),
+            )
+            .await?
+            .error_for_status()?
             .json()
-            .await
-            .unwrap();
+            .await?;
+
+        // checked before anything is stored, so people outside the server don't get an account
+        let guild_roles = match &self.info().guild {
+            Some(guild) => Some(
+                self.check_guild_member(guild, &discord_token_info.access_token)
+                    .await?
+                    .ok_or(DiscordError::NotAllowedInGuild)?,
+            ),
+            None => None,
+        };
 
-        let user_id = if self
+        let existing_user_id = self
             .database
-            .discord_user_registered(&discord_auth_info.user.id)
-            .await
-            .unwrap()
-        {
-            self.database
-                .get_user_by_discord_id(&discord_auth_info.user.id)
-                .await
-                .unwrap()
-                .unwrap()
+            .get_user_by_discord_id(&discord_auth_info.user.id)
+            .await?;
+
+        let user_id = match &existing_user_id {
+            Some(user_id) => user_id.clone(),
+            // linking to a guest keeps everything they did before signing up
+            None => match upgrade_guest {
+                Some(guest_id) if self.database.upgrade_guest_user(&guest_id).await? => {
+                    info!(user_id = guest_id.0, "upgraded guest to a full account");
+                    guest_id
+                }
+                _ => self.database.create_new_user(UserKind::Full).await?.user_id,
+            },
+        };
+
+        let link = DiscordOauthUser {
+            discord_id: discord_auth_info.user.id,
+            linked_to_user_id: user_id.clone(),
+            refresh_token: discord_token_info.refresh_token,
+            access_token: discord_token_info.access_token,
+            expires_at: discord_auth_info.expires,
+        };
+
+        if existing_user_id.is_some() {
+            self.database.update_discord_tokens(&link).await?;
         } else {
-            let user = self.database.create_new_user().await.unwrap();
             self.database
-                .link_discord_id_to_user_id(
-                    &user.user_id,
-                    &DiscordOauthUser {
-                        discord_id: discord_auth_info.user.id,
-                        linked_to_user_id: user.user_id.clone(),
-                        refresh_token: discord_token_info.refresh_token,
-                        access_token: discord_token_info.access_token,
-                        expires_at: discord_auth_info.expires,
-                    },
-                )
-                .await
-                .unwrap();
-
-            user.user_id
+                .link_discord_id_to_user_id(&user_id, &link)
+                .await?;
+        }
+
+        if let Some(roles) = guild_roles {
+            self.database.sync_discord_roles(&user_id, &roles).await?;
+        }
+
+        Ok(user_id)
+    }
+
+    /// Returns the internal roles the member's discord roles map to, or `None` if they aren't
+    /// in the guild or lack every required role.
+    async fn check_guild_member(
+        &self,
+        guild: &GuildGate,
+        access_token: &str,
+    ) -> Result<Option<Vec<Role>>, DiscordError> {
+        let response = self
+            .send(
+                "guild_member",
+                self.client
+                    .get(self.info().api_url(&format!(
+                        "/api/v10/users/@me/guilds/{}/member",
+                        guild.guild_id
+                    )))
+                    .bearer_auth(access_token),
+            )
+            .await?;
+
+        // not a member, or a token from before the guilds.members.read scope was requested
+        if matches!(
+            response.status(),
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN
+        ) {
+            return Ok(None);
+        }
+
+        let member: DiscordGuildMemberResponse = response.error_for_status()?.json().await?;
+
+        let has_required_role = guild.required_roles.is_empty()
+            || member
+                .roles
+                .iter()
+                .any(|role_id| guild.required_roles.contains(role_id));
+        if !has_required_role {
+            return Ok(None);
+        }
+
+        let mut roles: Vec<Role> = member
+            .roles
+            .iter()
+            .filter_map(|role_id| guild.role_mapping.get(role_id).copied())
+            .collect();
+        roles.sort_by_key(Role::as_str);
+        roles.dedup();
+
+        Ok(Some(roles))
+    }
+
+    /// Refreshes the discord tokens of every member that hasn't been checked within the recheck
+    /// interval, and checks them against the guild gate again. Members that no longer pass are
+    /// logged out and lose their mapped roles.
+    pub async fn recheck_guild_members(&self) -> Result<(), StoreError> {
+        let Some(guild) = &self.info().guild else {
+            return Ok(());
         };
 
-        let auth_token = self
+        let links = self
             .database
-            .create_auth_token(&user_id)
-            .await
-            .unwrap();
+            .list_discord_users_refreshed_before(
+                time::OffsetDateTime::now_utc() - guild.recheck_interval,
+            )
+            .await?;
+
+        for link in links {
+            let user_id = link.linked_to_user_id.clone();
 
-        auth_token
+            if let Err(error) = self.recheck_guild_member(guild, link).await {
+                warn!(
+                    ?error,
+                    user_id = user_id.0,
+                    "failed to re-check discord member"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn recheck_guild_member(
+        &self,
+        guild: &GuildGate,
+        link: DiscordOauthUser,
+    ) -> Result<(), DiscordError> {
+        let response = self
+            .send(
+                "token",
+                self.client
+                    .post(self.info().api_url("/api/v10/oauth2/token"))
+                    .form(&HashMap::from([
+                        ("grant_type", "refresh_token"),
+                        ("refresh_token", &link.refresh_token),
+                    ]))
+                    .basic_auth(
+                        self.info().client_id.to_owned(),
+                        Some(self.info().client_secret.to_owned()),
+                    ),
+            )
+            .await?;
+
+        // discord answers with invalid_grant once the user has removed the app
+        let roles = if response.status() == StatusCode::BAD_REQUEST {
+            // still counts as checked, so they aren't retried on every tick
+            self.database.update_discord_tokens(&link).await?;
+            None
+        } else {
+            let discord_token_info: DiscordTokenResponse =
+                response.error_for_status()?.json().await?;
+
+            self.database
+                .update_discord_tokens(&DiscordOauthUser {
+                    refresh_token: discord_token_info.refresh_token,
+                    access_token: discord_token_info.access_token.clone(),
+                    expires_at: time::OffsetDateTime::now_utc()
+                        + Duration::from_secs(discord_token_info.expires_in.max(0) as u64),
+                    ..link.clone()
+                })
+                .await?;
+
+            self.check_guild_member(guild, &discord_token_info.access_token)
+                .await?
+        };
+
+        match roles {
+            Some(roles) => {
+                self.database
+                    .sync_discord_roles(&link.linked_to_user_id, &roles)
+                    .await?
+            }
+            None => {
+                let num_revoked = self
+                    .database
+                    .revoke_user_auth_tokens(&link.linked_to_user_id)
+                    .await?;
+                self.database
+                    .sync_discord_roles(&link.linked_to_user_id, &[])
+                    .await?;
+
+                info!(
+                    user_id = link.linked_to_user_id.0,
+                    num_revoked, "discord member no longer passes the guild gate, logged them out"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a discord api request, recording its latency and whether it failed.
+    async fn send(
+        &self,
+        endpoint: &'static str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let _timer = metrics::DISCORD_API_REQUEST_DURATION
+            .with_label_values(&[endpoint])
+            .start_timer();
+
+        let response = request.send().await;
+
+        let failed = match &response {
+            Ok(response) => {
+                response.status().is_server_error()
+                    || response.status() == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(_) => true,
+        };
+        if failed {
+            metrics::DISCORD_API_ERRORS
+                .with_label_values(&[endpoint])
+                .inc();
+        }
+
+        response
+    }
+
+    /// Whether the discord api answers at all, for readiness checks. Doesn't need any token.
+    pub async fn api_reachable(&self, timeout: Duration) -> bool {
+        let request = self
+            .client
+            .get(self.info().api_url("/api/v10/gateway"))
+            .timeout(timeout);
+
+        match self.send("gateway", request).await {
+            Ok(response) => response.status().is_success(),
+            Err(error) => {
+                debug!(?error, "discord api is unreachable");
+                false
+            }
+        }
+    }
+
+    /// Includes expired logins that haven't been pruned yet.
+    pub fn pending_logins(&self) -> usize {
+        self.state_codes.len()
     }
 
     fn generate_state_code(&self, size: usize) -> StateCode {
@@ -168,52 +440,97 @@ impl Authenticator {
                 .push(STATE_CODE_CHARACTERS[rng.gen_range(0..STATE_CODE_CHARACTERS.len())]);
         }
 
-        let state_code = StateCode(random_string);
-
-        return state_code;
+        StateCode(random_string)
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct DiscordInfo {
-    client_id: String,
-    client_secret: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the discord api (and oauth consent page) lives. Only ever changed to point at a mock
+    /// server.
+    pub api_base: url::Url,
+    pub guild: Option<GuildGate>,
+}
+
+/// Only lets members of a discord server log in, e.g. for closed betas.
+#[derive(Debug, Clone)]
+pub struct GuildGate {
+    pub guild_id: String,
+    /// Members need at least one of these discord role ids. Empty lets every member in.
+    pub required_roles: Vec<String>,
+    /// Discord role ids and the internal role each one grants.
+    pub role_mapping: HashMap<String, Role>,
+    /// Members are re-checked, by refreshing their discord token, about this often.
+    pub recheck_interval: Duration,
 }
 
 impl DiscordInfo {
-    pub fn from_env() -> Self {
-        Self {
-            client_id: std::env::var("DISCORD_OAUTH_CLIENT_ID")
-                .expect("failed to read DISCORD_OAUTH_CLIENT_ID environment variable"),
-            client_secret: std::env::var("DISCORD_OAUTH_CLIENT_SECRET")
-                .expect("failed to read DISCORD_OAUTH_CLIENT_SECRET environment variable"),
-        }
+    pub fn api_url(&self, path: &str) -> String {
+        format!("{}{}", self.api_base.as_str().trim_end_matches('/'), path)
     }
 }
 
-async fn start_auth(State(state): State<WebState>) -> impl IntoResponse {
-    let state_code = state.discord_authenticator.start_auth();
-
-    // TODO: figure out how to make this use the DISCORD_BASE constant?
-    let uri = http::Uri::builder()
-        .scheme("https")
-        .authority("discord.com")
-        .path_and_query(format!(
-            "/oauth2/authorize?{}",
-            url::form_urlencoded::Serializer::new(String::new())
-                .append_pair("client_id", &state.discord_authenticator.info.client_id)
-                .append_pair("response_type", "code")
-                .append_pair(
-                    "redirect_uri",
-                    &format!("{}/auth/providers/discord/redirect", state.webserver_base)
-                )
-                .append_pair("scope", "identify")
-                .append_pair("state", state_code.get())
-                .finish()
-        ))
-        .build()
-        .unwrap();
-
-    Redirect::to(&uri.to_string())
+async fn start_auth(
+    State(state): State<WebState>,
+    caller: Option<PlayerCaller>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let upgrade_guest = caller
+        .map(|PlayerCaller(user)| user)
+        .filter(|user| user.is_guest)
+        .map(|user| user.user_id);
+    let state_code = state.discord_authenticator.start_auth(upgrade_guest);
+
+    let uri = format!(
+        "{}?{}",
+        state.config.providers.discord.api_url("/oauth2/authorize"),
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", &state.config.providers.discord.client_id)
+            .append_pair("response_type", "code")
+            .append_pair(
+                "redirect_uri",
+                &state.config.public_url("/auth/providers/discord/redirect")
+            )
+            .append_pair(
+                "scope",
+                match state.config.providers.discord.guild {
+                    Some(_) => "identify guilds.members.read",
+                    None => "identify",
+                }
+            )
+            .append_pair("state", state_code.get())
+            .finish()
+    );
+
+    (
+        jar.add(state_cookie(&state, &state_code)),
+        Redirect::to(&uri),
+    )
+}
+
+fn state_cookie(state: &WebState, state_code: &StateCode) -> Cookie<'static> {
+    let mut cookie = Cookie::build((STATE_COOKIE_NAME, state_code.get().to_string()))
+        .path("/auth/providers/discord")
+        .http_only(true)
+        .secure(true)
+        // sent along when discord redirects back, which strict would leave it out of
+        .same_site(SameSite::Lax)
+        .max_age(
+            state
+                .config
+                .tokens
+                .state_code_ttl
+                .try_into()
+                .expect("state code ttl was validated on startup"),
+        );
+
+    if let Some(domain) = &state.config.cookie.domain {
+        cookie = cookie.domain(domain.clone());
+    }
+
+    cookie.build()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -227,37 +544,41 @@ async fn handle_redirect(
     State(state): State<WebState>,
     Query(params): Query<QueryParams>,
     OriginalUri(uri): OriginalUri,
-) -> impl IntoResponse {
-    let token = state
+    jar: CookieJar,
+) -> Result<Response, DiscordError> {
+    // otherwise someone could have another person finish a login they started, e.g. to link
+    // that person's discord account to their own guest
+    let started_here = jar
+        .get(STATE_COOKIE_NAME)
+        .is_some_and(|cookie| cookie.value() == params.state);
+    if !started_here {
+        return Err(DiscordError::UnknownState);
+    }
+    let mut removal = Cookie::build(STATE_COOKIE_NAME).path("/auth/providers/discord");
+    if let Some(domain) = &state.config.cookie.domain {
+        removal = removal.domain(domain.clone());
+    }
+    let jar = jar.remove(removal);
+
+    let user_id = state
         .discord_authenticator
         .auth_response(
             &params.state,
             &params.code,
-            &format!("{}{}", state.webserver_base, uri.path()),
+            &state.config.public_url(uri.path()),
         )
-        .await;
-
-    let mut response = Redirect::to("/").into_response();
-
-    response
-        .headers_mut()
-        .append(
-            "Set-Cookie",
-            format!(
-                "AuthToken={}; HttpOnly; SameSite=Strict; Secure",
-                token.to_hex_string()
-            )
-            .try_into()
-            .unwrap(),
-        );
+        .await?;
 
-    response
+    Ok(two_factor::finish_login(&state, user_id, jar).await)
 }
 
 pub fn routes() -> Router<WebState> {
-    Router::new()
-        .route("/begin", get(start_auth))
-        .route("/redirect", get(handle_redirect))
+    Router::new().route("/begin", get(start_auth)).route(
+        "/redirect",
+        get(handle_redirect).layer(axum::middleware::map_response(|response: Response| async {
+            metrics::record_login("discord", response)
+        })),
+    )
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -278,6 +599,12 @@ struct DiscordAuthInfoResponse {
     user: DiscordUserResponse,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct DiscordGuildMemberResponse {
+    roles: Vec<String>,
+    // other fields are not relevant
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DiscordApplicationResponse {
     id: String,
@@ -0,0 +1,427 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use http::StatusCode;
+use lettre::{message::Mailbox, Address};
+use rand::RngCore;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    caller::PlayerCaller,
+    database::{
+        models::{EmailLoginCode, Token, UserId, UserKind},
+        repository::StoreError,
+    },
+    mail::{Mail, MailError},
+    metrics, two_factor, WebState,
+};
+
+/// Ties login links to the browser that asked for them, see [`EmailLoginCode::browser_hash`].
+pub const BROWSER_COOKIE_NAME: &str = "EmailLoginBrowser";
+
+#[derive(Debug, Clone)]
+pub struct EmailInfo {
+    /// How long a login link stays valid.
+    pub code_ttl: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailError {
+    #[error("invalid email address")]
+    InvalidAddress,
+    #[error(
+        "this login link is invalid, was already used, has expired or was requested from another browser"
+    )]
+    InvalidCode,
+    #[error(transparent)]
+    Mail(#[from] MailError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl IntoResponse for EmailError {
+    fn into_response(self) -> Response {
+        match self {
+            EmailError::InvalidAddress => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            EmailError::InvalidCode => (StatusCode::UNAUTHORIZED, self.to_string()).into_response(),
+            EmailError::Mail(error) => {
+                warn!(?error, "failed to send login link");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            EmailError::Store(error) => {
+                warn!(?error, "database error while handling email login");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// Mail scanners open links to check them, so opening the login link only shows
+/// [`CONFIRM_PAGE`]. The code is used up by the `POST` it sends once the player confirms.
+pub fn routes() -> Router<WebState> {
+    Router::new().route("/begin", post(start_auth)).route(
+        "/redirect",
+        post(handle_redirect)
+            .layer(axum::middleware::map_response(|response: Response| async {
+                metrics::record_login("email", response)
+            }))
+            .get(confirm_page),
+    )
+}
+
+/// Sends the code from the link back with the csrf token, then follows wherever the login
+/// redirects to.
+const CONFIRM_PAGE: &str = r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Log in</title>
+</head>
+<body>
+<p>Continue logging in?</p>
+<button id="confirm">Log in</button>
+<p id="failed" hidden>This login link is invalid, was already used, has expired or was requested
+from another browser.</p>
+<script>
+document.getElementById("confirm").addEventListener("click", async () => {
+    const csrfToken = document.cookie
+        .split("; ")
+        .find((cookie) => cookie.startsWith("CsrfToken="))
+        ?.slice("CsrfToken=".length);
+    const response = await fetch(location.pathname, {
+        method: "POST",
+        headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken ?? "" },
+        body: JSON.stringify({ code: new URLSearchParams(location.search).get("code") }),
+    });
+
+    if (response.redirected) {
+        location.href = response.url;
+    } else {
+        document.getElementById("failed").hidden = false;
+    }
+});
+</script>
+</body>
+</html>
+"#;
+
+#[derive(Debug, Deserialize)]
+struct StartAuthRequest {
+    email: String,
+}
+
+/// Addresses are compared case-insensitively, even though the local part technically isn't.
+fn normalize_address(email: &str) -> Option<Address> {
+    email.trim().to_lowercase().parse().ok()
+}
+
+// the response is the same whether or not the address belongs to an account
+async fn start_auth(
+    State(state): State<WebState>,
+    caller: Option<PlayerCaller>,
+    jar: CookieJar,
+    Json(request): Json<StartAuthRequest>,
+) -> Result<(CookieJar, StatusCode), EmailError> {
+    let address = normalize_address(&request.email).ok_or(EmailError::InvalidAddress)?;
+    let upgrade_guest = caller
+        .map(|PlayerCaller(user)| user)
+        .filter(|user| user.is_guest)
+        .map(|user| user.user_id);
+
+    // links asked for earlier from the same browser keep working
+    let browser = jar
+        .get(BROWSER_COOKIE_NAME)
+        .and_then(|cookie| Token::from_hex_string(cookie.value()))
+        .unwrap_or_else(random_token);
+    let code = random_token();
+
+    let code_ttl = state.config.providers.email.code_ttl;
+    state
+        .database
+        .create_email_login_code(&EmailLoginCode {
+            code_hash: code.get_hash(),
+            email: address.to_string(),
+            upgrade_guest,
+            expires_at: time::OffsetDateTime::now_utc() + code_ttl,
+            browser_hash: browser.get_hash(),
+        })
+        .await?;
+
+    let link = format!(
+        "{}?{}",
+        state.config.public_url("/auth/providers/email/redirect"),
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("code", &code.to_hex_string())
+            .finish()
+    );
+
+    state
+        .mailer
+        .send(&Mail {
+            to: Mailbox::new(None, address),
+            subject: "Your login link".to_string(),
+            body: format!(
+                "Open this link, in the browser you asked for it from, to log in. It can only be \
+                used once and expires in {} minutes.\n\n\
+                {link}\n\n\
+                If you didn't ask for it, you can ignore this email.",
+                code_ttl.as_secs().div_ceil(60)
+            ),
+        })
+        .await?;
+
+    Ok((
+        jar.add(browser_cookie(&state, browser)),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+fn random_token() -> Token {
+    let mut bytes = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Token(bytes)
+}
+
+fn browser_cookie(state: &WebState, browser: Token) -> Cookie<'static> {
+    let mut cookie = Cookie::build((BROWSER_COOKIE_NAME, browser.to_hex_string()))
+        .path("/auth/providers/email")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(
+            state
+                .config
+                .providers
+                .email
+                .code_ttl
+                .try_into()
+                .expect("code ttl was validated on startup"),
+        );
+
+    if let Some(domain) = &state.config.cookie.domain {
+        cookie = cookie.domain(domain.clone());
+    }
+
+    cookie.build()
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmRequest {
+    code: String,
+}
+
+async fn confirm_page() -> Html<&'static str> {
+    Html(CONFIRM_PAGE)
+}
+
+async fn handle_redirect(
+    State(state): State<WebState>,
+    jar: CookieJar,
+    Json(request): Json<ConfirmRequest>,
+) -> Result<Response, EmailError> {
+    let code = Token::from_hex_string(&request.code).ok_or(EmailError::InvalidCode)?;
+    let browser = jar
+        .get(BROWSER_COOKIE_NAME)
+        .and_then(|cookie| Token::from_hex_string(cookie.value()))
+        .ok_or(EmailError::InvalidCode)?;
+    let login = state
+        .database
+        .consume_email_login_code(&code.get_hash(), &browser.get_hash())
+        .await?
+        .ok_or(EmailError::InvalidCode)?;
+
+    let user_id = match state.database.get_user_by_email(&login.email).await? {
+        Some(user_id) => user_id,
+        None => register(&state, &login).await?,
+    };
+
+    Ok(two_factor::finish_login(&state, user_id, jar).await)
+}
+
+async fn register(state: &WebState, login: &EmailLoginCode) -> Result<UserId, EmailError> {
+    // linking to a guest keeps everything they did before signing up
+    let user_id = match &login.upgrade_guest {
+        Some(guest_id) if state.database.upgrade_guest_user(guest_id).await? => {
+            info!(user_id = guest_id.0, "upgraded guest to a full account");
+            guest_id.clone()
+        }
+        _ => {
+            state
+                .database
+                .create_new_user(UserKind::Full)
+                .await?
+                .user_id
+        }
+    };
+
+    if state
+        .database
+        .link_email_to_user_id(&user_id, &login.email)
+        .await?
+    {
+        return Ok(user_id);
+    }
+
+    // another link for the same address was used at the same time and won the race
+    state
+        .database
+        .get_user_by_email(&login.email)
+        .await?
+        .ok_or(EmailError::InvalidCode)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::{header, Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::csrf::{CSRF_COOKIE_NAME, CSRF_HEADER_NAME};
+
+    const REDIRECT: &str = "/auth/providers/email/redirect";
+
+    fn browser() -> Token {
+        Token(vec![3; 32])
+    }
+
+    async fn create_code(state: &WebState) -> String {
+        let code = Token(vec![7; 32]);
+        state
+            .database
+            .create_email_login_code(&EmailLoginCode {
+                code_hash: code.get_hash(),
+                email: "player@example.com".to_string(),
+                upgrade_guest: None,
+                browser_hash: browser().get_hash(),
+                expires_at: time::OffsetDateTime::now_utc() + Duration::from_secs(60),
+            })
+            .await
+            .unwrap();
+
+        code.to_hex_string()
+    }
+
+    async fn confirm(state: &WebState, code: &str) -> Response {
+        confirm_from(state, code, &browser()).await
+    }
+
+    async fn confirm_from(state: &WebState, code: &str, browser: &Token) -> Response {
+        crate::router(state.clone())
+            .oneshot(
+                Request::post(REDIRECT)
+                    .header(header::ORIGIN, "https://game.example")
+                    .header(
+                        header::COOKIE,
+                        format!(
+                            "{CSRF_COOKIE_NAME}=token; {BROWSER_COOKIE_NAME}={}",
+                            browser.to_hex_string()
+                        ),
+                    )
+                    .header(CSRF_HEADER_NAME, "token")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(format!(r#"{{"code":"{code}"}}"#)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn opening_the_link_does_not_use_up_the_code() {
+        let state = crate::test_state();
+        let code = create_code(&state).await;
+
+        let response = crate::router(state.clone())
+            .oneshot(
+                Request::get(format!("{REDIRECT}?code={code}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .all(|value| !value.to_str().unwrap().starts_with("AuthToken=")));
+
+        let response = confirm(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn confirming_uses_up_the_code() {
+        let state = crate::test_state();
+        let code = create_code(&state).await;
+
+        let response = confirm(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .any(|value| value.to_str().unwrap().starts_with("AuthToken=")));
+        assert!(state
+            .database
+            .get_user_by_email("player@example.com")
+            .await
+            .unwrap()
+            .is_some());
+
+        let response = confirm(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn confirming_needs_a_csrf_token() {
+        let state = crate::test_state();
+        let code = create_code(&state).await;
+
+        let response = crate::router(state.clone())
+            .oneshot(
+                Request::post(REDIRECT)
+                    .header(header::ORIGIN, "https://game.example")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(format!(r#"{{"code":"{code}"}}"#)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // the rejected request didn't use up the code
+        assert_eq!(confirm(&state, &code).await.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn links_only_work_in_the_browser_that_asked_for_them() {
+        let state = crate::test_state();
+        let code = create_code(&state).await;
+
+        let response = confirm_from(&state, &code, &Token(vec![4; 32])).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(state
+            .database
+            .get_user_by_email("player@example.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        // the other browser didn't use up the code
+        assert_eq!(confirm(&state, &code).await.status(), StatusCode::SEE_OTHER);
+    }
+}
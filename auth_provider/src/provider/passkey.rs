@@ -0,0 +1,709 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use dashmap::DashMap;
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    caller::PlayerCaller,
+    config::Config,
+    database::{
+        models::{PasskeyCredential, User, UserId},
+        repository::{Repository, StoreError},
+    },
+    metrics, WebState,
+};
+
+pub mod webauthn;
+
+use webauthn::{RelyingParty, WebAuthnError, COSE_ALG_EDDSA, COSE_ALG_ES256};
+
+const BASE64URL: data_encoding::Encoding = data_encoding::BASE64URL_NOPAD;
+
+#[derive(Debug, Clone)]
+pub struct PasskeyInfo {
+    pub rp_id: String,
+    pub rp_name: String,
+    /// The origin of `public_base_url`, the only place ceremonies are accepted from.
+    pub origin: String,
+    pub ceremony_ttl: std::time::Duration,
+}
+
+enum Ceremony {
+    /// Credentials can only be added to an account that's already logged in, guest or not.
+    Registration {
+        user_id: UserId,
+    },
+    Authentication,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasskeyError {
+    #[error("credential is malformed")]
+    Malformed,
+    #[error("unknown or expired ceremony")]
+    UnknownCeremony,
+    #[error("unknown credential")]
+    UnknownCredential,
+    #[error("credential is already registered")]
+    AlreadyRegistered,
+    #[error(transparent)]
+    WebAuthn(#[from] WebAuthnError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl IntoResponse for PasskeyError {
+    fn into_response(self) -> Response {
+        match self {
+            PasskeyError::Malformed => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+            PasskeyError::UnknownCeremony
+            | PasskeyError::UnknownCredential
+            | PasskeyError::WebAuthn(_) => {
+                (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+            }
+            PasskeyError::AlreadyRegistered => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            PasskeyError::Store(error) => {
+                warn!(?error, "database error while handling passkey request");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    database: Arc<dyn Repository>,
+    /// Pending ceremonies by their base64url encoded challenge.
+    ceremonies: Arc<DashMap<String, (Instant, Ceremony)>>,
+    config: Arc<Config>,
+}
+
+impl Authenticator {
+    pub fn new(database: Arc<dyn Repository>, config: Arc<Config>) -> Self {
+        Self {
+            database,
+            ceremonies: Arc::default(),
+            config,
+        }
+    }
+
+    fn info(&self) -> &PasskeyInfo {
+        &self.config.providers.passkey
+    }
+
+    fn relying_party(&self) -> RelyingParty<'_> {
+        RelyingParty {
+            id: &self.info().rp_id,
+            origin: &self.info().origin,
+        }
+    }
+
+    fn start_ceremony(&self, ceremony: Ceremony) -> String {
+        let ceremony_ttl = self.info().ceremony_ttl;
+        self.ceremonies
+            .retain(|_, (created_at, _)| created_at.elapsed() < ceremony_ttl);
+
+        let mut challenge = [0; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        let challenge = BASE64URL.encode(&challenge);
+
+        self.ceremonies
+            .insert(challenge.clone(), (Instant::now(), ceremony));
+
+        challenge
+    }
+
+    /// Ceremonies are single use, whether or not the response turns out to be valid.
+    fn take_ceremony(&self, challenge: &str) -> Result<Ceremony, PasskeyError> {
+        match self.ceremonies.remove(challenge) {
+            Some((_, (created_at, ceremony)))
+                if created_at.elapsed() < self.info().ceremony_ttl =>
+            {
+                Ok(ceremony)
+            }
+            _ => Err(PasskeyError::UnknownCeremony),
+        }
+    }
+
+    pub async fn start_registration(&self, user: &User) -> Result<CreationOptions, PasskeyError> {
+        let existing = self
+            .database
+            .list_passkey_credentials(&user.user_id)
+            .await?;
+
+        let challenge = self.start_ceremony(Ceremony::Registration {
+            user_id: user.user_id.clone(),
+        });
+
+        Ok(CreationOptions {
+            challenge,
+            rp: RelyingPartyEntity {
+                id: self.info().rp_id.clone(),
+                name: self.info().rp_name.clone(),
+            },
+            user: UserEntity {
+                id: BASE64URL.encode(user.user_id.0.as_bytes()),
+                name: user.user_id.0.clone(),
+                display_name: user.user_id.0.clone(),
+            },
+            pub_key_cred_params: [COSE_ALG_ES256, COSE_ALG_EDDSA]
+                .into_iter()
+                .map(|alg| CredentialParameters {
+                    kind: "public-key",
+                    alg,
+                })
+                .collect(),
+            timeout: self.info().ceremony_ttl.as_millis() as u64,
+            exclude_credentials: existing
+                .iter()
+                .map(|credential| CredentialDescriptor {
+                    kind: "public-key",
+                    id: BASE64URL.encode(&credential.credential_id),
+                })
+                .collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "required",
+                require_resident_key: true,
+                user_verification: "required",
+            },
+            attestation: "none",
+        })
+    }
+
+    pub async fn finish_registration(
+        &self,
+        user: &User,
+        name: Option<String>,
+        credential: RegistrationCredential,
+    ) -> Result<PasskeyCredential, PasskeyError> {
+        let client_data_json = decode(&credential.response.client_data_json)?;
+        let attestation_object = decode(&credential.response.attestation_object)?;
+
+        let client_data = webauthn::parse_client_data(&client_data_json)?;
+        match self.take_ceremony(&client_data.challenge)? {
+            Ceremony::Registration { user_id } if user_id == user.user_id => (),
+            _ => return Err(PasskeyError::UnknownCeremony),
+        }
+
+        let attested = webauthn::verify_registration(
+            self.relying_party(),
+            &client_data_json,
+            &attestation_object,
+        )?;
+
+        let credential = PasskeyCredential {
+            credential_id: attested.credential_id,
+            user_id: user.user_id.clone(),
+            name: name.unwrap_or_else(|| "Passkey".to_string()),
+            public_key: attested.public_key,
+            sign_count: attested.sign_count,
+            created_at: time::OffsetDateTime::now_utc(),
+            last_used_at: None,
+        };
+
+        if !self.database.add_passkey_credential(&credential).await? {
+            return Err(PasskeyError::AlreadyRegistered);
+        }
+
+        // a passkey is a way back into the account, so guests keep it from here on
+        if user.is_guest && self.database.upgrade_guest_user(&user.user_id).await? {
+            info!(user_id = user.user_id.0, "upgraded guest to a full account");
+        }
+
+        info!(user_id = user.user_id.0, "registered passkey");
+
+        Ok(credential)
+    }
+
+    /// Only discoverable credentials are supported, so no user needs to be named up front.
+    pub fn start_authentication(&self) -> RequestOptions {
+        RequestOptions {
+            challenge: self.start_ceremony(Ceremony::Authentication),
+            rp_id: self.info().rp_id.clone(),
+            timeout: self.info().ceremony_ttl.as_millis() as u64,
+            allow_credentials: Vec::new(),
+            user_verification: "required",
+        }
+    }
+
+    pub async fn finish_authentication(
+        &self,
+        credential: AuthenticationCredential,
+    ) -> Result<UserId, PasskeyError> {
+        let credential_id = decode(&credential.id)?;
+        let client_data_json = decode(&credential.response.client_data_json)?;
+        let authenticator_data = decode(&credential.response.authenticator_data)?;
+        let signature = decode(&credential.response.signature)?;
+
+        let client_data = webauthn::parse_client_data(&client_data_json)?;
+        let Ceremony::Authentication = self.take_ceremony(&client_data.challenge)? else {
+            return Err(PasskeyError::UnknownCeremony);
+        };
+
+        let stored = self
+            .database
+            .get_passkey_credential(&credential_id)
+            .await?
+            .ok_or(PasskeyError::UnknownCredential)?;
+
+        if let Some(user_handle) = &credential.response.user_handle {
+            if decode(user_handle)? != stored.user_id.0.as_bytes() {
+                return Err(PasskeyError::UnknownCredential);
+            }
+        }
+
+        let sign_count = webauthn::verify_assertion(
+            self.relying_party(),
+            &client_data_json,
+            &authenticator_data,
+            &signature,
+            &stored.public_key,
+            stored.sign_count,
+        )?;
+
+        self.database
+            .update_passkey_sign_count(&credential_id, sign_count)
+            .await?;
+
+        Ok(stored.user_id)
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, PasskeyError> {
+    BASE64URL
+        .decode(value.as_bytes())
+        .map_err(|_| PasskeyError::Malformed)
+}
+
+/// `PublicKeyCredentialCreationOptionsJSON`, ready for `PublicKeyCredential.parseCreationOptionsFromJSON()`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    challenge: String,
+    rp: RelyingPartyEntity,
+    user: UserEntity,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    timeout: u64,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+    attestation: &'static str,
+}
+
+/// `PublicKeyCredentialRequestOptionsJSON`, ready for `PublicKeyCredential.parseRequestOptionsFromJSON()`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    challenge: String,
+    rp_id: String,
+    timeout: u64,
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct RelyingPartyEntity {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CredentialParameters {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alg: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthenticatorSelection {
+    resident_key: &'static str,
+    require_resident_key: bool,
+    user_verification: &'static str,
+}
+
+/// The parts of `PublicKeyCredential.toJSON()` after `navigator.credentials.create()` we use.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// The parts of `PublicKeyCredential.toJSON()` after `navigator.credentials.get()` we use.
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+pub fn routes() -> Router<WebState> {
+    Router::new()
+        .route("/register/begin", post(start_registration))
+        .route("/register/finish", post(finish_registration))
+        .route("/login/begin", post(start_authentication))
+        .route(
+            "/login/finish",
+            post(finish_authentication).layer(axum::middleware::map_response(
+                |response: Response| async { metrics::record_login("passkey", response) },
+            )),
+        )
+        .route("/credentials", get(list_credentials))
+        .route("/credentials/:credential_id", delete(delete_credential))
+}
+
+#[derive(Debug, Deserialize)]
+struct FinishRegistrationRequest {
+    /// Shown when listing credentials, so players can tell their devices apart.
+    name: Option<String>,
+    credential: RegistrationCredential,
+}
+
+#[derive(Debug, Serialize)]
+struct LoginResponse {
+    user_id: UserId,
+}
+
+async fn start_registration(
+    State(state): State<WebState>,
+    PlayerCaller(user): PlayerCaller,
+) -> Result<Json<CreationOptions>, PasskeyError> {
+    Ok(Json(
+        state
+            .passkey_authenticator
+            .start_registration(&user)
+            .await?,
+    ))
+}
+
+async fn finish_registration(
+    State(state): State<WebState>,
+    jar: CookieJar,
+    PlayerCaller(user): PlayerCaller,
+    Json(request): Json<FinishRegistrationRequest>,
+) -> Result<impl IntoResponse, PasskeyError> {
+    let credential = state
+        .passkey_authenticator
+        .finish_registration(&user, request.name, request.credential)
+        .await?;
+
+    // upgrading revoked the guest's tokens, so it continues with a fresh one
+    let jar = if user.is_guest {
+        let token = state
+            .database
+            .create_auth_token(&user.user_id, state.config.tokens.auth_token_ttl)
+            .await?;
+        jar.add(state.config.cookie.auth_cookie(token.to_hex_string()))
+    } else {
+        jar
+    };
+
+    Ok((StatusCode::CREATED, jar, Json(credential)))
+}
+
+async fn start_authentication(State(state): State<WebState>) -> Json<RequestOptions> {
+    Json(state.passkey_authenticator.start_authentication())
+}
+
+// user verification is required, so a passkey counts as both factors and skips the TOTP step
+// `two_factor::finish_login` would ask for
+async fn finish_authentication(
+    State(state): State<WebState>,
+    jar: CookieJar,
+    Json(credential): Json<AuthenticationCredential>,
+) -> Result<impl IntoResponse, PasskeyError> {
+    let user_id = state
+        .passkey_authenticator
+        .finish_authentication(credential)
+        .await?;
+
+    let token = state
+        .database
+        .create_auth_token(&user_id, state.config.tokens.auth_token_ttl)
+        .await?;
+
+    Ok((
+        jar.add(state.config.cookie.auth_cookie(token.to_hex_string())),
+        Json(LoginResponse { user_id }),
+    ))
+}
+
+async fn list_credentials(
+    State(state): State<WebState>,
+    PlayerCaller(user): PlayerCaller,
+) -> Result<Json<Vec<PasskeyCredential>>, PasskeyError> {
+    Ok(Json(
+        state
+            .database
+            .list_passkey_credentials(&user.user_id)
+            .await?,
+    ))
+}
+
+async fn delete_credential(
+    State(state): State<WebState>,
+    PlayerCaller(user): PlayerCaller,
+    Path(credential_id): Path<String>,
+) -> Result<StatusCode, PasskeyError> {
+    let credential_id = decode(&credential_id)?;
+
+    let was_deleted = state
+        .database
+        .delete_passkey_credential(&user.user_id, &credential_id)
+        .await?;
+
+    if !was_deleted {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    info!(user_id = user.user_id.0, "deleted passkey");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::UserKind;
+    use webauthn::{SoftwareAuthenticator, SoftwareKey};
+
+    async fn register(
+        state: &WebState,
+        user: &User,
+        key: SoftwareKey,
+    ) -> Result<SoftwareAuthenticator, PasskeyError> {
+        let authenticator = state.passkey_authenticator.as_ref();
+        let software = SoftwareAuthenticator::new(key, authenticator.relying_party());
+
+        let options = authenticator.start_registration(user).await?;
+        let (client_data_json, attestation_object) = software.register(&options.challenge);
+
+        authenticator
+            .finish_registration(
+                user,
+                None,
+                RegistrationCredential {
+                    response: AttestationResponse {
+                        client_data_json: BASE64URL.encode(&client_data_json),
+                        attestation_object: BASE64URL.encode(&attestation_object),
+                    },
+                },
+            )
+            .await?;
+
+        Ok(software)
+    }
+
+    fn assertion(
+        software: &mut SoftwareAuthenticator,
+        challenge: &str,
+    ) -> AuthenticationCredential {
+        let (client_data_json, authenticator_data, signature) = software.assert(challenge);
+
+        AuthenticationCredential {
+            id: BASE64URL.encode(&software.credential_id),
+            response: AssertionResponse {
+                client_data_json: BASE64URL.encode(&client_data_json),
+                authenticator_data: BASE64URL.encode(&authenticator_data),
+                signature: BASE64URL.encode(&signature),
+                user_handle: None,
+            },
+        }
+    }
+
+    async fn guest(state: &WebState) -> User {
+        state
+            .database
+            .create_new_user(UserKind::Guest)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn registered_passkeys_log_in() {
+        let state = crate::test_state();
+
+        for key in [SoftwareKey::es256(), SoftwareKey::eddsa()] {
+            let user = guest(&state).await;
+            let mut software = register(&state, &user, key).await.unwrap();
+
+            // the passkey is a way back in, so the guest became a full account
+            assert!(!state
+                .database
+                .upgrade_guest_user(&user.user_id)
+                .await
+                .unwrap());
+
+            let options = state.passkey_authenticator.start_authentication();
+            let user_id = state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await
+                .unwrap();
+            assert_eq!(user_id, user.user_id);
+
+            let stored = state
+                .database
+                .get_passkey_credential(&software.credential_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.sign_count, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn ceremonies_are_single_use() {
+        let state = crate::test_state();
+        let user = guest(&state).await;
+        let mut software = register(&state, &user, SoftwareKey::es256()).await.unwrap();
+
+        let options = state.passkey_authenticator.start_authentication();
+        state
+            .passkey_authenticator
+            .finish_authentication(assertion(&mut software, &options.challenge))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await,
+            Err(PasskeyError::UnknownCeremony)
+        ));
+        assert!(matches!(
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, "never issued"))
+                .await,
+            Err(PasskeyError::UnknownCeremony)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cloned_authenticators_are_caught() {
+        let state = crate::test_state();
+        let user = guest(&state).await;
+        let mut software = register(&state, &user, SoftwareKey::eddsa()).await.unwrap();
+
+        for _ in 0..2 {
+            let options = state.passkey_authenticator.start_authentication();
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await
+                .unwrap();
+        }
+
+        // a copy of the authenticator from before the second login
+        software.sign_count = 1;
+        let options = state.passkey_authenticator.start_authentication();
+        assert!(matches!(
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await,
+            Err(PasskeyError::WebAuthn(WebAuthnError::CounterRegressed))
+        ));
+    }
+
+    #[tokio::test]
+    async fn registration_ceremonies_belong_to_their_user() {
+        let state = crate::test_state();
+        let user = guest(&state).await;
+        let other = guest(&state).await;
+        let authenticator = state.passkey_authenticator.as_ref();
+        let software =
+            SoftwareAuthenticator::new(SoftwareKey::es256(), authenticator.relying_party());
+
+        let options = authenticator.start_registration(&user).await.unwrap();
+        let (client_data_json, attestation_object) = software.register(&options.challenge);
+
+        assert!(matches!(
+            authenticator
+                .finish_registration(
+                    &other,
+                    None,
+                    RegistrationCredential {
+                        response: AttestationResponse {
+                            client_data_json: BASE64URL.encode(&client_data_json),
+                            attestation_object: BASE64URL.encode(&attestation_object),
+                        },
+                    },
+                )
+                .await,
+            Err(PasskeyError::UnknownCeremony)
+        ));
+    }
+
+    #[tokio::test]
+    async fn credentials_from_another_site_are_rejected() {
+        let state = crate::test_state();
+        let user = guest(&state).await;
+        let mut software = register(&state, &user, SoftwareKey::es256()).await.unwrap();
+
+        software.origin = "https://evil.example".to_string();
+        let options = state.passkey_authenticator.start_authentication();
+        assert!(matches!(
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await,
+            Err(PasskeyError::WebAuthn(WebAuthnError::WrongOrigin(_)))
+        ));
+
+        software.origin = state.config.providers.passkey.origin.clone();
+        software.rp_id = "evil.example".to_string();
+        let options = state.passkey_authenticator.start_authentication();
+        assert!(matches!(
+            state
+                .passkey_authenticator
+                .finish_authentication(assertion(&mut software, &options.challenge))
+                .await,
+            Err(PasskeyError::WebAuthn(WebAuthnError::WrongRelyingParty))
+        ));
+    }
+}
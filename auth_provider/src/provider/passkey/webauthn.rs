@@ -0,0 +1,571 @@
+//! The WebAuthn checks themselves, kept free of storage and http so a software authenticator can
+//! drive them directly.
+
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The COSE algorithms we accept, in order of preference.
+pub const COSE_ALG_ES256: i64 = -7;
+pub const COSE_ALG_EDDSA: i64 = -8;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebAuthnError {
+    #[error("client data is malformed")]
+    MalformedClientData,
+    #[error("expected a `{expected}` ceremony, got `{actual}`")]
+    WrongCeremony {
+        expected: &'static str,
+        actual: String,
+    },
+    #[error("origin `{0}` is not allowed")]
+    WrongOrigin(String),
+    #[error("attestation object is malformed")]
+    MalformedAttestation,
+    #[error("authenticator data is malformed")]
+    MalformedAuthenticatorData,
+    #[error("credential belongs to a different relying party")]
+    WrongRelyingParty,
+    #[error("user presence was not confirmed")]
+    UserNotPresent,
+    #[error("user was not verified")]
+    UserNotVerified,
+    #[error("unsupported public key")]
+    UnsupportedKey,
+    #[error("signature is invalid")]
+    BadSignature,
+    #[error("signature counter went backwards, the authenticator may have been cloned")]
+    CounterRegressed,
+}
+
+/// Who credentials are created for and where ceremonies may happen.
+#[derive(Debug, Clone, Copy)]
+pub struct RelyingParty<'a> {
+    pub id: &'a str,
+    pub origin: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    pub ceremony: String,
+    /// Base64url encoded, exactly as we issued it.
+    pub challenge: String,
+    pub origin: String,
+}
+
+pub fn parse_client_data(client_data_json: &[u8]) -> Result<ClientData, WebAuthnError> {
+    serde_json::from_slice(client_data_json).map_err(|_| WebAuthnError::MalformedClientData)
+}
+
+/// A newly created credential that passed every check.
+#[derive(Debug, Clone)]
+pub struct AttestedCredential {
+    pub credential_id: Vec<u8>,
+    /// COSE_Key encoded.
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Checks the response to `navigator.credentials.create()`. Attestation statements are ignored,
+/// since we ask for `none` and don't restrict which authenticators may be used.
+pub fn verify_registration(
+    relying_party: RelyingParty,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<AttestedCredential, WebAuthnError> {
+    check_client_data(relying_party, client_data_json, "webauthn.create")?;
+
+    let attestation: Value = ciborium::from_reader(attestation_object)
+        .map_err(|_| WebAuthnError::MalformedAttestation)?;
+    let authenticator_data = attestation
+        .as_map()
+        .and_then(|entries| {
+            entries
+                .iter()
+                .find(|(key, _)| key.as_text() == Some("authData"))
+        })
+        .and_then(|(_, value)| value.as_bytes())
+        .ok_or(WebAuthnError::MalformedAttestation)?;
+
+    let (flags, sign_count) = check_authenticator_data(relying_party, authenticator_data)?;
+    if flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(WebAuthnError::MalformedAuthenticatorData);
+    }
+
+    // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE_Key
+    let attested = authenticator_data
+        .get(37 + 16..)
+        .ok_or(WebAuthnError::MalformedAuthenticatorData)?;
+    let (id_length, attested) = attested
+        .split_first_chunk::<2>()
+        .ok_or(WebAuthnError::MalformedAuthenticatorData)?;
+    let id_length = u16::from_be_bytes(*id_length) as usize;
+    if attested.len() < id_length {
+        return Err(WebAuthnError::MalformedAuthenticatorData);
+    }
+    let (credential_id, mut rest) = attested.split_at(id_length);
+
+    let key_start = rest;
+    let _: Value =
+        ciborium::from_reader(&mut rest).map_err(|_| WebAuthnError::MalformedAuthenticatorData)?;
+    let public_key = &key_start[..key_start.len() - rest.len()];
+
+    // reject keys we wouldn't be able to verify assertions with later
+    PublicKey::from_cose(public_key)?;
+
+    Ok(AttestedCredential {
+        credential_id: credential_id.to_vec(),
+        public_key: public_key.to_vec(),
+        sign_count,
+    })
+}
+
+/// Checks the response to `navigator.credentials.get()` against a stored credential, returning
+/// the new signature counter.
+pub fn verify_assertion(
+    relying_party: RelyingParty,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+) -> Result<u32, WebAuthnError> {
+    check_client_data(relying_party, client_data_json, "webauthn.get")?;
+    let (_, sign_count) = check_authenticator_data(relying_party, authenticator_data)?;
+
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    PublicKey::from_cose(public_key)?.verify(&signed, signature)?;
+
+    // authenticators that don't keep a counter always report zero
+    if (sign_count != 0 || stored_sign_count != 0) && sign_count <= stored_sign_count {
+        return Err(WebAuthnError::CounterRegressed);
+    }
+
+    Ok(sign_count)
+}
+
+fn check_client_data(
+    relying_party: RelyingParty,
+    client_data_json: &[u8],
+    ceremony: &'static str,
+) -> Result<(), WebAuthnError> {
+    let client_data = parse_client_data(client_data_json)?;
+
+    if client_data.ceremony != ceremony {
+        return Err(WebAuthnError::WrongCeremony {
+            expected: ceremony,
+            actual: client_data.ceremony,
+        });
+    }
+
+    if client_data.origin != relying_party.origin {
+        return Err(WebAuthnError::WrongOrigin(client_data.origin));
+    }
+
+    Ok(())
+}
+
+/// Returns the flags and signature counter. Both user presence and verification are required,
+/// since a passkey login skips any other factor.
+fn check_authenticator_data(
+    relying_party: RelyingParty,
+    authenticator_data: &[u8],
+) -> Result<(u8, u32), WebAuthnError> {
+    if authenticator_data.len() < 37 {
+        return Err(WebAuthnError::MalformedAuthenticatorData);
+    }
+
+    if authenticator_data[..32] != Sha256::digest(relying_party.id.as_bytes())[..] {
+        return Err(WebAuthnError::WrongRelyingParty);
+    }
+
+    let flags = authenticator_data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebAuthnError::UserNotPresent);
+    }
+    if flags & FLAG_USER_VERIFIED == 0 {
+        return Err(WebAuthnError::UserNotVerified);
+    }
+
+    let sign_count = u32::from_be_bytes(
+        authenticator_data[33..37]
+            .try_into()
+            .expect("slice is four bytes long"),
+    );
+
+    Ok((flags, sign_count))
+}
+
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+}
+
+impl PublicKey {
+    fn from_cose(public_key: &[u8]) -> Result<Self, WebAuthnError> {
+        let key: Value =
+            ciborium::from_reader(public_key).map_err(|_| WebAuthnError::UnsupportedKey)?;
+        let entries = key.as_map().ok_or(WebAuthnError::UnsupportedKey)?;
+
+        let parameter = |label: i64| {
+            entries
+                .iter()
+                .find(|(key, _)| key.as_integer().map(i128::from) == Some(label.into()))
+                .map(|(_, value)| value)
+        };
+        let integer = |label: i64| parameter(label).and_then(Value::as_integer).map(i128::from);
+        let bytes = |label: i64| -> Result<[u8; 32], WebAuthnError> {
+            parameter(label)
+                .and_then(Value::as_bytes)
+                .and_then(|bytes| bytes.as_slice().try_into().ok())
+                .ok_or(WebAuthnError::UnsupportedKey)
+        };
+
+        // kty (1), alg (3), crv (-1), x (-2), y (-3)
+        match (integer(1), integer(3), integer(-1)) {
+            (Some(2), Some(alg), Some(1)) if alg == COSE_ALG_ES256.into() => {
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    &bytes(-2)?.into(),
+                    &bytes(-3)?.into(),
+                    false,
+                );
+
+                p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map(PublicKey::Es256)
+                    .map_err(|_| WebAuthnError::UnsupportedKey)
+            }
+            (Some(1), Some(alg), Some(6)) if alg == COSE_ALG_EDDSA.into() => {
+                ed25519_dalek::VerifyingKey::from_bytes(&bytes(-2)?)
+                    .map(PublicKey::EdDsa)
+                    .map_err(|_| WebAuthnError::UnsupportedKey)
+            }
+            _ => Err(WebAuthnError::UnsupportedKey),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebAuthnError> {
+        let verified = match self {
+            PublicKey::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            PublicKey::EdDsa(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify_strict(message, &signature).is_ok()),
+        };
+
+        if verified {
+            Ok(())
+        } else {
+            Err(WebAuthnError::BadSignature)
+        }
+    }
+}
+
+/// Plays the authenticator and browser side of both ceremonies. Every field can be changed to
+/// produce responses a real authenticator wouldn't.
+#[cfg(test)]
+pub(crate) struct SoftwareAuthenticator {
+    pub key: SoftwareKey,
+    pub credential_id: Vec<u8>,
+    pub rp_id: String,
+    pub origin: String,
+    pub flags: u8,
+    pub sign_count: u32,
+}
+
+#[cfg(test)]
+pub(crate) enum SoftwareKey {
+    Es256(p256::ecdsa::SigningKey),
+    EdDsa(ed25519_dalek::SigningKey),
+}
+
+#[cfg(test)]
+impl SoftwareKey {
+    pub fn es256() -> Self {
+        SoftwareKey::Es256(p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng))
+    }
+
+    pub fn eddsa() -> Self {
+        SoftwareKey::EdDsa(ed25519_dalek::SigningKey::from_bytes(&rand::random()))
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let int = |value: i64| Value::Integer(value.into());
+
+        let entries = match self {
+            SoftwareKey::Es256(key) => {
+                let point = key.verifying_key().to_encoded_point(false);
+                vec![
+                    (int(1), int(2)),
+                    (int(3), int(COSE_ALG_ES256)),
+                    (int(-1), int(1)),
+                    (int(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                    (int(-3), Value::Bytes(point.y().unwrap().to_vec())),
+                ]
+            }
+            SoftwareKey::EdDsa(key) => vec![
+                (int(1), int(1)),
+                (int(3), int(COSE_ALG_EDDSA)),
+                (int(-1), int(6)),
+                (
+                    int(-2),
+                    Value::Bytes(key.verifying_key().to_bytes().to_vec()),
+                ),
+            ],
+        };
+
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&Value::Map(entries), &mut encoded).unwrap();
+        encoded
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        // the same `signature` trait for both algorithms
+        use ed25519_dalek::Signer as _;
+
+        match self {
+            SoftwareKey::Es256(key) => {
+                let signature: p256::ecdsa::Signature = key.sign(message);
+                signature.to_der().as_bytes().to_vec()
+            }
+            SoftwareKey::EdDsa(key) => key.sign(message).to_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl SoftwareAuthenticator {
+    pub fn new(key: SoftwareKey, relying_party: RelyingParty) -> Self {
+        Self {
+            key,
+            credential_id: rand::random::<[u8; 16]>().to_vec(),
+            rp_id: relying_party.id.to_string(),
+            origin: relying_party.origin.to_string(),
+            flags: FLAG_USER_PRESENT | FLAG_USER_VERIFIED,
+            sign_count: 0,
+        }
+    }
+
+    pub fn client_data(&self, ceremony: &str, challenge: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": ceremony,
+            "challenge": challenge,
+            "origin": self.origin,
+        }))
+        .unwrap()
+    }
+
+    fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    /// Returns the client data json and the attestation object.
+    pub fn register(&self, challenge: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut authenticator_data =
+            self.authenticator_data(self.flags | FLAG_ATTESTED_CREDENTIAL_DATA);
+        authenticator_data.extend_from_slice(&[0; 16]);
+        authenticator_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        authenticator_data.extend_from_slice(&self.credential_id);
+        authenticator_data.extend_from_slice(&self.key.cose_key());
+
+        let attestation = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(Vec::new())),
+            (
+                Value::Text("authData".into()),
+                Value::Bytes(authenticator_data),
+            ),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        (
+            self.client_data("webauthn.create", challenge),
+            attestation_object,
+        )
+    }
+
+    /// Bumps the signature counter, then returns the client data json, authenticator data and
+    /// signature.
+    pub fn assert(&mut self, challenge: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        self.sign_count += 1;
+
+        let client_data_json = self.client_data("webauthn.get", challenge);
+        let authenticator_data = self.authenticator_data(self.flags);
+
+        let mut signed = authenticator_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+
+        (client_data_json, authenticator_data, self.key.sign(&signed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELYING_PARTY: RelyingParty = RelyingParty {
+        id: "game.example",
+        origin: "https://game.example",
+    };
+
+    fn registered(key: SoftwareKey) -> (SoftwareAuthenticator, AttestedCredential) {
+        let authenticator = SoftwareAuthenticator::new(key, RELYING_PARTY);
+        let (client_data_json, attestation_object) = authenticator.register("challenge");
+
+        let attested =
+            verify_registration(RELYING_PARTY, &client_data_json, &attestation_object).unwrap();
+
+        (authenticator, attested)
+    }
+
+    fn assert_with(
+        authenticator: &mut SoftwareAuthenticator,
+        attested: &AttestedCredential,
+    ) -> Result<u32, WebAuthnError> {
+        let (client_data_json, authenticator_data, signature) = authenticator.assert("challenge");
+
+        verify_assertion(
+            RELYING_PARTY,
+            &client_data_json,
+            &authenticator_data,
+            &signature,
+            &attested.public_key,
+            attested.sign_count,
+        )
+    }
+
+    #[test]
+    fn both_algorithms_round_trip() {
+        for key in [SoftwareKey::es256(), SoftwareKey::eddsa()] {
+            let (mut authenticator, attested) = registered(key);
+            assert_eq!(attested.credential_id, authenticator.credential_id);
+            assert_eq!(attested.sign_count, 0);
+
+            assert_eq!(assert_with(&mut authenticator, &attested).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn signatures_from_another_key_are_rejected() {
+        for (key, other_key) in [
+            (SoftwareKey::es256(), SoftwareKey::es256()),
+            (SoftwareKey::eddsa(), SoftwareKey::eddsa()),
+        ] {
+            let (mut authenticator, attested) = registered(key);
+            authenticator.key = other_key;
+
+            assert!(matches!(
+                assert_with(&mut authenticator, &attested),
+                Err(WebAuthnError::BadSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn sign_count_must_go_up() {
+        let (mut authenticator, mut attested) = registered(SoftwareKey::es256());
+        attested.sign_count = 5;
+
+        // behind, then equal
+        authenticator.sign_count = 3;
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::CounterRegressed)
+        ));
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::CounterRegressed)
+        ));
+        assert_eq!(assert_with(&mut authenticator, &attested).unwrap(), 6);
+    }
+
+    #[test]
+    fn counterless_authenticators_are_accepted() {
+        let (authenticator, attested) = registered(SoftwareKey::eddsa());
+
+        // reports zero every time, rather than counting up
+        let client_data_json = authenticator.client_data("webauthn.get", "challenge");
+        let authenticator_data = authenticator.authenticator_data(authenticator.flags);
+        let mut signed = authenticator_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+
+        for _ in 0..2 {
+            assert_eq!(
+                verify_assertion(
+                    RELYING_PARTY,
+                    &client_data_json,
+                    &authenticator_data,
+                    &authenticator.key.sign(&signed),
+                    &attested.public_key,
+                    0,
+                )
+                .unwrap(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn other_relying_parties_and_origins_are_rejected() {
+        let (mut authenticator, attested) = registered(SoftwareKey::es256());
+
+        authenticator.rp_id = "evil.example".to_string();
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::WrongRelyingParty)
+        ));
+        let (client_data_json, attestation_object) = authenticator.register("challenge");
+        assert!(matches!(
+            verify_registration(RELYING_PARTY, &client_data_json, &attestation_object),
+            Err(WebAuthnError::WrongRelyingParty)
+        ));
+
+        authenticator.rp_id = RELYING_PARTY.id.to_string();
+        authenticator.origin = "https://evil.example".to_string();
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::WrongOrigin(_))
+        ));
+        let (client_data_json, attestation_object) = authenticator.register("challenge");
+        assert!(matches!(
+            verify_registration(RELYING_PARTY, &client_data_json, &attestation_object),
+            Err(WebAuthnError::WrongOrigin(_))
+        ));
+    }
+
+    #[test]
+    fn ceremony_type_and_user_verification_are_checked() {
+        let (authenticator, _) = registered(SoftwareKey::es256());
+
+        let (_, attestation_object) = authenticator.register("challenge");
+        assert!(matches!(
+            verify_registration(
+                RELYING_PARTY,
+                &authenticator.client_data("webauthn.get", "challenge"),
+                &attestation_object
+            ),
+            Err(WebAuthnError::WrongCeremony { .. })
+        ));
+
+        let (mut authenticator, attested) = registered(SoftwareKey::eddsa());
+        authenticator.flags = FLAG_USER_PRESENT;
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::UserNotVerified)
+        ));
+        authenticator.flags = FLAG_USER_VERIFIED;
+        assert!(matches!(
+            assert_with(&mut authenticator, &attested),
+            Err(WebAuthnError::UserNotPresent)
+        ));
+    }
+}
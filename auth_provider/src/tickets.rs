@@ -0,0 +1,436 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    caller::ServiceCaller,
+    database::models::{ServiceScope, Token, User},
+    WebState,
+};
+
+/// What a join ticket vouches for. Game servers can check these offline with the public key from
+/// `/auth/tickets/public-key`, but must still redeem the ticket to stop it being replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinTicketClaims {
+    pub ticket_id: String,
+    pub user_id: String,
+    /// Lets game servers keep guests out of modes they aren't allowed in, such as ranked play.
+    pub guest: bool,
+    pub match_id: String,
+    /// The service credential id of the game server the ticket may be redeemed on.
+    pub server_id: String,
+    /// Unix timestamp in seconds.
+    pub issued_at: i64,
+    /// Unix timestamp in seconds.
+    pub expires_at: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TicketError {
+    #[error("ticket is malformed")]
+    Malformed,
+    #[error("ticket signature is invalid")]
+    BadSignature,
+    #[error("ticket has expired")]
+    Expired,
+}
+
+/// Signs join tickets. Tickets are encoded as `<hex claims json>.<hex ed25519 signature>`.
+pub struct TicketSigner {
+    signing_key: SigningKey,
+    ttl: std::time::Duration,
+}
+
+impl TicketSigner {
+    /// Without a configured key a random one is generated, which invalidates every outstanding
+    /// ticket on restart. That's only acceptable because tickets are so short-lived.
+    pub fn new(signing_key: Option<[u8; 32]>, ttl: std::time::Duration) -> Self {
+        let signing_key = signing_key.unwrap_or_else(|| {
+            warn!("no ticket signing key configured, generating a temporary one");
+
+            let mut seed = [0; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+            seed
+        });
+
+        Self {
+            signing_key: SigningKey::from_bytes(&signing_key),
+            ttl,
+        }
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn issue(&self, user: &User, match_id: &str, server_id: &str) -> (String, i64) {
+        let mut ticket_id = [0; 16];
+        rand::thread_rng().fill_bytes(&mut ticket_id);
+
+        let issued_at = time::OffsetDateTime::now_utc().unix_timestamp();
+        let expires_at = issued_at + self.ttl.as_secs() as i64;
+
+        let claims = JoinTicketClaims {
+            ticket_id: hex::encode(ticket_id),
+            user_id: user.user_id.0.clone(),
+            guest: user.is_guest,
+            match_id: match_id.to_string(),
+            server_id: server_id.to_string(),
+            issued_at,
+            expires_at,
+        };
+
+        let payload = serde_json::to_vec(&claims).expect("claims are always serializable");
+        let signature = self.signing_key.sign(&payload);
+
+        (
+            format!(
+                "{}.{}",
+                hex::encode(&payload),
+                hex::encode(signature.to_bytes())
+            ),
+            expires_at,
+        )
+    }
+}
+
+/// Checks a ticket's signature and expiry. This is everything a game server can do offline.
+pub fn verify_ticket(
+    ticket: &str,
+    verifying_key: &VerifyingKey,
+) -> Result<JoinTicketClaims, TicketError> {
+    let (payload, signature) = ticket.split_once('.').ok_or(TicketError::Malformed)?;
+
+    let payload = hex::decode(payload).map_err(|_| TicketError::Malformed)?;
+    let signature: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|signature| signature.try_into().ok())
+        .ok_or(TicketError::Malformed)?;
+
+    verifying_key
+        .verify(&payload, &ed25519_dalek::Signature::from_bytes(&signature))
+        .map_err(|_| TicketError::BadSignature)?;
+
+    let claims: JoinTicketClaims =
+        serde_json::from_slice(&payload).map_err(|_| TicketError::Malformed)?;
+
+    if claims.expires_at <= time::OffsetDateTime::now_utc().unix_timestamp() {
+        return Err(TicketError::Expired);
+    }
+
+    Ok(claims)
+}
+
+pub fn routes() -> Router<WebState> {
+    Router::new()
+        .route("/", post(issue_ticket))
+        .route("/public-key", get(public_key))
+        .route("/redeem", post(redeem_ticket))
+}
+
+/// Players can't ask for tickets themselves, or they could pick any match and server. The
+/// matchmaker asks on their behalf once it has assigned them one.
+#[derive(Debug, Deserialize)]
+struct IssueTicketRequest {
+    /// The auth token the player connected to the matchmaker with.
+    player_token: String,
+    match_id: String,
+    server_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct IssueTicketResponse {
+    ticket: String,
+    expires_at: i64,
+}
+
+async fn issue_ticket(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Json(request): Json<IssueTicketRequest>,
+) -> Result<Json<IssueTicketResponse>, Response> {
+    caller
+        .require(ServiceScope::IssueJoinTickets)
+        .map_err(IntoResponse::into_response)?;
+
+    let unknown_player =
+        || (StatusCode::NOT_FOUND, "unknown or expired player token").into_response();
+
+    let player_token = Token::from_hex_string(&request.player_token).ok_or_else(unknown_player)?;
+    let user = state
+        .database
+        .get_user_by_token(&player_token)
+        .await
+        .map_err(|error| {
+            warn!(?error, "failed to look up player token");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?
+        .ok_or_else(unknown_player)?;
+
+    let (ticket, expires_at) =
+        state
+            .ticket_signer
+            .issue(&user, &request.match_id, &request.server_id);
+
+    debug!(
+        issued_by = caller.0.credential_id.0,
+        user_id = user.user_id.0,
+        match_id = request.match_id,
+        server_id = request.server_id,
+        "issued join ticket"
+    );
+
+    Ok(Json(IssueTicketResponse { ticket, expires_at }))
+}
+
+#[derive(Debug, Serialize)]
+struct PublicKeyResponse {
+    algorithm: &'static str,
+    public_key: String,
+}
+
+async fn public_key(State(state): State<WebState>) -> Json<PublicKeyResponse> {
+    Json(PublicKeyResponse {
+        algorithm: "ed25519",
+        public_key: hex::encode(state.ticket_signer.verifying_key().as_bytes()),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct RedeemTicketRequest {
+    ticket: String,
+}
+
+async fn redeem_ticket(
+    State(state): State<WebState>,
+    caller: ServiceCaller,
+    Json(request): Json<RedeemTicketRequest>,
+) -> Result<Json<JoinTicketClaims>, Response> {
+    caller
+        .require(ServiceScope::GameServer)
+        .map_err(IntoResponse::into_response)?;
+
+    let claims = verify_ticket(&request.ticket, &state.ticket_signer.verifying_key())
+        .map_err(|error| (StatusCode::UNAUTHORIZED, error.to_string()).into_response())?;
+
+    if claims.server_id != caller.0.credential_id.0 {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "ticket was issued for a different server",
+        )
+            .into_response());
+    }
+
+    let expires_at = time::OffsetDateTime::from_unix_timestamp(claims.expires_at)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "ticket is malformed").into_response())?;
+
+    let first_redemption = state
+        .database
+        .mark_join_ticket_redeemed(&claims.ticket_id, expires_at)
+        .await
+        .map_err(|error| {
+            warn!(?error, "failed to record join ticket redemption");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    if !first_redemption {
+        return Err((StatusCode::CONFLICT, "ticket has already been redeemed").into_response());
+    }
+
+    Ok(Json(claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use http::{header, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::database::models::{UserId, UserKind};
+
+    fn user() -> User {
+        User {
+            user_id: UserId("player".to_string()),
+            is_guest: true,
+        }
+    }
+
+    #[test]
+    fn issued_tickets_verify() {
+        let signer = TicketSigner::new(None, Duration::from_secs(60));
+        let (ticket, expires_at) = signer.issue(&user(), "match", "server");
+
+        let claims = verify_ticket(&ticket, &signer.verifying_key()).unwrap();
+        assert_eq!(claims.user_id, "player");
+        assert!(claims.guest);
+        assert_eq!(claims.match_id, "match");
+        assert_eq!(claims.server_id, "server");
+        assert_eq!(claims.expires_at, expires_at);
+    }
+
+    #[test]
+    fn tickets_from_another_key_or_tampered_with_are_rejected() {
+        let signer = TicketSigner::new(None, Duration::from_secs(60));
+        let (ticket, _) = signer.issue(&user(), "match", "server");
+
+        let other = TicketSigner::new(None, Duration::from_secs(60));
+        assert!(matches!(
+            verify_ticket(&ticket, &other.verifying_key()),
+            Err(TicketError::BadSignature)
+        ));
+
+        let (payload, signature) = ticket.split_once('.').unwrap();
+        let mut claims: JoinTicketClaims =
+            serde_json::from_slice(&hex::decode(payload).unwrap()).unwrap();
+        claims.server_id = "other server".to_string();
+        let forged = format!(
+            "{}.{signature}",
+            hex::encode(serde_json::to_vec(&claims).unwrap())
+        );
+        assert!(matches!(
+            verify_ticket(&forged, &signer.verifying_key()),
+            Err(TicketError::BadSignature)
+        ));
+
+        assert!(matches!(
+            verify_ticket("not a ticket", &signer.verifying_key()),
+            Err(TicketError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn expired_tickets_are_rejected() {
+        let signer = TicketSigner::new(None, Duration::ZERO);
+        let (ticket, _) = signer.issue(&user(), "match", "server");
+
+        assert!(matches!(
+            verify_ticket(&ticket, &signer.verifying_key()),
+            Err(TicketError::Expired)
+        ));
+    }
+
+    async fn post(state: &WebState, uri: &str, bearer: &str, body: Value) -> (StatusCode, Value) {
+        let response = crate::router(state.clone())
+            .oneshot(
+                Request::post(uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {bearer}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn service_key(state: &WebState, scope: ServiceScope) -> (String, String) {
+        let (credential, key) = state
+            .database
+            .create_service_credential(scope.as_str(), &[scope])
+            .await
+            .unwrap();
+
+        (credential.credential_id.0, key.to_string())
+    }
+
+    async fn player_token(state: &WebState) -> String {
+        let user = state
+            .database
+            .create_new_user(UserKind::Full)
+            .await
+            .unwrap();
+
+        state
+            .database
+            .create_auth_token(&user.user_id, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .to_hex_string()
+    }
+
+    #[tokio::test]
+    async fn only_the_matchmaker_may_issue_tickets() {
+        let state = crate::test_state();
+        let (_, matchmaker) = service_key(&state, ServiceScope::IssueJoinTickets).await;
+        let (server_id, game_server) = service_key(&state, ServiceScope::GameServer).await;
+        let player_token = player_token(&state).await;
+        let request = json!({
+            "player_token": player_token,
+            "match_id": "match",
+            "server_id": server_id,
+        });
+
+        let (status, _) = post(&state, "/auth/tickets", &player_token, request.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post(&state, "/auth/tickets", &game_server, request.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = post(
+            &state,
+            "/auth/tickets",
+            &matchmaker,
+            json!({ "player_token": "00", "match_id": "match", "server_id": server_id }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, issued) = post(&state, "/auth/tickets", &matchmaker, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = verify_ticket(
+            issued["ticket"].as_str().unwrap(),
+            &state.ticket_signer.verifying_key(),
+        )
+        .unwrap();
+        assert_eq!(claims.server_id, server_id);
+        assert!(!claims.guest);
+    }
+
+    #[tokio::test]
+    async fn tickets_are_redeemed_once_on_their_own_server() {
+        let state = crate::test_state();
+        let (server_id, game_server) = service_key(&state, ServiceScope::GameServer).await;
+        let (_, other_server) = service_key(&state, ServiceScope::GameServer).await;
+        let (ticket, _) = state.ticket_signer.issue(&user(), "match", &server_id);
+        let request = json!({ "ticket": ticket });
+
+        let (status, _) = post(
+            &state,
+            "/auth/tickets/redeem",
+            &other_server,
+            request.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, claims) = post(
+            &state,
+            "/auth/tickets/redeem",
+            &game_server,
+            request.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(claims["user_id"], "player");
+
+        let (status, _) = post(&state, "/auth/tickets/redeem", &game_server, request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,815 @@
+use std::time::Instant;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use http::StatusCode;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tracing::{info, warn};
+
+use crate::{
+    caller::{FullPlayerCaller, PlayerCaller},
+    database::{
+        models::{Role, Token, TokenHash, TotpCredential, UserId},
+        repository::StoreError,
+    },
+    metrics, WebState,
+};
+
+pub const CHALLENGE_COOKIE_NAME: &str = "TwoFactorChallenge";
+
+const TOTP_DIGITS: u32 = 6;
+const TOTP_PERIOD_SECS: i64 = 30;
+/// How many steps either side of the current one are accepted, to allow for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+const TOTP_SECRET_BYTES: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_CHARACTERS: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Wrong codes allowed per login before it has to start over.
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+
+/// Logins that got through their provider but still need a second factor.
+#[derive(Default)]
+pub struct Challenges {
+    pending: DashMap<String, PendingLogin>,
+}
+
+struct PendingLogin {
+    user_id: UserId,
+    created_at: Instant,
+    attempts: u32,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn start(&self, user_id: UserId, ttl: std::time::Duration) -> String {
+        self.pending
+            .retain(|_, pending| pending.created_at.elapsed() < ttl);
+
+        let mut challenge = [0; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        let challenge = hex::encode(challenge);
+
+        self.pending.insert(
+            challenge.clone(),
+            PendingLogin {
+                user_id,
+                created_at: Instant::now(),
+                attempts: 0,
+            },
+        );
+
+        challenge
+    }
+
+    /// Counts an attempt against the challenge, dropping it once it has expired or run out of
+    /// attempts.
+    fn attempt(&self, challenge: &str, ttl: std::time::Duration) -> Option<UserId> {
+        let mut pending = self.pending.get_mut(challenge)?;
+        pending.attempts += 1;
+
+        if pending.created_at.elapsed() >= ttl || pending.attempts > MAX_CHALLENGE_ATTEMPTS {
+            drop(pending);
+            self.pending.remove(challenge);
+            return None;
+        }
+
+        Some(pending.user_id.clone())
+    }
+
+    fn finish(&self, challenge: &str) {
+        self.pending.remove(challenge);
+    }
+}
+
+/// Issues the auth token once a provider has identified the user, or sends them to the second
+/// step first if they have two-factor authentication enabled. Every provider ends its login here,
+/// except passkeys: those require user verification, so the authenticator has already checked a
+/// second factor and the passkey provider issues the token itself.
+pub async fn finish_login(state: &WebState, user_id: UserId, jar: CookieJar) -> Response {
+    let credential = match state.database.get_totp_credential(&user_id).await {
+        Ok(credential) => credential,
+        Err(error) => return internal_error(error),
+    };
+
+    if credential.is_some_and(|credential| credential.is_enabled()) {
+        let challenge = state
+            .two_factor_challenges
+            .start(user_id, state.config.two_factor.challenge_ttl);
+
+        let mut response = (
+            jar.add(challenge_cookie(state, challenge)),
+            Redirect::to(&state.config.two_factor.challenge_page),
+        )
+            .into_response();
+        response.extensions_mut().insert(metrics::LoginChallenged);
+        return response;
+    }
+
+    match state
+        .database
+        .create_auth_token(&user_id, state.config.tokens.auth_token_ttl)
+        .await
+    {
+        Ok(token) => (
+            jar.add(state.config.cookie.auth_cookie(token.to_hex_string())),
+            Redirect::to("/"),
+        )
+            .into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+/// The user's roles, minus any sensitive ones if they haven't enabled two-factor authentication.
+pub async fn effective_roles(state: &WebState, user_id: &UserId) -> Result<Vec<Role>, StoreError> {
+    let roles = state.database.get_user_roles(user_id).await?;
+
+    if !roles.iter().any(|role| is_sensitive(state, *role)) {
+        return Ok(roles);
+    }
+
+    let enabled = state
+        .database
+        .get_totp_credential(user_id)
+        .await?
+        .is_some_and(|credential| credential.is_enabled());
+
+    Ok(roles
+        .into_iter()
+        .filter(|role| enabled || !is_sensitive(state, *role))
+        .collect())
+}
+
+fn is_sensitive(state: &WebState, role: Role) -> bool {
+    state.config.two_factor.sensitive_roles.contains(&role)
+}
+
+pub fn routes() -> Router<WebState> {
+    Router::new()
+        .route("/", get(status))
+        .route("/enroll", post(enroll))
+        .route("/enroll/confirm", post(confirm_enrollment))
+        .route("/recovery-codes", post(regenerate_recovery_codes))
+        .route("/disable", post(disable))
+        .route(
+            "/verify",
+            post(verify).layer(axum::middleware::map_response(|response: Response| async {
+                metrics::record_login("two_factor", response)
+            })),
+        )
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    enabled: bool,
+    /// Whether the user has a sensitive role that won't take effect until this is enabled.
+    required: bool,
+    recovery_codes_remaining: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct EnrollResponse {
+    /// Base32, for typing into an authenticator app by hand.
+    secret: String,
+    /// An `otpauth://` uri to show as a QR code.
+    provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RecoveryCodesResponse {
+    /// Each can be used once instead of a code. Only ever shown once.
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+/// Either a code from the authenticator app or one of the recovery codes.
+#[derive(Debug, Deserialize)]
+struct SecondFactorRequest {
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyRequest {
+    /// Taken from the challenge cookie if not given.
+    challenge: Option<String>,
+    #[serde(flatten)]
+    factor: SecondFactorRequest,
+}
+
+async fn status(
+    State(state): State<WebState>,
+    PlayerCaller(user): PlayerCaller,
+) -> Result<Json<StatusResponse>, Response> {
+    let credential = state
+        .database
+        .get_totp_credential(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let roles = state
+        .database
+        .get_user_roles(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let recovery_codes_remaining = state
+        .database
+        .count_recovery_codes(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(StatusResponse {
+        enabled: credential.is_some_and(|credential| credential.is_enabled()),
+        required: roles.iter().any(|role| is_sensitive(&state, *role)),
+        recovery_codes_remaining,
+    }))
+}
+
+async fn enroll(
+    State(state): State<WebState>,
+    FullPlayerCaller(user): FullPlayerCaller,
+) -> Result<Json<EnrollResponse>, Response> {
+    let existing = state
+        .database
+        .get_totp_credential(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+    if existing.is_some_and(|credential| credential.is_enabled()) {
+        return Err((
+            StatusCode::CONFLICT,
+            "two-factor authentication is already enabled",
+        )
+            .into_response());
+    }
+
+    let mut secret = vec![0; TOTP_SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    state
+        .database
+        .set_pending_totp_secret(&user.user_id, &secret)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(EnrollResponse {
+        secret: data_encoding::BASE32_NOPAD.encode(&secret),
+        provisioning_uri: provisioning_uri(&state.config.two_factor.issuer, &user.user_id, &secret),
+    }))
+}
+
+async fn confirm_enrollment(
+    State(state): State<WebState>,
+    FullPlayerCaller(user): FullPlayerCaller,
+    Json(request): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, Response> {
+    let credential = state
+        .database
+        .get_totp_credential(&user.user_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|credential| !credential.is_enabled())
+        .ok_or_else(|| (StatusCode::CONFLICT, "no enrollment in progress").into_response())?;
+
+    if !check_code(&state, &user.user_id, &credential, &request.code)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(invalid_code());
+    }
+
+    let (recovery_codes, recovery_code_hashes) = generate_recovery_codes();
+
+    let enabled = state
+        .database
+        .enable_totp(&user.user_id, &recovery_code_hashes)
+        .await
+        .map_err(internal_error)?;
+    if !enabled {
+        return Err((StatusCode::CONFLICT, "no enrollment in progress").into_response());
+    }
+
+    info!(
+        user_id = user.user_id.0,
+        "enabled two-factor authentication"
+    );
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn regenerate_recovery_codes(
+    State(state): State<WebState>,
+    FullPlayerCaller(user): FullPlayerCaller,
+    Json(request): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, Response> {
+    let credential = enabled_credential(&state, &user.user_id).await?;
+
+    if !check_code(&state, &user.user_id, &credential, &request.code)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(invalid_code());
+    }
+
+    let (recovery_codes, recovery_code_hashes) = generate_recovery_codes();
+
+    state
+        .database
+        .replace_recovery_codes(&user.user_id, &recovery_code_hashes)
+        .await
+        .map_err(internal_error)?;
+
+    info!(user_id = user.user_id.0, "regenerated recovery codes");
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn disable(
+    State(state): State<WebState>,
+    FullPlayerCaller(user): FullPlayerCaller,
+    Json(request): Json<SecondFactorRequest>,
+) -> Result<StatusCode, Response> {
+    let credential = enabled_credential(&state, &user.user_id).await?;
+
+    let roles = state
+        .database
+        .get_user_roles(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+    if let Some(role) = roles.iter().find(|role| is_sensitive(&state, **role)) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!(
+                "the `{}` role requires two-factor authentication",
+                role.as_str()
+            ),
+        )
+            .into_response());
+    }
+
+    if !check_second_factor(&state, &user.user_id, &credential, &request)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(invalid_code());
+    }
+
+    state
+        .database
+        .disable_totp(&user.user_id)
+        .await
+        .map_err(internal_error)?;
+
+    info!(
+        user_id = user.user_id.0,
+        "disabled two-factor authentication"
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn verify(
+    State(state): State<WebState>,
+    jar: CookieJar,
+    Json(request): Json<VerifyRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let challenge = request
+        .challenge
+        .or_else(|| {
+            jar.get(CHALLENGE_COOKIE_NAME)
+                .map(|cookie| cookie.value().to_string())
+        })
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "no pending login").into_response())?;
+
+    let user_id = state
+        .two_factor_challenges
+        .attempt(&challenge, state.config.two_factor.challenge_ttl)
+        .ok_or_else(|| {
+            (StatusCode::UNAUTHORIZED, "login expired, please start over").into_response()
+        })?;
+
+    // a missing credential means two-factor authentication was disabled mid-login, so the login
+    // has to start over rather than skip the second step
+    let credential = enabled_credential(&state, &user_id).await?;
+
+    if !check_second_factor(&state, &user_id, &credential, &request.factor)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(invalid_code());
+    }
+
+    state.two_factor_challenges.finish(&challenge);
+
+    let token = state
+        .database
+        .create_auth_token(&user_id, state.config.tokens.auth_token_ttl)
+        .await
+        .map_err(internal_error)?;
+
+    Ok((
+        jar.remove(challenge_removal_cookie(&state))
+            .add(state.config.cookie.auth_cookie(token.to_hex_string())),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn enabled_credential(
+    state: &WebState,
+    user_id: &UserId,
+) -> Result<TotpCredential, Response> {
+    state
+        .database
+        .get_totp_credential(user_id)
+        .await
+        .map_err(internal_error)?
+        .filter(TotpCredential::is_enabled)
+        .ok_or_else(|| {
+            (
+                StatusCode::CONFLICT,
+                "two-factor authentication is not enabled",
+            )
+                .into_response()
+        })
+}
+
+async fn check_second_factor(
+    state: &WebState,
+    user_id: &UserId,
+    credential: &TotpCredential,
+    factor: &SecondFactorRequest,
+) -> Result<bool, StoreError> {
+    match (&factor.code, &factor.recovery_code) {
+        (Some(code), _) => check_code(state, user_id, credential, code).await,
+        (None, Some(recovery_code)) => {
+            let used = state
+                .database
+                .consume_recovery_code(user_id, &hash_recovery_code(recovery_code))
+                .await?;
+
+            if used {
+                info!(user_id = user_id.0, "used a recovery code");
+            }
+
+            Ok(used)
+        }
+        (None, None) => Ok(false),
+    }
+}
+
+/// Also marks the code's step as used, so the same code can't be accepted twice.
+async fn check_code(
+    state: &WebState,
+    user_id: &UserId,
+    credential: &TotpCredential,
+    code: &str,
+) -> Result<bool, StoreError> {
+    match matching_step(&credential.secret, code, time::OffsetDateTime::now_utc()) {
+        Some(step) => state.database.record_totp_step(user_id, step).await,
+        None => Ok(false),
+    }
+}
+
+fn matching_step(secret: &[u8], code: &str, now: time::OffsetDateTime) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current_step = now.unix_timestamp() / TOTP_PERIOD_SECS;
+
+    (current_step - TOTP_SKEW_STEPS..=current_step + TOTP_SKEW_STEPS)
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+/// RFC 6238 with the defaults every authenticator app supports: HMAC-SHA1, 6 digits, 30 seconds.
+fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes(
+        digest[offset..offset + 4]
+            .try_into()
+            .expect("slice is four bytes long"),
+    ) & 0x7fff_ffff;
+
+    truncated % 10u32.pow(TOTP_DIGITS)
+}
+
+fn provisioning_uri(issuer: &str, user_id: &UserId, secret: &[u8]) -> String {
+    let mut uri = url::Url::parse("otpauth://totp/").expect("static url is valid");
+    uri.set_path(&format!("{issuer}:{}", user_id.0));
+    uri.query_pairs_mut()
+        .append_pair("secret", &data_encoding::BASE32_NOPAD.encode(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_PERIOD_SECS.to_string());
+
+    uri.to_string()
+}
+
+/// Returns the codes to show the user alongside the hashes to store.
+fn generate_recovery_codes() -> (Vec<String>, Vec<TokenHash>) {
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_CHARACTERS[rng.gen_range(0..RECOVERY_CODE_CHARACTERS.len())]
+                        as char
+                })
+                .collect();
+            let code = format!("{}-{}", &code[..5], &code[5..]);
+            let hash = hash_recovery_code(&code);
+
+            (code, hash)
+        })
+        .unzip()
+}
+
+/// Dashes, whitespace and case don't matter when typing a recovery code back in.
+fn hash_recovery_code(code: &str) -> TokenHash {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Token(normalized.into_bytes()).get_hash()
+}
+
+fn challenge_cookie(state: &WebState, challenge: String) -> Cookie<'static> {
+    let mut cookie = Cookie::build((CHALLENGE_COOKIE_NAME, challenge))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(
+            state
+                .config
+                .two_factor
+                .challenge_ttl
+                .try_into()
+                .expect("challenge ttl was validated on startup"),
+        );
+
+    if let Some(domain) = &state.config.cookie.domain {
+        cookie = cookie.domain(domain.clone());
+    }
+
+    cookie.build()
+}
+
+fn challenge_removal_cookie(state: &WebState) -> Cookie<'static> {
+    let mut cookie = Cookie::build(CHALLENGE_COOKIE_NAME).path("/");
+
+    if let Some(domain) = &state.config.cookie.domain {
+        cookie = cookie.domain(domain.clone());
+    }
+
+    cookie.build()
+}
+
+fn invalid_code() -> Response {
+    (StatusCode::UNAUTHORIZED, "invalid code").into_response()
+}
+
+fn internal_error(error: StoreError) -> Response {
+    warn!(?error, "database error while handling two-factor request");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::UserKind;
+
+    /// The SHA1 secret from RFC 6238 appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(unix_timestamp: i64) -> time::OffsetDateTime {
+        time::OffsetDateTime::from_unix_timestamp(unix_timestamp).unwrap()
+    }
+
+    #[test]
+    fn codes_match_the_rfc_6238_vectors() {
+        // the appendix lists 8 digit codes, we only use the last 6
+        for (unix_timestamp, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            let step = unix_timestamp / TOTP_PERIOD_SECS;
+            assert_eq!(
+                format!("{:06}", totp_code(RFC_SECRET, step)),
+                code[2..],
+                "{unix_timestamp}"
+            );
+            assert_eq!(
+                matching_step(RFC_SECRET, &code[2..], at(unix_timestamp)),
+                Some(step)
+            );
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side() {
+        let now = 1234567890;
+        let step = now / TOTP_PERIOD_SECS;
+        let code = |step| format!("{:06}", totp_code(RFC_SECRET, step));
+
+        for offset in [-1, 0, 1] {
+            assert_eq!(
+                matching_step(RFC_SECRET, &code(step + offset), at(now)),
+                Some(step + offset)
+            );
+        }
+        for offset in [-2, 2] {
+            assert_eq!(
+                matching_step(RFC_SECRET, &code(step + offset), at(now)),
+                None
+            );
+        }
+
+        assert_eq!(matching_step(RFC_SECRET, "not a code", at(now)), None);
+        assert_eq!(matching_step(RFC_SECRET, "0059240", at(now)), None);
+    }
+
+    async fn enrolled_user(state: &WebState) -> (UserId, Vec<String>) {
+        let user = state
+            .database
+            .create_new_user(UserKind::Full)
+            .await
+            .unwrap();
+        let (recovery_codes, recovery_code_hashes) = generate_recovery_codes();
+
+        state
+            .database
+            .set_pending_totp_secret(&user.user_id, RFC_SECRET)
+            .await
+            .unwrap();
+        state
+            .database
+            .enable_totp(&user.user_id, &recovery_code_hashes)
+            .await
+            .unwrap();
+
+        (user.user_id, recovery_codes)
+    }
+
+    #[tokio::test]
+    async fn codes_are_only_accepted_once() {
+        let state = crate::test_state();
+        let (user_id, _) = enrolled_user(&state).await;
+        let credential = enabled_credential(&state, &user_id).await.unwrap();
+        let code = format!(
+            "{:06}",
+            totp_code(
+                RFC_SECRET,
+                time::OffsetDateTime::now_utc().unix_timestamp() / TOTP_PERIOD_SECS
+            )
+        );
+
+        assert!(check_code(&state, &user_id, &credential, &code)
+            .await
+            .unwrap());
+        assert!(!check_code(&state, &user_id, &credential, &code)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_single_use() {
+        let state = crate::test_state();
+        let (user_id, recovery_codes) = enrolled_user(&state).await;
+        let credential = enabled_credential(&state, &user_id).await.unwrap();
+        let recovery_code = |code: &str| SecondFactorRequest {
+            code: None,
+            recovery_code: Some(code.to_string()),
+        };
+
+        // typed back in without the dash and in upper case
+        let retyped = recovery_codes[0].replace('-', "").to_uppercase();
+        assert!(
+            check_second_factor(&state, &user_id, &credential, &recovery_code(&retyped))
+                .await
+                .unwrap()
+        );
+        assert!(!check_second_factor(
+            &state,
+            &user_id,
+            &credential,
+            &recovery_code(&recovery_codes[0])
+        )
+        .await
+        .unwrap());
+
+        assert_eq!(
+            state.database.count_recovery_codes(&user_id).await.unwrap(),
+            RECOVERY_CODE_COUNT - 1
+        );
+        assert!(
+            !check_second_factor(&state, &user_id, &credential, &recovery_code("aaaaa-aaaaa"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn sensitive_roles_are_withheld_until_enrolled() {
+        let state = crate::test_state();
+        let user = state
+            .database
+            .create_new_user(UserKind::Full)
+            .await
+            .unwrap();
+        for role in [Role::Admin, Role::Moderator] {
+            state
+                .database
+                .grant_role(&user.user_id, role)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            effective_roles(&state, &user.user_id).await.unwrap(),
+            [Role::Moderator]
+        );
+
+        // a pending enrollment isn't enough
+        state
+            .database
+            .set_pending_totp_secret(&user.user_id, RFC_SECRET)
+            .await
+            .unwrap();
+        assert_eq!(
+            effective_roles(&state, &user.user_id).await.unwrap(),
+            [Role::Moderator]
+        );
+
+        state
+            .database
+            .enable_totp(&user.user_id, &[])
+            .await
+            .unwrap();
+        let mut roles = effective_roles(&state, &user.user_id).await.unwrap();
+        roles.sort_by_key(Role::as_str);
+        assert_eq!(roles, [Role::Admin, Role::Moderator]);
+    }
+
+    #[tokio::test]
+    async fn enrolled_users_are_sent_to_the_second_step() {
+        let state = crate::test_state();
+        let (user_id, _) = enrolled_user(&state).await;
+
+        let response = finish_login(&state, user_id, CookieJar::new()).await;
+
+        assert_eq!(
+            response.headers()[http::header::LOCATION],
+            state.config.two_factor.challenge_page
+        );
+        let cookies: Vec<_> = response
+            .headers()
+            .get_all(http::header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect();
+        assert!(cookies
+            .iter()
+            .any(|cookie| cookie.starts_with(CHALLENGE_COOKIE_NAME)));
+        assert!(!cookies
+            .iter()
+            .any(|cookie| cookie.starts_with(&state.config.cookie.name)));
+
+        let challenged = metrics::LOGIN_ATTEMPTS.with_label_values(&["test", "challenged"]);
+        let succeeded = metrics::LOGIN_ATTEMPTS.with_label_values(&["test", "success"]);
+        metrics::record_login("test", response);
+        assert_eq!((challenged.get(), succeeded.get()), (1, 0));
+    }
+}
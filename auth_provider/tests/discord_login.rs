@@ -0,0 +1,375 @@
+//! Drives the whole discord login against a mock of the discord api, with everything stored in a
+//! [`MemoryDatabase`].
+
+use std::{collections::HashMap, sync::Arc};
+
+use auth_provider::{
+    config::Config,
+    database::{
+        memory::MemoryDatabase,
+        models::{DiscordUserId, Role},
+        repository::{DiscordIdentityStore, RoleStore, UserStore},
+    },
+    mail, WebState,
+};
+use axum::{
+    body::Body,
+    extract::{Form, Path},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use http::{header, HeaderMap, Request, StatusCode};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+const GUILD_ID: &str = "100000000000000001";
+const MEMBER_ROLE_ID: &str = "200000000000000001";
+const MODERATOR_ROLE_ID: &str = "200000000000000002";
+
+/// Hands out an access token equal to the authorization code, and treats that token as the
+/// discord user id. Users whose id starts with `outsider` aren't in the guild.
+async fn mock_discord() -> url::Url {
+    async fn token(Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+        Json(json!({
+            "access_token": form["code"],
+            "token_type": "Bearer",
+            "expires_in": 604800,
+            "refresh_token": format!("refresh-{}", form["code"]),
+            "scope": "identify guilds.members.read",
+        }))
+    }
+
+    async fn me(headers: HeaderMap) -> Json<Value> {
+        let discord_id = bearer(&headers);
+
+        Json(json!({
+            "application": { "id": "300000000000000001" },
+            "scopes": ["identify", "guilds.members.read"],
+            "expires": "2100-01-01T00:00:00.000000+00:00",
+            "user": {
+                "id": discord_id,
+                "username": "player",
+                "global_name": "Player",
+                "avatar": "avatar",
+                "discriminator": "0",
+                "public_flags": 0,
+            },
+        }))
+    }
+
+    async fn guild_member(Path(guild_id): Path<String>, headers: HeaderMap) -> Response {
+        if guild_id != GUILD_ID || bearer(&headers).starts_with("outsider") {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+
+        Json(json!({ "roles": [MEMBER_ROLE_ID, MODERATOR_ROLE_ID] })).into_response()
+    }
+
+    fn bearer(headers: &HeaderMap) -> String {
+        headers[header::AUTHORIZATION]
+            .to_str()
+            .unwrap()
+            .strip_prefix("Bearer ")
+            .unwrap()
+            .to_string()
+    }
+
+    let router = Router::new()
+        .route("/api/v10/oauth2/token", post(token))
+        .route("/api/v10/oauth2/@me", get(me))
+        .route(
+            "/api/v10/users/@me/guilds/:guild_id/member",
+            get(guild_member),
+        );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    format!("http://{address}").parse().unwrap()
+}
+
+struct TestServer {
+    database: Arc<MemoryDatabase>,
+    router: Router,
+}
+
+impl TestServer {
+    async fn start(guild: bool) -> Self {
+        let mut toml = format!(
+            r#"
+            public_base_url = "https://game.example"
+
+            [providers.discord]
+            client_id = "client"
+            client_secret = "secret"
+            api_base = "{}"
+            "#,
+            mock_discord().await
+        );
+        if guild {
+            toml.push_str(&format!(
+                r#"
+                [providers.discord.guild]
+                id = "{GUILD_ID}"
+                required_roles = ["{MEMBER_ROLE_ID}"]
+                role_mapping = {{ "{MODERATOR_ROLE_ID}" = "moderator" }}
+                "#
+            ));
+        }
+        let config = Config::from_toml(&toml).unwrap();
+
+        let database = Arc::new(MemoryDatabase::new());
+        let state = WebState::new(
+            database.clone(),
+            Arc::new(config.clone()),
+            mail::from_config(&config.mail).unwrap(),
+        );
+
+        Self {
+            database,
+            router: auth_provider::router(state),
+        }
+    }
+
+    async fn send(&self, request: Request<Body>) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Goes through `/begin` and the redirect back, as if the player allowed access on discord.
+    /// `auth_token` is sent along, as a logged in guest would.
+    async fn log_in(&self, discord_id: &str, auth_token: Option<&str>) -> Response {
+        let (state_code, state_cookie) = self.begin(auth_token).await;
+        self.redirect(&state_code, discord_id, Some(&state_cookie))
+            .await
+    }
+
+    /// Starts a login, returning the state code sent to discord and the cookie it's bound to.
+    async fn begin(&self, auth_token: Option<&str>) -> (String, String) {
+        let mut begin = Request::get("/auth/providers/discord/begin");
+        if let Some(auth_token) = auth_token {
+            begin = begin.header(header::AUTHORIZATION, format!("Bearer {auth_token}"));
+        }
+        let response = self.send(begin.body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let consent_page: url::Url = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let state_code = consent_page
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+
+        (state_code, cookie(&response, "DiscordLoginState"))
+    }
+
+    /// The redirect back from discord, sent with `state_cookie` if there is one.
+    async fn redirect(
+        &self,
+        state_code: &str,
+        discord_id: &str,
+        state_cookie: Option<&str>,
+    ) -> Response {
+        let mut redirect = Request::get(format!(
+            "/auth/providers/discord/redirect?state={state_code}&code={discord_id}"
+        ));
+        if let Some(state_cookie) = state_cookie {
+            redirect = redirect.header(header::COOKIE, format!("DiscordLoginState={state_cookie}"));
+        }
+        self.send(redirect.body(Body::empty()).unwrap()).await
+    }
+
+    async fn me(&self, auth_token: &str) -> Value {
+        let response = self
+            .send(
+                Request::get("/auth/me")
+                    .header(header::COOKIE, format!("AuthToken={auth_token}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        json_body(response).await
+    }
+}
+
+/// A state-changing request as the browser sends it from our own page, passing the csrf checks.
+fn browser_post(path: &str) -> http::request::Builder {
+    Request::post(path)
+        .header(header::ORIGIN, "https://game.example")
+        .header(header::COOKIE, "CsrfToken=token")
+        .header("X-CSRF-Token", "token")
+}
+
+async fn json_body(response: Response) -> Value {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn auth_cookie(response: &Response) -> String {
+    cookie(response, "AuthToken")
+}
+
+fn cookie(response: &Response, name: &str) -> String {
+    let prefix = format!("{name}=");
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok()?.strip_prefix(prefix.as_str()))
+        .map(|value| value.split(';').next().unwrap().to_string())
+        .next()
+        .unwrap_or_else(|| panic!("no {name} cookie was set"))
+}
+
+#[tokio::test]
+async fn logging_in_creates_an_account_once() {
+    let server = TestServer::start(false).await;
+
+    let response = server.log_in("400000000000000001", None).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()[header::LOCATION], "/");
+    let me = server.me(&auth_cookie(&response)).await;
+    assert_eq!(me["is_guest"], false);
+
+    let user_id = server
+        .database
+        .get_user_by_discord_id(&DiscordUserId("400000000000000001".to_string()))
+        .await
+        .unwrap()
+        .expect("discord account wasn't linked");
+    assert_eq!(me["user_id"], user_id.0);
+
+    // logging in again finds the same account
+    let response = server.log_in("400000000000000001", None).await;
+    assert_eq!(
+        server.me(&auth_cookie(&response)).await["user_id"],
+        user_id.0
+    );
+}
+
+#[tokio::test]
+async fn unknown_state_is_rejected() {
+    let server = TestServer::start(false).await;
+
+    let response = server
+        .send(
+            Request::get("/auth/providers/discord/redirect?state=forged&code=400000000000000001")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(!server
+        .database
+        .discord_user_registered(&DiscordUserId("400000000000000001".to_string()))
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn logins_only_finish_in_the_browser_that_started_them() {
+    let server = TestServer::start(false).await;
+    let (state_code, _) = server.begin(None).await;
+    let (_, other_browser) = server.begin(None).await;
+
+    for state_cookie in [None, Some(other_browser.as_str())] {
+        let response = server
+            .redirect(&state_code, "400000000000000004", state_cookie)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    assert!(!server
+        .database
+        .discord_user_registered(&DiscordUserId("400000000000000004".to_string()))
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn logging_in_as_a_guest_upgrades_the_guest() {
+    let server = TestServer::start(false).await;
+
+    let response = server
+        .send(browser_post("/auth/guest").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let guest = json_body(response).await;
+    let guest_token = guest["token"].as_str().unwrap();
+
+    let response = server.log_in("400000000000000002", Some(guest_token)).await;
+    let me = server.me(&auth_cookie(&response)).await;
+
+    assert_eq!(me["user_id"], guest["user_id"]);
+    assert_eq!(me["is_guest"], false);
+
+    // whoever else held the guest token doesn't get into the upgraded account
+    let response = server
+        .send(
+            Request::get("/auth/me")
+                .header(header::AUTHORIZATION, format!("Bearer {guest_token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn guild_gate_keeps_outsiders_out_and_maps_roles() {
+    let server = TestServer::start(true).await;
+
+    let response = server.log_in("outsider", None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(!server
+        .database
+        .discord_user_registered(&DiscordUserId("outsider".to_string()))
+        .await
+        .unwrap());
+
+    let response = server.log_in("400000000000000003", None).await;
+    let me = server.me(&auth_cookie(&response)).await;
+    assert_eq!(me["roles"], json!(["moderator"]));
+
+    let user_id = server
+        .database
+        .get_user_by_discord_id(&DiscordUserId("400000000000000003".to_string()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(server.database.user_id_exists(&user_id).await.unwrap());
+    assert_eq!(
+        server.database.get_user_roles(&user_id).await.unwrap(),
+        [Role::Moderator]
+    );
+}
+
+#[tokio::test]
+async fn guild_gate_turns_off_other_ways_in() {
+    let server = TestServer::start(true).await;
+
+    for path in [
+        "/auth/guest",
+        "/auth/providers/email/begin",
+        "/auth/providers/passkey/login/begin",
+    ] {
+        let response = server
+            .send(
+                browser_post(path)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"email":"player@example.com"}"#))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
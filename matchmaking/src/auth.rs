@@ -0,0 +1,335 @@
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::AppState;
+
+/// Lets a service register as a game server.
+pub const GAME_SERVER_SCOPE: &str = "game_server";
+/// Lets a service drain any game server and inspect the server lifecycle.
+pub const MANAGE_SERVERS_SCOPE: &str = "manage_servers";
+
+/// A user's id, as handed out by auth_provider.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(pub String);
+
+/// A machine identity from auth_provider, such as a game server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceCredential {
+    pub credential_id: String,
+    pub scopes: Vec<String>,
+}
+
+impl ServiceCredential {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("failed to reach auth_provider")]
+    Request(#[from] reqwest::Error),
+    #[error("auth_provider responded with {0}")]
+    UnexpectedStatus(StatusCode),
+}
+
+/// Resolves players' auth tokens and services' keys to who they are, and issues join tickets.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// `None` if the token doesn't belong to a player.
+    async fn resolve_token(&self, token: &str) -> Result<Option<UserId>, AuthError>;
+
+    /// `None` if the key doesn't belong to a service.
+    async fn resolve_service_key(&self, key: &str) -> Result<Option<ServiceCredential>, AuthError>;
+
+    /// Issues the ticket the player redeems on the game server with the credential
+    /// `server_credential_id`. `None` if the token no longer belongs to a player.
+    async fn issue_join_ticket(
+        &self,
+        player_token: &str,
+        match_id: &str,
+        server_credential_id: &str,
+    ) -> Result<Option<String>, AuthError>;
+}
+
+/// Asks auth_provider over http.
+pub struct HttpAuthProvider {
+    client: reqwest::Client,
+    base_url: reqwest::Url,
+    /// The matchmaker's own service key, with the `issue_join_tickets` scope.
+    service_key: String,
+}
+
+impl HttpAuthProvider {
+    pub fn new(base_url: reqwest::Url, service_key: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url,
+            service_key,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MeResponse {
+    user_id: UserId,
+}
+
+#[derive(Debug, Serialize)]
+struct IssueTicketRequest<'a> {
+    player_token: &'a str,
+    match_id: &'a str,
+    server_id: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueTicketResponse {
+    ticket: String,
+}
+
+#[async_trait]
+impl AuthProvider for HttpAuthProvider {
+    async fn resolve_token(&self, token: &str) -> Result<Option<UserId>, AuthError> {
+        let url = self
+            .base_url
+            .join("auth/me")
+            .expect("`auth/me` is a valid relative url");
+
+        let response = self.client.get(url).bearer_auth(token).send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.json::<MeResponse>().await?.user_id)),
+            // service keys are turned away as forbidden, they don't belong to a player
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(None),
+            status => {
+                warn!(%status, "auth_provider failed to resolve a token");
+                Err(AuthError::UnexpectedStatus(status))
+            }
+        }
+    }
+
+    async fn resolve_service_key(&self, key: &str) -> Result<Option<ServiceCredential>, AuthError> {
+        let url = self
+            .base_url
+            .join("auth/services/me")
+            .expect("`auth/services/me` is a valid relative url");
+
+        let response = self.client.get(url).bearer_auth(key).send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.json().await?)),
+            // player tokens are turned away as forbidden, they don't belong to a service
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(None),
+            status => {
+                warn!(%status, "auth_provider failed to resolve a service key");
+                Err(AuthError::UnexpectedStatus(status))
+            }
+        }
+    }
+
+    async fn issue_join_ticket(
+        &self,
+        player_token: &str,
+        match_id: &str,
+        server_credential_id: &str,
+    ) -> Result<Option<String>, AuthError> {
+        let url = self
+            .base_url
+            .join("auth/tickets")
+            .expect("`auth/tickets` is a valid relative url");
+
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(&self.service_key)
+            .json(&IssueTicketRequest {
+                player_token,
+                match_id,
+                server_id: server_credential_id,
+            })
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.json::<IssueTicketResponse>().await?.ticket)),
+            // the player's token expired or was revoked since they connected
+            StatusCode::NOT_FOUND => Ok(None),
+            status => {
+                warn!(%status, "auth_provider failed to issue a join ticket");
+                Err(AuthError::UnexpectedStatus(status))
+            }
+        }
+    }
+}
+
+/// A service such as a game server, identified by its key. Only accepts the key as a bearer
+/// token.
+#[derive(Debug, Clone)]
+pub struct ServiceCaller(pub ServiceCredential);
+
+impl ServiceCaller {
+    pub fn require(&self, scope: &'static str) -> Result<(), CallerRejection> {
+        if self.0.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(CallerRejection::MissingScope(scope))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CallerRejection {
+    Unauthenticated,
+    InvalidCredentials,
+    MissingScope(&'static str),
+    Unavailable,
+}
+
+impl IntoResponse for CallerRejection {
+    fn into_response(self) -> Response {
+        match self {
+            CallerRejection::Unauthenticated => {
+                (StatusCode::UNAUTHORIZED, "missing credentials").into_response()
+            }
+            CallerRejection::InvalidCredentials => {
+                (StatusCode::UNAUTHORIZED, "invalid or expired credentials").into_response()
+            }
+            CallerRejection::MissingScope(scope) => (
+                StatusCode::FORBIDDEN,
+                format!("missing the `{scope}` scope"),
+            )
+                .into_response(),
+            CallerRejection::Unavailable => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ServiceCaller {
+    type Rejection = CallerRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let key = bearer_token(&parts.headers).ok_or(CallerRejection::Unauthenticated)?;
+
+        match state.auth.resolve_service_key(key).await {
+            Ok(Some(credential)) => Ok(ServiceCaller(credential)),
+            Ok(None) => Err(CallerRejection::InvalidCredentials),
+            Err(error) => {
+                warn!(?error, "failed to authenticate service");
+                Err(CallerRejection::Unavailable)
+            }
+        }
+    }
+}
+
+/// Finds the caller's auth token, preferring the `Authorization` header over the cookie.
+pub fn auth_token(headers: &HeaderMap, cookie_name: &str) -> Option<String> {
+    match bearer_token(headers) {
+        Some(bearer) => Some(bearer.to_string()),
+        None => CookieJar::from_headers(headers)
+            .get(cookie_name)
+            .map(|cookie| cookie.value().to_string()),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Knows a fixed set of tokens and keys, and fails for `unavailable` as if auth_provider were
+/// down. Tickets are issued for every known player, as `<match id>:<user id>@<server>`.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct StubAuth {
+    pub players: std::collections::HashMap<String, UserId>,
+    pub services: std::collections::HashMap<String, ServiceCredential>,
+    /// Fails every ticket, as if auth_provider went down after players connected.
+    pub tickets_unavailable: bool,
+}
+
+#[cfg(test)]
+impl StubAuth {
+    /// Serves `routes` on a random local port, returning the address and the matchmaker behind
+    /// them.
+    pub(crate) async fn serve(
+        self,
+        routes: axum::Router<AppState>,
+    ) -> (
+        std::net::SocketAddr,
+        std::sync::Arc<crate::matchmaker::Matchmaker<UserId>>,
+    ) {
+        let matchmaker = std::sync::Arc::new(crate::matchmaker::Matchmaker::default());
+        let state = AppState {
+            matchmaker: matchmaker.clone(),
+            auth: std::sync::Arc::new(self),
+            auth_cookie_name: "AuthToken".into(),
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // shuts down like the binary does
+        let told = matchmaker.clone();
+        tokio::spawn(std::future::IntoFuture::into_future(
+            axum::serve(listener, routes.with_state(state))
+                .with_graceful_shutdown(async move { told.servers_told_of_shutdown().await }),
+        ));
+
+        (address, matchmaker)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl AuthProvider for StubAuth {
+    async fn resolve_token(&self, token: &str) -> Result<Option<UserId>, AuthError> {
+        if token == "unavailable" {
+            return Err(AuthError::UnexpectedStatus(
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+
+        Ok(self.players.get(token).cloned())
+    }
+
+    async fn resolve_service_key(&self, key: &str) -> Result<Option<ServiceCredential>, AuthError> {
+        if key == "unavailable" {
+            return Err(AuthError::UnexpectedStatus(
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+
+        Ok(self.services.get(key).cloned())
+    }
+
+    async fn issue_join_ticket(
+        &self,
+        player_token: &str,
+        match_id: &str,
+        server_credential_id: &str,
+    ) -> Result<Option<String>, AuthError> {
+        if self.tickets_unavailable {
+            return Err(AuthError::UnexpectedStatus(
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+
+        Ok(self
+            .players
+            .get(player_token)
+            .map(|user_id| format!("{match_id}:{}@{server_credential_id}", user_id.0)))
+    }
+}
@@ -0,0 +1,341 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::selection::ServerSelection;
+
+/// No timeout can be longer than this. It's far more than any deployment needs, and keeps adding
+/// one to an `Instant` from overflowing.
+const MAX_TIMEOUT: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind_address: String,
+    /// Where auth_provider is reached, to resolve players' auth tokens and issue their join
+    /// tickets.
+    pub auth_provider_url: reqwest::Url,
+    /// The matchmaker's own service key, which needs the `issue_join_tickets` scope.
+    pub service_key: String,
+    /// Browsers can't set headers on a websocket, so they send the auth token in this cookie.
+    pub auth_cookie_name: String,
+    /// How long open requests and connections get to finish after a shutdown signal.
+    pub shutdown_timeout: Duration,
+    pub matchmaker: MatchmakerConfig,
+}
+
+impl Config {
+    /// Everything is read from the environment. All problems are collected rather than stopping
+    /// at the first one.
+    pub fn from_env() -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let bind_address =
+            env_string("MATCHMAKER_BIND_ADDRESS").unwrap_or_else(|| "localhost:12121".to_string());
+
+        let auth_provider_url = match env_string("MATCHMAKER_AUTH_PROVIDER_URL") {
+            Some(url) => match url.parse::<reqwest::Url>() {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Some(url),
+                Ok(_) => {
+                    errors.push(format!(
+                        "MATCHMAKER_AUTH_PROVIDER_URL `{url}` must be an http or https url"
+                    ));
+                    None
+                }
+                Err(error) => {
+                    errors.push(format!("MATCHMAKER_AUTH_PROVIDER_URL `{url}`: {error}"));
+                    None
+                }
+            },
+            None => {
+                errors.push("MATCHMAKER_AUTH_PROVIDER_URL is required".to_string());
+                None
+            }
+        };
+
+        let service_key = env_string("MATCHMAKER_SERVICE_KEY");
+        if service_key.is_none() {
+            errors.push("MATCHMAKER_SERVICE_KEY is required".to_string());
+        }
+
+        let auth_cookie_name =
+            env_string("MATCHMAKER_AUTH_COOKIE_NAME").unwrap_or_else(|| "AuthToken".to_string());
+
+        let shutdown_timeout = Duration::from_secs(
+            env_parse("MATCHMAKER_SHUTDOWN_TIMEOUT_SECS", &mut errors).unwrap_or(30),
+        );
+        if shutdown_timeout.is_zero() {
+            errors.push("MATCHMAKER_SHUTDOWN_TIMEOUT_SECS must be greater than 0".to_string());
+        }
+        check_at_most_max_timeout(
+            "MATCHMAKER_SHUTDOWN_TIMEOUT_SECS",
+            shutdown_timeout,
+            &mut errors,
+        );
+
+        let matchmaker = MatchmakerConfig::from_env(&mut errors);
+
+        match (auth_provider_url, service_key) {
+            (Some(auth_provider_url), Some(service_key)) if errors.is_empty() => Ok(Self {
+                bind_address,
+                auth_provider_url,
+                service_key,
+                auth_cookie_name,
+                shutdown_timeout,
+                matchmaker,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchmakerConfig {
+    /// The largest rating difference a player accepts right after queueing.
+    pub initial_elo_window: usize,
+    /// How much the window widens for every second spent waiting.
+    pub elo_window_growth_per_sec: usize,
+    /// The window never grows past this, however long a player waits.
+    pub max_elo_window: usize,
+    /// How often the pool is scanned for matchups.
+    pub tick_interval: Duration,
+    /// Looks for opponents for at most this many players on each tick.
+    pub tick_batch_size: usize,
+    /// Looks for opponents for whoever has waited longest first, instead of in no particular
+    /// order.
+    pub tick_oldest_first: bool,
+    /// Servers that haven't sent a heartbeat for this long are evicted.
+    pub server_heartbeat_timeout: Duration,
+    pub server_selection: ServerSelection,
+    /// How long slots stay reserved for matched players who haven't joined their server yet.
+    /// Once they have, the server's heartbeats count them instead.
+    pub slot_reservation_timeout: Duration,
+    /// Regions a player measured a round trip time of at most this to are good for them.
+    /// Players are matched with someone sharing a good region, on a server in one, if they can.
+    pub good_region_rtt: Duration,
+    /// How long both players have to wait before they are matched without sharing a good
+    /// region, or sent to a server outside one.
+    pub cross_region_after: Duration,
+    /// How long matched players have to accept before their ready check fails. No server slot
+    /// is reserved until both have.
+    pub ready_check_timeout: Duration,
+    /// How long a player who declines a match, or doesn't accept in time, has to wait before
+    /// queueing again. Doubles with every further decline.
+    pub decline_cooldown: Duration,
+    /// The cooldown never grows past this.
+    pub max_decline_cooldown: Duration,
+    /// Declines are forgotten once a player hasn't declined for this long.
+    pub declines_forgotten_after: Duration,
+}
+
+impl Default for MatchmakerConfig {
+    fn default() -> Self {
+        Self {
+            initial_elo_window: 50,
+            elo_window_growth_per_sec: 5,
+            max_elo_window: 400,
+            tick_interval: Duration::from_secs(1),
+            tick_batch_size: 256,
+            tick_oldest_first: true,
+            server_heartbeat_timeout: Duration::from_secs(15),
+            server_selection: ServerSelection::default(),
+            slot_reservation_timeout: Duration::from_secs(30),
+            good_region_rtt: Duration::from_millis(80),
+            cross_region_after: Duration::from_secs(30),
+            ready_check_timeout: Duration::from_secs(15),
+            decline_cooldown: Duration::from_secs(30),
+            max_decline_cooldown: Duration::from_secs(15 * 60),
+            declines_forgotten_after: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl MatchmakerConfig {
+    /// Starts from the defaults and overrides whatever is set in the environment.
+    fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut config = Self::default();
+
+        if let Some(value) = env_parse("MATCHMAKER_INITIAL_ELO_WINDOW", errors) {
+            config.initial_elo_window = value;
+        }
+        if let Some(value) = env_parse("MATCHMAKER_ELO_WINDOW_GROWTH_PER_SEC", errors) {
+            config.elo_window_growth_per_sec = value;
+        }
+        if let Some(value) = env_parse("MATCHMAKER_MAX_ELO_WINDOW", errors) {
+            config.max_elo_window = value;
+        }
+
+        if let Some(value) = env_parse("MATCHMAKER_TICK_INTERVAL_MS", errors) {
+            config.tick_interval = Duration::from_millis(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_TICK_BATCH_SIZE", errors) {
+            config.tick_batch_size = value;
+        }
+        if let Some(value) = env_parse("MATCHMAKER_TICK_OLDEST_FIRST", errors) {
+            config.tick_oldest_first = value;
+        }
+
+        if let Some(value) = env_parse("MATCHMAKER_SERVER_HEARTBEAT_TIMEOUT_SECS", errors) {
+            config.server_heartbeat_timeout = Duration::from_secs(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_SERVER_SELECTION", errors) {
+            config.server_selection = value;
+        }
+        if let Some(value) = env_parse("MATCHMAKER_SLOT_RESERVATION_TIMEOUT_SECS", errors) {
+            config.slot_reservation_timeout = Duration::from_secs(value);
+        }
+
+        if let Some(value) = env_parse("MATCHMAKER_GOOD_REGION_RTT_MS", errors) {
+            config.good_region_rtt = Duration::from_millis(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_CROSS_REGION_AFTER_SECS", errors) {
+            config.cross_region_after = Duration::from_secs(value);
+        }
+
+        if let Some(value) = env_parse("MATCHMAKER_READY_CHECK_TIMEOUT_SECS", errors) {
+            config.ready_check_timeout = Duration::from_secs(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_DECLINE_COOLDOWN_SECS", errors) {
+            config.decline_cooldown = Duration::from_secs(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_MAX_DECLINE_COOLDOWN_SECS", errors) {
+            config.max_decline_cooldown = Duration::from_secs(value);
+        }
+        if let Some(value) = env_parse("MATCHMAKER_DECLINES_FORGOTTEN_AFTER_SECS", errors) {
+            config.declines_forgotten_after = Duration::from_secs(value);
+        }
+
+        if config.tick_interval.is_zero() {
+            errors.push("MATCHMAKER_TICK_INTERVAL_MS must be greater than 0".to_string());
+        }
+        if config.tick_batch_size == 0 {
+            errors.push("MATCHMAKER_TICK_BATCH_SIZE must be greater than 0".to_string());
+        }
+        if config.server_heartbeat_timeout.is_zero() {
+            errors.push(
+                "MATCHMAKER_SERVER_HEARTBEAT_TIMEOUT_SECS must be greater than 0".to_string(),
+            );
+        }
+        if config.ready_check_timeout.is_zero() {
+            errors.push("MATCHMAKER_READY_CHECK_TIMEOUT_SECS must be greater than 0".to_string());
+        }
+        for (name, timeout) in [
+            ("MATCHMAKER_TICK_INTERVAL_MS", config.tick_interval),
+            (
+                "MATCHMAKER_SERVER_HEARTBEAT_TIMEOUT_SECS",
+                config.server_heartbeat_timeout,
+            ),
+            (
+                "MATCHMAKER_SLOT_RESERVATION_TIMEOUT_SECS",
+                config.slot_reservation_timeout,
+            ),
+            (
+                "MATCHMAKER_CROSS_REGION_AFTER_SECS",
+                config.cross_region_after,
+            ),
+            (
+                "MATCHMAKER_READY_CHECK_TIMEOUT_SECS",
+                config.ready_check_timeout,
+            ),
+            ("MATCHMAKER_DECLINE_COOLDOWN_SECS", config.decline_cooldown),
+            (
+                "MATCHMAKER_MAX_DECLINE_COOLDOWN_SECS",
+                config.max_decline_cooldown,
+            ),
+            (
+                "MATCHMAKER_DECLINES_FORGOTTEN_AFTER_SECS",
+                config.declines_forgotten_after,
+            ),
+        ] {
+            check_at_most_max_timeout(name, timeout, errors);
+        }
+        if config.max_decline_cooldown < config.decline_cooldown {
+            errors.push(format!(
+                "MATCHMAKER_MAX_DECLINE_COOLDOWN_SECS ({}) must be at least MATCHMAKER_DECLINE_COOLDOWN_SECS ({})",
+                config.max_decline_cooldown.as_secs(),
+                config.decline_cooldown.as_secs()
+            ));
+        }
+        if config.max_elo_window < config.initial_elo_window {
+            errors.push(format!(
+                "MATCHMAKER_MAX_ELO_WINDOW ({}) must be at least MATCHMAKER_INITIAL_ELO_WINDOW ({})",
+                config.max_elo_window, config.initial_elo_window
+            ));
+        }
+
+        config
+    }
+
+    /// The largest rating difference a player accepts after waiting for `waited`.
+    pub fn elo_window(&self, waited: Duration) -> usize {
+        let growth = self.elo_window_growth_per_sec as f64 * waited.as_secs_f64();
+
+        self.initial_elo_window
+            .saturating_add(growth as usize)
+            .min(self.max_elo_window)
+    }
+
+    /// How long a player has to wait before queueing again after their `declines`th decline.
+    pub fn decline_cooldown(&self, declines: u32) -> Duration {
+        let doublings = declines.saturating_sub(1).min(31);
+
+        self.decline_cooldown
+            .saturating_mul(1 << doublings)
+            .min(self.max_decline_cooldown)
+    }
+
+    /// How often servers are asked to send heartbeats, so a couple can get lost before they
+    /// are evicted.
+    pub fn server_heartbeat_interval(&self) -> Duration {
+        self.server_heartbeat_timeout / 3
+    }
+}
+
+fn check_at_most_max_timeout(name: &str, timeout: Duration, errors: &mut Vec<String>) {
+    if timeout > MAX_TIMEOUT {
+        errors.push(format!(
+            "{name} must be at most {} seconds",
+            MAX_TIMEOUT.as_secs()
+        ));
+    }
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+fn env_parse<T>(name: &str, errors: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = env_string(name)?;
+
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(error) => {
+            errors.push(format!("environment variable {name}=`{value}`: {error}"));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_too_long_to_add_to_an_instant_are_rejected() {
+        std::env::set_var("MATCHMAKER_READY_CHECK_TIMEOUT_SECS", u64::MAX.to_string());
+        std::env::set_var("MATCHMAKER_SLOT_RESERVATION_TIMEOUT_SECS", "31536001");
+        std::env::set_var("MATCHMAKER_MAX_DECLINE_COOLDOWN_SECS", "31536000");
+        let mut errors = Vec::new();
+        let config = MatchmakerConfig::from_env(&mut errors);
+
+        assert_eq!(
+            errors,
+            [
+                "MATCHMAKER_SLOT_RESERVATION_TIMEOUT_SECS must be at most 31536000 seconds",
+                "MATCHMAKER_READY_CHECK_TIMEOUT_SECS must be at most 31536000 seconds",
+            ]
+        );
+        assert_eq!(config.max_decline_cooldown, MAX_TIMEOUT);
+    }
+}
@@ -0,0 +1,51 @@
+use std::{hash::Hash, sync::Arc};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use serde::Serialize;
+
+use crate::matchmaker::{Matchmaker, ServerState};
+
+pub fn routes<T>() -> Router<Arc<Matchmaker<T>>>
+where
+    T: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz::<T>))
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    shutting_down: bool,
+    normal_servers: usize,
+}
+
+// players can only be matched once there is a server to send them to, and not while the
+// matchmaker is shutting down
+async fn readyz<T>(State(matchmaker): State<Arc<Matchmaker<T>>>) -> impl IntoResponse
+where
+    T: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    let normal_servers = matchmaker.count_servers_in_state(ServerState::Normal);
+    let shutting_down = matchmaker.is_shutting_down();
+    let ready = normal_servers > 0 && !shutting_down;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            shutting_down,
+            normal_servers,
+        }),
+    )
+}
@@ -1 +1,22 @@
+use std::sync::Arc;
+
+use auth::{AuthProvider, UserId};
+use matchmaker::Matchmaker;
+
+pub mod auth;
+pub mod clock;
+pub mod config;
+pub mod health;
 pub mod matchmaker;
+pub mod protocol;
+pub mod queue;
+pub mod selection;
+pub mod servers;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub matchmaker: Arc<Matchmaker<UserId>>,
+    pub auth: Arc<dyn AuthProvider>,
+    /// The cookie players' auth tokens are read from when there is no `Authorization` header.
+    pub auth_cookie_name: Arc<str>,
+}
@@ -1,6 +1,15 @@
+use std::{future::IntoFuture, sync::Arc};
+
 use axum::Router;
+use matchmaking::{
+    auth::{HttpAuthProvider, UserId},
+    config::Config,
+    health,
+    matchmaker::Matchmaker,
+    queue, servers, AppState,
+};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() {
@@ -24,13 +33,92 @@ async fn main() {
             .init();
     }
 
-    let router = Router::new().layer(TraceLayer::new_for_http());
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors {
+                error!("{error}");
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let matchmaker = Arc::new(Matchmaker::<UserId>::new(config.matchmaker));
+    matchmaker.spawn_tick_loop();
+
+    let state = AppState {
+        matchmaker: matchmaker.clone(),
+        auth: Arc::new(HttpAuthProvider::new(
+            config.auth_provider_url,
+            config.service_key,
+        )),
+        auth_cookie_name: config.auth_cookie_name.into(),
+    };
+
+    let router = Router::new()
+        .merge(queue::routes())
+        .merge(servers::routes())
+        .layer(TraceLayer::new_for_http())
+        .merge(health::routes().with_state(matchmaker.clone()))
+        .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("localhost:12121")
+    let listener = tokio::net::TcpListener::bind(&config.bind_address)
         .await
         .unwrap();
 
     info!(bind=%listener.local_addr().unwrap(), "created listener");
 
-    axum::serve(listener, router).await.unwrap();
+    let server = tokio::spawn(
+        axum::serve(listener, router)
+            // kept listening after the signal, so servers hear about it from their heartbeats
+            .with_graceful_shutdown({
+                let matchmaker = matchmaker.clone();
+                async move { matchmaker.servers_told_of_shutdown().await }
+            })
+            .into_future(),
+    );
+
+    shutdown_signal().await;
+    matchmaker.shutdown();
+
+    // upgraded connections outlive the http server, they are waited on separately
+    let drained = async {
+        match server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => warn!(%error, "server failed"),
+            Err(error) => warn!(%error, "server task panicked"),
+        }
+        matchmaker.connections_closed().await;
+    };
+    if tokio::time::timeout(config.shutdown_timeout, drained)
+        .await
+        .is_err()
+    {
+        warn!("shutdown timed out, dropping the remaining connections");
+    }
+
+    info!("shut down");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
@@ -1,39 +1,1189 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-struct Matchmaker<T>
+use dashmap::{
+    mapref::{entry::Entry, one::RefMut},
+    DashMap,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+use tokio_util::{
+    sync::CancellationToken,
+    task::{task_tracker::TaskTrackerWaitFuture, TaskTracker},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    config::MatchmakerConfig,
+    selection::{ServerCandidate, ServerSelector},
+};
+
+/// How much each new wait time counts towards the estimate.
+const WAIT_SMOOTHING: f64 = 0.1;
+/// How many server lifecycle steps are kept around to be inspected.
+const MAX_TRANSITIONS: usize = 256;
+/// Every match is one player against another.
+const MATCH_SLOTS: usize = 2;
+
+pub struct Matchmaker<T>
 where
-    T: Clone,
+    T: Clone + Eq + Hash,
 {
-    _player_id: std::marker::PhantomData<T>,
+    config: MatchmakerConfig,
+    clock: Arc<dyn Clock>,
+    selector: Box<dyn ServerSelector>,
+    players: DashMap<T, QueueEntry<T>>,
+    /// The ticket of each player's live handle, whether they are queued, in a ready check or
+    /// just matched. Keeps a player from queueing twice while out of the pool.
+    sessions: DashMap<T, u64>,
+    average_wait: Mutex<Option<Duration>>,
+    servers: DashMap<ServerId, RegisteredServer>,
+    /// Matchups waiting for both players to accept, by id.
+    ready_checks: DashMap<u64, PendingReadyCheck<T>>,
+    /// Players who declined recently, so declining again costs them more.
+    declines: DashMap<T, Declines>,
+    next_ready_check: AtomicU64,
+    /// Delivered matches by id, so they can be failed if their server goes away.
+    matches: DashMap<String, AssignedMatch>,
+    transitions: Mutex<VecDeque<ServerTransition>>,
+    /// Tells a player's current queue entry apart from an earlier one, so a stale handle can't
+    /// remove a player who queued again.
+    next_ticket: AtomicU64,
+    /// Cancelled once shutdown starts, so connections can tell their player or server before
+    /// closing.
+    shutdown: CancellationToken,
+    /// Player and server connections, which are no longer tracked by the http server once they
+    /// have been upgraded.
+    connections: TaskTracker,
+    /// Notified whenever another server has been told about the shutdown.
+    server_told: Notify,
 }
 
 impl<T> Matchmaker<T>
 where
-    T: Clone,
+    T: Clone + Eq + Hash,
 {
-    pub fn add_server(self: Arc<Self>, info: ServerInfo) {
-        todo!()
+    pub fn new(config: MatchmakerConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: MatchmakerConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            selector: config.server_selection.selector(),
+            config,
+            clock,
+            players: DashMap::new(),
+            sessions: DashMap::new(),
+            average_wait: Mutex::default(),
+            servers: DashMap::new(),
+            ready_checks: DashMap::new(),
+            declines: DashMap::new(),
+            next_ready_check: AtomicU64::new(0),
+            matches: DashMap::new(),
+            transitions: Mutex::default(),
+            next_ticket: AtomicU64::new(0),
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            server_told: Notify::new(),
+        }
+    }
+
+    /// Tells every connection to notify its player or server and close. No new connections are
+    /// tracked afterwards.
+    pub fn shutdown(&self) {
+        info!(
+            players = self.players.len(),
+            servers = self.servers.len(),
+            connections = self.connections.len(),
+            "matchmaker shutting down"
+        );
+
+        self.shutdown.cancel();
+        self.connections.close();
+        // resolves every handle's `next_event` with `None`
+        self.players.clear();
+        self.ready_checks.clear();
+    }
+
+    /// Replaces the selector picked by the config, e.g. with one that isn't built in.
+    pub fn with_server_selector(mut self, selector: Box<dyn ServerSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    pub fn config(&self) -> &MatchmakerConfig {
+        &self.config
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Resolves once [`Matchmaker::shutdown`] has been called.
+    pub async fn shutting_down(&self) {
+        self.shutdown.cancelled().await
+    }
+
+    /// Wraps a connection's future, so shutdown can wait for it to say goodbye.
+    pub fn track_connection<F>(&self, connection: F) -> impl Future<Output = F::Output>
+    where
+        F: Future,
+    {
+        self.connections.track_future(connection)
+    }
+
+    /// Resolves once shutdown has started and every tracked connection has closed.
+    pub fn connections_closed(&self) -> TaskTrackerWaitFuture<'_> {
+        self.connections.wait()
+    }
+
+    /// Resolves once shutdown has started and every registered server has been told about it,
+    /// or after `server_heartbeat_timeout`, when servers that didn't send a heartbeat would have
+    /// been evicted anyway.
+    pub async fn servers_told_of_shutdown(&self) {
+        self.shutting_down().await;
+
+        let mut deadline = std::pin::pin!(tokio::time::sleep(self.config.server_heartbeat_timeout));
+        loop {
+            // enabled before checking, so a server told in between isn't missed
+            let mut told = std::pin::pin!(self.server_told.notified());
+            told.as_mut().enable();
+
+            let untold = self
+                .servers
+                .iter()
+                .filter(|server| !server.told_of_shutdown)
+                .count();
+            if untold == 0 {
+                return;
+            }
+
+            tokio::select! {
+                _ = &mut deadline => {
+                    warn!(untold, "gave up telling servers about the shutdown");
+                    return;
+                }
+                _ = told => {}
+            }
+        }
+    }
+
+    /// Records that the server heard about the shutdown, see
+    /// [`Matchmaker::servers_told_of_shutdown`].
+    pub fn tell_server_of_shutdown(
+        &self,
+        server_id: &ServerId,
+        credential_id: &str,
+    ) -> Result<(), ServerError> {
+        self.owned_server(server_id, credential_id)?
+            .told_of_shutdown = true;
+        self.server_told.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Registers the server in `Startup`, whatever state `info` is in, counting as its first
+    /// heartbeat. Returns `true` if it wasn't registered yet.
+    ///
+    /// Only the credential that registered a server may replace it, e.g. after a restart. The
+    /// restarted server knows nothing about the matches assigned to it before, so they are
+    /// failed.
+    pub fn add_server(
+        &self,
+        server_id: ServerId,
+        mut info: ServerInfo,
+    ) -> Result<bool, ServerError> {
+        let now = self.clock.now();
+        info.state = ServerState::Startup;
+        let registered = RegisteredServer {
+            info,
+            last_heartbeat: now,
+            state_since: now,
+            reservations: VecDeque::new(),
+            told_of_shutdown: false,
+        };
+
+        let replaced = match self.servers.entry(server_id.clone()) {
+            Entry::Occupied(mut entry) => {
+                if entry.get().info.credential_id != registered.info.credential_id {
+                    return Err(ServerError::NotOwner);
+                }
+                Some(entry.insert(registered).info.state)
+            }
+            Entry::Vacant(entry) => {
+                let info = &entry.insert(registered).info;
+                debug!(
+                    server_id = server_id.0,
+                    address = info.address,
+                    region = info.region,
+                    version = info.version,
+                    max_players = info.max_players,
+                    "server registered"
+                );
+                None
+            }
+        };
+
+        if replaced.is_some() {
+            self.fail_matches_on(&server_id);
+        }
+        self.record_transition(
+            &server_id,
+            replaced,
+            Some(ServerState::Startup),
+            TransitionCause::Registered,
+        );
+
+        Ok(replaced.is_none())
+    }
+
+    /// Keeps the server from being evicted, and updates how full it is. A reported state moves
+    /// the server along its lifecycle, as if it had been reported on its own. The heartbeat
+    /// counts even when the reported state is rejected.
+    pub fn server_heartbeat(
+        &self,
+        server_id: &ServerId,
+        credential_id: &str,
+        current_players: usize,
+        state: Option<ServerState>,
+    ) -> Result<(), ServerError> {
+        let result = {
+            let mut server = self.owned_server(server_id, credential_id)?;
+            let now = self.clock.now();
+            server.info.current_players = current_players;
+            server.last_heartbeat = now;
+            server.release_expired_reservations(now);
+
+            match state {
+                // drained by an admin, which the server doesn't have to know about
+                Some(ServerState::Normal) if server.info.state == ServerState::Draining => Ok(()),
+                Some(state) => {
+                    let cause = match state {
+                        ServerState::Normal => TransitionCause::ReadinessReport,
+                        ServerState::Draining => TransitionCause::DrainedByServer,
+                        // only valid while still starting up, which isn't a transition
+                        ServerState::Startup => TransitionCause::Registered,
+                    };
+                    self.transition(server_id, &mut server, state, cause)
+                }
+                None => Ok(()),
+            }
+        };
+
+        self.remove_if_drained(server_id);
+
+        result
+    }
+
+    /// Moves a server that has finished starting up to `Normal`, so it starts getting matches.
+    pub fn report_server_ready(
+        &self,
+        server_id: &ServerId,
+        credential_id: &str,
+    ) -> Result<(), ServerError> {
+        let mut server = self.owned_server(server_id, credential_id)?;
+
+        self.transition(
+            server_id,
+            &mut server,
+            ServerState::Normal,
+            TransitionCause::ReadinessReport,
+        )
+    }
+
+    /// Stops giving the server new matches. It is removed once its last player has left.
+    pub fn drain_server(
+        &self,
+        server_id: &ServerId,
+        drained_by: DrainedBy<'_>,
+    ) -> Result<(), ServerError> {
+        {
+            let (mut server, cause) = match drained_by {
+                DrainedBy::Server(credential_id) => (
+                    self.owned_server(server_id, credential_id)?,
+                    TransitionCause::DrainedByServer,
+                ),
+                DrainedBy::Admin => (
+                    self.servers
+                        .get_mut(server_id)
+                        .ok_or(ServerError::NotRegistered)?,
+                    TransitionCause::DrainedByAdmin,
+                ),
+            };
+            self.transition(server_id, &mut server, ServerState::Draining, cause)?;
+        }
+
+        self.remove_if_drained(server_id);
+
+        Ok(())
+    }
+
+    fn owned_server(
+        &self,
+        server_id: &ServerId,
+        credential_id: &str,
+    ) -> Result<RefMut<'_, ServerId, RegisteredServer>, ServerError> {
+        let server = self
+            .servers
+            .get_mut(server_id)
+            .ok_or(ServerError::NotRegistered)?;
+        if server.info.credential_id != credential_id {
+            return Err(ServerError::NotOwner);
+        }
+
+        Ok(server)
+    }
+
+    // the only place a registered server's state changes
+    fn transition(
+        &self,
+        server_id: &ServerId,
+        server: &mut RegisteredServer,
+        to: ServerState,
+        cause: TransitionCause,
+    ) -> Result<(), ServerError> {
+        let from = server.info.state;
+        if from == to {
+            return Ok(());
+        }
+        if !from.can_become(to) {
+            return Err(ServerError::InvalidTransition { from, to });
+        }
+
+        server.info.state = to;
+        server.state_since = self.clock.now();
+        self.record_transition(server_id, Some(from), Some(to), cause);
+
+        Ok(())
+    }
+
+    fn remove_if_drained(&self, server_id: &ServerId) {
+        let Some((server_id, _)) = self.servers.remove_if(server_id, |_, server| {
+            server.info.state == ServerState::Draining && server.info.current_players == 0
+        }) else {
+            return;
+        };
+
+        self.fail_matches_on(&server_id);
+        self.record_transition(
+            &server_id,
+            Some(ServerState::Draining),
+            None,
+            TransitionCause::Drained,
+        );
     }
 
-    pub fn add_player_to_pool(self: Arc<Self>, info: PlayerInfo<T>) -> MatchmakerPlayerHandle<T> {
-        MatchmakerPlayerHandle {
+    /// Fails the matches assigned to the server.
+    pub fn remove_server(&self, server_id: &ServerId) -> Option<ServerInfo> {
+        let (_, server) = self.servers.remove(server_id)?;
+        self.fail_matches_on(server_id);
+        self.record_transition(
+            server_id,
+            Some(server.info.state),
+            None,
+            TransitionCause::Removed,
+        );
+
+        Some(server.info)
+    }
+
+    /// Removes every server that missed its heartbeats, and fails the matches assigned to them.
+    /// Returns how many were evicted.
+    pub fn evict_stale_servers(&self) -> usize {
+        let now = self.clock.now();
+        let timeout = self.config.server_heartbeat_timeout;
+        let is_stale = |server: &RegisteredServer| {
+            now.saturating_duration_since(server.last_heartbeat) > timeout
+        };
+
+        let stale: Vec<_> = self
+            .servers
+            .iter()
+            .filter(|server| is_stale(server))
+            .map(|server| server.key().clone())
+            .collect();
+
+        let mut evicted = 0;
+        for server_id in stale {
+            // it may have sent a heartbeat since it was picked
+            let Some((server_id, server)) = self
+                .servers
+                .remove_if(&server_id, |_, server| is_stale(server))
+            else {
+                continue;
+            };
+
+            let failed_matches = self.fail_matches_on(&server_id);
+            warn!(
+                server_id = server_id.0,
+                address = server.info.address,
+                failed_matches,
+                "evicted server after missed heartbeats"
+            );
+            self.record_transition(
+                &server_id,
+                Some(server.info.state),
+                None,
+                TransitionCause::Evicted,
+            );
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    fn record_transition(
+        &self,
+        server_id: &ServerId,
+        from: Option<ServerState>,
+        to: Option<ServerState>,
+        cause: TransitionCause,
+    ) {
+        info!(
+            server_id = server_id.0,
+            ?from,
+            ?to,
+            ?cause,
+            "server changed state"
+        );
+
+        let mut transitions = self.transitions.lock().unwrap();
+        if transitions.len() == MAX_TRANSITIONS {
+            transitions.pop_front();
+        }
+        transitions.push_back(ServerTransition {
+            server_id: server_id.clone(),
+            from,
+            to,
+            cause,
+            at: self.clock.now(),
+        });
+    }
+
+    /// The last few lifecycle steps of every server, oldest first.
+    pub fn recent_transitions(&self) -> Vec<ServerTransition> {
+        self.transitions.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn servers(&self) -> Vec<ServerStatus> {
+        let now = self.clock.now();
+
+        self.servers
+            .iter()
+            .map(|server| ServerStatus {
+                server_id: server.key().clone(),
+                info: server.info.clone(),
+                reserved_slots: server.reserved_slots(now),
+                state_since: server.state_since,
+            })
+            .collect()
+    }
+
+    /// The matchmaker's clock, to tell how long ago something happened.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    // players still holding one of the matches find out through it
+    fn fail_matches_on(&self, server_id: &ServerId) -> usize {
+        let mut failed = 0;
+        self.matches.retain(|_, assigned| {
+            if assigned.server != *server_id {
+                return true;
+            }
+            if let Some(failure) = assigned.failure.upgrade() {
+                failure.cancel();
+                failed += 1;
+            }
+            false
+        });
+
+        failed
+    }
+
+    pub fn server_address(&self, server_id: &ServerId) -> Option<String> {
+        self.servers
+            .get(server_id)
+            .map(|server| server.info.address.clone())
+    }
+
+    /// The service credential the server authenticated with, which join tickets are issued for.
+    pub fn server_credential_id(&self, server_id: &ServerId) -> Option<String> {
+        self.servers
+            .get(server_id)
+            .map(|server| server.info.credential_id.clone())
+    }
+
+    pub fn count_servers_in_state(&self, state: ServerState) -> usize {
+        self.servers
+            .iter()
+            .filter(|server| server.info.state == state)
+            .count()
+    }
+
+    /// The player stays queued until the returned handle is dropped, or they are removed some
+    /// other way. They can't queue again while the handle is alive.
+    pub fn add_player_to_pool(
+        self: Arc<Self>,
+        info: PlayerInfo<T>,
+    ) -> Result<MatchmakerPlayerHandle<T>, QueueError> {
+        if self.is_shutting_down() {
+            return Err(QueueError::ShuttingDown);
+        }
+        if let Some(remaining) = self.cooldown_remaining(&info.id) {
+            return Err(QueueError::CoolingDown { remaining });
+        }
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let player_id = info.id.clone();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        match self.sessions.entry(player_id.clone()) {
+            Entry::Occupied(_) => return Err(QueueError::AlreadyQueued),
+            Entry::Vacant(entry) => {
+                entry.insert(ticket);
+            }
+        }
+        match self.players.entry(player_id.clone()) {
+            // left over from a handle that is just being dropped
+            Entry::Occupied(_) => {
+                self.sessions.remove(&player_id);
+                return Err(QueueError::AlreadyQueued);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(QueueEntry {
+                    info,
+                    ticket,
+                    queued_at: self.clock.now(),
+                    matched: false,
+                    sender,
+                });
+            }
+        }
+
+        Ok(MatchmakerPlayerHandle {
             matchmaker: self,
-            player_info: info,
+            player_id,
+            ticket,
+            receiver,
+        })
+    }
+
+    /// Returns `false` if the player wasn't queued. Their handle goes stale, so they can queue
+    /// again right away.
+    pub fn remove_player_from_pool(&self, player_id: &T) -> bool {
+        self.sessions.remove(player_id);
+        self.players.remove(player_id).is_some()
+    }
+
+    pub fn is_queued(&self, player_id: &T) -> bool {
+        self.players.contains_key(player_id)
+    }
+
+    /// Whether the player has a live handle, even while out of the pool for a ready check.
+    pub fn has_session(&self, player_id: &T) -> bool {
+        self.sessions.contains_key(player_id)
+    }
+
+    pub fn queued_players(&self) -> usize {
+        self.players.len()
+    }
+
+    /// How long the player has been queued, `None` if they aren't.
+    pub fn waited(&self, player_id: &T) -> Option<Duration> {
+        let queued_at = self.players.get(player_id)?.queued_at;
+
+        Some(self.clock.now().saturating_duration_since(queued_at))
+    }
+
+    /// A moving average of how long recently matched players waited. `None` until the first
+    /// match.
+    pub fn estimated_wait(&self) -> Option<Duration> {
+        *self.average_wait.lock().unwrap()
+    }
+
+    fn record_wait(&self, waited: Duration) {
+        let mut average_wait = self.average_wait.lock().unwrap();
+
+        *average_wait = Some(match *average_wait {
+            Some(average) => average.mul_f64(1.0 - WAIT_SMOOTHING) + waited.mul_f64(WAIT_SMOOTHING),
+            None => waited,
+        });
+    }
+
+    // only removes the entry the handle was created for, not a later one for the same player
+    fn remove_queue_entry(&self, player_id: &T, ticket: u64) {
+        self.players
+            .remove_if(player_id, |_, entry| entry.ticket == ticket);
+        self.sessions
+            .remove_if(player_id, |_, session| *session == ticket);
+    }
+
+    fn holds_session(&self, entry: &QueueEntry<T>) -> bool {
+        self.sessions
+            .get(&entry.info.id)
+            .is_some_and(|session| *session == entry.ticket)
+    }
+
+    /// Looks for opponents for up to `tick_batch_size` players, and starts a ready check for
+    /// every matchup that would fit on a server. Returns how many ready checks were started.
+    ///
+    /// Servers that missed their heartbeats are evicted first, so nobody is sent to them, and
+    /// ready checks that ran out fail.
+    pub fn tick(&self) -> usize {
+        self.evict_stale_servers();
+        self.expire_ready_checks();
+        self.forget_declines();
+        // matches nobody holds anymore can't be failed
+        self.matches
+            .retain(|_, assigned| assigned.failure.strong_count() > 0);
+        // entries whose handle dropped while they were being matched
+        self.players.retain(|_, entry| !entry.sender.is_closed());
+
+        let mut seekers: Vec<_> = self
+            .players
+            .iter()
+            .filter(|entry| !entry.matched)
+            .map(|entry| (entry.queued_at, entry.key().clone()))
+            .collect();
+        if self.config.tick_oldest_first {
+            seekers.sort_by_key(|(queued_at, _)| *queued_at);
+        }
+
+        let mut started = 0;
+        let mut looked_at = 0;
+        for (_, player_id) in seekers {
+            if looked_at == self.config.tick_batch_size {
+                break;
+            }
+            // already taken as someone else's opponent this tick
+            if !self.is_queued(&player_id) {
+                continue;
+            }
+            looked_at += 1;
+
+            let Some((player, opponent)) = self.take_match(&player_id) else {
+                continue;
+            };
+
+            // nobody is going to answer the ready check
+            if player.sender.is_closed() || opponent.sender.is_closed() {
+                self.requeue(player);
+                self.requeue(opponent);
+                continue;
+            }
+
+            // the players would accept only to find out there is nowhere to play
+            let now = self.clock.now();
+            let cross_region = self.may_cross_regions(player.queued_at, opponent.queued_at, now);
+            if self
+                .server_candidates(&player.info, &opponent.info, cross_region, now)
+                .is_none()
+            {
+                // players elsewhere may still find a server in their region
+                debug!("no server has room for a match, waiting for the next tick");
+                self.requeue(player);
+                self.requeue(opponent);
+                continue;
+            }
+
+            self.start_ready_check(player, opponent, now);
+            started += 1;
+        }
+
+        started
+    }
+
+    fn start_ready_check(&self, player: QueueEntry<T>, opponent: QueueEntry<T>, now: Instant) {
+        let ready_check = ReadyCheck {
+            ready_check_id: self.next_ready_check.fetch_add(1, Ordering::Relaxed),
+            timeout: self.config.ready_check_timeout,
+        };
+        let senders = [player.sender.clone(), opponent.sender.clone()];
+
+        // inserted before either player hears of it, so an answer can't arrive too early
+        self.ready_checks.insert(
+            ready_check.ready_check_id,
+            PendingReadyCheck {
+                players: [player, opponent],
+                accepted: [false; MATCH_SLOTS],
+                expires_at: now + self.config.ready_check_timeout,
+            },
+        );
+
+        for sender in senders {
+            let _ = sender.send(PlayerEvent::ReadyCheck(ready_check.clone()));
+        }
+    }
+
+    /// Once both players have accepted, a server is picked for them.
+    pub fn accept_ready_check(
+        &self,
+        ready_check_id: u64,
+        player_id: &T,
+    ) -> Result<(), ReadyCheckError> {
+        let now = self.clock.now();
+        let Entry::Occupied(mut pending) = self.ready_checks.entry(ready_check_id) else {
+            return Err(ReadyCheckError::Over);
+        };
+        // left for the next tick to fail
+        if pending.get().expires_at <= now {
+            return Err(ReadyCheckError::Over);
+        }
+        let index = pending
+            .get()
+            .position(player_id)
+            .ok_or(ReadyCheckError::Over)?;
+
+        pending.get_mut().accepted[index] = true;
+        if pending.get().accepted.iter().all(|accepted| *accepted) {
+            let [player, opponent] = pending.remove().players;
+            self.start_match(player, opponent);
+        }
+
+        Ok(())
+    }
+
+    /// The opponent is queued again, and the player can't queue for the returned cooldown.
+    pub fn decline_ready_check(
+        &self,
+        ready_check_id: u64,
+        player_id: &T,
+    ) -> Result<Duration, ReadyCheckError> {
+        let (_, pending) = self
+            .ready_checks
+            .remove_if(&ready_check_id, |_, pending| {
+                pending.position(player_id).is_some()
+            })
+            .ok_or(ReadyCheckError::Over)?;
+
+        let now = self.clock.now();
+        let mut cooldown = Duration::ZERO;
+        for entry in pending.players {
+            if entry.info.id == *player_id {
+                cooldown = self.record_decline(player_id, now);
+            } else {
+                self.return_to_queue(entry);
+            }
+        }
+
+        Ok(cooldown)
+    }
+
+    /// Fails ready checks that ran out, or that a player left. Whoever didn't accept is
+    /// treated as having declined.
+    fn expire_ready_checks(&self) {
+        let now = self.clock.now();
+        let over: Vec<_> = self
+            .ready_checks
+            .iter()
+            .filter(|pending| {
+                pending.expires_at <= now
+                    || pending.players.iter().any(|entry| entry.sender.is_closed())
+            })
+            .map(|pending| *pending.key())
+            .collect();
+
+        for ready_check_id in over {
+            let Some((_, pending)) = self.ready_checks.remove(&ready_check_id) else {
+                continue;
+            };
+
+            for (entry, accepted) in pending.players.into_iter().zip(pending.accepted) {
+                if accepted && !entry.sender.is_closed() {
+                    self.return_to_queue(entry);
+                } else {
+                    let cooldown = self.record_decline(&entry.info.id, now);
+                    let _ = entry.sender.send(PlayerEvent::TimedOut { cooldown });
+                }
+            }
+        }
+    }
+
+    /// Reserves slots for both players on a server, or queues them again if there is no room
+    /// anymore.
+    fn start_match(&self, player: QueueEntry<T>, opponent: QueueEntry<T>) {
+        let now = self.clock.now();
+        let cross_region = self.may_cross_regions(player.queued_at, opponent.queued_at, now);
+        let Some(found) =
+            self.find_best_server_for_match(&player.info, &opponent.info, cross_region)
+        else {
+            debug!("the server filled up during the ready check");
+            self.return_to_queue(player);
+            self.return_to_queue(opponent);
+            return;
+        };
+
+        self.record_wait(now.saturating_duration_since(player.queued_at));
+        self.record_wait(now.saturating_duration_since(opponent.queued_at));
+
+        let _ = player.sender.send(PlayerEvent::Matched(found.clone()));
+        let _ = opponent.sender.send(PlayerEvent::Matched(found));
+    }
+
+    /// Puts a player back into the pool after their ready check failed without it being their
+    /// fault, and tells them.
+    fn return_to_queue(&self, entry: QueueEntry<T>) {
+        // the player left in the meantime
+        if !self.holds_session(&entry) {
+            return;
+        }
+
+        // sent first, so it can't arrive after the next ready check
+        let _ = entry.sender.send(PlayerEvent::Requeued);
+        self.requeue(entry);
+    }
+
+    /// Returns the cooldown the player gets for it.
+    fn record_decline(&self, player_id: &T, now: Instant) -> Duration {
+        let mut declines = self.declines.entry(player_id.clone()).or_insert(Declines {
+            count: 0,
+            last_declined_at: now,
+        });
+        if now.saturating_duration_since(declines.last_declined_at)
+            >= self.config.declines_forgotten_after
+        {
+            declines.count = 0;
+        }
+        declines.count += 1;
+        declines.last_declined_at = now;
+
+        let cooldown = self.config.decline_cooldown(declines.count);
+        debug!(
+            declines = declines.count,
+            cooldown_secs = cooldown.as_secs(),
+            "player declined a match"
+        );
+
+        cooldown
+    }
+
+    /// `None` if the player may queue.
+    pub fn cooldown_remaining(&self, player_id: &T) -> Option<Duration> {
+        let declines = self.declines.get(player_id)?;
+        let cooldown_over_at =
+            declines.last_declined_at + self.config.decline_cooldown(declines.count);
+
+        Some(cooldown_over_at.saturating_duration_since(self.clock.now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // once forgotten, the cooldown has long been over
+    fn forget_declines(&self) {
+        let now = self.clock.now();
+        self.declines.retain(|_, declines| {
+            let kept_for = self
+                .config
+                .declines_forgotten_after
+                .max(self.config.decline_cooldown(declines.count));
+            now.saturating_duration_since(declines.last_declined_at) < kept_for
+        });
+    }
+
+    /// Puts a player taken out for a matchup that fell through back into the pool, keeping
+    /// their wait time.
+    fn requeue(&self, entry: QueueEntry<T>) {
+        // the handle is gone, so there is nobody left to match
+        if entry.sender.is_closed() || !self.holds_session(&entry) {
+            return;
+        }
+
+        // a newer entry means they left and queued again in the meantime
+        if let Entry::Vacant(vacant) = self.players.entry(entry.info.id.clone()) {
+            vacant.insert(QueueEntry {
+                matched: false,
+                ..entry
+            });
+        }
+    }
+
+    /// Finds the best opponent for the player and takes both out of the pool. Each player ends
+    /// up in at most one matchup, however many of these run at the same time.
+    ///
+    /// A player whose handle drops while being matched may still be part of the matchup.
+    fn take_match(&self, player_id: &T) -> Option<(QueueEntry<T>, QueueEntry<T>)> {
+        loop {
+            let matchup = self.find_best_match(player_id)?;
+
+            if !self.claim(&matchup.player) {
+                return None;
+            }
+            if !self.claim(&matchup.opponent) {
+                // someone else got to the opponent first, look for another one
+                self.release(&matchup.player);
+                continue;
+            }
+
+            let player = self.players.remove(&matchup.player);
+            let opponent = self.players.remove(&matchup.opponent);
+
+            return match (player, opponent) {
+                (Some((_, player)), Some((_, opponent))) => Some((player, opponent)),
+                // explicitly removed from the pool while being matched
+                (Some((_, entry)), None) | (None, Some((_, entry))) => {
+                    self.requeue(entry);
+                    None
+                }
+                (None, None) => None,
+            };
+        }
+    }
+
+    // the flag is only ever set through here, so exactly one caller wins each player
+    fn claim(&self, player_id: &T) -> bool {
+        match self.players.get_mut(player_id) {
+            Some(mut entry) if !entry.matched => {
+                entry.matched = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&self, player_id: &T) {
+        if let Some(mut entry) = self.players.get_mut(player_id) {
+            entry.matched = false;
+        }
+    }
+
+    /// The acceptable opponent closest in rating, preferring anyone sharing a good region with
+    /// the player, and whoever has waited longest on a tie. Opponents without a good region in
+    /// common are only acceptable once both have waited `cross_region_after`.
+    fn find_best_match(&self, player_id: &T) -> Option<PotentialMatchup<T>> {
+        let now = self.clock.now();
+        // copied out, the pool can't be iterated while holding a reference into it
+        let (player, region_rtts) = self
+            .players
+            .get(player_id)
+            .filter(|entry| !entry.matched)
+            .map(|entry| {
+                (
+                    (entry.info.elo, entry.queued_at),
+                    entry.info.region_rtts.clone(),
+                )
+            })?;
+
+        self.players
+            .iter()
+            .filter(|entry| entry.key() != player_id && !entry.matched)
+            .filter_map(|entry| {
+                let score = self.score_matchup(player, (entry.info.elo, entry.queued_at), now)?;
+                let same_region = share_good_region(
+                    &region_rtts,
+                    &entry.info.region_rtts,
+                    self.config.good_region_rtt,
+                );
+                if !same_region && !self.may_cross_regions(player.1, entry.queued_at, now) {
+                    return None;
+                }
+                Some((!same_region, score, entry.queued_at, entry.key().clone()))
+            })
+            .min_by_key(|(cross_region, score, queued_at, _)| (*cross_region, *score, *queued_at))
+            .map(|(_, _, _, opponent)| PotentialMatchup {
+                player: player_id.clone(),
+                opponent,
+            })
+    }
+
+    /// Whether both players have waited long enough to be matched across regions.
+    fn may_cross_regions(
+        &self,
+        player_queued_at: Instant,
+        opponent_queued_at: Instant,
+        now: Instant,
+    ) -> bool {
+        let waited = now.saturating_duration_since(player_queued_at.max(opponent_queued_at));
+        waited >= self.config.cross_region_after
+    }
+
+    /// Lower is better. `None` if the rating difference is outside either player's window, so
+    /// neither gets an opponent they wouldn't have accepted themselves.
+    fn score_matchup(
+        &self,
+        (player_elo, player_queued_at): (usize, Instant),
+        (opponent_elo, opponent_queued_at): (usize, Instant),
+        now: Instant,
+    ) -> Option<usize> {
+        let difference = player_elo.abs_diff(opponent_elo);
+        let player_window = self
+            .config
+            .elo_window(now.saturating_duration_since(player_queued_at));
+        let opponent_window = self
+            .config
+            .elo_window(now.saturating_duration_since(opponent_queued_at));
+
+        (difference <= player_window.min(opponent_window)).then_some(difference)
+    }
+
+    /// The worse of the two players' round trip times to the server, if the match may be sent
+    /// there. Servers outside a region that is good for both are only allowed with
+    /// `cross_region`.
+    fn match_latency(
+        &self,
+        player: &PlayerInfo<T>,
+        opponent: &PlayerInfo<T>,
+        server: &ServerInfo,
+        cross_region: bool,
+    ) -> Option<Duration> {
+        let latency = player
+            .rtt_to(&server.region)
+            .zip(opponent.rtt_to(&server.region))
+            .map(|(player_rtt, opponent_rtt)| player_rtt.max(opponent_rtt));
+
+        match latency {
+            Some(latency) if latency <= self.config.good_region_rtt => Some(latency),
+            _ if !cross_region => None,
+            // somewhere nobody measured is still better than no match at all
+            latency => Some(latency.unwrap_or(Duration::MAX)),
         }
     }
 
-    pub fn remove_player_from_pool(self: Arc<Self>, player_id: T) {
-        todo!()
+    /// The accepting servers with room for both players and the lowest latency for the worse off
+    /// of them, sorted by id, along with that latency. `None` if there are none.
+    fn server_candidates(
+        &self,
+        player: &PlayerInfo<T>,
+        opponent: &PlayerInfo<T>,
+        cross_region: bool,
+        now: Instant,
+    ) -> Option<(Duration, Vec<ServerCandidate>)> {
+        let mut candidates: Vec<_> = self
+            .servers
+            .iter()
+            .filter_map(|server| {
+                let info = server.counting_reservations(now);
+                if !info.accepting_players() || info.free_slots() < MATCH_SLOTS {
+                    return None;
+                }
+                let latency = self.match_latency(player, opponent, &info, cross_region)?;
+                Some((
+                    latency,
+                    ServerCandidate {
+                        server_id: server.key().clone(),
+                        info,
+                    },
+                ))
+            })
+            .collect();
+        let best_latency = candidates.iter().map(|(latency, _)| *latency).min()?;
+        candidates.retain(|(latency, _)| *latency == best_latency);
+
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate)
+            .collect();
+        candidates.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+
+        Some((best_latency, candidates))
     }
 
-    fn find_best_match(self: Arc<Self>, info: PlayerInfo<T>) -> Option<PotentialMatchup<T>> {
-        todo!()
+    /// Lets the selector pick one of the accepting servers with room for both players, among
+    /// those with the lowest latency for the worse off player, and reserves their slots on it.
+    /// Returns `None` if no server has room, or the selector didn't pick one.
+    fn find_best_server_for_match(
+        &self,
+        player: &PlayerInfo<T>,
+        opponent: &PlayerInfo<T>,
+        cross_region: bool,
+    ) -> Option<Match<T>> {
+        loop {
+            let now = self.clock.now();
+            let (best_latency, candidates) =
+                self.server_candidates(player, opponent, cross_region, now)?;
+
+            let server_id = self.selector.select(&candidates)?;
+            if !candidates
+                .iter()
+                .any(|candidate| candidate.server_id == server_id)
+            {
+                warn!(
+                    server_id = server_id.0,
+                    "server selector picked a server without room"
+                );
+                return None;
+            }
+
+            // the server may have filled up or changed state since it was picked, the check and
+            // the reservation happen under the same lock so nothing can slip in between
+            let Some(mut server) = self.servers.get_mut(&server_id) else {
+                continue;
+            };
+            server.release_expired_reservations(now);
+            let info = server.counting_reservations(now);
+            if !info.accepting_players() || info.free_slots() < MATCH_SLOTS {
+                continue;
+            }
+            // re-registered somewhere else
+            if self.match_latency(player, opponent, &info, cross_region) != Some(best_latency) {
+                continue;
+            }
+            let expires_at = now + self.config.slot_reservation_timeout;
+            server.reservations.extend([expires_at; MATCH_SLOTS]);
+
+            // recorded before the server is let go of, so evicting it can't miss the match
+            let match_id = format!("{:032x}", rand::random::<u128>());
+            let failure = Arc::new(CancellationToken::new());
+            self.matches.insert(
+                match_id.clone(),
+                AssignedMatch {
+                    server: server_id.clone(),
+                    failure: Arc::downgrade(&failure),
+                },
+            );
+
+            return Some(Match {
+                match_id,
+                player: player.id.clone(),
+                opponent: opponent.id.clone(),
+                server: server_id,
+                failure,
+            });
+        }
     }
+}
+
+impl<T> Matchmaker<T>
+where
+    T: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    /// Ticks every `tick_interval` until shutdown.
+    pub fn spawn_tick_loop(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let matchmaker = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(matchmaker.config.tick_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-    fn score_matchup(self: Arc<Self>) {}
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = matchmaker.shutting_down() => return,
+                }
 
-    fn find_best_server_for_match(self: Arc<Self>, matchup: PotentialMatchup<T>) -> Match<T> {
-        todo!()
+                let started = matchmaker.tick();
+                if started > 0 {
+                    debug!(started, "started ready checks");
+                }
+            }
+        })
+    }
+}
+
+impl<T> Default for Matchmaker<T>
+where
+    T: Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new(MatchmakerConfig::default())
     }
 }
 
@@ -45,57 +1195,1566 @@ where
     opponent: T,
 }
 
-struct Match<T>
+/// What a queued player hears about through their handle.
+#[derive(Debug, Clone)]
+pub enum PlayerEvent<T>
 where
     T: Clone,
 {
-    player: T,
-    opponent: T,
-    server: ServerInfo,
+    /// An opponent was found. Both have to accept before a server is picked for them.
+    ReadyCheck(ReadyCheck),
+    /// Both players accepted.
+    Matched(Match<T>),
+    /// The opponent declined or didn't accept in time, or the server filled up in the
+    /// meantime. The player is queued again, keeping their wait time.
+    Requeued,
+    /// The player didn't accept in time, which counts as declining. They are no longer queued.
+    TimedOut { cooldown: Duration },
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadyCheck {
+    pub ready_check_id: u64,
+    /// How long the players have to accept.
+    pub timeout: Duration,
 }
 
-struct PlayerInfo<T>
+struct PendingReadyCheck<T>
 where
     T: Clone,
 {
-    id: T,
-    elo: usize,
+    players: [QueueEntry<T>; MATCH_SLOTS],
+    accepted: [bool; MATCH_SLOTS],
+    expires_at: Instant,
+}
+
+impl<T> PendingReadyCheck<T>
+where
+    T: Clone + Eq,
+{
+    fn position(&self, player_id: &T) -> Option<usize> {
+        self.players
+            .iter()
+            .position(|entry| entry.info.id == *player_id)
+    }
+}
+
+struct Declines {
+    /// Since the last time they were forgotten.
+    count: u32,
+    last_declined_at: Instant,
 }
 
-struct MatchmakerPlayerHandle<T>
+#[derive(Debug, Clone)]
+pub struct Match<T>
 where
     T: Clone,
 {
-    matchmaker: Arc<Matchmaker<T>>,
-    player_info: PlayerInfo<T>,
+    /// Random, so it can't be guessed from another match's id.
+    pub match_id: String,
+    pub player: T,
+    pub opponent: T,
+    /// Has already had a slot reserved for each player.
+    pub server: ServerId,
+    /// Shared by every copy of the match.
+    failure: Arc<CancellationToken>,
 }
 
-impl<T> Drop for MatchmakerPlayerHandle<T>
+impl<T> Match<T>
 where
     T: Clone,
 {
-    fn drop(&mut self) {
-        self.matchmaker
-            .clone()
-            .remove_player_from_pool(self.player_info.id.to_owned())
+    /// Resolves if the match falls through after it was found, e.g. because its server was
+    /// evicted.
+    pub async fn failed(&self) {
+        self.failure.cancelled().await
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failure.is_cancelled()
+    }
+
+    /// Fails the match for both players, e.g. because one of them can't be let onto the server.
+    /// Their reserved slots run out as usual.
+    pub fn fail(&self) {
+        self.failure.cancel()
     }
 }
 
-struct ServerInfo {
-    max_players: usize,
-    current_players: usize,
-    state: ServerState,
+struct AssignedMatch {
+    server: ServerId,
+    /// Can't be upgraded once every copy of the match has been dropped.
+    failure: Weak<CancellationToken>,
 }
 
-impl ServerInfo {
-    pub fn accepting_players(&self) -> bool {
-        self.current_players < self.max_players && self.state == ServerState::Normal
+pub struct PlayerInfo<T>
+where
+    T: Clone,
+{
+    pub id: T,
+    pub elo: usize,
+    /// The round trip time the player measured to each server region. Players who didn't
+    /// measure any are treated as close to every region.
+    pub region_rtts: HashMap<String, Duration>,
+}
+
+impl<T> PlayerInfo<T>
+where
+    T: Clone,
+{
+    /// `None` if the region wasn't measured, so it may be anywhere.
+    fn rtt_to(&self, region: &str) -> Option<Duration> {
+        if self.region_rtts.is_empty() {
+            return Some(Duration::ZERO);
+        }
+
+        self.region_rtts.get(region).copied()
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum ServerState {
-    Startup,
-    Normal,
-    Draining,
+/// Whether some region is good for both players.
+fn share_good_region(
+    player: &HashMap<String, Duration>,
+    opponent: &HashMap<String, Duration>,
+    good_rtt: Duration,
+) -> bool {
+    if player.is_empty() || opponent.is_empty() {
+        return true;
+    }
+
+    player.iter().any(|(region, rtt)| {
+        *rtt <= good_rtt
+            && opponent
+                .get(region)
+                .is_some_and(|opponent_rtt| *opponent_rtt <= good_rtt)
+    })
+}
+
+struct QueueEntry<T>
+where
+    T: Clone,
+{
+    info: PlayerInfo<T>,
+    ticket: u64,
+    queued_at: Instant,
+    /// Set while the player is being taken out of the pool for a matchup.
+    matched: bool,
+    sender: mpsc::UnboundedSender<PlayerEvent<T>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("the player is already queued")]
+    AlreadyQueued,
+    #[error("the matchmaker is shutting down")]
+    ShuttingDown,
+    #[error("the player declined a match and can't queue again for {}s", remaining.as_secs().max(1))]
+    CoolingDown { remaining: Duration },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadyCheckError {
+    #[error("the ready check is over, or the player isn't part of it")]
+    Over,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("the server isn't registered")]
+    NotRegistered,
+    #[error("the server was registered with a different credential")]
+    NotOwner,
+    #[error("a {from:?} server can't become {to:?}")]
+    InvalidTransition { from: ServerState, to: ServerState },
+}
+
+pub struct MatchmakerPlayerHandle<T>
+where
+    T: Clone + Eq + Hash,
+{
+    matchmaker: Arc<Matchmaker<T>>,
+    player_id: T,
+    ticket: u64,
+    receiver: mpsc::UnboundedReceiver<PlayerEvent<T>>,
+}
+
+impl<T> MatchmakerPlayerHandle<T>
+where
+    T: Clone + Eq + Hash,
+{
+    pub fn player_id(&self) -> &T {
+        &self.player_id
+    }
+
+    /// `None` once the player is no longer queued.
+    pub fn waited(&self) -> Option<Duration> {
+        self.matchmaker.waited(&self.player_id)
+    }
+
+    /// `None` once there is nothing more to hear, e.g. after the player was matched, or taken
+    /// out of the pool because the matchmaker is shutting down.
+    pub async fn next_event(&mut self) -> Option<PlayerEvent<T>> {
+        self.receiver.recv().await
+    }
+
+    pub fn accept(&self, ready_check: &ReadyCheck) -> Result<(), ReadyCheckError> {
+        self.matchmaker
+            .accept_ready_check(ready_check.ready_check_id, &self.player_id)
+    }
+
+    /// Returns how long the player has to wait before queueing again.
+    pub fn decline(&self, ready_check: &ReadyCheck) -> Result<Duration, ReadyCheckError> {
+        self.matchmaker
+            .decline_ready_check(ready_check.ready_check_id, &self.player_id)
+    }
+}
+
+impl<T> Drop for MatchmakerPlayerHandle<T>
+where
+    T: Clone + Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.matchmaker
+            .remove_queue_entry(&self.player_id, self.ticket)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ServerId(pub String);
+
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    /// The service credential the server authenticated with. Join tickets are issued for it.
+    pub credential_id: String,
+    /// Where players connect to once matched, as `host:port`.
+    pub address: String,
+    pub region: String,
+    pub version: String,
+    pub max_players: usize,
+    pub current_players: usize,
+    pub state: ServerState,
+}
+
+impl ServerInfo {
+    pub fn accepting_players(&self) -> bool {
+        self.current_players < self.max_players && self.state == ServerState::Normal
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.max_players.saturating_sub(self.current_players)
+    }
+
+    /// A freshly registered server in `eu`, authenticated as `credential`.
+    #[cfg(test)]
+    pub(crate) fn test(max_players: usize, current_players: usize) -> Self {
+        Self {
+            credential_id: "credential".to_string(),
+            address: "localhost:1".to_string(),
+            region: "eu".to_string(),
+            version: "1".to_string(),
+            max_players,
+            current_players,
+            state: ServerState::Startup,
+        }
+    }
+}
+
+struct RegisteredServer {
+    /// As reported by the server.
+    info: ServerInfo,
+    last_heartbeat: Instant,
+    state_since: Instant,
+    /// When each slot reserved for a matched player runs out, soonest first.
+    reservations: VecDeque<Instant>,
+    told_of_shutdown: bool,
+}
+
+impl RegisteredServer {
+    fn reserved_slots(&self, now: Instant) -> usize {
+        self.reservations
+            .iter()
+            .filter(|expires_at| **expires_at > now)
+            .count()
+    }
+
+    /// The server as new matches see it, with reserved slots counted as taken.
+    fn counting_reservations(&self, now: Instant) -> ServerInfo {
+        let mut info = self.info.clone();
+        info.current_players = info
+            .current_players
+            .saturating_add(self.reserved_slots(now));
+        info
+    }
+
+    fn release_expired_reservations(&mut self, now: Instant) {
+        while self
+            .reservations
+            .front()
+            .is_some_and(|expires_at| *expires_at <= now)
+        {
+            self.reservations.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub server_id: ServerId,
+    pub info: ServerInfo,
+    /// Slots held for matched players who haven't joined yet.
+    pub reserved_slots: usize,
+    pub state_since: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    Startup,
+    Normal,
+    Draining,
+}
+
+impl ServerState {
+    /// States only ever move forward. A server that wants to start over registers again.
+    pub fn can_become(self, to: ServerState) -> bool {
+        matches!(
+            (self, to),
+            (ServerState::Startup, ServerState::Normal)
+                | (ServerState::Startup, ServerState::Draining)
+                | (ServerState::Normal, ServerState::Draining)
+        )
+    }
+}
+
+/// Who asked for a server to be drained.
+#[derive(Debug, Clone, Copy)]
+pub enum DrainedBy<'a> {
+    /// The server itself, identified by the credential it registered with.
+    Server(&'a str),
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionCause {
+    Registered,
+    ReadinessReport,
+    DrainedByServer,
+    DrainedByAdmin,
+    /// A draining server ran out of players.
+    Drained,
+    Evicted,
+    Removed,
+}
+
+/// One step in a server's lifecycle.
+#[derive(Debug, Clone)]
+pub struct ServerTransition {
+    pub server_id: ServerId,
+    /// `None` if the server was just registered.
+    pub from: Option<ServerState>,
+    /// `None` if the server was removed.
+    pub to: Option<ServerState>,
+    pub cause: TransitionCause,
+    pub at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc, thread, time::Duration};
+
+    use super::*;
+    use crate::{clock::MockClock, selection::ServerSelection};
+
+    const THREADS: usize = 8;
+    const PLAYERS_PER_THREAD: usize = 500;
+
+    fn player(id: usize, elo: usize) -> PlayerInfo<usize> {
+        PlayerInfo {
+            id,
+            elo,
+            region_rtts: HashMap::new(),
+        }
+    }
+
+    fn player_near(id: usize, elo: usize, region_rtts_ms: &[(&str, u64)]) -> PlayerInfo<usize> {
+        PlayerInfo {
+            region_rtts: region_rtts_ms
+                .iter()
+                .map(|(region, rtt)| (region.to_string(), Duration::from_millis(*rtt)))
+                .collect(),
+            ..player(id, elo)
+        }
+    }
+
+    // windows of 50 right away, 150 after 10 seconds and 200 from 15 seconds on
+    fn matchmaker_with_clock() -> (Arc<Matchmaker<usize>>, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new());
+        let config = MatchmakerConfig {
+            initial_elo_window: 50,
+            elo_window_growth_per_sec: 10,
+            max_elo_window: 200,
+            ..MatchmakerConfig::default()
+        };
+
+        (
+            Arc::new(Matchmaker::with_clock(config, clock.clone())),
+            clock,
+        )
+    }
+
+    fn add_ready_server(matchmaker: &Matchmaker<usize>, id: &str, info: ServerInfo) {
+        let id = ServerId(id.to_string());
+        matchmaker.add_server(id.clone(), info).unwrap();
+        matchmaker.report_server_ready(&id, "credential").unwrap();
+    }
+
+    #[test]
+    fn concurrent_adds_keep_every_player_queued() {
+        let matchmaker = Arc::new(Matchmaker::default());
+
+        let handles: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let matchmaker = matchmaker.clone();
+                    scope.spawn(move || {
+                        (0..PLAYERS_PER_THREAD)
+                            .map(|i| {
+                                let id = thread * PLAYERS_PER_THREAD + i;
+                                matchmaker
+                                    .clone()
+                                    .add_player_to_pool(player(id, id))
+                                    .unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(matchmaker.queued_players(), THREADS * PLAYERS_PER_THREAD);
+        drop(handles);
+        assert_eq!(matchmaker.queued_players(), 0);
+    }
+
+    #[test]
+    fn concurrent_adds_of_the_same_player_queue_them_once() {
+        let matchmaker = Arc::new(Matchmaker::default());
+
+        let handles: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let matchmaker = matchmaker.clone();
+                    scope.spawn(move || matchmaker.add_player_to_pool(player(7, 1000)).ok())
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .filter_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(handles.len(), 1);
+        assert_eq!(matchmaker.queued_players(), 1);
+    }
+
+    #[test]
+    fn concurrent_drops_and_removals_empty_the_pool() {
+        let matchmaker = Arc::new(Matchmaker::default());
+
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let matchmaker = matchmaker.clone();
+                scope.spawn(move || {
+                    for i in 0..PLAYERS_PER_THREAD {
+                        let id = thread * PLAYERS_PER_THREAD + i;
+                        let handle = matchmaker
+                            .clone()
+                            .add_player_to_pool(player(id, id))
+                            .unwrap();
+                        assert!(matchmaker.is_queued(&id));
+
+                        // half are removed explicitly before their handle drops
+                        if i % 2 == 0 {
+                            assert!(matchmaker.remove_player_from_pool(&id));
+                        }
+                        drop(handle);
+                        assert!(!matchmaker.is_queued(&id));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(matchmaker.queued_players(), 0);
+    }
+
+    #[test]
+    fn stale_handle_does_not_remove_requeued_player() {
+        let matchmaker = Arc::new(Matchmaker::default());
+
+        let stale = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        assert!(matchmaker.remove_player_from_pool(&1));
+        let current = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+
+        drop(stale);
+        assert!(matchmaker.is_queued(&1));
+
+        drop(current);
+        assert!(!matchmaker.is_queued(&1));
+    }
+
+    #[test]
+    fn shutting_down_rejects_new_players() {
+        let matchmaker = Arc::new(Matchmaker::default());
+        matchmaker.shutdown();
+
+        assert!(matches!(
+            matchmaker.clone().add_player_to_pool(player(1, 1000)),
+            Err(QueueError::ShuttingDown)
+        ));
+    }
+
+    #[test]
+    fn best_match_is_closest_in_rating() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _handles = [(1, 1000), (2, 1400), (3, 1080), (4, 900)].map(|(id, elo)| {
+            matchmaker
+                .clone()
+                .add_player_to_pool(player(id, elo))
+                .unwrap()
+        });
+        clock.advance(Duration::from_secs(15));
+
+        let matchup = matchmaker.find_best_match(&1).unwrap();
+        assert_eq!((matchup.player, matchup.opponent), (1, 3));
+        assert!(matchmaker.find_best_match(&5).is_none());
+    }
+
+    #[test]
+    fn window_widens_with_wait_time() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _handles = [(1, 1000), (2, 1100)].map(|(id, elo)| {
+            matchmaker
+                .clone()
+                .add_player_to_pool(player(id, elo))
+                .unwrap()
+        });
+
+        assert!(matchmaker.find_best_match(&1).is_none());
+
+        clock.advance(Duration::from_secs(4));
+        assert!(matchmaker.find_best_match(&1).is_none());
+
+        clock.advance(Duration::from_secs(1));
+        let matchup = matchmaker.find_best_match(&1).unwrap();
+        assert_eq!((matchup.player, matchup.opponent), (1, 2));
+    }
+
+    #[test]
+    fn window_stops_at_the_cap() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _handles = [(1, 1000), (2, 1201)].map(|(id, elo)| {
+            matchmaker
+                .clone()
+                .add_player_to_pool(player(id, elo))
+                .unwrap()
+        });
+
+        clock.advance(Duration::from_secs(60 * 60));
+        assert!(matchmaker.find_best_match(&1).is_none());
+        assert!(matchmaker.find_best_match(&2).is_none());
+    }
+
+    #[test]
+    fn both_windows_have_to_accept_the_matchup() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _veteran = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        clock.advance(Duration::from_secs(15));
+        let _newcomer = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1100))
+            .unwrap();
+
+        // the veteran's window already covers the difference, the newcomer's doesn't yet
+        assert!(matchmaker.find_best_match(&1).is_none());
+        assert!(matchmaker.find_best_match(&2).is_none());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(matchmaker.find_best_match(&2).is_some());
+    }
+
+    #[test]
+    fn ties_go_to_the_longest_waiting_opponent() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1020))
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 980))
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        let _player = matchmaker
+            .clone()
+            .add_player_to_pool(player(3, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.find_best_match(&3).unwrap().opponent, 1);
+    }
+
+    #[test]
+    fn taken_players_leave_the_pool() {
+        let (matchmaker, _clock) = matchmaker_with_clock();
+        let _handles = [(1, 1000), (2, 1010), (3, 1020)].map(|(id, elo)| {
+            matchmaker
+                .clone()
+                .add_player_to_pool(player(id, elo))
+                .unwrap()
+        });
+
+        let (player, opponent) = matchmaker.take_match(&1).unwrap();
+        assert_eq!((player.info.id, opponent.info.id), (1, 2));
+        assert!(!matchmaker.is_queued(&1));
+        assert!(!matchmaker.is_queued(&2));
+
+        assert!(matchmaker.take_match(&1).is_none());
+        assert!(matchmaker.take_match(&3).is_none());
+        assert!(matchmaker.is_queued(&3));
+    }
+
+    #[test]
+    fn concurrent_matching_never_matches_a_player_twice() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let players = THREADS * PLAYERS_PER_THREAD;
+        let _handles: Vec<_> = (0..players)
+            .map(|id| {
+                matchmaker
+                    .clone()
+                    .add_player_to_pool(player(id, 1000 + id % 200))
+                    .unwrap()
+            })
+            .collect();
+        clock.advance(Duration::from_secs(15));
+
+        let matchups: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let matchmaker = &matchmaker;
+                    // every worker goes through every player, starting at a different one
+                    scope.spawn(move || {
+                        (0..players)
+                            .filter_map(|i| {
+                                matchmaker
+                                    .take_match(&((i + thread * PLAYERS_PER_THREAD) % players))
+                                    .map(|(player, opponent)| (player.info.id, opponent.info.id))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        let mut matched = HashSet::new();
+        for (player, opponent) in matchups {
+            assert!(matched.insert(player));
+            assert!(matched.insert(opponent));
+        }
+        assert_eq!(matched.len(), players);
+        assert_eq!(matchmaker.queued_players(), 0);
+    }
+
+    #[test]
+    fn server_registry_tracks_servers_by_id() {
+        let matchmaker = Matchmaker::<usize>::default();
+        let id = ServerId("a".to_string());
+
+        assert!(matchmaker
+            .add_server(id.clone(), ServerInfo::test(4, 0))
+            .unwrap());
+        assert!(!matchmaker
+            .add_server(id.clone(), ServerInfo::test(8, 0))
+            .unwrap());
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Startup), 1);
+
+        assert_eq!(matchmaker.remove_server(&id).unwrap().max_players, 8);
+        assert!(matchmaker.remove_server(&id).is_none());
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Startup), 0);
+    }
+
+    #[test]
+    fn only_the_registering_credential_may_update_a_server() {
+        let matchmaker = Matchmaker::<usize>::default();
+        let id = ServerId("a".to_string());
+        matchmaker
+            .add_server(id.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+
+        let impostor = ServerInfo {
+            credential_id: "impostor".to_string(),
+            ..ServerInfo::test(100, 0)
+        };
+        assert!(matches!(
+            matchmaker.add_server(id.clone(), impostor),
+            Err(ServerError::NotOwner)
+        ));
+        assert!(matches!(
+            matchmaker.server_heartbeat(&id, "impostor", 0, Some(ServerState::Draining)),
+            Err(ServerError::NotOwner)
+        ));
+        assert!(matches!(
+            matchmaker.server_heartbeat(
+                &ServerId("b".to_string()),
+                "credential",
+                0,
+                Some(ServerState::Normal)
+            ),
+            Err(ServerError::NotRegistered)
+        ));
+
+        matchmaker
+            .server_heartbeat(&id, "credential", 3, Some(ServerState::Normal))
+            .unwrap();
+        let server = matchmaker.servers.get(&id).unwrap();
+        assert_eq!(server.info.max_players, 4);
+        assert_eq!(server.info.current_players, 3);
+    }
+
+    #[test]
+    fn server_states_only_move_forward() {
+        let matchmaker = Matchmaker::<usize>::default();
+        let id = ServerId("a".to_string());
+        let state =
+            |matchmaker: &Matchmaker<usize>| matchmaker.servers.get(&id).unwrap().info.state;
+        matchmaker
+            .add_server(id.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+        assert_eq!(state(&matchmaker), ServerState::Startup);
+
+        matchmaker
+            .server_heartbeat(&id, "credential", 0, Some(ServerState::Startup))
+            .unwrap();
+        matchmaker.report_server_ready(&id, "credential").unwrap();
+        assert_eq!(state(&matchmaker), ServerState::Normal);
+        assert!(matches!(
+            matchmaker.server_heartbeat(&id, "credential", 2, Some(ServerState::Startup)),
+            Err(ServerError::InvalidTransition {
+                from: ServerState::Normal,
+                to: ServerState::Startup
+            })
+        ));
+        // the rejected state didn't keep the heartbeat from counting
+        assert_eq!(matchmaker.servers.get(&id).unwrap().info.current_players, 2);
+
+        matchmaker
+            .server_heartbeat(&id, "credential", 1, Some(ServerState::Draining))
+            .unwrap();
+        assert_eq!(state(&matchmaker), ServerState::Draining);
+        assert!(matches!(
+            matchmaker.report_server_ready(&id, "credential"),
+            Err(ServerError::InvalidTransition { .. })
+        ));
+    }
+
+    #[test]
+    fn servers_only_get_matches_once_ready() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        let id = ServerId("a".to_string());
+        matchmaker
+            .add_server(id.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+        let _first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.tick(), 0);
+        matchmaker.report_server_ready(&id, "credential").unwrap();
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+    }
+
+    #[test]
+    fn drained_servers_are_removed_once_empty() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        let id = ServerId("a".to_string());
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        matchmaker
+            .server_heartbeat(&id, "credential", 2, None)
+            .unwrap();
+
+        assert!(matches!(
+            matchmaker.drain_server(&id, DrainedBy::Server("impostor")),
+            Err(ServerError::NotOwner)
+        ));
+        matchmaker.drain_server(&id, DrainedBy::Admin).unwrap();
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Draining), 1);
+
+        let _first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        assert_eq!(matchmaker.tick(), 0);
+
+        matchmaker
+            .server_heartbeat(&id, "credential", 0, None)
+            .unwrap();
+        assert!(matchmaker.server_address(&id).is_none());
+
+        let transitions: Vec<_> = matchmaker
+            .recent_transitions()
+            .into_iter()
+            .map(|transition| (transition.from, transition.to, transition.cause))
+            .collect();
+        assert_eq!(
+            transitions,
+            [
+                (
+                    None,
+                    Some(ServerState::Startup),
+                    TransitionCause::Registered
+                ),
+                (
+                    Some(ServerState::Startup),
+                    Some(ServerState::Normal),
+                    TransitionCause::ReadinessReport
+                ),
+                (
+                    Some(ServerState::Normal),
+                    Some(ServerState::Draining),
+                    TransitionCause::DrainedByAdmin
+                ),
+                (Some(ServerState::Draining), None, TransitionCause::Drained),
+            ]
+        );
+    }
+
+    #[test]
+    fn servers_are_evicted_after_missing_heartbeats() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        let kept = ServerId("kept".to_string());
+        let stale = ServerId("stale".to_string());
+        matchmaker
+            .add_server(kept.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+        matchmaker
+            .add_server(stale.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        matchmaker
+            .server_heartbeat(&kept, "credential", 0, Some(ServerState::Normal))
+            .unwrap();
+        assert_eq!(matchmaker.evict_stale_servers(), 0);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(matchmaker.evict_stale_servers(), 1);
+        assert!(matchmaker.server_address(&kept).is_some());
+        assert!(matchmaker.server_address(&stale).is_none());
+        assert!(matches!(
+            matchmaker.server_heartbeat(&stale, "credential", 0, Some(ServerState::Normal)),
+            Err(ServerError::NotRegistered)
+        ));
+    }
+
+    #[test]
+    fn evicting_a_server_fails_its_matches() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+        let first = try_matched(&mut first).unwrap();
+        let second = try_matched(&mut second).unwrap();
+        assert!(!first.is_failed());
+
+        clock.advance(Duration::from_secs(20));
+        matchmaker.tick();
+
+        assert!(first.is_failed());
+        assert!(second.is_failed());
+        assert!(matchmaker.matches.is_empty());
+    }
+
+    #[test]
+    fn registering_again_fails_earlier_matches() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        let id = ServerId("a".to_string());
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+        let found = try_matched(&mut first).unwrap();
+
+        assert!(!matchmaker.add_server(id, ServerInfo::test(4, 0)).unwrap());
+        assert!(found.is_failed());
+    }
+
+    #[test]
+    fn matches_nobody_holds_are_forgotten() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+        assert_eq!(matchmaker.matches.len(), 1);
+
+        // the delivered matches are dropped along with the handles
+        drop((first, second));
+        matchmaker.tick();
+
+        assert!(matchmaker.matches.is_empty());
+    }
+
+    #[test]
+    fn concurrent_matches_never_overfill_a_server() {
+        let matchmaker = Matchmaker::<usize>::default();
+        add_ready_server(&matchmaker, "a", ServerInfo::test(10, 0));
+        add_ready_server(&matchmaker, "b", ServerInfo::test(6, 1));
+
+        let matches: usize = thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let matchmaker = &matchmaker;
+                    scope.spawn(move || {
+                        (0..10)
+                            .filter_map(|i| {
+                                matchmaker.find_best_server_for_match(
+                                    &player(thread * 100 + i * 2, 1000),
+                                    &player(thread * 100 + i * 2 + 1, 1000),
+                                    false,
+                                )
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .sum()
+        });
+
+        // 5 matches fit on a, 2 on b
+        assert_eq!(matches, 7);
+        for server in matchmaker.servers.iter() {
+            let occupied = server.info.current_players + server.reserved_slots(matchmaker.now());
+            assert!(occupied <= server.info.max_players);
+        }
+    }
+
+    fn tick_matchmaker(
+        tick_batch_size: usize,
+        tick_oldest_first: bool,
+    ) -> (Arc<Matchmaker<usize>>, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new());
+        let config = MatchmakerConfig {
+            initial_elo_window: 50,
+            elo_window_growth_per_sec: 10,
+            max_elo_window: 200,
+            tick_batch_size,
+            tick_oldest_first,
+            ..MatchmakerConfig::default()
+        };
+
+        (
+            Arc::new(Matchmaker::with_clock(config, clock.clone())),
+            clock,
+        )
+    }
+
+    fn try_event(handle: &mut MatchmakerPlayerHandle<usize>) -> Option<PlayerEvent<usize>> {
+        handle.receiver.try_recv().ok()
+    }
+
+    // skips past the ready check
+    fn try_matched(handle: &mut MatchmakerPlayerHandle<usize>) -> Option<Match<usize>> {
+        loop {
+            if let PlayerEvent::Matched(found) = try_event(handle)? {
+                return Some(found);
+            }
+        }
+    }
+
+    fn try_ready_check(handle: &mut MatchmakerPlayerHandle<usize>) -> ReadyCheck {
+        match try_event(handle) {
+            Some(PlayerEvent::ReadyCheck(ready_check)) => ready_check,
+            event => panic!("expected a ready check, got {event:?}"),
+        }
+    }
+
+    // ticks, and accepts every ready check on both players' behalf
+    fn tick_and_accept(matchmaker: &Matchmaker<usize>) -> usize {
+        let started = matchmaker.tick();
+
+        let pending: Vec<_> = matchmaker
+            .ready_checks
+            .iter()
+            .map(|pending| {
+                let players = pending.players.each_ref().map(|entry| entry.info.id);
+                (*pending.key(), players)
+            })
+            .collect();
+        for (ready_check_id, players) in pending {
+            for player_id in players {
+                matchmaker
+                    .accept_ready_check(ready_check_id, &player_id)
+                    .unwrap();
+            }
+        }
+
+        started
+    }
+
+    #[test]
+    fn tick_delivers_the_match_to_both_players() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1010))
+            .unwrap();
+
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+
+        for handle in [&mut first, &mut second] {
+            let found = try_matched(handle).unwrap();
+            assert_eq!(found.server, ServerId("a".to_string()));
+            assert_eq!(
+                HashSet::from([found.player, found.opponent]),
+                HashSet::from([1, 2])
+            );
+        }
+        assert_eq!(matchmaker.queued_players(), 0);
+        assert_eq!(
+            matchmaker
+                .servers
+                .get(&ServerId("a".to_string()))
+                .unwrap()
+                .reserved_slots(matchmaker.now()),
+            2
+        );
+    }
+
+    #[test]
+    fn heartbeats_do_not_release_reserved_slots() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        let id = ServerId("a".to_string());
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let matchup = |id| {
+            matchmaker.find_best_server_for_match(&player(id, 1000), &player(id + 1, 1000), false)
+        };
+
+        assert!(matchup(1).is_some());
+        assert!(matchup(3).is_some());
+        // the matched players haven't joined yet
+        matchmaker
+            .server_heartbeat(&id, "credential", 0, None)
+            .unwrap();
+        assert!(matchup(5).is_none());
+
+        clock.advance(Duration::from_secs(31));
+        assert!(matchup(5).is_some());
+    }
+
+    #[test]
+    fn the_configured_selector_picks_the_server() {
+        let config = MatchmakerConfig {
+            server_selection: ServerSelection::BinPacking,
+            ..MatchmakerConfig::default()
+        };
+        let matchmaker = Matchmaker::<usize>::new(config);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(8, 0));
+        add_ready_server(&matchmaker, "b", ServerInfo::test(8, 2));
+
+        let servers: Vec<_> = (0..4)
+            .map(|i| {
+                matchmaker
+                    .find_best_server_for_match(
+                        &player(i * 2, 1000),
+                        &player(i * 2 + 1, 1000),
+                        false,
+                    )
+                    .unwrap()
+                    .server
+                    .0
+            })
+            .collect();
+
+        // b is filled up before a gets anyone
+        assert_eq!(servers, ["b", "b", "b", "a"]);
+    }
+
+    #[test]
+    fn tick_without_a_server_keeps_players_queued_with_their_wait_time() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1100))
+            .unwrap();
+        clock.advance(Duration::from_secs(5));
+
+        assert_eq!(matchmaker.tick(), 0);
+        assert_eq!(matchmaker.queued_players(), 2);
+        assert!(try_matched(&mut first).is_none());
+
+        // still matchable right away, the wait didn't start over
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+        assert!(try_matched(&mut first).is_some());
+    }
+
+    fn server_in(region: &str, max_players: usize) -> ServerInfo {
+        ServerInfo {
+            region: region.to_string(),
+            ..ServerInfo::test(max_players, 0)
+        }
+    }
+
+    #[test]
+    fn opponents_sharing_a_good_region_are_preferred() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _handles: Vec<_> = [
+            player_near(1, 1000, &[("eu", 20), ("us", 150)]),
+            player_near(2, 1000, &[("eu", 150), ("us", 20)]),
+            player_near(3, 1040, &[("eu", 60)]),
+        ]
+        .into_iter()
+        .map(|info| matchmaker.clone().add_player_to_pool(info).unwrap())
+        .collect();
+        clock.advance(Duration::from_secs(31));
+
+        // 2 is closer in rating and may be matched across regions by now
+        assert_eq!(matchmaker.find_best_match(&1).unwrap().opponent, 3);
+    }
+
+    #[test]
+    fn cross_region_matches_wait_for_both_players() {
+        let (matchmaker, clock) = matchmaker_with_clock();
+        let _first = matchmaker
+            .clone()
+            .add_player_to_pool(player_near(1, 1000, &[("eu", 20)]))
+            .unwrap();
+        clock.advance(Duration::from_secs(20));
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player_near(2, 1000, &[("us", 20)]))
+            .unwrap();
+
+        clock.advance(Duration::from_secs(20));
+        assert!(matchmaker.find_best_match(&1).is_none());
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(matchmaker.find_best_match(&1).unwrap().opponent, 2);
+    }
+
+    #[test]
+    fn players_without_measurements_match_anyone() {
+        let (matchmaker, _clock) = matchmaker_with_clock();
+        let _first = matchmaker
+            .clone()
+            .add_player_to_pool(player_near(1, 1000, &[("eu", 20)]))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.find_best_match(&1).unwrap().opponent, 2);
+    }
+
+    #[test]
+    fn the_server_with_the_best_worst_latency_is_picked() {
+        let matchmaker = Matchmaker::<usize>::default();
+        add_ready_server(&matchmaker, "a", server_in("eu", 8));
+        add_ready_server(&matchmaker, "b", server_in("us", 8));
+        add_ready_server(&matchmaker, "c", server_in("asia", 8));
+
+        let found = matchmaker
+            .find_best_server_for_match(
+                &player_near(1, 1000, &[("eu", 20), ("us", 60)]),
+                &player_near(2, 1000, &[("eu", 70), ("us", 30)]),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(found.server, ServerId("b".to_string()));
+    }
+
+    #[test]
+    fn far_servers_are_only_used_across_regions() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", server_in("us", 8));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player_near(1, 1000, &[("eu", 20), ("us", 150)]))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player_near(2, 1000, &[("eu", 30), ("us", 140)]))
+            .unwrap();
+
+        assert_eq!(matchmaker.tick(), 0);
+        assert_eq!(matchmaker.queued_players(), 2);
+
+        clock.advance(Duration::from_secs(30));
+        matchmaker
+            .server_heartbeat(&ServerId("a".to_string()), "credential", 0, None)
+            .unwrap();
+        assert_eq!(tick_and_accept(&matchmaker), 1);
+        assert_eq!(try_matched(&mut first).unwrap().server.0, "a");
+    }
+
+    #[test]
+    fn tick_looks_at_no_more_than_the_batch_size() {
+        let (matchmaker, _clock) = tick_matchmaker(2, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(100, 0));
+        let _handles: Vec<_> = (0..8)
+            .map(|id| {
+                matchmaker
+                    .clone()
+                    .add_player_to_pool(player(id, 1000))
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(tick_and_accept(&matchmaker), 2);
+        assert_eq!(matchmaker.queued_players(), 4);
+        assert_eq!(tick_and_accept(&matchmaker), 2);
+        assert_eq!(matchmaker.queued_players(), 0);
+    }
+
+    #[test]
+    fn tick_serves_the_longest_waiting_players_first() {
+        let (matchmaker, clock) = tick_matchmaker(1, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(100, 0));
+        let mut handles: Vec<_> = (0..6)
+            .map(|id| {
+                clock.advance(Duration::from_secs(1));
+                matchmaker
+                    .clone()
+                    .add_player_to_pool(player(id, 1000))
+                    .unwrap()
+            })
+            .collect();
+
+        for pair in 0..3 {
+            assert_eq!(tick_and_accept(&matchmaker), 1);
+            for id in [pair * 2, pair * 2 + 1] {
+                assert!(try_matched(&mut handles[id]).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn tick_skips_players_whose_handle_dropped() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let _second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1010))
+            .unwrap();
+
+        // as if the handle dropped while the player was being matched
+        let (sender, _) = mpsc::unbounded_channel();
+        let stale = std::mem::replace(&mut matchmaker.players.get_mut(&1).unwrap().sender, sender);
+        std::mem::forget(first);
+        drop(stale);
+
+        assert_eq!(matchmaker.tick(), 0);
+        assert!(!matchmaker.is_queued(&1));
+        assert!(matchmaker.is_queued(&2));
+        assert_eq!(
+            matchmaker
+                .servers
+                .get(&ServerId("a".to_string()))
+                .unwrap()
+                .reserved_slots(matchmaker.now()),
+            0
+        );
+    }
+
+    fn reserved_slots(matchmaker: &Matchmaker<usize>, id: &str) -> usize {
+        matchmaker
+            .servers
+            .get(&ServerId(id.to_string()))
+            .unwrap()
+            .reserved_slots(matchmaker.now())
+    }
+
+    #[test]
+    fn slots_are_only_reserved_once_both_players_accept() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.tick(), 1);
+        let ready_check = try_ready_check(&mut first);
+        assert_eq!(
+            try_ready_check(&mut second).ready_check_id,
+            ready_check.ready_check_id
+        );
+        assert_eq!(reserved_slots(&matchmaker, "a"), 0);
+
+        first.accept(&ready_check).unwrap();
+        assert_eq!(reserved_slots(&matchmaker, "a"), 0);
+        assert!(try_event(&mut first).is_none());
+
+        second.accept(&ready_check).unwrap();
+        assert_eq!(reserved_slots(&matchmaker, "a"), 2);
+        assert_eq!(try_matched(&mut first).unwrap().server.0, "a");
+        assert_eq!(try_matched(&mut second).unwrap().server.0, "a");
+    }
+
+    #[test]
+    fn reserved_slots_saturate_on_top_of_reported_players() {
+        let (matchmaker, _clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.tick(), 1);
+        let ready_check = try_ready_check(&mut first);
+        try_ready_check(&mut second);
+        first.accept(&ready_check).unwrap();
+        second.accept(&ready_check).unwrap();
+        assert_eq!(reserved_slots(&matchmaker, "a"), 2);
+
+        let id = ServerId("a".to_string());
+        matchmaker
+            .server_heartbeat(&id, "credential", usize::MAX, None)
+            .unwrap();
+        let _third = matchmaker
+            .clone()
+            .add_player_to_pool(player(3, 1000))
+            .unwrap();
+        let _fourth = matchmaker
+            .clone()
+            .add_player_to_pool(player(4, 1000))
+            .unwrap();
+
+        // the server is full either way, so nobody gets a ready check
+        assert_eq!(matchmaker.tick(), 0);
+    }
+
+    #[test]
+    fn declining_requeues_the_opponent_with_their_wait_time() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        clock.advance(Duration::from_secs(5));
+
+        assert_eq!(matchmaker.tick(), 1);
+        let ready_check = try_ready_check(&mut first);
+        try_ready_check(&mut second);
+        first.accept(&ready_check).unwrap();
+
+        assert_eq!(
+            second.decline(&ready_check).unwrap(),
+            Duration::from_secs(30)
+        );
+        assert!(matches!(try_event(&mut first), Some(PlayerEvent::Requeued)));
+        assert_eq!(first.waited(), Some(Duration::from_secs(5)));
+        assert!(!matchmaker.is_queued(&2));
+        assert!(first.accept(&ready_check).is_err());
+        assert_eq!(reserved_slots(&matchmaker, "a"), 0);
+
+        drop(second);
+        assert!(matches!(
+            matchmaker.clone().add_player_to_pool(player(2, 1000)),
+            Err(QueueError::CoolingDown { .. })
+        ));
+        clock.advance(Duration::from_secs(30));
+        assert!(matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .is_ok());
+    }
+
+    #[test]
+    fn not_accepting_in_time_counts_as_declining() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+
+        assert_eq!(matchmaker.tick(), 1);
+        let ready_check = try_ready_check(&mut first);
+        try_ready_check(&mut second);
+        first.accept(&ready_check).unwrap();
+
+        clock.advance(Duration::from_secs(15));
+        assert!(second.accept(&ready_check).is_err());
+        matchmaker
+            .server_heartbeat(&ServerId("a".to_string()), "credential", 0, None)
+            .unwrap();
+        assert_eq!(matchmaker.tick(), 0);
+
+        assert!(matches!(try_event(&mut first), Some(PlayerEvent::Requeued)));
+        assert!(matchmaker.is_queued(&1));
+        assert!(matches!(
+            try_event(&mut second),
+            Some(PlayerEvent::TimedOut { cooldown }) if cooldown == Duration::from_secs(30)
+        ));
+        assert!(!matchmaker.is_queued(&2));
+    }
+
+    #[test]
+    fn the_cooldown_grows_with_every_decline_until_forgotten() {
+        let (matchmaker, clock) = tick_matchmaker(256, true);
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+
+        let decline = || {
+            let _opponent = matchmaker
+                .clone()
+                .add_player_to_pool(player(1, 1000))
+                .unwrap();
+            let mut decliner = matchmaker
+                .clone()
+                .add_player_to_pool(player(2, 1000))
+                .unwrap();
+            matchmaker
+                .server_heartbeat(&ServerId("a".to_string()), "credential", 0, None)
+                .unwrap();
+            assert_eq!(matchmaker.tick(), 1);
+
+            let ready_check = try_ready_check(&mut decliner);
+            let cooldown = decliner.decline(&ready_check).unwrap();
+            clock.advance(cooldown);
+            cooldown.as_secs()
+        };
+
+        let cooldowns: Vec<_> = (0..7).map(|_| decline()).collect();
+        assert_eq!(cooldowns, [30, 60, 120, 240, 480, 900, 900]);
+
+        clock.advance(Duration::from_secs(60 * 60));
+        assert_eq!(decline(), 30);
+    }
+
+    #[tokio::test]
+    async fn tick_loop_resolves_handles_until_shutdown() {
+        let config = MatchmakerConfig {
+            tick_interval: Duration::from_millis(10),
+            ..MatchmakerConfig::default()
+        };
+        let matchmaker = Arc::new(Matchmaker::new(config));
+        add_ready_server(&matchmaker, "a", ServerInfo::test(4, 0));
+        let tick_loop = matchmaker.spawn_tick_loop();
+
+        let mut first = matchmaker
+            .clone()
+            .add_player_to_pool(player(1, 1000))
+            .unwrap();
+        let mut second = matchmaker
+            .clone()
+            .add_player_to_pool(player(2, 1000))
+            .unwrap();
+        for handle in [&mut first, &mut second] {
+            let Some(PlayerEvent::ReadyCheck(ready_check)) = handle.next_event().await else {
+                panic!("expected a ready check");
+            };
+            handle.accept(&ready_check).unwrap();
+        }
+        for handle in [&mut first, &mut second] {
+            assert!(matches!(
+                handle.next_event().await,
+                Some(PlayerEvent::Matched(_))
+            ));
+            assert!(handle.next_event().await.is_none());
+        }
+
+        let mut waiting = matchmaker
+            .clone()
+            .add_player_to_pool(player(3, 1000))
+            .unwrap();
+        matchmaker.shutdown();
+        assert!(waiting.next_event().await.is_none());
+        tick_loop.await.unwrap();
+    }
 }
@@ -0,0 +1,120 @@
+//! Messages on the `/queue` websocket. Every message is a JSON object with the protocol
+//! `version` and a `type`, next to the fields of that type.
+//!
+//! Players are identified by the auth token they opened the connection with, never by anything
+//! they send.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// `join` messages measuring more regions than this are rejected.
+pub const MAX_REPORTED_REGIONS: usize = 64;
+
+#[derive(Debug, Deserialize)]
+pub struct ClientEnvelope {
+    pub version: u32,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Join {
+        elo: usize,
+        /// The round trip time the client measured to each server region, in milliseconds.
+        /// Regions left out are treated as too far away, unless none are reported at all.
+        #[serde(default)]
+        region_rtts_ms: HashMap<String, u64>,
+    },
+    /// Only valid during a `ready_check`.
+    Accept,
+    /// Only valid during a `ready_check`. Leaves the queue as well, and the player can't join
+    /// again until their cooldown is over.
+    Decline,
+    /// Valid while queued, or after `match_found` to be able to join again. During a
+    /// `ready_check` it counts as declining.
+    Leave,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerEnvelope {
+    pub version: u32,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+impl From<ServerMessage> for ServerEnvelope {
+    fn from(message: ServerMessage) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Queued,
+    /// Sent periodically while queued.
+    Status {
+        waited_secs: u64,
+        /// How long recently matched players waited in total. Missing until someone has been
+        /// matched.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_wait_secs: Option<u64>,
+        players_in_queue: usize,
+    },
+    /// An opponent was found. Both players have to `accept` within the timeout, or the
+    /// ready check fails.
+    ReadyCheck {
+        timeout_secs: u64,
+    },
+    /// Sent after both players accepted.
+    MatchFound {
+        match_id: String,
+        server_address: String,
+        /// Handed to the game server to prove the player was matched onto it. Only valid for a
+        /// short while, and only once.
+        join_ticket: String,
+    },
+    /// `match_found` follows once the opponent has accepted too.
+    Accepted,
+    /// The opponent declined or didn't accept in time. The player is queued again, keeping
+    /// their wait time.
+    Requeued,
+    /// The player declined, or didn't accept in time. They are no longer queued, and can't join
+    /// again for the cooldown, which grows with every decline.
+    Declined {
+        cooldown_secs: u64,
+    },
+    /// The match fell through after it was found, e.g. because its server went away or a join
+    /// ticket couldn't be issued. The player is no longer queued and has to join again.
+    MatchFailed {
+        match_id: String,
+    },
+    Left,
+    /// Sent right before the matchmaker closes the connection.
+    ShuttingDown,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The connection is closed after this one.
+    UnsupportedVersion,
+    InvalidMessage,
+    /// The message isn't valid in the connection's current state, e.g. `accept` while queued.
+    UnexpectedMessage,
+    AlreadyQueued,
+    /// The player declined a match recently and can't join yet.
+    CoolingDown,
+    NoServer,
+}
@@ -0,0 +1,762 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{auth_token, AuthProvider, UserId},
+    matchmaker::{
+        Match, Matchmaker, MatchmakerPlayerHandle, PlayerEvent, PlayerInfo, QueueError, ReadyCheck,
+    },
+    protocol::{
+        ClientEnvelope, ClientMessage, ErrorCode, ServerEnvelope, ServerMessage,
+        MAX_REPORTED_REGIONS, PROTOCOL_VERSION,
+    },
+    AppState,
+};
+
+/// How often queued players are sent their status.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/queue", get(upgrade))
+}
+
+// players are authenticated before the upgrade, so a rejected client gets a plain http error
+async fn upgrade(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let Some(token) = auth_token(&headers, &state.auth_cookie_name) else {
+        return (StatusCode::UNAUTHORIZED, "missing credentials").into_response();
+    };
+
+    let user_id = match state.auth.resolve_token(&token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return (StatusCode::UNAUTHORIZED, "invalid or expired credentials").into_response()
+        }
+        Err(error) => {
+            warn!(?error, "failed to authenticate queue connection");
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    let matchmaker = state.matchmaker;
+    let auth = state.auth;
+    upgrade.on_upgrade(move |socket| {
+        let session = Session {
+            socket,
+            matchmaker: matchmaker.clone(),
+            auth,
+            user_id,
+            token,
+        };
+        matchmaker.track_connection(session.run())
+    })
+}
+
+enum SessionState {
+    Idle,
+    Queued(MatchmakerPlayerHandle<UserId>),
+    /// Still holds the handle, the player is queued again if the opponent doesn't accept.
+    ReadyCheck(MatchmakerPlayerHandle<UserId>, ReadyCheck),
+    Matched(Match<UserId>),
+}
+
+enum Event {
+    Socket(Option<Result<Message, axum::Error>>),
+    Player(Option<PlayerEvent<UserId>>),
+    MatchFailed,
+    Status,
+    Shutdown,
+}
+
+/// What to do with the connection after handling an event.
+enum Flow {
+    Continue(SessionState),
+    Close,
+}
+
+struct Session {
+    socket: WebSocket,
+    matchmaker: Arc<Matchmaker<UserId>>,
+    auth: Arc<dyn AuthProvider>,
+    user_id: UserId,
+    /// Join tickets are issued against the token the connection was opened with.
+    token: String,
+}
+
+impl Session {
+    // the player handle lives in the state, so it is dropped, and the player removed from the
+    // pool, whenever this returns
+    async fn run(mut self) {
+        let matchmaker = self.matchmaker.clone();
+        let mut state = SessionState::Idle;
+        let mut status_interval = tokio::time::interval(STATUS_INTERVAL);
+        status_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            let event = match &mut state {
+                SessionState::Queued(handle) => tokio::select! {
+                    message = self.socket.recv() => Event::Socket(message),
+                    event = handle.next_event() => Event::Player(event),
+                    _ = status_interval.tick() => Event::Status,
+                    _ = matchmaker.shutting_down() => Event::Shutdown,
+                },
+                SessionState::ReadyCheck(handle, _) => tokio::select! {
+                    message = self.socket.recv() => Event::Socket(message),
+                    event = handle.next_event() => Event::Player(event),
+                    _ = matchmaker.shutting_down() => Event::Shutdown,
+                },
+                SessionState::Matched(found) => tokio::select! {
+                    message = self.socket.recv() => Event::Socket(message),
+                    _ = found.failed() => Event::MatchFailed,
+                    _ = matchmaker.shutting_down() => Event::Shutdown,
+                },
+                SessionState::Idle => tokio::select! {
+                    message = self.socket.recv() => Event::Socket(message),
+                    _ = matchmaker.shutting_down() => Event::Shutdown,
+                },
+            };
+
+            let was_queued = matches!(state, SessionState::Queued(_));
+            let flow = match event {
+                Event::Socket(message) => self.handle_socket_message(message, state).await,
+                Event::Player(event) => self.handle_player_event(event, state).await,
+                Event::MatchFailed => self.handle_match_failure(state).await,
+                Event::Status => {
+                    if let SessionState::Queued(handle) = &state {
+                        self.send_status(handle).await;
+                    }
+                    Flow::Continue(state)
+                }
+                Event::Shutdown => self.close_for_shutdown().await,
+            };
+
+            // joining starts a fresh round of status updates
+            if let Flow::Continue(SessionState::Queued(_)) = &flow {
+                if !was_queued {
+                    status_interval.reset();
+                }
+            }
+
+            state = match flow {
+                Flow::Continue(state) => state,
+                Flow::Close => return,
+            };
+        }
+    }
+
+    async fn handle_socket_message(
+        &mut self,
+        message: Option<Result<Message, axum::Error>>,
+        state: SessionState,
+    ) -> Flow {
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => return Flow::Close,
+            // pings are answered by axum
+            Some(Ok(_)) => return Flow::Continue(state),
+            Some(Err(error)) => {
+                debug!(?error, "queue connection failed");
+                return Flow::Close;
+            }
+        };
+
+        let envelope: ClientEnvelope = match serde_json::from_str(&text) {
+            Ok(envelope) => envelope,
+            Err(error) => {
+                self.send_error(ErrorCode::InvalidMessage, error.to_string())
+                    .await;
+                return Flow::Continue(state);
+            }
+        };
+
+        if envelope.version != PROTOCOL_VERSION {
+            self.send_error(
+                ErrorCode::UnsupportedVersion,
+                format!("only version {PROTOCOL_VERSION} is supported"),
+            )
+            .await;
+            return self.close().await;
+        }
+
+        self.handle_client_message(envelope.message, state).await
+    }
+
+    async fn handle_client_message(&mut self, message: ClientMessage, state: SessionState) -> Flow {
+        match (message, state) {
+            (
+                ClientMessage::Join {
+                    elo,
+                    region_rtts_ms,
+                },
+                SessionState::Idle,
+            ) => {
+                if region_rtts_ms.len() > MAX_REPORTED_REGIONS {
+                    self.send_error(
+                        ErrorCode::InvalidMessage,
+                        format!("at most {MAX_REPORTED_REGIONS} regions can be reported"),
+                    )
+                    .await;
+                    return Flow::Continue(SessionState::Idle);
+                }
+
+                let info = PlayerInfo {
+                    id: self.user_id.clone(),
+                    elo,
+                    region_rtts: region_rtts_ms
+                        .into_iter()
+                        .map(|(region, rtt)| (region, Duration::from_millis(rtt)))
+                        .collect(),
+                };
+
+                // the same user queueing from a second connection is turned away here too
+                match self.matchmaker.clone().add_player_to_pool(info) {
+                    Ok(handle) => {
+                        self.send(ServerMessage::Queued).await;
+                        Flow::Continue(SessionState::Queued(handle))
+                    }
+                    Err(QueueError::AlreadyQueued) => {
+                        self.send_error(ErrorCode::AlreadyQueued, QueueError::AlreadyQueued)
+                            .await;
+                        Flow::Continue(SessionState::Idle)
+                    }
+                    Err(error @ QueueError::CoolingDown { .. }) => {
+                        self.send_error(ErrorCode::CoolingDown, error).await;
+                        Flow::Continue(SessionState::Idle)
+                    }
+                    Err(QueueError::ShuttingDown) => self.close_for_shutdown().await,
+                }
+            }
+            (ClientMessage::Leave, SessionState::Queued(handle)) => {
+                drop(handle);
+                self.send(ServerMessage::Left).await;
+                Flow::Continue(SessionState::Idle)
+            }
+            (ClientMessage::Leave, SessionState::Matched(found)) => {
+                debug!(match_id = found.match_id, "left match");
+                self.send(ServerMessage::Left).await;
+                Flow::Continue(SessionState::Idle)
+            }
+            (ClientMessage::Accept, SessionState::ReadyCheck(handle, ready_check)) => {
+                match handle.accept(&ready_check) {
+                    Ok(()) => self.send(ServerMessage::Accepted).await,
+                    // whatever ended it is on its way
+                    Err(error) => self.send_error(ErrorCode::UnexpectedMessage, error).await,
+                }
+                Flow::Continue(SessionState::ReadyCheck(handle, ready_check))
+            }
+            // leaving during a ready check is declining it, so the opponent isn't left waiting
+            (
+                ClientMessage::Decline | ClientMessage::Leave,
+                SessionState::ReadyCheck(handle, ready_check),
+            ) => {
+                let declined = handle.decline(&ready_check);
+                drop(handle);
+                match declined {
+                    Ok(cooldown) => {
+                        self.send(ServerMessage::Declined {
+                            cooldown_secs: cooldown.as_secs(),
+                        })
+                        .await
+                    }
+                    // the opponent got there first, the player only leaves the queue
+                    Err(_) => self.send(ServerMessage::Left).await,
+                }
+                Flow::Continue(SessionState::Idle)
+            }
+            (message, state) => {
+                self.send_error(
+                    ErrorCode::UnexpectedMessage,
+                    format!("{message:?} isn't valid right now"),
+                )
+                .await;
+                Flow::Continue(state)
+            }
+        }
+    }
+
+    async fn handle_player_event(
+        &mut self,
+        event: Option<PlayerEvent<UserId>>,
+        state: SessionState,
+    ) -> Flow {
+        let handle = match state {
+            SessionState::Queued(handle) | SessionState::ReadyCheck(handle, _) => handle,
+            state => return Flow::Continue(state),
+        };
+
+        match event {
+            // only happens when the pool is cleared for shutdown
+            None => self.close_for_shutdown().await,
+            Some(PlayerEvent::ReadyCheck(ready_check)) => {
+                self.send(ServerMessage::ReadyCheck {
+                    timeout_secs: ready_check.timeout.as_secs(),
+                })
+                .await;
+                Flow::Continue(SessionState::ReadyCheck(handle, ready_check))
+            }
+            Some(PlayerEvent::Matched(found)) => self.handle_match(found).await,
+            Some(PlayerEvent::Requeued) => {
+                self.send(ServerMessage::Requeued).await;
+                Flow::Continue(SessionState::Queued(handle))
+            }
+            Some(PlayerEvent::TimedOut { cooldown }) => {
+                self.send(ServerMessage::Declined {
+                    cooldown_secs: cooldown.as_secs(),
+                })
+                .await;
+                Flow::Continue(SessionState::Idle)
+            }
+        }
+    }
+
+    async fn handle_match(&mut self, found: Match<UserId>) -> Flow {
+        let (Some(server_address), Some(credential_id)) = (
+            self.matchmaker.server_address(&found.server),
+            self.matchmaker.server_credential_id(&found.server),
+        ) else {
+            warn!(match_id = found.match_id, "matched server disappeared");
+            self.send_error(
+                ErrorCode::NoServer,
+                "the matched server went away, queue again",
+            )
+            .await;
+            return Flow::Continue(SessionState::Idle);
+        };
+
+        let join_ticket = match self
+            .auth
+            .issue_join_ticket(&self.token, &found.match_id, &credential_id)
+            .await
+        {
+            Ok(Some(ticket)) => ticket,
+            result => {
+                if let Err(error) = result {
+                    warn!(
+                        ?error,
+                        match_id = found.match_id,
+                        "failed to issue join ticket"
+                    );
+                }
+                // the server would turn the player away, so the opponent can't play either
+                found.fail();
+                return self
+                    .handle_match_failure(SessionState::Matched(found))
+                    .await;
+            }
+        };
+
+        self.send(ServerMessage::MatchFound {
+            match_id: found.match_id.clone(),
+            server_address,
+            join_ticket,
+        })
+        .await;
+
+        Flow::Continue(SessionState::Matched(found))
+    }
+
+    async fn handle_match_failure(&mut self, state: SessionState) -> Flow {
+        let SessionState::Matched(found) = state else {
+            return Flow::Continue(state);
+        };
+
+        debug!(match_id = found.match_id, "match failed");
+        self.send(ServerMessage::MatchFailed {
+            match_id: found.match_id,
+        })
+        .await;
+
+        Flow::Continue(SessionState::Idle)
+    }
+
+    async fn send_status(&mut self, handle: &MatchmakerPlayerHandle<UserId>) {
+        let Some(waited) = handle.waited() else {
+            return;
+        };
+
+        self.send(ServerMessage::Status {
+            waited_secs: waited.as_secs(),
+            estimated_wait_secs: self
+                .matchmaker
+                .estimated_wait()
+                .map(|estimate| estimate.as_secs()),
+            players_in_queue: self.matchmaker.queued_players(),
+        })
+        .await;
+    }
+
+    async fn close_for_shutdown(&mut self) -> Flow {
+        self.send(ServerMessage::ShuttingDown).await;
+        self.close().await
+    }
+
+    async fn close(&mut self) -> Flow {
+        let _ = self.socket.send(Message::Close(None)).await;
+
+        Flow::Close
+    }
+
+    async fn send_error(&mut self, code: ErrorCode, message: impl ToString) {
+        self.send(ServerMessage::Error {
+            code,
+            message: message.to_string(),
+        })
+        .await;
+    }
+
+    // a failed send shows up as a closed connection on the next receive
+    async fn send(&mut self, message: ServerMessage) {
+        let text = serde_json::to_string(&ServerEnvelope::from(message))
+            .expect("server messages are always serializable");
+
+        if let Err(error) = self.socket.send(Message::Text(text)).await {
+            debug!(?error, "failed to send to queue connection");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        tungstenite::{self, client::IntoClientRequest, http::header},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use super::*;
+    use crate::{
+        auth::StubAuth,
+        matchmaker::{ServerId, ServerInfo},
+    };
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    fn user(id: &str) -> UserId {
+        UserId(id.to_string())
+    }
+
+    async fn serve() -> (String, Arc<Matchmaker<UserId>>) {
+        serve_with(|_| {}).await
+    }
+
+    async fn serve_with(
+        configure: impl FnOnce(&mut StubAuth),
+    ) -> (String, Arc<Matchmaker<UserId>>) {
+        let mut auth = StubAuth {
+            players: HashMap::from([
+                ("alice-token".to_string(), user("alice")),
+                ("bob-token".to_string(), user("bob")),
+            ]),
+            ..StubAuth::default()
+        };
+        configure(&mut auth);
+
+        let (address, matchmaker) = auth.serve(routes()).await;
+        (format!("ws://{address}/queue"), matchmaker)
+    }
+
+    async fn connect(
+        url: &str,
+        header: Option<(header::HeaderName, &str)>,
+    ) -> Result<Client, tungstenite::Error> {
+        let mut request = url.into_client_request().unwrap();
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(name, value.parse().unwrap());
+        }
+
+        tokio_tungstenite::connect_async(request)
+            .await
+            .map(|(client, _)| client)
+    }
+
+    async fn connect_as(url: &str, token: &str) -> Client {
+        connect(
+            url,
+            Some((header::AUTHORIZATION, &format!("Bearer {token}"))),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn rejection_status(result: Result<Client, tungstenite::Error>) -> StatusCode {
+        match result {
+            Err(tungstenite::Error::Http(response)) => response.status(),
+            Err(error) => panic!("connection failed: {error}"),
+            Ok(_) => panic!("connection was accepted"),
+        }
+    }
+
+    async fn join(client: &mut Client, elo: usize) -> Value {
+        let message = json!({ "version": PROTOCOL_VERSION, "type": "join", "elo": elo });
+        client
+            .send(tungstenite::Message::Text(message.to_string()))
+            .await
+            .unwrap();
+
+        receive(client).await
+    }
+
+    async fn send(client: &mut Client, kind: &str) -> Value {
+        let message = json!({ "version": PROTOCOL_VERSION, "type": kind });
+        client
+            .send(tungstenite::Message::Text(message.to_string()))
+            .await
+            .unwrap();
+
+        receive(client).await
+    }
+
+    async fn receive(client: &mut Client) -> Value {
+        match client.next().await.unwrap().unwrap() {
+            tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_without_valid_credentials_are_rejected() {
+        let (url, _) = serve().await;
+
+        assert_eq!(
+            rejection_status(connect(&url, None).await),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            rejection_status(
+                connect(&url, Some((header::AUTHORIZATION, "Bearer mallory-token"))).await
+            ),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            rejection_status(
+                connect(&url, Some((header::AUTHORIZATION, "Bearer unavailable"))).await
+            ),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn joining_queues_the_authenticated_user() {
+        let (url, matchmaker) = serve().await;
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+
+        let mut bob = connect(&url, Some((header::COOKIE, "AuthToken=bob-token")))
+            .await
+            .unwrap();
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert!(matchmaker.is_queued(&user("alice")));
+        assert!(matchmaker.is_queued(&user("bob")));
+        assert_eq!(matchmaker.queued_players(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_bearer_token_wins_over_the_cookie() {
+        let (url, matchmaker) = serve().await;
+
+        let mut request = url.as_str().into_client_request().unwrap();
+        let headers = request.headers_mut();
+        headers.insert(header::AUTHORIZATION, "Bearer alice-token".parse().unwrap());
+        headers.insert(header::COOKIE, "AuthToken=bob-token".parse().unwrap());
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        assert_eq!(join(&mut client, 1000).await["type"], "queued");
+        assert!(matchmaker.is_queued(&user("alice")));
+        assert!(!matchmaker.is_queued(&user("bob")));
+    }
+
+    #[tokio::test]
+    async fn a_second_connection_for_the_same_user_cannot_queue() {
+        let (url, matchmaker) = serve().await;
+
+        let mut first = connect_as(&url, "alice-token").await;
+        let mut second = connect_as(&url, "alice-token").await;
+
+        assert_eq!(join(&mut first, 1000).await["type"], "queued");
+
+        let rejected = join(&mut second, 1200).await;
+        assert_eq!(rejected["type"], "error");
+        assert_eq!(rejected["code"], "already_queued");
+        assert_eq!(matchmaker.queued_players(), 1);
+
+        // the entry belongs to the first connection, so only it leaving frees the user up
+        drop(second);
+        first.close(None).await.unwrap();
+        while matchmaker.has_session(&user("alice")) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut third = connect_as(&url, "alice-token").await;
+        assert_eq!(join(&mut third, 1000).await["type"], "queued");
+    }
+
+    fn add_ready_server(matchmaker: &Matchmaker<UserId>) -> ServerId {
+        let server_id = ServerId("a".to_string());
+        matchmaker
+            .add_server(server_id.clone(), ServerInfo::test(4, 0))
+            .unwrap();
+        matchmaker
+            .report_server_ready(&server_id, "credential")
+            .unwrap();
+
+        server_id
+    }
+
+    #[tokio::test]
+    async fn declining_requeues_the_opponent_and_cools_the_player_down() {
+        let (url, matchmaker) = serve().await;
+        add_ready_server(&matchmaker);
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        let mut bob = connect_as(&url, "bob-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert_eq!(matchmaker.tick(), 1);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "ready_check");
+        }
+        assert_eq!(send(&mut alice, "accept").await["type"], "accepted");
+
+        let declined = send(&mut bob, "decline").await;
+        assert_eq!(declined["type"], "declined");
+        assert_eq!(declined["cooldown_secs"], 30);
+        assert_eq!(receive(&mut alice).await["type"], "requeued");
+        assert!(matchmaker.is_queued(&user("alice")));
+
+        let rejected = join(&mut bob, 1000).await;
+        assert_eq!(rejected["type"], "error");
+        assert_eq!(rejected["code"], "cooling_down");
+    }
+
+    #[tokio::test]
+    async fn a_second_connection_cannot_queue_during_a_ready_check() {
+        let (url, matchmaker) = serve().await;
+        add_ready_server(&matchmaker);
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        let mut bob = connect_as(&url, "bob-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert_eq!(matchmaker.tick(), 1);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "ready_check");
+        }
+        assert!(!matchmaker.is_queued(&user("alice")));
+
+        let mut second = connect_as(&url, "alice-token").await;
+        let rejected = join(&mut second, 1000).await;
+        assert_eq!(rejected["type"], "error");
+        assert_eq!(rejected["code"], "already_queued");
+
+        // the first connection is still the one queued again when the ready check fails
+        assert_eq!(send(&mut alice, "accept").await["type"], "accepted");
+        assert_eq!(send(&mut bob, "decline").await["type"], "declined");
+        assert_eq!(receive(&mut alice).await["type"], "requeued");
+        assert!(matchmaker.is_queued(&user("alice")));
+        assert_eq!(join(&mut second, 1000).await["code"], "already_queued");
+    }
+
+    #[tokio::test]
+    async fn players_are_told_when_their_server_goes_away() {
+        let (url, matchmaker) = serve().await;
+        let server_id = add_ready_server(&matchmaker);
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        let mut bob = connect_as(&url, "bob-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert_eq!(matchmaker.tick(), 1);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "ready_check");
+        }
+        assert_eq!(send(&mut alice, "accept").await["type"], "accepted");
+        assert_eq!(send(&mut bob, "accept").await["type"], "accepted");
+        let found = receive(&mut alice).await;
+        assert_eq!(found["type"], "match_found");
+        assert_eq!(
+            found["join_ticket"],
+            format!("{}:alice@credential", found["match_id"].as_str().unwrap())
+        );
+        assert_eq!(receive(&mut bob).await["type"], "match_found");
+
+        matchmaker.remove_server(&server_id);
+
+        for client in [&mut alice, &mut bob] {
+            let failed = receive(client).await;
+            assert_eq!(failed["type"], "match_failed");
+            assert_eq!(failed["match_id"], found["match_id"]);
+        }
+        // back to idle, so they can queue again
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+    }
+
+    #[tokio::test]
+    async fn matches_fail_without_join_tickets() {
+        let (url, matchmaker) = serve_with(|auth| auth.tickets_unavailable = true).await;
+        add_ready_server(&matchmaker);
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        let mut bob = connect_as(&url, "bob-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert_eq!(matchmaker.tick(), 1);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "ready_check");
+        }
+        assert_eq!(send(&mut alice, "accept").await["type"], "accepted");
+        assert_eq!(send(&mut bob, "accept").await["type"], "accepted");
+
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "match_failed");
+        }
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+    }
+
+    #[tokio::test]
+    async fn leaving_during_a_ready_check_declines_it() {
+        let (url, matchmaker) = serve().await;
+        add_ready_server(&matchmaker);
+
+        let mut alice = connect_as(&url, "alice-token").await;
+        let mut bob = connect_as(&url, "bob-token").await;
+        assert_eq!(join(&mut alice, 1000).await["type"], "queued");
+        assert_eq!(join(&mut bob, 1000).await["type"], "queued");
+
+        assert_eq!(matchmaker.tick(), 1);
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(receive(client).await["type"], "ready_check");
+        }
+
+        assert_eq!(send(&mut bob, "leave").await["type"], "declined");
+        assert_eq!(receive(&mut alice).await["type"], "requeued");
+        assert!(!matchmaker.is_queued(&user("bob")));
+
+        // and leaving works from the queue the opponent was put back in
+        assert_eq!(send(&mut alice, "leave").await["type"], "left");
+        assert!(!matchmaker.is_queued(&user("alice")));
+    }
+}
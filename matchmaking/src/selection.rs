@@ -0,0 +1,172 @@
+//! Which server a match is sent to. The matchmaker only offers servers that are accepting
+//! players and have room for the whole match, and reserves the slots on whichever one is picked.
+
+use std::{
+    cmp::Ordering as CmpOrdering,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::matchmaker::{ServerId, ServerInfo};
+
+/// A server that could take the match. `current_players` counts slots reserved for matches
+/// whose players haven't joined yet.
+#[derive(Debug, Clone)]
+pub struct ServerCandidate {
+    pub server_id: ServerId,
+    pub info: ServerInfo,
+}
+
+pub trait ServerSelector: Send + Sync {
+    /// `candidates` is never empty, and sorted by id so the order is stable between calls.
+    /// Returning `None` leaves the match unplaced until the next tick.
+    fn select(&self, candidates: &[ServerCandidate]) -> Option<ServerId>;
+}
+
+/// Sends players to the emptiest server, relative to its size, so they are spread out and
+/// each server stays responsive.
+#[derive(Debug, Default)]
+pub struct LeastLoaded;
+
+impl ServerSelector for LeastLoaded {
+    fn select(&self, candidates: &[ServerCandidate]) -> Option<ServerId> {
+        candidates
+            .iter()
+            .min_by(|a, b| {
+                compare_load(&a.info, &b.info).then(b.info.free_slots().cmp(&a.info.free_slots()))
+            })
+            .map(|candidate| candidate.server_id.clone())
+    }
+}
+
+/// Sends players to the fullest server that still fits the match, so idle servers stay idle
+/// and can be scaled down.
+#[derive(Debug, Default)]
+pub struct BinPacking;
+
+impl ServerSelector for BinPacking {
+    fn select(&self, candidates: &[ServerCandidate]) -> Option<ServerId> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| candidate.info.free_slots())
+            .map(|candidate| candidate.server_id.clone())
+    }
+}
+
+/// Takes turns, regardless of how full each server is.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl ServerSelector for RoundRobin {
+    fn select(&self, candidates: &[ServerCandidate]) -> Option<ServerId> {
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+
+        candidates
+            .get(turn % candidates.len())
+            .map(|candidate| candidate.server_id.clone())
+    }
+}
+
+// compares current / max without going through floats, widened so the products can't overflow
+fn compare_load(a: &ServerInfo, b: &ServerInfo) -> CmpOrdering {
+    let product = |x: usize, y: usize| x as u128 * y as u128;
+
+    product(a.current_players, b.max_players).cmp(&product(b.current_players, a.max_players))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ServerSelection {
+    #[default]
+    LeastLoaded,
+    BinPacking,
+    RoundRobin,
+}
+
+impl ServerSelection {
+    pub fn selector(self) -> Box<dyn ServerSelector> {
+        match self {
+            ServerSelection::LeastLoaded => Box::new(LeastLoaded),
+            ServerSelection::BinPacking => Box::new(BinPacking),
+            ServerSelection::RoundRobin => Box::new(RoundRobin::default()),
+        }
+    }
+}
+
+impl FromStr for ServerSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "least_loaded" => Ok(ServerSelection::LeastLoaded),
+            "bin_packing" => Ok(ServerSelection::BinPacking),
+            "round_robin" => Ok(ServerSelection::RoundRobin),
+            _ => Err(format!(
+                "unknown server selection `{s}`, expected least_loaded, bin_packing or round_robin"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchmaker::ServerState;
+
+    fn candidates(servers: &[(&str, usize, usize)]) -> Vec<ServerCandidate> {
+        servers
+            .iter()
+            .map(|(id, max_players, current_players)| ServerCandidate {
+                server_id: ServerId(id.to_string()),
+                info: ServerInfo {
+                    state: ServerState::Normal,
+                    ..ServerInfo::test(*max_players, *current_players)
+                },
+            })
+            .collect()
+    }
+
+    fn picked(selector: &dyn ServerSelector, servers: &[ServerCandidate]) -> String {
+        selector.select(servers).unwrap().0
+    }
+
+    #[test]
+    fn least_loaded_goes_by_how_full_servers_are() {
+        // a is a quarter full, b half full, despite having more free slots
+        let servers = candidates(&[("a", 8, 2), ("b", 100, 50)]);
+        assert_eq!(picked(&LeastLoaded, &servers), "a");
+
+        // equally loaded, the larger one has more room
+        let servers = candidates(&[("a", 8, 4), ("b", 16, 8)]);
+        assert_eq!(picked(&LeastLoaded, &servers), "b");
+
+        // the products don't fit in a usize
+        let servers = candidates(&[("a", usize::MAX, usize::MAX / 2), ("b", usize::MAX, 1)]);
+        assert_eq!(picked(&LeastLoaded, &servers), "b");
+    }
+
+    #[test]
+    fn bin_packing_goes_for_the_fullest_server() {
+        let servers = candidates(&[("a", 8, 0), ("b", 100, 97), ("c", 8, 5)]);
+        assert_eq!(picked(&BinPacking, &servers), "b");
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let selector = RoundRobin::default();
+        let servers = candidates(&[("a", 8, 0), ("b", 8, 6), ("c", 8, 2)]);
+
+        let picks: Vec<_> = (0..4).map(|_| picked(&selector, &servers)).collect();
+        assert_eq!(picks, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn selection_parses_from_config() {
+        assert_eq!(
+            "bin_packing".parse::<ServerSelection>(),
+            Ok(ServerSelection::BinPacking)
+        );
+        assert!("fastest".parse::<ServerSelection>().is_err());
+    }
+}
@@ -0,0 +1,607 @@
+//! Where game servers register themselves with the matchmaker, and keep sending heartbeats for
+//! as long as they want players.
+//!
+//! A server starts out in `startup` and only gets matches once it has reported itself ready.
+//! Draining, whether asked for by the server or an admin, stops new matches, and the server is
+//! removed once its last player has left.
+//!
+//! Once the matchmaker is shutting down, registrations and heartbeats are answered with
+//! `503 Service Unavailable` and `{"shutting_down": true}`. It keeps listening until every
+//! registered server has heard this from a heartbeat, or for one heartbeat timeout. Servers
+//! should keep trying to register until it is back.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{ServiceCaller, GAME_SERVER_SCOPE, MANAGE_SERVERS_SCOPE},
+    matchmaker::{DrainedBy, ServerError, ServerId, ServerInfo, ServerState, TransitionCause},
+    AppState,
+};
+
+/// Registrations and heartbeats with more players than this are rejected.
+pub const MAX_PLAYERS: usize = 100_000;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/servers", get(list).post(register))
+        .route("/servers/transitions", get(transitions))
+        .route("/servers/:server_id/heartbeat", post(heartbeat))
+        .route("/servers/:server_id/ready", post(ready))
+        .route("/servers/:server_id/drain", post(drain))
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            // e.g. evicted after missing heartbeats, the server should register again
+            ServerError::NotRegistered => StatusCode::NOT_FOUND,
+            ServerError::NotOwner => StatusCode::FORBIDDEN,
+            ServerError::InvalidTransition { .. } => StatusCode::CONFLICT,
+        };
+
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ShuttingDownResponse {
+    error: &'static str,
+    shutting_down: bool,
+}
+
+fn shutting_down() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ShuttingDownResponse {
+            error: "the matchmaker is shutting down",
+            shutting_down: true,
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    server_id: ServerId,
+    /// Where players connect to, as `host:port`.
+    address: String,
+    region: String,
+    version: String,
+    max_players: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RegisterResponse {
+    heartbeat_interval_secs: u64,
+    /// Servers that haven't sent a heartbeat for this long are evicted, and have to register
+    /// again.
+    heartbeat_timeout_secs: u64,
+}
+
+async fn register(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+    Json(request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<RegisterResponse>), Response> {
+    caller
+        .require(GAME_SERVER_SCOPE)
+        .map_err(IntoResponse::into_response)?;
+
+    if state.matchmaker.is_shutting_down() {
+        return Err(shutting_down());
+    }
+    if request.max_players == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "max_players must be greater than 0",
+        )
+            .into_response());
+    }
+    if request.max_players > MAX_PLAYERS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("max_players must be at most {MAX_PLAYERS}"),
+        )
+            .into_response());
+    }
+
+    let info = ServerInfo {
+        credential_id: caller.0.credential_id,
+        address: request.address,
+        region: request.region,
+        version: request.version,
+        max_players: request.max_players,
+        current_players: 0,
+        state: ServerState::Startup,
+    };
+    let added = state
+        .matchmaker
+        .add_server(request.server_id, info)
+        .map_err(IntoResponse::into_response)?;
+
+    let config = state.matchmaker.config();
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+
+    Ok((
+        status,
+        Json(RegisterResponse {
+            heartbeat_interval_secs: config.server_heartbeat_interval().as_secs(),
+            heartbeat_timeout_secs: config.server_heartbeat_timeout.as_secs(),
+        }),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct HeartbeatRequest {
+    current_players: usize,
+    /// Moves the server along its lifecycle, like the `ready` and `drain` endpoints.
+    #[serde(default)]
+    state: Option<ServerState>,
+}
+
+async fn heartbeat(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+    Path(server_id): Path<ServerId>,
+    Json(request): Json<HeartbeatRequest>,
+) -> Result<StatusCode, Response> {
+    caller
+        .require(GAME_SERVER_SCOPE)
+        .map_err(IntoResponse::into_response)?;
+
+    if state.matchmaker.is_shutting_down() {
+        // unknown servers have nothing to be told
+        let _ = state
+            .matchmaker
+            .tell_server_of_shutdown(&server_id, &caller.0.credential_id);
+        return Err(shutting_down());
+    }
+    if request.current_players > MAX_PLAYERS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("current_players must be at most {MAX_PLAYERS}"),
+        )
+            .into_response());
+    }
+
+    state
+        .matchmaker
+        .server_heartbeat(
+            &server_id,
+            &caller.0.credential_id,
+            request.current_players,
+            request.state,
+        )
+        .map_err(IntoResponse::into_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn ready(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+    Path(server_id): Path<ServerId>,
+) -> Result<StatusCode, Response> {
+    caller
+        .require(GAME_SERVER_SCOPE)
+        .map_err(IntoResponse::into_response)?;
+
+    state
+        .matchmaker
+        .report_server_ready(&server_id, &caller.0.credential_id)
+        .map_err(IntoResponse::into_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// servers may drain themselves, admins any server
+async fn drain(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+    Path(server_id): Path<ServerId>,
+) -> Result<StatusCode, Response> {
+    let drained_by = if caller.require(MANAGE_SERVERS_SCOPE).is_ok() {
+        DrainedBy::Admin
+    } else {
+        caller
+            .require(GAME_SERVER_SCOPE)
+            .map_err(IntoResponse::into_response)?;
+        DrainedBy::Server(&caller.0.credential_id)
+    };
+
+    state
+        .matchmaker
+        .drain_server(&server_id, drained_by)
+        .map_err(IntoResponse::into_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+struct ServerResponse {
+    server_id: ServerId,
+    address: String,
+    region: String,
+    version: String,
+    max_players: usize,
+    current_players: usize,
+    /// Held for matched players who haven't joined yet.
+    reserved_slots: usize,
+    state: ServerState,
+    secs_in_state: u64,
+}
+
+async fn list(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+) -> Result<Json<Vec<ServerResponse>>, Response> {
+    caller
+        .require(MANAGE_SERVERS_SCOPE)
+        .map_err(IntoResponse::into_response)?;
+
+    let now = state.matchmaker.now();
+    let servers = state
+        .matchmaker
+        .servers()
+        .into_iter()
+        .map(|server| ServerResponse {
+            server_id: server.server_id,
+            address: server.info.address,
+            region: server.info.region,
+            version: server.info.version,
+            max_players: server.info.max_players,
+            current_players: server.info.current_players,
+            reserved_slots: server.reserved_slots,
+            state: server.info.state,
+            secs_in_state: now.saturating_duration_since(server.state_since).as_secs(),
+        })
+        .collect();
+
+    Ok(Json(servers))
+}
+
+#[derive(Debug, Serialize)]
+struct TransitionResponse {
+    server_id: ServerId,
+    /// Missing if the server was just registered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<ServerState>,
+    /// Missing if the server was removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<ServerState>,
+    cause: TransitionCause,
+    secs_ago: u64,
+}
+
+async fn transitions(
+    State(state): State<AppState>,
+    caller: ServiceCaller,
+) -> Result<Json<Vec<TransitionResponse>>, Response> {
+    caller
+        .require(MANAGE_SERVERS_SCOPE)
+        .map_err(IntoResponse::into_response)?;
+
+    let now = state.matchmaker.now();
+    let transitions = state
+        .matchmaker
+        .recent_transitions()
+        .into_iter()
+        .map(|transition| TransitionResponse {
+            server_id: transition.server_id,
+            from: transition.from,
+            to: transition.to,
+            cause: transition.cause,
+            secs_ago: now.saturating_duration_since(transition.at).as_secs(),
+        })
+        .collect();
+
+    Ok(Json(transitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        auth::{ServiceCredential, StubAuth, UserId, GAME_SERVER_SCOPE, MANAGE_SERVERS_SCOPE},
+        matchmaker::Matchmaker,
+    };
+
+    fn credential(credential_id: &str, scopes: &[&str]) -> ServiceCredential {
+        ServiceCredential {
+            credential_id: credential_id.to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    async fn serve() -> (String, Arc<Matchmaker<UserId>>) {
+        let auth = StubAuth {
+            services: HashMap::from([
+                (
+                    "server-a-key".to_string(),
+                    credential("server-a", &[GAME_SERVER_SCOPE]),
+                ),
+                (
+                    "server-b-key".to_string(),
+                    credential("server-b", &[GAME_SERVER_SCOPE]),
+                ),
+                (
+                    "admin-key".to_string(),
+                    credential("admin", &["manage_credentials"]),
+                ),
+                (
+                    "ops-key".to_string(),
+                    credential("ops", &[MANAGE_SERVERS_SCOPE]),
+                ),
+            ]),
+            ..StubAuth::default()
+        };
+
+        let (address, matchmaker) = auth.serve(routes()).await;
+        (format!("http://{address}"), matchmaker)
+    }
+
+    fn registration(server_id: &str) -> Value {
+        json!({
+            "server_id": server_id,
+            "address": "203.0.113.7:7777",
+            "region": "eu",
+            "version": "1.2.0",
+            "max_players": 16,
+        })
+    }
+
+    async fn post(url: &str, key: Option<&str>, body: &Value) -> reqwest::Response {
+        let mut request = reqwest::Client::new().post(url).json(body);
+        if let Some(key) = key {
+            request = request.bearer_auth(key);
+        }
+
+        request.send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn registering_requires_a_game_server_key() {
+        let (url, matchmaker) = serve().await;
+        let servers = format!("{url}/servers");
+
+        for (key, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("unknown-key"), StatusCode::UNAUTHORIZED),
+            (Some("admin-key"), StatusCode::FORBIDDEN),
+            (Some("unavailable"), StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let response = post(&servers, key, &registration("a")).await;
+            assert_eq!(response.status(), status, "{key:?}");
+        }
+
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Normal), 0);
+    }
+
+    #[tokio::test]
+    async fn registered_servers_send_heartbeats() {
+        let (url, matchmaker) = serve().await;
+        let servers = format!("{url}/servers");
+        let heartbeat = format!("{url}/servers/a/heartbeat");
+
+        let response = post(&servers, Some("server-a-key"), &registration("a")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["heartbeat_interval_secs"], 5);
+        assert_eq!(body["heartbeat_timeout_secs"], 15);
+        assert_eq!(
+            matchmaker.server_address(&ServerId("a".to_string())),
+            Some("203.0.113.7:7777".to_string())
+        );
+
+        let response = post(&servers, Some("server-a-key"), &registration("a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = json!({ "current_players": 3, "state": "draining" });
+        let response = post(&heartbeat, Some("server-a-key"), &body).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Draining), 1);
+
+        // someone else's server can't be taken over or kept alive
+        let response = post(&servers, Some("server-b-key"), &registration("a")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = post(&heartbeat, Some("server-b-key"), &body).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = post(
+            &format!("{url}/servers/unknown/heartbeat"),
+            Some("server-a-key"),
+            &body,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn servers_are_told_when_the_matchmaker_shuts_down() {
+        let (url, matchmaker) = serve().await;
+        let servers = format!("{url}/servers");
+        let heartbeat = format!("{url}/servers/a/heartbeat");
+
+        post(&servers, Some("server-a-key"), &registration("a")).await;
+        matchmaker.shutdown();
+
+        // still listening for the server's next heartbeat
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = post(&servers, Some("server-b-key"), &registration("b")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json!({ "current_players": 0 });
+        let response = post(&heartbeat, Some("server-a-key"), &body).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["shutting_down"], true);
+
+        // every server has been told, so the listener can close
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            matchmaker.servers_told_of_shutdown(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn player_counts_are_capped() {
+        let (url, matchmaker) = serve().await;
+        let servers = format!("{url}/servers");
+
+        let mut too_large = registration("a");
+        too_large["max_players"] = json!(MAX_PLAYERS + 1);
+        let response = post(&servers, Some("server-a-key"), &too_large).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Startup), 0);
+
+        post(&servers, Some("server-a-key"), &registration("a")).await;
+        let body = json!({ "current_players": usize::MAX });
+        let response = post(
+            &format!("{url}/servers/a/heartbeat"),
+            Some("server-a-key"),
+            &body,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn get(url: &str, key: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth(key)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn servers_go_through_their_lifecycle() {
+        let (url, matchmaker) = serve().await;
+        let id = ServerId("a".to_string());
+        let none = json!({});
+
+        post(
+            &format!("{url}/servers"),
+            Some("server-a-key"),
+            &registration("a"),
+        )
+        .await;
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Startup), 1);
+
+        let response = post(
+            &format!("{url}/servers/a/ready"),
+            Some("server-a-key"),
+            &none,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(matchmaker.count_servers_in_state(ServerState::Normal), 1);
+
+        // only admins may drain someone else's server
+        let response = post(
+            &format!("{url}/servers/a/drain"),
+            Some("server-b-key"),
+            &none,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = post(&format!("{url}/servers/a/drain"), Some("ops-key"), &none).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // a heartbeat didn't report any players yet, so it is gone right away
+        assert!(matchmaker.server_address(&id).is_none());
+
+        let response = post(
+            &format!("{url}/servers/a/ready"),
+            Some("server-a-key"),
+            &none,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = get(&format!("{url}/servers/transitions"), "server-a-key").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let transitions: Vec<Value> = get(&format!("{url}/servers/transitions"), "ops-key")
+            .await
+            .json()
+            .await
+            .unwrap();
+        let causes: Vec<_> = transitions
+            .iter()
+            .map(|transition| transition["cause"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            causes,
+            [
+                "registered",
+                "readiness_report",
+                "drained_by_admin",
+                "drained"
+            ]
+        );
+        assert!(transitions[0].get("from").is_none());
+        assert!(transitions[3].get("to").is_none());
+    }
+
+    #[tokio::test]
+    async fn draining_servers_stay_listed_until_empty() {
+        let (url, _matchmaker) = serve().await;
+        let heartbeat = format!("{url}/servers/a/heartbeat");
+
+        post(
+            &format!("{url}/servers"),
+            Some("server-a-key"),
+            &registration("a"),
+        )
+        .await;
+        let body = json!({ "current_players": 4, "state": "normal" });
+        post(&heartbeat, Some("server-a-key"), &body).await;
+        let body = json!({ "current_players": 4, "state": "draining" });
+        post(&heartbeat, Some("server-a-key"), &body).await;
+
+        let servers: Vec<Value> = get(&format!("{url}/servers"), "ops-key")
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0]["state"], "draining");
+        assert_eq!(servers[0]["current_players"], 4);
+
+        // draining can't be undone, but a server still reporting normal keeps its heartbeat
+        let body = json!({ "current_players": 2, "state": "normal" });
+        let response = post(&heartbeat, Some("server-a-key"), &body).await;
+        assert!(response.status().is_success());
+        let servers: Vec<Value> = get(&format!("{url}/servers"), "ops-key")
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(servers[0]["state"], "draining");
+        assert_eq!(servers[0]["current_players"], 2);
+
+        let body = json!({ "current_players": 0 });
+        post(&heartbeat, Some("server-a-key"), &body).await;
+        let servers: Vec<Value> = get(&format!("{url}/servers"), "ops-key")
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert!(servers.is_empty());
+    }
+}